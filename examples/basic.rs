@@ -5,7 +5,7 @@ extern crate env_logger;
 
 use std::thread;
 
-use memcached::proto::{CasOperation, NoReplyOperation, Operation, ProtoType};
+use memcached::proto::{CasOperation, NoReplyOperation, ProtoType};
 use memcached::Client;
 
 fn main() {
@@ -0,0 +1,447 @@
+//! Command line client to poke at a cluster with the exact routing of this crate
+//!
+//! ```text
+//! cargo run --example mc_cli -- --server tcp://10.0.0.1:11211 --server tcp://10.0.0.2:11211=2 get user:42
+//! ```
+//!
+//! Options go before the subcommand:
+//!
+//! * `--server <address>[=<weight>]`, repeated for every server, with addresses in the form
+//!   `ClientBuilder::add_server` takes. `tcp://127.0.0.1:11211` if there is none.
+//! * `--protocol binary`, the only protocol there is
+//! * `--timeout <secs>`, connect, read and write timeout
+//! * `--sasl <username>:<password>`
+//!
+//! Subcommands:
+//!
+//! * `get <key>` prints `<key>\t<flags>\t<cas>\t<value>`
+//! * `set <key> <value> [--flags <n>] [--exptime <secs>]`
+//! * `delete <key>`
+//! * `incr <key> [<amount>] [--initial <n>] [--exptime <secs>]` prints the new value
+//! * `touch <key> <exptime>`
+//! * `stat [<address>]` prints `<address>\t<name>\t<value>` for every server or the one given
+//! * `flush [<exptime>]` flushes every server
+//! * `server-for-key <key>` prints the address of the server owning the key
+//! * `dump [<key>...]` prints the line of `get` for each key found, keys are read one per line from
+//!   stdin if none is given
+//! * `warm [--exptime <secs>]` adds the items of `dump` lines read from stdin, keeping values
+//!   already there, and prints how many it stored
+//!
+//! Keys and values are printed with the ASCII escapes of Rust (`\n`, `\t`, `\xff`...) so that every
+//! item is one line, and `warm` reads them back. The TTL of an item cannot be read without touching
+//! it, so it is not printed.
+//!
+//! The exit code is 0 on success, 1 for a missing key, 2 for invalid arguments, 3 when the key
+//! exists or the item was not stored, 4 for any other error status of the server, 5 for a
+//! connection error or a timeout, and 6 for anything else.
+
+extern crate memcached;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use memcached::proto::binary::Status;
+use memcached::proto::{self, ProtoType};
+use memcached::Client;
+
+const EXIT_OK: i32 = 0;
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_EXISTS: i32 = 3;
+const EXIT_SERVER_ERROR: i32 = 4;
+const EXIT_CONNECTION: i32 = 5;
+const EXIT_OTHER: i32 = 6;
+
+const DEFAULT_SERVER: &str = "tcp://127.0.0.1:11211";
+
+struct Options {
+    servers: Vec<(String, usize)>,
+    protocol: ProtoType,
+    timeout: Option<Duration>,
+    sasl: Option<(String, String)>,
+    command: Vec<String>,
+}
+
+enum Failure {
+    Usage(String),
+    Memcached(proto::Error),
+}
+
+impl From<proto::Error> for Failure {
+    fn from(err: proto::Error) -> Failure {
+        Failure::Memcached(err)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Memcached(proto::Error::IoError(err))
+    }
+}
+
+fn usage<T>(message: String) -> Result<T, Failure> {
+    Err(Failure::Usage(message))
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, Failure> {
+    value
+        .parse()
+        .or_else(|_| usage(format!("{} should be a number, got {:?}", name, value)))
+}
+
+fn parse_options(args: &[String]) -> Result<Options, Failure> {
+    let mut options = Options {
+        servers: Vec::new(),
+        protocol: ProtoType::Binary,
+        timeout: None,
+        sasl: None,
+        command: Vec::new(),
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            options.command = Some(arg).into_iter().chain(iter).cloned().collect();
+            break;
+        }
+        let value = match iter.next() {
+            Some(value) => value,
+            None => return usage(format!("missing value for {}", arg)),
+        };
+        match &arg[..] {
+            "--server" => options.servers.push(match value.rsplit_once('=') {
+                Some((addr, weight)) => (addr.to_owned(), number("weight", weight)?),
+                None => (value.clone(), 1),
+            }),
+            "--protocol" => match &value[..] {
+                "binary" => options.protocol = ProtoType::Binary,
+                _ => return usage(format!("unknown protocol {:?}", value)),
+            },
+            "--timeout" => options.timeout = Some(Duration::from_secs_f64(number("timeout", value)?)),
+            "--sasl" => match value.split_once(':') {
+                Some((username, password)) => options.sasl = Some((username.to_owned(), password.to_owned())),
+                None => return usage("--sasl should be <username>:<password>".to_owned()),
+            },
+            _ => return usage(format!("unknown option {}", arg)),
+        }
+    }
+    if options.servers.is_empty() {
+        options.servers.push((DEFAULT_SERVER.to_owned(), 1));
+    }
+    if options.command.is_empty() {
+        return usage("missing subcommand".to_owned());
+    }
+    Ok(options)
+}
+
+fn connect(options: &Options) -> io::Result<Client> {
+    let mut builder = Client::builder(options.protocol);
+    for (addr, weight) in options.servers.iter() {
+        builder = builder.add_server(addr, *weight);
+    }
+    if let Some(timeout) = options.timeout {
+        builder = builder
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .write_timeout(timeout);
+    }
+    if let Some((ref username, ref password)) = options.sasl {
+        builder = builder.sasl(username, password);
+    }
+    builder.build()
+}
+
+/// Positional arguments and `--name value` options of a subcommand, which takes `names`
+fn split_args<'a>(args: &'a [String], names: &[&str]) -> Result<(Vec<&'a str>, HashMap<&'a str, &'a str>), Failure> {
+    let mut positional = Vec::new();
+    let mut named = HashMap::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.strip_prefix("--") {
+            Some(name) if names.contains(&name) => match iter.next() {
+                Some(value) => {
+                    named.insert(name, &value[..]);
+                }
+                None => return usage(format!("missing value for {}", arg)),
+            },
+            Some(_) => return usage(format!("unknown option {}", arg)),
+            None => positional.push(&arg[..]),
+        }
+    }
+    Ok((positional, named))
+}
+
+fn item_line(key: &[u8], flags: u32, cas: u64, value: &[u8]) -> String {
+    format!("{}\t{}\t{}\t{}", key.escape_ascii(), flags, cas, value.escape_ascii())
+}
+
+/// Reverse of `escape_ascii`
+fn unescape(escaped: &str) -> Result<Vec<u8>, Failure> {
+    let mut bytes = escaped.bytes();
+    let mut unescaped = Vec::with_capacity(escaped.len());
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+        unescaped.push(match bytes.next() {
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'n') => b'\n',
+            Some(quoted @ (b'\\' | b'\'' | b'"')) => quoted,
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => byte,
+                    None => return usage(format!("invalid escape in {:?}", escaped)),
+                }
+            }
+            _ => return usage(format!("invalid escape in {:?}", escaped)),
+        });
+    }
+    Ok(unescaped)
+}
+
+fn run_command(
+    client: &mut Client,
+    command: &[String],
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), Failure> {
+    let (name, args) = (&command[0][..], &command[1..]);
+    let (positional, named) = split_args(args, &["flags", "exptime", "initial"])?;
+    let option = |name: &str, default: u64| named.get(name).map_or(Ok(default), |value| number(name, value));
+    let arity = |expected: std::ops::RangeInclusive<usize>| {
+        if expected.contains(&positional.len()) {
+            Ok(())
+        } else {
+            usage(format!("wrong number of arguments for {}", name))
+        }
+    };
+
+    match name {
+        "get" => {
+            arity(1..=1)?;
+            let key = positional[0].as_bytes();
+            let (value, flags, cas) = client.get_cas(key)?;
+            writeln!(out, "{}", item_line(key, flags, cas, &value))?;
+        }
+        "set" => {
+            arity(2..=2)?;
+            let flags = option("flags", 0)? as u32;
+            let exptime = option("exptime", 0)? as u32;
+            client.set(positional[0].as_bytes(), positional[1].as_bytes(), flags, exptime)?;
+        }
+        "delete" => {
+            arity(1..=1)?;
+            client.delete(positional[0].as_bytes())?;
+        }
+        "incr" => {
+            arity(1..=2)?;
+            let amount = positional.get(1).map_or(Ok(1), |amount| number("amount", amount))?;
+            let initial = option("initial", 0)?;
+            let exptime = option("exptime", 0)? as u32;
+            let value = client.increment(positional[0].as_bytes(), amount, initial, exptime)?;
+            writeln!(out, "{}", value)?;
+        }
+        "touch" => {
+            arity(2..=2)?;
+            client.touch(positional[0].as_bytes(), number("exptime", positional[1])?)?;
+        }
+        "stat" => {
+            arity(0..=1)?;
+            let servers: Vec<String> = match positional.first() {
+                Some(addr) => vec![addr.to_string()],
+                None => client.config().servers.iter().map(|(addr, _)| addr.clone()).collect(),
+            };
+            for addr in servers {
+                for (stat, value) in client.server_stats(&addr)? {
+                    writeln!(out, "{}\t{}\t{}", addr, stat, value)?;
+                }
+            }
+        }
+        "flush" => {
+            arity(0..=1)?;
+            let exptime = positional.first().map_or(Ok(0), |exptime| number("exptime", exptime))?;
+            let servers: Vec<String> = client.config().servers.iter().map(|(addr, _)| addr.clone()).collect();
+            for addr in servers {
+                client.flush_server(&addr, exptime)?;
+            }
+        }
+        "server-for-key" => {
+            arity(1..=1)?;
+            writeln!(out, "{}", client.route_chain(positional[0].as_bytes(), 0)[0])?;
+        }
+        "dump" => {
+            let keys: Vec<Vec<u8>> = if positional.is_empty() {
+                let lines: io::Result<Vec<String>> = input.lines().collect();
+                lines?
+                    .into_iter()
+                    .filter(|line| !line.is_empty())
+                    .map(String::into_bytes)
+                    .collect()
+            } else {
+                positional.iter().map(|key| key.as_bytes().to_vec()).collect()
+            };
+            for key in keys {
+                if let Some((value, flags, cas)) = proto::miss_as_none(client.get_cas(&key))? {
+                    writeln!(out, "{}", item_line(&key, flags, cas, &value))?;
+                }
+            }
+        }
+        "warm" => {
+            arity(0..=0)?;
+            let exptime = option("exptime", 0)? as u32;
+            let mut stored = 0;
+            for line in input.lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split('\t').collect();
+                let (key, flags, value) = match fields[..] {
+                    [key, flags, _cas, value] => (unescape(key)?, number("flags", flags)?, unescape(value)?),
+                    _ => return usage(format!("not a dump line: {:?}", line)),
+                };
+                match client.add(&key, &value, flags, exptime) {
+                    Ok(()) => stored += 1,
+                    Err(ref err) if err.status() == Some(Status::KeyExists) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            writeln!(out, "{}", stored)?;
+        }
+        _ => return usage(format!("unknown subcommand {}", name)),
+    }
+    Ok(())
+}
+
+fn exit_code(err: &proto::Error) -> i32 {
+    match err.status() {
+        Some(Status::KeyNotFound) => EXIT_NOT_FOUND,
+        Some(Status::KeyExists) | Some(Status::ItemNotStored) => EXIT_EXISTS,
+        Some(_) => EXIT_SERVER_ERROR,
+        None => match *err.root() {
+            proto::Error::IoError(_) | proto::Error::Timeout { .. } | proto::Error::ConnectionPoisoned { .. } => {
+                EXIT_CONNECTION
+            }
+            _ => EXIT_OTHER,
+        },
+    }
+}
+
+/// Run the command line `args`, without the program name, and return the exit code
+fn run(args: &[String], input: &mut dyn BufRead, out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    let result = parse_options(args).and_then(|options| {
+        let mut client = connect(&options)?;
+        run_command(&mut client, &options.command, input, out)
+    });
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(Failure::Usage(message)) => {
+            let _ = writeln!(err, "error: {}", message);
+            let _ = writeln!(err, "usage: mc_cli [--server <address>[=<weight>]]... <subcommand> [<args>...]");
+            EXIT_USAGE
+        }
+        Err(Failure::Memcached(failure)) => {
+            let _ = writeln!(err, "error: {}", failure);
+            exit_code(&failure)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdin = io::stdin();
+    let code = run(&args, &mut stdin.lock(), &mut io::stdout(), &mut io::stderr());
+    process::exit(code);
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod test {
+    use memcached::test_support::MockServer;
+
+    use super::{run, unescape, EXIT_CONNECTION, EXIT_NOT_FOUND, EXIT_OK, EXIT_SERVER_ERROR, EXIT_USAGE};
+
+    /// Run the command line `args` against `mock` with `input` on stdin, returning the exit code
+    /// and stdout
+    fn cli(mock: &MockServer, args: &[&str], input: &str) -> (i32, String) {
+        let mut full = vec![
+            "--server".to_owned(),
+            mock.url(),
+            "--timeout".to_owned(),
+            "5".to_owned(),
+        ];
+        full.extend(args.iter().map(|arg| arg.to_string()));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&full, &mut input.as_bytes(), &mut out, &mut err);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_unescape() {
+        let raw = b"tab\there \"quoted\" \\ \xff\x00\n";
+        assert_eq!(unescape(&raw.escape_ascii().to_string()).ok().unwrap(), raw.to_vec());
+        assert!(unescape("bad \\q").is_err());
+        assert!(unescape("short \\x4").is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        assert_eq!(cli(&mock, &["set", "test:cli", "value\t1", "--flags", "7"], ""), (EXIT_OK, String::new()));
+        let (code, out) = cli(&mock, &["get", "test:cli"], "");
+        assert_eq!(code, EXIT_OK);
+        let fields: Vec<&str> = out.trim_end().split('\t').collect();
+        assert_eq!((fields[0], fields[1], fields[3]), ("test:cli", "7", "value\\t1"));
+
+        assert_eq!(cli(&mock, &["get", "test:cli_missing"], "").0, EXIT_NOT_FOUND);
+        assert_eq!(cli(&mock, &["incr", "test:cli_counter", "--initial", "5"], ""), (EXIT_OK, "5\n".to_owned()));
+        assert_eq!(cli(&mock, &["incr", "test:cli_counter", "2"], ""), (EXIT_OK, "7\n".to_owned()));
+        assert_eq!(cli(&mock, &["touch", "test:cli", "60"], "").0, EXIT_OK);
+        assert_eq!(cli(&mock, &["server-for-key", "test:cli"], ""), (EXIT_OK, format!("{}\n", mock.url())));
+        assert!(cli(&mock, &["stat"], "").1.contains(&format!("{}\ttime\t", mock.url())));
+
+        // A dump warms the cache back after a flush, without overwriting what is there
+        let (code, dump) = cli(&mock, &["dump"], "test:cli\ntest:cli_counter\ntest:cli_missing\n");
+        assert_eq!((code, dump.lines().count()), (EXIT_OK, 2));
+        assert_eq!(cli(&mock, &["flush"], "").0, EXIT_OK);
+        assert_eq!(mock.item_count(), 0);
+        assert_eq!(cli(&mock, &["set", "test:cli_counter", "9"], "").0, EXIT_OK);
+        assert_eq!(cli(&mock, &["warm"], &dump), (EXIT_OK, "1\n".to_owned()));
+        let without_cas = |line: &str| {
+            let mut fields: Vec<String> = line.trim_end().split('\t').map(str::to_owned).collect();
+            fields.remove(2);
+            fields
+        };
+        assert_eq!(without_cas(&cli(&mock, &["dump", "test:cli"], "").1), without_cas(dump.lines().next().unwrap()));
+        assert!(cli(&mock, &["get", "test:cli_counter"], "").1.ends_with("\t9\n"));
+
+        assert_eq!(cli(&mock, &["delete", "test:cli"], "").0, EXIT_OK);
+        assert_eq!(cli(&mock, &["delete", "test:cli"], "").0, EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        assert_eq!(cli(&mock, &["frobnicate"], "").0, EXIT_USAGE);
+        assert_eq!(cli(&mock, &["get"], "").0, EXIT_USAGE);
+        assert_eq!(cli(&mock, &["set", "test:cli", "value", "--flags", "many"], "").0, EXIT_USAGE);
+        assert_eq!(cli(&mock, &["warm"], "not a dump line\n").0, EXIT_USAGE);
+        assert_eq!(cli(&mock, &["set", "test:cli", "value"], "").0, EXIT_OK);
+        assert_eq!(cli(&mock, &["incr", "test:cli"], "").0, EXIT_SERVER_ERROR);
+
+        let args: Vec<String> = ["--server", "tcp://127.0.0.1:1", "get", "test:cli"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(run(&args, &mut &b""[..], &mut Vec::new(), &mut Vec::new()), EXIT_CONNECTION);
+    }
+}
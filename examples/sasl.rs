@@ -1,6 +1,6 @@
 extern crate memcached;
 
-use memcached::proto::{Operation, ProtoType};
+use memcached::proto::ProtoType;
 use memcached::Client;
 
 fn main() {
@@ -0,0 +1,199 @@
+//! Mixed workload against mock servers that keep getting killed and restarted
+//!
+//! ```text
+//! cargo run --example soak --features test-support -- --duration 60 --rate 2000
+//! ```
+//!
+//! Options are `--duration <secs>` (chaos phase), `--recovery <secs>` (phase after all servers
+//! are back), `--rate <ops/sec>`, `--threads <n>`, `--servers <n>` and `--max-error-rate <ratio>`.
+//!
+//! At the end it checks that no worker panicked, that the error rate during recovery stays below
+//! the limit and that no sockets were leaked. The `Client` does not reconnect by itself, so workers
+//! throw a client away after its first error and connect again.
+
+extern crate memcached;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use memcached::proto::{MultiOperation, ProtoType};
+use memcached::test_support::{check_error_rate, Chaos, ChaosConfig, FdWatch, MockServer};
+use memcached::Client;
+
+struct Options {
+    duration: Duration,
+    recovery: Duration,
+    rate: u64,
+    threads: usize,
+    servers: usize,
+    max_error_rate: f64,
+}
+
+fn parse_options() -> Options {
+    let mut args: HashMap<String, String> = HashMap::new();
+    let mut iter = env::args().skip(1);
+    while let Some(name) = iter.next() {
+        let value = iter.next().unwrap_or_else(|| panic!("missing value for {}", name));
+        args.insert(name.trim_start_matches("--").to_owned(), value);
+    }
+    let get = |name: &str, default: &str| args.get(name).cloned().unwrap_or_else(|| default.to_owned());
+
+    Options {
+        duration: Duration::from_secs(get("duration", "10").parse().unwrap()),
+        recovery: Duration::from_secs(get("recovery", "3").parse().unwrap()),
+        rate: get("rate", "1000").parse().unwrap(),
+        threads: get("threads", "4").parse().unwrap(),
+        servers: get("servers", "3").parse().unwrap(),
+        max_error_rate: get("max-error-rate", "0.01").parse().unwrap(),
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    ops: AtomicU64,
+    errors: AtomicU64,
+    recovered_ops: AtomicU64,
+    recovered_errors: AtomicU64,
+}
+
+fn run_op(client: &mut Client, i: u64) -> bool {
+    let key = format!("soak:{}", i % 1000).into_bytes();
+    match fastrand::u8(..10) {
+        0..=4 => match client.get(&key) {
+            Ok(_) => true,
+            Err(ref err) if err.status().is_some() => true,
+            Err(_) => false,
+        },
+        5..=7 => client.set(&key, b"soak value", 0, 60).is_ok(),
+        8 => client.increment(b"soak:counter", 1, 0, 60).is_ok(),
+        _ => {
+            let keys: Vec<Vec<u8>> = (0..8)
+                .map(|j| format!("soak:{}", (i + j) % 1000).into_bytes())
+                .collect();
+            // `get_multi` only supports a single server, a dry-run `touch_multi` fans out instead
+            let keys: Vec<(&[u8], u32)> = keys.iter().map(|k| (&k[..], 60)).collect();
+            client
+                .touch_multi(&keys, true)
+                .map(|summary| summary.errors.is_empty())
+                .unwrap_or(false)
+        }
+    }
+}
+
+fn worker(
+    urls: Vec<String>,
+    pause: Duration,
+    stop: Arc<AtomicBool>,
+    recovered: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+) {
+    let servers: Vec<(&str, usize)> = urls.iter().map(|url| (&url[..], 1)).collect();
+    let mut client = None;
+    let mut i = 0;
+
+    while !stop.load(Ordering::SeqCst) {
+        let started = Instant::now();
+        let ok = match client {
+            Some(ref mut c) => run_op(c, i),
+            None => match Client::connect(&servers, ProtoType::Binary) {
+                Ok(mut c) => {
+                    let ok = run_op(&mut c, i);
+                    client = Some(c);
+                    ok
+                }
+                Err(_) => false,
+            },
+        };
+        if !ok {
+            client = None;
+        }
+
+        let (ops, errors) = if recovered.load(Ordering::SeqCst) {
+            (&counters.recovered_ops, &counters.recovered_errors)
+        } else {
+            (&counters.ops, &counters.errors)
+        };
+        ops.fetch_add(1, Ordering::SeqCst);
+        if !ok {
+            errors.fetch_add(1, Ordering::SeqCst);
+        }
+
+        i += 1;
+        if let Some(rest) = pause.checked_sub(started.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let opts = parse_options();
+
+    let servers: Vec<MockServer> = (0..opts.servers)
+        .map(|_| MockServer::start("127.0.0.1:0").unwrap())
+        .collect();
+    let urls: Vec<String> = servers.iter().map(|server| server.url()).collect();
+    info!("Soaking {:?} for {:?}", urls, opts.duration);
+
+    let fds = FdWatch::new();
+    let stop = Arc::new(AtomicBool::new(false));
+    let recovered = Arc::new(AtomicBool::new(false));
+    let counters = Arc::new(Counters::default());
+    let pause = Duration::from_secs_f64(opts.threads as f64 / opts.rate as f64);
+
+    let workers: Vec<_> = (0..opts.threads)
+        .map(|_| {
+            let (urls, stop, recovered, counters) = (urls.clone(), stop.clone(), recovered.clone(), counters.clone());
+            thread::spawn(move || worker(urls, pause, stop, recovered, counters))
+        })
+        .collect();
+
+    let chaos = Chaos::spawn(servers, ChaosConfig::default());
+    thread::sleep(opts.duration);
+    let kills = chaos.kills();
+    let servers = chaos.stop();
+
+    // Give every worker a chance to notice its broken client before measuring
+    thread::sleep(Duration::from_millis(500));
+    recovered.store(true, Ordering::SeqCst);
+    thread::sleep(opts.recovery);
+    stop.store(true, Ordering::SeqCst);
+
+    let panics = workers.into_iter().map(|w| w.join()).filter(|r| r.is_err()).count();
+    drop(servers);
+    // Connection threads of the stopped servers close their sockets asynchronously
+    thread::sleep(Duration::from_millis(100));
+
+    let ops = counters.ops.load(Ordering::SeqCst);
+    let errors = counters.errors.load(Ordering::SeqCst);
+    let recovered_ops = counters.recovered_ops.load(Ordering::SeqCst);
+    let recovered_errors = counters.recovered_errors.load(Ordering::SeqCst);
+    println!("{} kills, {} ops with {} errors under chaos", kills, ops, errors);
+    println!("{} ops with {} errors after recovery", recovered_ops, recovered_errors);
+
+    let mut failures = Vec::new();
+    if panics > 0 {
+        failures.push(format!("{} workers panicked", panics));
+    }
+    if let Err(err) = check_error_rate(recovered_errors, recovered_ops, opts.max_error_rate) {
+        failures.push(err);
+    }
+    if let Err(err) = fds.check(0) {
+        failures.push(err);
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("FAILED: {}", failure);
+        }
+        std::process::exit(1);
+    }
+    println!("OK");
+}
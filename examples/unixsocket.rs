@@ -3,7 +3,7 @@ extern crate memcached;
 extern crate log;
 extern crate env_logger;
 
-use memcached::proto::{CasOperation, NoReplyOperation, Operation, ProtoType};
+use memcached::proto::{CasOperation, NoReplyOperation, ProtoType};
 use memcached::Client;
 
 fn main() {
@@ -0,0 +1,673 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Builder for `Client`
+
+use std::io;
+use std::time::Duration;
+
+use super::cipher::Encryption;
+use super::observer::Observer;
+use super::prefetch::Prefetcher;
+use super::stats::{Classifier, PrefixTrie};
+use super::touch_cache::TouchCache;
+use super::{
+    AdaptiveTimeouts, CasConflict, Client, ClientConfig, Clock, ConnectOpts, OpEvent, Quirks, Sasl, ValueCipher,
+    ValueFramer, DEFAULT_CAS_BACKOFF_BASE, DEFAULT_CAS_BACKOFF_CAP,
+};
+use crate::proto;
+
+/// Default number of consistent hash points per unit of server weight
+pub const DEFAULT_REPLICAS_PER_NODE: usize = 1;
+
+/// Read and write buffer size of each connection with `ConnectPreset::BulkTransfer`
+pub const BULK_TRANSFER_BUFFER_CAPACITY: usize = 256 * 1024;
+
+/// Suggested wait before retrying after a server answered `Busy`, see `ClientBuilder::busy_backpressure`
+pub const DEFAULT_BUSY_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+/// Longest value of a server that does not report its `item_size_max`, memcached's default
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// Connection settings tuned for a kind of workload, set with `ClientBuilder::preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectPreset {
+    /// Tuned for latency: `TCP_NODELAY` on, default buffers, every request is flushed right away
+    Default,
+    /// Tuned for throughput of large pipelined loads, e.g. filling a cache with `set_noreply`
+    ///
+    /// Turns `TCP_NODELAY` off, uses `BULK_TRANSFER_BUFFER_CAPACITY` byte buffers, and leaves noreply
+    /// requests in the write buffer until it fills up or an operation waits for a response. Call
+    /// `drain_errors` at the end of a load to send what is left and collect the errors. Single
+    /// requests with a response can take longer, as Nagle's algorithm may delay them.
+    BulkTransfer,
+}
+
+/// Builder for `Client`
+///
+/// ```ignore
+/// use memcached::client::ClientBuilder;
+/// use memcached::proto::ProtoType;
+///
+/// let client = ClientBuilder::new(ProtoType::Binary)
+///     .add_server("tcp://127.0.0.1:11211", 1)
+///     .replicas_per_node(160)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ClientBuilder {
+    servers: Vec<(String, usize)>,
+    protocol: proto::ProtoType,
+    replicas_per_node: usize,
+    connect_timeout: Option<Duration>,
+    auth_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    noreply_max_outstanding_bytes: Option<usize>,
+    handshake: bool,
+    nodelay: bool,
+    tcp_linger: Option<Option<Duration>>,
+    buffer_capacity: Option<usize>,
+    read_buffer_pool: Option<usize>,
+    max_pipeline_depth: Option<usize>,
+    value_copy_cutoff: Option<usize>,
+    coalesce_noreply: bool,
+    missing_flags: proto::MissingFlags,
+    strict: bool,
+    max_value_size: Option<usize>,
+    discover_max_value_size: bool,
+    detect_quirks: bool,
+    quirks: Option<Quirks>,
+    busy_backpressure: Option<Duration>,
+    client_label: Option<String>,
+    adaptive_timeouts: Option<AdaptiveTimeouts>,
+    sasl: Option<(String, String)>,
+    default_expiration: u32,
+    default_flags: u32,
+    observer: Option<Box<dyn Fn(&OpEvent)>>,
+    observer_sampling_rate: u32,
+    classifier: Option<Classifier>,
+    framer: Option<Box<dyn ValueFramer>>,
+    encryption: Option<Encryption>,
+    replication_factor: usize,
+    prefetch: Option<(fn(&[u8]) -> Vec<Vec<u8>>, u32)>,
+    auto_hash_long_keys: bool,
+    cas_backoff: (Duration, Duration),
+    on_cas_conflict: Option<Box<dyn Fn(&CasConflict)>>,
+    clock: Option<Box<dyn Clock>>,
+    server_clock_refresh: Option<Duration>,
+    route_cache: Option<usize>,
+    fastest_replica_window: Option<u32>,
+    touch_cache: Option<(Duration, usize)>,
+}
+
+impl ClientBuilder {
+    /// Create a builder without any servers
+    pub fn new(protocol: proto::ProtoType) -> ClientBuilder {
+        ClientBuilder {
+            servers: Vec::new(),
+            protocol,
+            replicas_per_node: DEFAULT_REPLICAS_PER_NODE,
+            connect_timeout: None,
+            auth_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            noreply_max_outstanding_bytes: None,
+            handshake: true,
+            nodelay: true,
+            tcp_linger: None,
+            buffer_capacity: None,
+            read_buffer_pool: None,
+            max_pipeline_depth: None,
+            value_copy_cutoff: None,
+            coalesce_noreply: false,
+            missing_flags: proto::MissingFlags::default(),
+            strict: false,
+            max_value_size: None,
+            discover_max_value_size: false,
+            detect_quirks: false,
+            quirks: None,
+            busy_backpressure: None,
+            client_label: None,
+            adaptive_timeouts: None,
+            sasl: None,
+            default_expiration: 0,
+            default_flags: 0,
+            observer: None,
+            observer_sampling_rate: 1,
+            classifier: None,
+            framer: None,
+            encryption: None,
+            replication_factor: 1,
+            prefetch: None,
+            auto_hash_long_keys: false,
+            cas_backoff: (DEFAULT_CAS_BACKOFF_BASE, DEFAULT_CAS_BACKOFF_CAP),
+            on_cas_conflict: None,
+            clock: None,
+            server_clock_refresh: None,
+            route_cache: None,
+            fastest_replica_window: None,
+            touch_cache: None,
+        }
+    }
+
+    /// A builder with every setting of `config`, e.g. to connect like another process does
+    ///
+    /// The SASL password is not part of the config, call `sasl` again if `config.sasl_username` is
+    /// set.
+    pub fn from_config(config: &ClientConfig) -> ClientBuilder {
+        ClientBuilder {
+            servers: config.servers.clone(),
+            replicas_per_node: config.replicas_per_node,
+            connect_timeout: config.connect_timeout,
+            auth_timeout: config.auth_timeout,
+            read_timeout: config.read_timeout,
+            write_timeout: config.write_timeout,
+            noreply_max_outstanding_bytes: config.noreply_max_outstanding_bytes,
+            handshake: config.handshake,
+            nodelay: config.nodelay,
+            tcp_linger: config.tcp_linger,
+            buffer_capacity: config.buffer_capacity,
+            read_buffer_pool: config.read_buffer_pool,
+            max_pipeline_depth: config.max_pipeline_depth,
+            value_copy_cutoff: config.value_copy_cutoff,
+            coalesce_noreply: config.coalesce_noreply,
+            missing_flags: config.missing_flags,
+            strict: config.strict,
+            max_value_size: config.max_value_size,
+            discover_max_value_size: config.discover_max_value_size,
+            detect_quirks: config.detect_quirks,
+            quirks: config.quirks,
+            busy_backpressure: config.busy_backpressure,
+            client_label: config.client_label.clone(),
+            adaptive_timeouts: config.adaptive_timeouts,
+            default_expiration: config.default_expiration,
+            default_flags: config.default_flags,
+            replication_factor: config.replication_factor,
+            auto_hash_long_keys: config.auto_hash_long_keys,
+            cas_backoff: (config.cas_backoff_base, config.cas_backoff_cap),
+            server_clock_refresh: config.server_clock_refresh,
+            route_cache: config.route_cache,
+            fastest_replica_window: config.fastest_replica_window,
+            touch_cache: config.touch_cache,
+            ..ClientBuilder::new(config.protocol)
+        }
+    }
+
+    /// Add a server, `addr` is in the same form as in `Client::connect`
+    pub fn add_server<S: ToString>(mut self, addr: S, weight: usize) -> ClientBuilder {
+        self.servers.push((addr.to_string(), weight));
+        self
+    }
+
+    /// Number of points each unit of weight gets on the consistent hash ring
+    ///
+    /// A server with weight `w` is placed `w * replicas_per_node` times on the ring. More points
+    /// spread keys more evenly across servers at the cost of a larger ring. Changing this value
+    /// remaps keys, so all clients sharing a cluster should use the same setting.
+    pub fn replicas_per_node(mut self, replicas_per_node: usize) -> ClientBuilder {
+        assert!(replicas_per_node > 0, "replicas_per_node should be positive");
+        self.replicas_per_node = replicas_per_node;
+        self
+    }
+
+    /// Timeout for establishing connections
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Deadline for the SASL exchange on each connection, the `connect_timeout` without one
+    ///
+    /// The exchange runs with what is left of it as read and write timeouts instead of the
+    /// configured ones, so a server that accepts connections but never answers fails `build` with
+    /// `io::ErrorKind::TimedOut` naming the step that hung, `auth-start` or `auth-continue`. 5
+    /// seconds if neither is set.
+    pub fn auth_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.auth_timeout = Some(timeout);
+        self
+    }
+
+    /// Read timeout of connections
+    pub fn read_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Derive the read timeout of each operation from the latency it recently had on its server
+    ///
+    /// Latencies are tracked per server and per operation, and the timeout is set on the
+    /// connection before every request. Until an operation has `config.warmup` latencies on a
+    /// server, it runs with the `read_timeout` set here. See `AdaptiveTimeouts`.
+    pub fn adaptive_timeouts(mut self, config: AdaptiveTimeouts) -> ClientBuilder {
+        assert!(config.min <= config.max, "adaptive timeout min should not exceed max");
+        self.adaptive_timeouts = Some(config);
+        self
+    }
+
+    /// Write timeout of connections
+    pub fn write_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Cap on the bytes of noreply requests sent to a server before it is seen processing them
+    ///
+    /// When a noreply operation would exceed it, it first waits for the server to answer a Noop.
+    /// `try_set_noreply` fails with `io::ErrorKind::WouldBlock` instead. Unlimited by default.
+    pub fn noreply_max_outstanding_bytes(mut self, max: usize) -> ClientBuilder {
+        self.noreply_max_outstanding_bytes = Some(max);
+        self
+    }
+
+    /// Whether to check that each server speaks the binary protocol right after connecting
+    ///
+    /// Enabled by default, so that pointing the client at the wrong port fails in `build` instead of
+    /// on the first operation. The check is a Version request, which servers requiring SASL answer
+    /// before authentication too. Disable it for proxies that do not answer a Version.
+    pub fn handshake(mut self, enabled: bool) -> ClientBuilder {
+        self.handshake = enabled;
+        self
+    }
+
+    /// `SO_LINGER` of TCP connections, left to the system default unless this is called
+    ///
+    /// With `Some(timeout)`, closing a connection blocks for up to `timeout` while unsent data is
+    /// delivered, and a zero timeout resets the connection right away. `None` turns lingering off,
+    /// the data is then delivered in the background after the close.
+    pub fn tcp_linger(mut self, linger: Option<Duration>) -> ClientBuilder {
+        self.tcp_linger = Some(linger);
+        self
+    }
+
+    /// Tune connections for a workload, see `ConnectPreset`
+    ///
+    /// Options set after the preset override it.
+    pub fn preset(mut self, preset: ConnectPreset) -> ClientBuilder {
+        match preset {
+            ConnectPreset::Default => {
+                self.nodelay = true;
+                self.buffer_capacity = None;
+                self.coalesce_noreply = false;
+            }
+            ConnectPreset::BulkTransfer => {
+                self.nodelay = false;
+                self.buffer_capacity = Some(BULK_TRANSFER_BUFFER_CAPACITY);
+                self.coalesce_noreply = true;
+            }
+        }
+        self
+    }
+
+    /// Read response bodies into recycled buffers of `capacity` bytes, see `proto::ReadBufferPool`
+    ///
+    /// Cuts the allocations of get-heavy loads that drop values soon after reading them. A value
+    /// kept for long keeps its whole buffer alive, unless it is shorter than the cutoff of
+    /// `value_copy_cutoff`.
+    pub fn read_buffer_pool(mut self, capacity: usize) -> ClientBuilder {
+        self.read_buffer_pool = Some(capacity);
+        self
+    }
+
+    /// Copy values returned as `Bytes` that are shorter than `cutoff` out of the buffer they were
+    /// read into, see `BinaryProto::set_value_copy_cutoff`
+    ///
+    /// `proto::binary::DEFAULT_VALUE_COPY_CUTOFF` by default, 0 never copies.
+    pub fn value_copy_cutoff(mut self, cutoff: usize) -> ClientBuilder {
+        self.value_copy_cutoff = Some(cutoff);
+        self
+    }
+
+    /// Split multi operations into pipelines of at most `depth` requests per server, see
+    /// `BinaryProto::set_max_pipeline_depth`
+    ///
+    /// Each pipeline ends with its own Noop and is read back before the next one is sent, which
+    /// costs a round trip per `depth` keys. Unbounded by default.
+    pub fn max_pipeline_depth(mut self, depth: usize) -> ClientBuilder {
+        assert!(depth > 0, "pipeline depth should be positive");
+        self.max_pipeline_depth = Some(depth);
+        self
+    }
+
+    /// How to handle get responses without flags, see `MissingFlags`
+    ///
+    /// By default they are returned with flags 0.
+    pub fn missing_flags(mut self, missing_flags: proto::MissingFlags) -> ClientBuilder {
+        self.missing_flags = missing_flags;
+        self
+    }
+
+    /// Fail with `Error::ProtocolAnomaly` on any deviation from the protocol the client would
+    /// otherwise cope with, see `Anomaly`
+    ///
+    /// The connection that saw it is poisoned until `Client::reset_connection`. Meant for protocol
+    /// development and CI against servers and proxies, off by default. In strict mode a get
+    /// response without flags is an anomaly whatever `missing_flags` says.
+    pub fn strict(mut self, strict: bool) -> ClientBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Fail with `Error::ValueTooLarge` instead of sending a value longer than `max_len` bytes
+    ///
+    /// Checked for the operations on a single key and the multi stores, where one value over it
+    /// fails the whole batch with `Error::ValidationFailed` before anything is sent. The others
+    /// leave it to the server. memcached counts the key and its item header against its limit too, so a value just
+    /// under it can still be refused with `Status::ValueTooLarge`. No limit by default.
+    pub fn max_value_size(mut self, max_len: usize) -> ClientBuilder {
+        self.max_value_size = Some(max_len);
+        self
+    }
+
+    /// Read the limit of `max_value_size` from each server when connecting, as the
+    /// `item_size_max` of its `settings` stats, i.e. its `-I` option
+    ///
+    /// A server that does not report it gets the limit set with `max_value_size`, or
+    /// `DEFAULT_MAX_VALUE_SIZE`. Costs a round trip per server when connecting.
+    pub fn discover_max_value_size(mut self, enabled: bool) -> ClientBuilder {
+        self.discover_max_value_size = enabled;
+        self
+    }
+
+    /// Ask each server for its version when connecting, and work around the known bugs of that
+    /// version, see `Quirks`
+    ///
+    /// A server whose version cannot be read gets no workarounds. Costs a round trip per server
+    /// when connecting. `Client::quirks` tells which workarounds a server got.
+    pub fn detect_quirks(mut self, enabled: bool) -> ClientBuilder {
+        self.detect_quirks = enabled;
+        self
+    }
+
+    /// Work around `quirks` on every server, instead of detecting them with `detect_quirks`
+    pub fn quirks(mut self, quirks: Quirks) -> ClientBuilder {
+        self.quirks = Some(quirks);
+        self
+    }
+
+    /// Fail with `Error::Backpressure` instead of a `Busy` status error when a server is overloaded
+    ///
+    /// The binary protocol carries no hint of how long the server stays busy, so `retry_after` is
+    /// what the errors suggest, `DEFAULT_BUSY_RETRY_AFTER` is a reasonable start.
+    pub fn busy_backpressure(mut self, retry_after: Duration) -> ClientBuilder {
+        self.busy_backpressure = Some(retry_after);
+        self
+    }
+
+    /// Name this client, e.g. with its service and pod, to match its errors and metrics with server logs
+    ///
+    /// The label is part of the context of every error and of the observer events, and is exported
+    /// as `memcached_client_info` by `Client::render_prometheus`.
+    pub fn client_label(mut self, label: String) -> ClientBuilder {
+        self.client_label = Some(label);
+        self
+    }
+
+    /// Authenticate with SASL `PLAIN` on every TCP connection
+    pub fn sasl(mut self, username: &str, password: &str) -> ClientBuilder {
+        self.sasl = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Expiration used by `Client::set_default` and `Client::add_default`, never expire by default
+    pub fn default_expiration(mut self, expiration: u32) -> ClientBuilder {
+        self.default_expiration = expiration;
+        self
+    }
+
+    /// Flags used by `Client::set_default` and `Client::add_default`, 0 by default
+    pub fn default_flags(mut self, flags: u32) -> ClientBuilder {
+        self.default_flags = flags;
+        self
+    }
+
+    /// Call `observer` with the timing of single key operations
+    pub fn observer<F>(mut self, observer: F) -> ClientBuilder
+    where
+        F: Fn(&OpEvent) + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Only time and report 1 in every `rate` operations to the observer, all of them by default
+    ///
+    /// Sampling is a plain counter, so it costs next to nothing for the operations that are skipped.
+    pub fn observer_sampling_rate(mut self, rate: u32) -> ClientBuilder {
+        assert!(rate > 0, "observer sampling rate should be positive");
+        self.observer_sampling_rate = rate;
+        self
+    }
+
+    /// Count hits, misses, sets and bytes per class of keys, see `ClientStats::by_class`
+    ///
+    /// A key belongs to the class of its longest matching prefix, keys without one go to
+    /// `DEFAULT_CLASS`. Replaces any `key_classifier`.
+    pub fn key_classes(mut self, prefixes: &[(&[u8], &'static str)]) -> ClientBuilder {
+        self.classifier = Some(Classifier::Prefixes(PrefixTrie::new(prefixes)));
+        self
+    }
+
+    /// Like `key_classes`, with classes assigned by `classifier`, which runs on every operation
+    pub fn key_classifier(mut self, classifier: fn(&[u8]) -> &'static str) -> ClientBuilder {
+        self.classifier = Some(Classifier::Callback(classifier));
+        self
+    }
+
+    /// Frame values with `framer` when they are stored and unframe them when they are read, values
+    /// are stored as they are by default
+    ///
+    /// Every operation storing a whole value goes through the framer, single, CAS, noreply and
+    /// multi alike, and so does every read. `append` and `prepend` send their bytes unframed.
+    pub fn value_framer<F>(mut self, framer: F) -> ClientBuilder
+    where
+        F: ValueFramer + 'static,
+    {
+        self.framer = Some(Box::new(framer));
+        self
+    }
+
+    /// Encrypt the values of the keys `applies_to` accepts with `cipher`, e.g. an `AesGcmCipher`
+    ///
+    /// Encrypted values are stored with `flags::reserved::ENCRYPTED`, which reads clear again.
+    /// Every operation storing a whole value goes through the cipher, after the `value_framer`, and
+    /// so does every read. Reading a value that does not decrypt, or a plaintext value under a key
+    /// that should be encrypted, fails with `IntegrityError`. Operations changing a value in place
+    /// (`append`, `prepend`, `increment`, `decrement` and their variants) cannot work on a
+    /// ciphertext and fail for the keys `applies_to` accepts. A client without a cipher reads
+    /// encrypted values as they are.
+    pub fn value_cipher<C, F>(mut self, cipher: C, applies_to: F) -> ClientBuilder
+    where
+        C: ValueCipher + 'static,
+        F: Fn(&[u8]) -> bool + 'static,
+    {
+        self.encryption = Some(Encryption::new(Box::new(cipher), Box::new(applies_to)));
+        self
+    }
+
+    /// Keep every key on `factor` servers: its owner and the next distinct servers on the ring
+    ///
+    /// Every single key write, noreply ones included, goes to every replica. The answer of the owner
+    /// decides whether it succeeded, the other replicas only stand in while the owner cannot answer.
+    /// `get`, `getk`, `get_cas` and `getk_cas` try the owner first, then the other replicas on a
+    /// miss or an error. CAS tokens are per server, so the CAS variants of these writes only check
+    /// the token against the owner, then apply the same change to the other replicas without a
+    /// check; they fail while the owner is down. `touch_multi`, `set_multi_collect`,
+    /// `delete_multi_collect` and `set_cas_multi` send every item to each of its replicas too, with
+    /// the results of the owners deciding and, again, only the owners checking CAS tokens. The other
+    /// multi key operations read from or write to the owners only. The factor is capped by the number
+    /// of servers, 1 (no replication) by default.
+    pub fn replication_factor(mut self, factor: usize) -> ClientBuilder {
+        assert!(factor > 0, "replication factor should be positive");
+        self.replication_factor = factor;
+        self
+    }
+
+    /// After each successful `get`, fetch the keys `related` derives from its key, at most
+    /// `budget_per_sec` keys per second
+    ///
+    /// The client has no background executor, so related keys are fetched right after the get, in
+    /// one pipelined batch per server. A later `get` of a related key is served from the prefetched
+    /// value once, if it is younger than `PREFETCH_MAX_AGE`. Writes through this client drop the
+    /// prefetched value of their key, writes by others are only seen after it expires.
+    pub fn prefetch(mut self, related: fn(&[u8]) -> Vec<Vec<u8>>, budget_per_sec: u32) -> ClientBuilder {
+        self.prefetch = Some((related, budget_per_sec));
+        self
+    }
+
+    /// Replace keys longer than `proto::MAX_KEY_LEN` by the hex MD5 of the whole key
+    ///
+    /// Without it such keys fail with `Error::KeyTooLong`. This changes the key memcached stores
+    /// the value under, so every client sharing those keys needs the same setting. Results of
+    /// multi-key operations still use the keys the caller passed.
+    pub fn auto_hash_long_keys(mut self, enabled: bool) -> ClientBuilder {
+        self.auto_hash_long_keys = enabled;
+        self
+    }
+
+    /// Wait a random delay of up to `base * 2^(retry - 1)`, at most `cap`, before each retry of
+    /// `Client::cas_update`
+    ///
+    /// `DEFAULT_CAS_BACKOFF_BASE` and `DEFAULT_CAS_BACKOFF_CAP` by default, a zero `base` retries
+    /// right away.
+    pub fn cas_backoff(mut self, base: Duration, cap: Duration) -> ClientBuilder {
+        self.cas_backoff = (base, cap);
+        self
+    }
+
+    /// Call `callback` every time a `Client::cas_update` store loses against another writer and is
+    /// retried
+    pub fn on_cas_conflict<F>(mut self, callback: F) -> ClientBuilder
+    where
+        F: Fn(&CasConflict) + 'static,
+    {
+        self.on_cas_conflict = Some(Box::new(callback));
+        self
+    }
+
+    /// Wait between retries with `clock` instead of sleeping the thread
+    pub fn clock<C>(mut self, clock: C) -> ClientBuilder
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Compute `Client::expiration_at` on the clock of each server, measuring how far it is off at
+    /// most once per `refresh`
+    pub fn server_clock(mut self, refresh: Duration) -> ClientBuilder {
+        self.server_clock_refresh = Some(refresh);
+        self
+    }
+
+    /// Send reads of replicated keys to the replica that has been answering fastest
+    ///
+    /// Each server keeps an exponentially weighted mean of the latency of every operation the
+    /// client runs on it, spanning about the last `window` operations. Reads covered by
+    /// `replication_factor` try the replicas from the lowest mean up, and still fall back to the
+    /// next replica on a miss or an error. Without replication this changes nothing.
+    pub fn fastest_replica_reads(mut self, window: u32) -> ClientBuilder {
+        assert!(window > 0, "latency window should be positive");
+        self.fastest_replica_window = Some(window);
+        self
+    }
+
+    /// Remember which server owns each of the last `entries` routed keys
+    ///
+    /// A hit skips the md5 of the key and the ring lookup, which shows with long keys at high
+    /// rates. Each key has a single slot picked by a cheaper hash, so keys sharing a slot evict
+    /// each other. The cache is flushed whenever a server joins or leaves the ring.
+    pub fn route_cache(mut self, entries: usize) -> ClientBuilder {
+        assert!(entries > 0, "route cache should have at least one entry");
+        self.route_cache = Some(entries);
+        self
+    }
+
+    /// Skip touching a key again with the same expiration within `granularity` of its last touch
+    ///
+    /// The last successful touch of up to `entries` keys is remembered, the least recently touched
+    /// key goes first. Within the window, `touch` succeeds without a request. Any other write of a
+    /// key through this client forgets it, even one that failed, but a key deleted, replaced or
+    /// evicted elsewhere is still taken as touched until the window passes, so keep `granularity`
+    /// well below the expirations.
+    pub fn touch_cache(mut self, granularity: Duration, entries: usize) -> ClientBuilder {
+        assert!(entries > 0, "touch cache should have at least one entry");
+        self.touch_cache = Some((granularity, entries));
+        self
+    }
+
+    /// Connect to all servers
+    pub fn build(self) -> io::Result<Client> {
+        let sasl = self
+            .sasl
+            .as_ref()
+            .map(|(username, password)| Sasl { username, password });
+        let opts = Some(ConnectOpts {
+            connect_timeout: self.connect_timeout,
+            auth_timeout: self.auth_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            noreply_max_outstanding_bytes: self.noreply_max_outstanding_bytes,
+            handshake: self.handshake,
+            nodelay: self.nodelay,
+            tcp_linger: self.tcp_linger,
+            buffer_capacity: self.buffer_capacity,
+            read_buffer_pool: self.read_buffer_pool,
+            max_pipeline_depth: self.max_pipeline_depth,
+            value_copy_cutoff: self.value_copy_cutoff,
+            coalesce_noreply: self.coalesce_noreply,
+            missing_flags: self.missing_flags,
+            strict: self.strict,
+            max_value_size: self.max_value_size,
+            discover_max_value_size: self.discover_max_value_size,
+            detect_quirks: self.detect_quirks,
+            quirks: self.quirks,
+            busy_backpressure: self.busy_backpressure,
+            client_label: self.client_label,
+            adaptive_timeouts: self.adaptive_timeouts,
+        });
+
+        let mut client = Client::conn(&self.servers, self.protocol, sasl, opts, self.replicas_per_node)?;
+        client.default_expiration = self.default_expiration;
+        client.default_flags = self.default_flags;
+        let sampling_rate = self.observer_sampling_rate;
+        client.observer = self.observer.map(|callback| Observer::new(callback, sampling_rate));
+        client.classifier = self.classifier;
+        client.framer = self.framer;
+        client.encryption = self.encryption;
+        client.replication_factor = self.replication_factor;
+        client.config.default_expiration = self.default_expiration;
+        client.config.default_flags = self.default_flags;
+        client.config.replication_factor = self.replication_factor;
+        client.auto_hash_long_keys = self.auto_hash_long_keys;
+        client.config.auto_hash_long_keys = self.auto_hash_long_keys;
+        client.cas_backoff = self.cas_backoff;
+        client.config.cas_backoff_base = self.cas_backoff.0;
+        client.config.cas_backoff_cap = self.cas_backoff.1;
+        client.on_cas_conflict = self.on_cas_conflict;
+        client.server_clock_refresh = self.server_clock_refresh;
+        client.config.server_clock_refresh = self.server_clock_refresh;
+        if let Some(entries) = self.route_cache {
+            client.servers.set_route_cache(entries);
+        }
+        client.config.route_cache = self.route_cache;
+        client.fastest_replica_window = self.fastest_replica_window;
+        client.config.fastest_replica_window = self.fastest_replica_window;
+        client.touch_cache = self
+            .touch_cache
+            .map(|(granularity, entries)| TouchCache::new(granularity, entries));
+        client.config.touch_cache = self.touch_cache;
+        if let Some(clock) = self.clock {
+            client.clock = clock;
+        }
+        client.prefetcher = self.prefetch.map(|(related, budget)| Prefetcher::new(related, budget));
+        Ok(client)
+    }
+}
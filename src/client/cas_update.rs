@@ -0,0 +1,304 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Read-modify-write of a single key, backing off between conflicting attempts
+
+use std::time::{Duration, Instant};
+
+use super::Client;
+use crate::proto::{binary::Status, Error, MemCachedResult};
+use crate::random;
+
+/// Default bound of the first backoff of `Client::cas_update`, see `ClientBuilder::cas_backoff`
+pub const DEFAULT_CAS_BACKOFF_BASE: Duration = Duration::from_millis(1);
+
+/// Default cap of the backoffs of `Client::cas_update`, see `ClientBuilder::cas_backoff`
+pub const DEFAULT_CAS_BACKOFF_CAP: Duration = Duration::from_millis(100);
+
+/// Conflicts and retries of `Client::cas_update`, for one call or summed over a client
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CasUpdateStats {
+    /// Calls of `cas_update`
+    pub calls: u64,
+    /// Stores that lost against another writer
+    pub conflicts: u64,
+    /// Attempts after the first one of each call
+    pub retries: u64,
+    /// Time spent backing off before retries
+    pub backoff: Duration,
+}
+
+impl CasUpdateStats {
+    fn merge(&mut self, other: &CasUpdateStats) {
+        self.calls += other.calls;
+        self.conflicts += other.conflicts;
+        self.retries += other.retries;
+        self.backoff += other.backoff;
+    }
+}
+
+/// A store of `Client::cas_update` that lost, passed to `ClientBuilder::on_cas_conflict`
+#[derive(Debug)]
+pub struct CasConflict<'a> {
+    pub key: &'a [u8],
+    /// The attempt that lost, starting at 1
+    pub attempt: usize,
+    /// Length of the value that won, as read by the next attempt
+    pub competing_len: usize,
+}
+
+/// Full jitter: a uniformly random delay up to `base * 2^(retry - 1)`, and never above `cap`
+pub(crate) fn backoff_delay(base: Duration, cap: Duration, retry: u32) -> Duration {
+    let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+    let bound = base.saturating_mul(factor).min(cap);
+    Duration::from_nanos(random::u64(0..=bound.as_nanos() as u64))
+}
+
+impl Client {
+    /// Replace the value of `key` by `f(value)`, keeping its flags
+    ///
+    /// This is a `get_cas` followed by a `set_cas`. When another writer stored the key in between,
+    /// the client waits a random delay of up to `base * 2^(retry - 1)`, capped (see
+    /// `ClientBuilder::cas_backoff`), and starts over, at most `max_attempts` times in total. The
+    /// random delays keep writers of a hot key from retrying in lockstep. If the last attempt still
+    /// conflicts, its `KeyExists` error is returned; a missing key fails with `KeyNotFound`.
+    ///
+    /// Like `CasOperation::update`, no attempt is started after `deadline` and no backoff waits past
+    /// it; the update then fails with `Error::Timeout`.
+    ///
+    /// Returns the new CAS token and the conflicts and retries of this call, which are also added
+    /// to `cas_update_stats`.
+    pub fn cas_update(
+        &mut self,
+        key: &[u8],
+        expiration: u32,
+        deadline: Instant,
+        max_attempts: usize,
+        f: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+    ) -> MemCachedResult<(u64, CasUpdateStats)> {
+        assert!(max_attempts > 0, "max_attempts should be positive");
+
+        let mut stats = CasUpdateStats {
+            calls: 1,
+            ..CasUpdateStats::default()
+        };
+        let result = self.cas_update_attempts(key, expiration, deadline, max_attempts, f, &mut stats);
+        self.cas_update_stats.merge(&stats);
+        result.map(|cas| (cas, stats))
+    }
+
+    fn cas_update_attempts(
+        &mut self,
+        key: &[u8],
+        expiration: u32,
+        deadline: Instant,
+        max_attempts: usize,
+        f: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+        stats: &mut CasUpdateStats,
+    ) -> MemCachedResult<u64> {
+        let (base, cap) = self.cas_backoff;
+        let mut attempt = 0;
+        loop {
+            if attempt > 0 {
+                let delay =
+                    backoff_delay(base, cap, attempt as u32).min(deadline.saturating_duration_since(Instant::now()));
+                if delay > Duration::ZERO {
+                    self.clock.sleep(delay);
+                }
+                stats.backoff += delay;
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout { attempts: attempt });
+            }
+            attempt += 1;
+            if attempt > 1 {
+                stats.retries += 1;
+            }
+
+            let (value, flags, cas) = self.get_cas(key)?;
+            if attempt > 1 {
+                if let Some(ref on_conflict) = self.on_cas_conflict {
+                    on_conflict(&CasConflict {
+                        key,
+                        attempt: attempt - 1,
+                        competing_len: value.len(),
+                    });
+                }
+            }
+
+            let err = match self.set_cas(key, f(&value), flags, expiration, cas) {
+                Ok(cas) => return Ok(cas),
+                Err(err) => err,
+            };
+            if err.status() != Some(Status::KeyExists) {
+                return Err(err);
+            }
+            stats.conflicts += 1;
+            if attempt == max_attempts {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Conflicts and retries of every `cas_update` of this client
+    pub fn cas_update_stats(&self) -> CasUpdateStats {
+        self.cas_update_stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{backoff_delay, CasUpdateStats};
+    use crate::client::{Client, Clock};
+    use crate::proto::{self, binary::Status, ProtoType};
+    use crate::test_support::MockServer;
+
+    /// A deadline no test reaches
+    fn far() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    /// Adds up the waits instead of sleeping
+    struct Skipping(Rc<Cell<Duration>>);
+
+    impl Clock for Skipping {
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let (base, cap) = (Duration::from_millis(1), Duration::from_millis(20));
+        for retry in 1..40 {
+            let bound = if retry < 6 { base * (1 << (retry - 1)) } else { cap };
+            for _ in 0..50 {
+                assert!(backoff_delay(base, cap, retry) <= bound);
+            }
+        }
+        assert_eq!(backoff_delay(Duration::ZERO, cap, 3), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_cas_update() {
+        const KEY: &[u8] = b"test:cas_update";
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+
+        let err = client
+            .cas_update(KEY, 0, far(), 3, &mut |value| value.to_vec())
+            .unwrap_err();
+        assert_eq!(err.status(), Some(Status::KeyNotFound));
+
+        client.set(KEY, b"a", 7, 0).unwrap();
+        let (_, stats) = client
+            .cas_update(KEY, 0, far(), 3, &mut |value| [value, b"b"].concat())
+            .unwrap();
+        assert_eq!(
+            stats,
+            CasUpdateStats {
+                calls: 1,
+                ..CasUpdateStats::default()
+            }
+        );
+        assert_eq!(client.get(KEY).unwrap(), (b"ab".to_vec(), 7));
+
+        // Every attempt is raced by another writer
+        let mut other = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+        let err = client
+            .cas_update(KEY, 0, far(), 3, &mut |value| {
+                other.set(KEY, b"other", 7, 0).unwrap();
+                value.to_vec()
+            })
+            .unwrap_err();
+        assert_eq!(err.status(), Some(Status::KeyExists));
+        let stats = client.cas_update_stats();
+        assert_eq!((stats.calls, stats.conflicts, stats.retries), (3, 3, 2));
+
+        // Stops at the deadline rather than after the attempts
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = client
+            .cas_update(KEY, 0, deadline, 1_000_000, &mut |value| {
+                other.set(KEY, b"other", 7, 0).unwrap();
+                value.to_vec()
+            })
+            .unwrap_err();
+        assert!(matches!(err, proto::Error::Timeout { attempts } if attempts > 0));
+        let err = client
+            .cas_update(KEY, 0, Instant::now(), 3, &mut |value| value.to_vec())
+            .unwrap_err();
+        assert!(matches!(err, proto::Error::Timeout { attempts: 0 }));
+    }
+
+    #[test]
+    fn test_cas_update_contention() {
+        const KEY: &[u8] = b"test:cas_update_contention";
+        const THREADS: u64 = 8;
+        const INCREMENTS: u64 = 25;
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        Client::connect(&[(&url[..], 1)], ProtoType::Binary)
+            .unwrap()
+            .set(KEY, br#"{"count":0}"#, 0, 0)
+            .unwrap();
+
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let url = url.clone();
+                thread::spawn(move || {
+                    let slept = Rc::new(Cell::new(Duration::ZERO));
+                    let conflicts_seen = Rc::new(Cell::new(0));
+                    let seen = conflicts_seen.clone();
+                    let mut client = Client::builder(ProtoType::Binary)
+                        .add_server(&url, 1)
+                        .cas_backoff(Duration::from_millis(1), Duration::from_millis(50))
+                        .clock(Skipping(slept.clone()))
+                        .on_cas_conflict(move |conflict| {
+                            assert!(conflict.competing_len > 0);
+                            seen.set(seen.get() + 1);
+                        })
+                        .build()
+                        .unwrap();
+
+                    for _ in 0..INCREMENTS {
+                        client
+                            .cas_update(KEY, 0, far(), 1000, &mut |value| {
+                                let mut counter: serde_json::Value = serde_json::from_slice(value).unwrap();
+                                counter["count"] = (counter["count"].as_u64().unwrap() + 1).into();
+                                serde_json::to_vec(&counter).unwrap()
+                            })
+                            .unwrap();
+                    }
+                    let stats = client.cas_update_stats();
+                    assert_eq!(stats.backoff, slept.get());
+                    assert_eq!(stats.conflicts, conflicts_seen.get());
+                    stats
+                })
+            })
+            .collect();
+
+        let mut total = CasUpdateStats::default();
+        for worker in workers {
+            total.merge(&worker.join().unwrap());
+        }
+
+        let mut client = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        let counter: serde_json::Value = serde_json::from_slice(&client.get(KEY).unwrap().0).unwrap();
+        assert_eq!(counter["count"], THREADS * INCREMENTS);
+        assert_eq!(total.calls, THREADS * INCREMENTS);
+        assert_eq!(total.retries, total.conflicts);
+        // Every conflict is a store of another thread landing first
+        assert!(total.conflicts <= THREADS * INCREMENTS * (THREADS - 1));
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Encrypting the values of sensitive keys
+
+use std::borrow::Cow;
+
+#[cfg(feature = "crypto")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "crypto")]
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+use crate::flags::{self, reserved};
+use crate::proto::{self, MemCachedResult};
+
+/// Encrypts values before they are stored and decrypts them when they are read back
+///
+/// Set with `ClientBuilder::value_cipher`. `key` is the key the value is stored under, a cipher
+/// should bind the ciphertext to it so that a value copied to another key does not decrypt.
+pub trait ValueCipher {
+    fn encrypt(&self, key: &[u8], plaintext: &[u8]) -> Vec<u8>;
+    /// Fails with `IntegrityError` for a ciphertext that was altered or encrypted for another key
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> MemCachedResult<Vec<u8>>;
+}
+
+/// AES-256-GCM with a random 96 bits nonce per value, stored in front of the ciphertext
+///
+/// The key of the item is authenticated along with the value.
+#[cfg(feature = "crypto")]
+pub struct AesGcmCipher {
+    cipher: Aes256Gcm,
+}
+
+#[cfg(feature = "crypto")]
+impl AesGcmCipher {
+    /// Length of the nonce in front of every ciphertext
+    pub const NONCE_LEN: usize = 12;
+
+    pub fn new(key: &[u8; 32]) -> AesGcmCipher {
+        AesGcmCipher {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl ValueCipher for AesGcmCipher {
+    fn encrypt(&self, key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: key,
+                },
+            )
+            .expect("value too large for AES-GCM");
+        let mut sealed = Vec::with_capacity(nonce.len() + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> MemCachedResult<Vec<u8>> {
+        if ciphertext.len() < AesGcmCipher::NONCE_LEN {
+            return Err(proto::Error::IntegrityError {
+                detail: "ciphertext is shorter than its nonce".to_owned(),
+            });
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(AesGcmCipher::NONCE_LEN);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key,
+                },
+            )
+            .map_err(|_| proto::Error::IntegrityError {
+                detail: "ciphertext does not authenticate".to_owned(),
+            })
+    }
+}
+
+/// A cipher together with the keys it applies to, see `ClientBuilder::value_cipher`
+pub(crate) struct Encryption {
+    cipher: Box<dyn ValueCipher>,
+    applies_to: Box<dyn Fn(&[u8]) -> bool>,
+}
+
+impl Encryption {
+    pub(crate) fn new(cipher: Box<dyn ValueCipher>, applies_to: Box<dyn Fn(&[u8]) -> bool>) -> Encryption {
+        Encryption { cipher, applies_to }
+    }
+
+    /// Whether the values of `key` are encrypted
+    pub(crate) fn applies_to(&self, key: &[u8]) -> bool {
+        (self.applies_to)(key)
+    }
+
+    /// `value` and `flags` as stored under `key`, encrypted and marked `ENCRYPTED` if it applies to `key`
+    pub(crate) fn seal<'v>(&self, key: &[u8], value: Cow<'v, [u8]>, flags: u32) -> (Cow<'v, [u8]>, u32) {
+        if (self.applies_to)(key) {
+            (Cow::Owned(self.cipher.encrypt(key, &value)), flags | reserved::ENCRYPTED)
+        } else {
+            (value, flags)
+        }
+    }
+}
+
+/// The plaintext and flags of `value` stored under `key`, reverses `Encryption::seal`
+///
+/// A value marked `ENCRYPTED` that does not decrypt, or a plaintext value under a key that should
+/// be encrypted, fails with `IntegrityError` rather than being returned as it is. Without a cipher,
+/// every value is returned as it is, `ENCRYPTED` flag included.
+pub(crate) fn open(
+    encryption: Option<&Encryption>,
+    key: &[u8],
+    value: Vec<u8>,
+    flags: u32,
+) -> MemCachedResult<(Vec<u8>, u32)> {
+    match encryption {
+        Some(encryption) if flags::has(flags, reserved::ENCRYPTED) => {
+            let plaintext = encryption.cipher.decrypt(key, &value)?;
+            Ok((plaintext, flags & !reserved::ENCRYPTED))
+        }
+        Some(encryption) if encryption.applies_to(key) => Err(proto::Error::IntegrityError {
+            detail: "value of an encrypted key is not encrypted".to_owned(),
+        }),
+        _ => Ok((value, flags)),
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod test {
+    use std::borrow::Cow;
+    use std::net::TcpStream;
+
+    use bufstream::BufStream;
+
+    use super::{open, AesGcmCipher, Encryption, ValueCipher};
+    use crate::client::Client;
+    use crate::flags::reserved;
+    use crate::proto::{self, BinaryProto, CasOperation, MultiOperation, NoReplyOperation, Operation, ProtoType};
+    use crate::test_support::MockServer;
+
+    const SECRET: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+    fn encryption() -> Encryption {
+        Encryption::new(Box::new(AesGcmCipher::new(SECRET)), Box::new(|key: &[u8]| key.starts_with(b"secret:")))
+    }
+
+    fn is_integrity_error(err: &proto::Error) -> bool {
+        matches!(err.root(), proto::Error::IntegrityError { .. })
+    }
+
+    #[test]
+    fn test_aes_gcm_cipher() {
+        let cipher = AesGcmCipher::new(SECRET);
+        let sealed = cipher.encrypt(b"secret:a", b"plaintext");
+        assert_eq!(sealed.len(), AesGcmCipher::NONCE_LEN + b"plaintext".len() + 16);
+        assert_ne!(cipher.encrypt(b"secret:a", b"plaintext"), sealed);
+        assert_eq!(cipher.decrypt(b"secret:a", &sealed).unwrap(), b"plaintext");
+
+        // Another key, another secret, a flipped bit or a truncated value never decrypt
+        assert!(is_integrity_error(&cipher.decrypt(b"secret:b", &sealed).unwrap_err()));
+        let other = AesGcmCipher::new(b"fedcba9876543210fedcba9876543210");
+        assert!(is_integrity_error(&other.decrypt(b"secret:a", &sealed).unwrap_err()));
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(is_integrity_error(&cipher.decrypt(b"secret:a", &tampered).unwrap_err()));
+        assert!(is_integrity_error(&cipher.decrypt(b"secret:a", &sealed[..8]).unwrap_err()));
+    }
+
+    #[test]
+    fn test_open() {
+        let encryption = encryption();
+        let (sealed, flags) = encryption.seal(b"secret:a", Cow::Borrowed(b"plaintext"), 7);
+        assert_eq!(flags, reserved::ENCRYPTED | 7);
+        assert_eq!(open(Some(&encryption), b"secret:a", sealed.to_vec(), flags).unwrap(), (b"plaintext".to_vec(), 7));
+        assert_eq!(open(None, b"secret:a", sealed.to_vec(), flags).unwrap(), (sealed.to_vec(), flags));
+
+        let (plain, flags) = encryption.seal(b"public:a", Cow::Borrowed(b"plaintext"), 7);
+        assert_eq!((&plain[..], flags), (&b"plaintext"[..], 7));
+        assert_eq!(open(Some(&encryption), b"public:a", plain.to_vec(), 7).unwrap().0, b"plaintext");
+        assert!(is_integrity_error(&open(Some(&encryption), b"secret:a", plain.to_vec(), 7).unwrap_err()));
+    }
+
+    #[test]
+    fn test_value_cipher() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .value_cipher(AesGcmCipher::new(SECRET), |key: &[u8]| key.starts_with(b"secret:"))
+            .build()
+            .unwrap();
+        // Sees the values as stored
+        let mut raw = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+
+        client.set(b"secret:token", b"hunter2", 3, 0).unwrap();
+        client.set(b"public:name", b"alice", 3, 0).unwrap();
+        assert_eq!(raw.get(b"public:name").unwrap(), (b"alice".to_vec(), 3));
+        let (stored, flags) = raw.get(b"secret:token").unwrap();
+        assert_eq!(flags, reserved::ENCRYPTED | 3);
+        assert!(!stored.windows(7).any(|window| window == b"hunter2"));
+        assert_eq!(client.get(b"secret:token").unwrap(), (b"hunter2".to_vec(), 3));
+
+        let items = [
+            (&b"secret:a"[..], &b"one"[..]),
+            (&b"secret:b"[..], &b"two"[..]),
+            (&b"public:c"[..], &b"three"[..]),
+        ];
+        client
+            .set_multi(items.iter().map(|&(key, value)| (key, (value, 0, 0))).collect())
+            .unwrap();
+        let keys: Vec<&[u8]> = items.iter().map(|&(key, _)| key).collect();
+        let found = client.get_multi(&keys).unwrap();
+        for &(key, value) in items.iter() {
+            assert_eq!(found[key], (value.to_vec(), 0));
+        }
+
+        // A tampered value is refused, not returned as garbage
+        let (mut stored, flags) = raw.get(b"secret:a").unwrap();
+        stored[AesGcmCipher::NONCE_LEN] ^= 1;
+        raw.set(b"secret:a", &stored, flags, 0).unwrap();
+        assert!(is_integrity_error(&client.get(b"secret:a").unwrap_err()));
+        assert!(is_integrity_error(&client.get_multi(&keys).unwrap_err()));
+
+        // So is a value swapped in from another key, or written in plaintext
+        let (stored, flags) = raw.get(b"secret:b").unwrap();
+        raw.set(b"secret:a", &stored, flags, 0).unwrap();
+        assert!(is_integrity_error(&client.get(b"secret:a").unwrap_err()));
+        raw.set(b"secret:a", b"one", 0, 0).unwrap();
+        assert!(is_integrity_error(&client.get(b"secret:a").unwrap_err()));
+
+        // A client without the cipher sees the values as stored
+        let mut plain = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        assert_eq!(plain.get(b"secret:token").unwrap(), raw.get(b"secret:token").unwrap());
+        assert_eq!(plain.get(b"public:name").unwrap(), (b"alice".to_vec(), 3));
+    }
+
+    #[test]
+    fn test_value_cipher_every_path() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .value_cipher(AesGcmCipher::new(SECRET), |key: &[u8]| key.starts_with(b"secret:"))
+            .build()
+            .unwrap();
+        let mut raw = Client::connect(&[(mock.url(), 1)], ProtoType::Binary).unwrap();
+
+        client.add(b"secret:add", b"plain", 1, 0).unwrap();
+        raw.set(b"secret:replace", b"", 0, 0).unwrap();
+        client.replace(b"secret:replace", b"plain", 1, 0).unwrap();
+        client.set_noreply(b"secret:set_noreply", b"plain", 1, 0).unwrap();
+        client.add_noreply(b"secret:add_noreply", b"plain", 1, 0).unwrap();
+        client
+            .try_set_noreply(b"secret:try_set_noreply", b"plain", 1, 0)
+            .unwrap();
+        client.send_pending().unwrap();
+        let cas = client.add_cas(b"secret:cas", b"plain", 1, 0).unwrap();
+        let cas = client.set_cas(b"secret:cas", b"plain", 1, 0, cas).unwrap();
+        client.replace_cas(b"secret:cas", b"plain", 1, 0, cas).unwrap();
+        client
+            .set_cas_multi(&[(b"secret:set_cas_multi", b"plain", 1, 0, 0)])
+            .unwrap()[0]
+            .as_ref()
+            .unwrap();
+        let stored: [&[u8]; 7] = [
+            b"secret:add",
+            b"secret:replace",
+            b"secret:set_noreply",
+            b"secret:add_noreply",
+            b"secret:try_set_noreply",
+            b"secret:cas",
+            b"secret:set_cas_multi",
+        ];
+        for key in stored.iter() {
+            let (value, flags) = raw.get(key).unwrap();
+            assert_eq!(flags, reserved::ENCRYPTED | 1, "{}", String::from_utf8_lossy(key));
+            assert_ne!(value, b"plain");
+            assert_eq!(client.get(key).unwrap(), (b"plain".to_vec(), 1));
+        }
+
+        assert_eq!(client.getk(b"secret:add").unwrap().1, b"plain");
+        assert_eq!(client.get_cas(b"secret:add").unwrap().0, b"plain");
+        assert_eq!(client.getk_cas(b"secret:add").unwrap().1, b"plain");
+        assert_eq!(&client.get_item(b"secret:add").unwrap().value[..], b"plain");
+        assert_eq!(client.gat_item(b"secret:add", 0).unwrap().flags, 1);
+        let keys: Vec<&[u8]> = stored.to_vec();
+        assert!(client
+            .gets_multi(&keys)
+            .unwrap()
+            .values()
+            .all(|found| found.0 == b"plain"));
+        assert!(client
+            .multi_get(&keys)
+            .unwrap()
+            .iter()
+            .all(|found| found == &Some((b"plain".to_vec(), 1))));
+        let by_server = client.get_multi_by_server(&keys).unwrap();
+        assert!(by_server[&mock.url()]
+            .values()
+            .all(|found| found == &(b"plain".to_vec(), 1)));
+        let mut hits = 0;
+        client
+            .get_multi_foreach(&keys, &mut |_, value, _| {
+                assert_eq!(value, b"plain");
+                hits += 1;
+            })
+            .unwrap();
+        assert_eq!(hits, keys.len());
+
+        // A ciphertext cannot be changed in place
+        let in_place = [
+            client.append(b"secret:add", b"more").unwrap_err(),
+            client.prepend(b"secret:add", b"more").unwrap_err(),
+            client.increment(b"secret:add", 1, 0, 0).unwrap_err(),
+            client.decrement(b"secret:add", 1, 0, 0).unwrap_err(),
+            client.append_noreply(b"secret:add", b"more").unwrap_err(),
+            client.increment_cas(b"secret:add", 1, 0, 0, 0).unwrap_err(),
+            client.append_cas(b"secret:add", b"more", 0).unwrap_err(),
+            client.append_bounded(b"secret:add", b"more", 100).unwrap_err(),
+            client
+                .increment_multi([(&b"secret:add"[..], (1, 0, 0)), (b"public:n", (1, 0, 0))].into())
+                .unwrap_err(),
+        ];
+        for err in in_place.iter() {
+            assert!(matches!(err, proto::Error::OtherError { .. }), "{}", err);
+        }
+        assert_eq!(client.get(b"secret:add").unwrap(), (b"plain".to_vec(), 1));
+        client.set(b"public:n", b"1", 0, 0).unwrap();
+        client.append(b"public:n", b"0").unwrap();
+        assert_eq!(client.increment(b"public:n", 1, 0, 0).unwrap(), 11);
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Waiting between retries
+
+use std::thread;
+use std::time::Duration;
+
+use super::Client;
+
+/// How the client waits between retries, set with `ClientBuilder::clock`
+///
+/// Tests replace it to skip the waits or to count them.
+pub trait Clock {
+    fn sleep(&self, duration: Duration);
+}
+
+/// Waits with `thread::sleep`, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+impl Client {
+    /// Wait on the clock set with `ClientBuilder::clock`
+    pub(crate) fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration)
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Snapshot of how a `Client` was configured
+
+use std::time::Duration;
+
+use super::{AdaptiveTimeouts, Quirks};
+use crate::proto;
+
+/// The settings a `Client` was created with, returned by `Client::config`
+///
+/// Keys are always placed on the ring by the MD5 of `"{address}:{point}"`, like `conhash` does, so
+/// `servers` and `replicas_per_node` are enough to reproduce the placement. Callbacks set on the
+/// builder (observer, key classifier, value framer, prefetch, CAS conflict callback and clock) cannot be captured and are left out.
+/// So is the SASL password, only the username is kept. `ClientBuilder::from_config` turns it back
+/// into a builder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientConfig {
+    /// `(address, weight)` of every server, in the order they were added
+    pub servers: Vec<(String, usize)>,
+    pub protocol: proto::ProtoType,
+    pub replicas_per_node: usize,
+    pub replication_factor: usize,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub auth_timeout: Option<Duration>,
+    pub noreply_max_outstanding_bytes: Option<usize>,
+    pub handshake: bool,
+    pub nodelay: bool,
+    /// `None` for the system default, `Some(None)` with lingering turned off
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "present")
+    )]
+    pub tcp_linger: Option<Option<Duration>>,
+    pub buffer_capacity: Option<usize>,
+    pub read_buffer_pool: Option<usize>,
+    pub max_pipeline_depth: Option<usize>,
+    pub value_copy_cutoff: Option<usize>,
+    pub coalesce_noreply: bool,
+    pub missing_flags: proto::MissingFlags,
+    pub strict: bool,
+    pub max_value_size: Option<usize>,
+    pub discover_max_value_size: bool,
+    pub detect_quirks: bool,
+    /// The quirks every server was given, rather than detected
+    pub quirks: Option<Quirks>,
+    pub busy_backpressure: Option<Duration>,
+    pub client_label: Option<String>,
+    pub adaptive_timeouts: Option<AdaptiveTimeouts>,
+    pub sasl_username: Option<String>,
+    pub default_expiration: u32,
+    pub default_flags: u32,
+    pub auto_hash_long_keys: bool,
+    pub cas_backoff_base: Duration,
+    pub cas_backoff_cap: Duration,
+    pub server_clock_refresh: Option<Duration>,
+    pub route_cache: Option<usize>,
+    pub fastest_replica_window: Option<u32>,
+    /// `(granularity, entries)`
+    pub touch_cache: Option<(Duration, usize)>,
+}
+
+/// A field that is present, even as `null`, is `Some`
+///
+/// A missing field is `None` by `serde(default)`, so `Option<Option<_>>` survives a round-trip.
+#[cfg(feature = "serde")]
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
@@ -0,0 +1,129 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Application metadata stored inside values
+
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::proto::{self, MemCachedResult};
+
+/// Metadata recovered by `ValueFramer::unframe`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Meta {
+    /// The header stored in front of the value, empty if there is none
+    pub header: Vec<u8>,
+}
+
+/// Wraps values before they are stored and unwraps them when they are read back
+///
+/// Set with `ClientBuilder::value_framer`, it is applied by every `Client` operation storing a
+/// whole value and reversed by every read, `Client::get_with_meta` also returns the metadata.
+/// `append` and `prepend` send their bytes as they are.
+pub trait ValueFramer {
+    fn frame(&self, value: &[u8]) -> Vec<u8>;
+    fn unframe(&self, bytes: &[u8]) -> MemCachedResult<(Vec<u8>, Meta)>;
+}
+
+/// Stores values as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopFramer;
+
+impl ValueFramer for NoopFramer {
+    fn frame(&self, value: &[u8]) -> Vec<u8> {
+        value.to_vec()
+    }
+
+    fn unframe(&self, bytes: &[u8]) -> MemCachedResult<(Vec<u8>, Meta)> {
+        Ok((bytes.to_vec(), Meta::default()))
+    }
+}
+
+/// Prepends a header to every value: its length as a big endian `u16`, then the header itself
+///
+/// The header is built by a callback on every write, e.g. a version byte and a timestamp.
+pub struct LengthPrefixedFramer {
+    header: Box<dyn Fn() -> Vec<u8>>,
+}
+
+impl LengthPrefixedFramer {
+    pub fn new<F>(header: F) -> LengthPrefixedFramer
+    where
+        F: Fn() -> Vec<u8> + 'static,
+    {
+        LengthPrefixedFramer {
+            header: Box::new(header),
+        }
+    }
+}
+
+impl ValueFramer for LengthPrefixedFramer {
+    fn frame(&self, value: &[u8]) -> Vec<u8> {
+        let header = (self.header)();
+        assert!(header.len() <= u16::MAX as usize, "header should be at most 65535 bytes");
+
+        let mut framed = Vec::with_capacity(2 + header.len() + value.len());
+        framed.write_u16::<BigEndian>(header.len() as u16).unwrap();
+        framed.extend_from_slice(&header);
+        framed.extend_from_slice(value);
+        framed
+    }
+
+    fn unframe(&self, bytes: &[u8]) -> MemCachedResult<(Vec<u8>, Meta)> {
+        let too_short = |needed: usize| proto::Error::OtherError {
+            desc: "Value too short for its header",
+            detail: Some(format!("value is {} bytes, its header needs {}", bytes.len(), needed)),
+        };
+
+        let mut cursor = Cursor::new(bytes);
+        let header_len = cursor.read_u16::<BigEndian>().map_err(|_| too_short(2))? as usize;
+        let mut header = vec![0u8; header_len];
+        cursor.read_exact(&mut header).map_err(|_| too_short(2 + header_len))?;
+
+        let value = bytes[2 + header_len..].to_vec();
+        Ok((value, Meta { header }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LengthPrefixedFramer, Meta, NoopFramer, ValueFramer};
+
+    #[test]
+    fn test_noop_round_trip() {
+        let framer = NoopFramer;
+        let framed = framer.frame(b"value");
+        assert_eq!(framed, b"value");
+        assert_eq!(framer.unframe(&framed).unwrap(), (b"value".to_vec(), Meta::default()));
+    }
+
+    #[test]
+    fn test_length_prefixed_round_trip() {
+        let framer = LengthPrefixedFramer::new(|| vec![1, 0, 0, 0, 0, 0x65, 0x2f, 0x7a, 0x00]);
+        let framed = framer.frame(b"value");
+        assert_eq!(&framed[..2], &[0, 9]);
+
+        let (value, meta) = framer.unframe(&framed).unwrap();
+        assert_eq!(value, b"value");
+        assert_eq!(meta.header, vec![1, 0, 0, 0, 0, 0x65, 0x2f, 0x7a, 0x00]);
+
+        let framed = framer.frame(b"");
+        assert_eq!(framer.unframe(&framed).unwrap().0, b"");
+    }
+
+    #[test]
+    fn test_length_prefixed_too_short() {
+        let framer = LengthPrefixedFramer::new(|| vec![1, 2, 3]);
+        assert!(framer.unframe(b"").is_err());
+        assert!(framer.unframe(b"\x00").is_err());
+        // Claims a 3 byte header but only has 2
+        assert!(framer.unframe(b"\x00\x03\x01\x02").is_err());
+    }
+}
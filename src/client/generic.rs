@@ -0,0 +1,254 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! The common operations for keys and values that are not byte slices yet
+//!
+//! The traits in `proto` take `&[u8]` so they stay object safe. `Client` shadows their most used
+//! methods with inherent ones that take anything `AsRef<[u8]>`, so `&str`, `String`, `Vec<u8>`
+//! and byte arrays are passed as they are. Each one forwards to the trait method of the same name.
+
+use std::collections::BTreeMap;
+
+use super::Client;
+use crate::proto::{BatchResult, CasOperation, MemCachedResult, MultiOperation, Operation};
+
+/// Generic forms of the `Operation`, `CasOperation` and `MultiOperation` methods
+///
+/// ```no_run
+/// use memcached::proto::ProtoType;
+/// use memcached::Client;
+///
+/// let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+///
+/// let user = String::from("alice");
+/// let key = format!("session:{}", user);
+/// client.set(&key, "data", 0, 300).unwrap();
+/// client.set_many([("a", vec![1u8], 0, 300), ("b", vec![2u8], 0, 300)]).unwrap();
+/// assert_eq!(client.get(key).unwrap(), (b"data".to_vec(), 0));
+/// assert_eq!(client.get_many(["a", "b", "c"]).unwrap()[2], None);
+/// ```
+impl Client {
+    /// `Operation::set`
+    pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        flags: u32,
+        expiration: u32,
+    ) -> MemCachedResult<()> {
+        Operation::set(self, key.as_ref(), value.as_ref(), flags, expiration)
+    }
+
+    /// `Operation::add`
+    pub fn add<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        flags: u32,
+        expiration: u32,
+    ) -> MemCachedResult<()> {
+        Operation::add(self, key.as_ref(), value.as_ref(), flags, expiration)
+    }
+
+    /// `Operation::replace`
+    pub fn replace<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        flags: u32,
+        expiration: u32,
+    ) -> MemCachedResult<()> {
+        Operation::replace(self, key.as_ref(), value.as_ref(), flags, expiration)
+    }
+
+    /// `Operation::get`
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> MemCachedResult<(Vec<u8>, u32)> {
+        Operation::get(self, key.as_ref())
+    }
+
+    /// `Operation::get_opt`
+    pub fn get_opt<K: AsRef<[u8]>>(&mut self, key: K) -> MemCachedResult<Option<(Vec<u8>, u32)>> {
+        Operation::get_opt(self, key.as_ref())
+    }
+
+    /// `Operation::delete`
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> MemCachedResult<()> {
+        Operation::delete(self, key.as_ref())
+    }
+
+    /// `Operation::increment`
+    pub fn increment<K: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+    ) -> MemCachedResult<u64> {
+        Operation::increment(self, key.as_ref(), amount, initial, expiration)
+    }
+
+    /// `Operation::decrement`
+    pub fn decrement<K: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+    ) -> MemCachedResult<u64> {
+        Operation::decrement(self, key.as_ref(), amount, initial, expiration)
+    }
+
+    /// `Operation::append`
+    pub fn append<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> MemCachedResult<()> {
+        Operation::append(self, key.as_ref(), value.as_ref())
+    }
+
+    /// `Operation::prepend`
+    pub fn prepend<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> MemCachedResult<()> {
+        Operation::prepend(self, key.as_ref(), value.as_ref())
+    }
+
+    /// `Operation::touch`
+    pub fn touch<K: AsRef<[u8]>>(&mut self, key: K, expiration: u32) -> MemCachedResult<()> {
+        Operation::touch(self, key.as_ref(), expiration)
+    }
+
+    /// `Operation::exists`
+    pub fn exists<K: AsRef<[u8]>>(&mut self, key: K) -> MemCachedResult<bool> {
+        Operation::exists(self, key.as_ref())
+    }
+
+    /// `CasOperation::get_cas`
+    pub fn get_cas<K: AsRef<[u8]>>(&mut self, key: K) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+        CasOperation::get_cas(self, key.as_ref())
+    }
+
+    /// `CasOperation::set_cas`
+    pub fn set_cas<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+    ) -> MemCachedResult<u64> {
+        CasOperation::set_cas(self, key.as_ref(), value.as_ref(), flags, expiration, cas)
+    }
+
+    /// Store every `(key, value, flags, expiration)` of `items`, with `MultiOperation::set_multi_collect`
+    ///
+    /// Unlike `set_multi` the keys may live on any number of servers, and there may be any number of
+    /// them. Of a key given twice the last value is stored.
+    pub fn set_many<I, K, V>(&mut self, items: I) -> MemCachedResult<BatchResult>
+    where
+        I: IntoIterator<Item = (K, V, u32, u32)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let items: Vec<_> = items.into_iter().collect();
+        let kv: BTreeMap<&[u8], (&[u8], u32, u32)> = items
+            .iter()
+            .map(|(key, value, flags, expiration)| (key.as_ref(), (value.as_ref(), *flags, *expiration)))
+            .collect();
+        self.set_multi_collect(kv)
+    }
+
+    /// The value and flags of every key of `keys`, in the same order, with `Client::multi_get`
+    pub fn get_many<I, K>(&mut self, keys: I) -> MemCachedResult<Vec<Option<(Vec<u8>, u32)>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        self.multi_get(&keys)
+    }
+
+    /// Delete every key of `keys`, with `MultiOperation::delete_multi_collect`
+    pub fn delete_many<I, K>(&mut self, keys: I) -> MemCachedResult<BatchResult>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        self.delete_multi_collect(&keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    #[test]
+    fn test_generic_arguments() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::connect(&[(mock.url(), 1)], ProtoType::Binary).unwrap();
+
+        let string = String::from("test:generic_string");
+        let vec = b"test:generic_vec".to_vec();
+        let array = *b"test:generic_array";
+
+        client.set("test:generic_str", "str", 1, 0).unwrap();
+        client.set(&string, string.clone(), 2, 0).unwrap();
+        client.set(vec.clone(), &vec, 3, 0).unwrap();
+        client.set(array, array, 4, 0).unwrap();
+        client.set(&b"test:generic_slice"[..], &b"slice"[..], 5, 0).unwrap();
+
+        assert_eq!(client.get("test:generic_str").unwrap(), (b"str".to_vec(), 1));
+        assert_eq!(client.get(&string).unwrap(), (string.clone().into_bytes(), 2));
+        assert_eq!(client.get(&vec).unwrap(), (vec.clone(), 3));
+        assert_eq!(client.get(array).unwrap(), (array.to_vec(), 4));
+        assert_eq!(client.get_opt(String::from("test:generic_missing")).unwrap(), None);
+        assert!(client.exists(array).unwrap());
+
+        client.append(&string, "!").unwrap();
+        client.prepend(string.as_str(), vec![b'<']).unwrap();
+        assert_eq!(client.get(string.clone()).unwrap().0, b"<test:generic_string!".to_vec());
+
+        assert_eq!(client.increment("test:generic_counter", 2, 10, 0).unwrap(), 10);
+        assert_eq!(
+            client
+                .increment(String::from("test:generic_counter"), 2, 10, 0)
+                .unwrap(),
+            12
+        );
+        assert_eq!(client.decrement(b"test:generic_counter", 5, 0, 0).unwrap(), 7);
+
+        let (_, _, cas) = client.get_cas(&vec).unwrap();
+        client.set_cas(&vec, "new", 3, 0, cas).unwrap();
+        client.set_cas(&vec, "newer", 3, 0, cas).unwrap_err();
+        client.touch(&vec, 60).unwrap();
+        client.replace(&vec, [b'x'; 4], 3, 0).unwrap();
+        client.add(&vec, "taken", 0, 0).unwrap_err();
+
+        let stored = client
+            .set_many(vec![
+                (String::from("test:generic_many1"), "one", 0, 0),
+                (String::from("test:generic_many2"), "two", 0, 0),
+                (String::from("test:generic_many1"), "last", 0, 0),
+            ])
+            .unwrap();
+        assert_eq!(stored.succeeded, 2);
+        assert!(stored.is_complete());
+        let found = client
+            .get_many(["test:generic_many1", "test:generic_missing", "test:generic_many2"])
+            .unwrap();
+        assert_eq!(found, vec![Some((b"last".to_vec(), 0)), None, Some((b"two".to_vec(), 0))]);
+
+        let deleted = client
+            .delete_many(vec![b"test:generic_many1".to_vec(), b"test:generic_many2".to_vec()])
+            .unwrap();
+        assert_eq!(deleted.succeeded, 2);
+        client.delete("test:generic_str").unwrap();
+        assert_eq!(client.get_many(Vec::<String>::new()).unwrap(), Vec::new());
+    }
+}
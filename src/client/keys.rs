@@ -0,0 +1,150 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Keys as they are sent to the servers
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::proto::{self, MemCachedResult, MAX_KEY_LEN};
+
+/// `key` as it goes on the wire
+///
+/// Keys up to `MAX_KEY_LEN` bytes are sent as they are. Longer ones fail with
+/// `Error::KeyTooLong`, or with `hash_long_keys` are replaced by the hex MD5 of the whole key.
+pub(crate) fn wire_key(key: &[u8], hash_long_keys: bool) -> MemCachedResult<Cow<'_, [u8]>> {
+    if key.len() <= MAX_KEY_LEN {
+        Ok(Cow::Borrowed(key))
+    } else if hash_long_keys {
+        Ok(Cow::Owned(format!("{:x}", md5::compute(key)).into_bytes()))
+    } else {
+        Err(proto::Error::KeyTooLong { len: key.len() })
+    }
+}
+
+/// Maps the keys `wire_key` hashed back to the keys the caller asked for
+pub(crate) struct Originals<'a>(HashMap<&'a [u8], &'a [u8]>);
+
+impl<'a> Originals<'a> {
+    /// The hashed ones of `keys`, which were sent as `wire`
+    pub(crate) fn new(keys: impl IntoIterator<Item = &'a [u8]>, wire: &'a [Cow<'a, [u8]>]) -> Originals<'a> {
+        Originals(
+            keys.into_iter()
+                .zip(wire)
+                .filter(|(_, wire)| matches!(wire, Cow::Owned(..)))
+                .map(|(key, wire)| (&wire[..], key))
+                .collect(),
+        )
+    }
+
+    /// The key the caller asked for that was sent as `key`
+    pub(crate) fn restore(&self, key: Vec<u8>) -> Vec<u8> {
+        match self.0.get(&key[..]) {
+            Some(original) => original.to_vec(),
+            None => key,
+        }
+    }
+
+    /// Borrowed version of `restore`
+    pub(crate) fn original<'k>(&'k self, key: &'k [u8]) -> &'k [u8] {
+        self.0.get(key).copied().unwrap_or(key)
+    }
+
+    /// `found` with the keys the caller asked for
+    pub(crate) fn restore_map<V>(&self, found: HashMap<Vec<u8>, V>) -> HashMap<Vec<u8>, V> {
+        if self.0.is_empty() {
+            return found;
+        }
+        found.into_iter().map(|(key, item)| (self.restore(key), item)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::wire_key;
+    use crate::client::Client;
+    use crate::proto::{self, MultiOperation, ProtoType, MAX_KEY_LEN};
+    use crate::test_support::MockServer;
+
+    fn long_key(tail: u8) -> Vec<u8> {
+        let mut key = vec![b'k'; MAX_KEY_LEN + 10];
+        *key.last_mut().unwrap() = tail;
+        key
+    }
+
+    #[test]
+    fn test_wire_key() {
+        let fits = vec![b'k'; MAX_KEY_LEN];
+        assert_eq!(&wire_key(&fits, false).unwrap()[..], &fits[..]);
+        assert_eq!(&wire_key(&fits, true).unwrap()[..], &fits[..]);
+
+        let (a, b) = (long_key(b'a'), long_key(b'b'));
+        match wire_key(&a, false) {
+            Err(proto::Error::KeyTooLong { len }) => assert_eq!(len, MAX_KEY_LEN + 10),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Differing only past the limit, still two keys
+        let (hashed_a, hashed_b) = (wire_key(&a, true).unwrap(), wire_key(&b, true).unwrap());
+        assert_eq!(hashed_a.len(), 32);
+        assert_ne!(hashed_a, hashed_b);
+        assert_eq!(hashed_a, wire_key(&a, true).unwrap());
+    }
+
+    #[test]
+    fn test_max_key_len() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+
+        let longest = vec![b'k'; MAX_KEY_LEN];
+        client.set(&longest, b"value", 0, 0).unwrap();
+        assert_eq!(client.get(&longest).unwrap().0, b"value");
+
+        let too_long = vec![b'k'; MAX_KEY_LEN + 1];
+        match client.set(&too_long, b"value", 0, 0) {
+            Err(proto::Error::KeyTooLong { len }) => assert_eq!(len, MAX_KEY_LEN + 1),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(mock.item_count(), 1);
+    }
+
+    #[test]
+    fn test_auto_hash_long_keys() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        let (a, b) = (long_key(b'a'), long_key(b'b'));
+
+        let mut strict = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        assert!(matches!(strict.set(&a, b"value", 0, 0), Err(proto::Error::KeyTooLong { .. })));
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(&url, 1)
+            .auto_hash_long_keys(true)
+            .build()
+            .unwrap();
+        client.set(&a, b"first", 1, 0).unwrap();
+        client.set(&b, b"second", 2, 0).unwrap();
+        assert_eq!(client.get(&a).unwrap(), (b"first".to_vec(), 1));
+        assert_eq!(client.get(&b).unwrap(), (b"second".to_vec(), 2));
+        assert_eq!(client.get_cas(&a).unwrap().0, b"first".to_vec());
+
+        let found = client.gets_multi(&[&a, &b, b"test:short"]).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[&a].0, b"first".to_vec());
+        assert_eq!(found[&b].0, b"second".to_vec());
+
+        // Stored under the hash, which is an ordinary key for a client without hashing
+        let hashed = wire_key(&a, true).unwrap();
+        assert_eq!(strict.get(&hashed).unwrap().0, b"first".to_vec());
+
+        client.delete(&a).unwrap();
+        assert!(client.get_opt(&a).unwrap().is_none());
+        assert!(client.get_opt(&b).unwrap().is_some());
+    }
+}
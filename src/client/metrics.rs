@@ -0,0 +1,154 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Observer that reports to the `metrics` facade
+
+use ::metrics::{counter, histogram, Label};
+
+use super::OpEvent;
+
+/// Sends every observed operation to the recorder installed for the `metrics` crate
+///
+/// It emits, labelled with `op` and `server`:
+///
+/// * `memcached_client_ops_total`, a counter also labelled with the `status` of the operation
+/// * `memcached_client_errors_total`, a counter of failed operations, also labelled with `status`
+/// * `memcached_client_op_duration_seconds`, a histogram of latencies
+///
+/// Every metric is also labelled with `client` if `ClientBuilder::client_label` is set.
+///
+/// `status` is one of `ok`, `not_found`, `exists`, `not_stored`, `server_error`, `io_error`,
+/// `timeout`, `busy` and `error`. Only sampled operations are reported, see
+/// `ClientBuilder::observer_sampling_rate`.
+///
+/// ```ignore
+/// let client = Client::builder(ProtoType::Binary)
+///     .add_server("tcp://127.0.0.1:11211", 1)
+///     .observer(|event| MetricsObserver.observe(event))
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsObserver;
+
+impl MetricsObserver {
+    pub fn observe(&self, event: &OpEvent) {
+        let mut labels = vec![
+            Label::new("op", event.op),
+            Label::new("server", event.server.to_owned()),
+        ];
+        if let Some(client) = event.client {
+            labels.push(Label::new("client", client.to_owned()));
+        }
+        let mut with_status = labels.clone();
+        with_status.push(Label::new("status", event.status));
+
+        counter!("memcached_client_ops_total", with_status.clone()).increment(1);
+        if !event.ok {
+            counter!("memcached_client_errors_total", with_status).increment(1);
+        }
+        histogram!("memcached_client_op_duration_seconds", labels).record(event.elapsed.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MetricsObserver;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use ::metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Sums counters and counts histogram samples by rendered key
+    #[derive(Clone, Default)]
+    struct TestRecorder {
+        values: Arc<Mutex<BTreeMap<String, u64>>>,
+    }
+
+    struct Handle {
+        key: String,
+        values: Arc<Mutex<BTreeMap<String, u64>>>,
+    }
+
+    impl CounterFn for Handle {
+        fn increment(&self, value: u64) {
+            *self.values.lock().unwrap().entry(self.key.clone()).or_insert(0) += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            self.values.lock().unwrap().insert(self.key.clone(), value);
+        }
+    }
+
+    impl HistogramFn for Handle {
+        fn record(&self, _value: f64) {
+            *self.values.lock().unwrap().entry(self.key.clone()).or_insert(0) += 1;
+        }
+    }
+
+    impl TestRecorder {
+        fn handle(&self, key: &Key) -> Arc<Handle> {
+            let labels: Vec<String> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            Arc::new(Handle {
+                key: format!("{}{{{}}}", key.name(), labels.join(",")),
+                values: self.values.clone(),
+            })
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.handle(key))
+        }
+    }
+
+    #[test]
+    fn test_metrics_observer() {
+        let recorder = TestRecorder::default();
+        ::metrics::with_local_recorder(&recorder, || {
+            let mut client = Client::builder(ProtoType::Binary)
+                .add_server("tcp://127.0.0.1:11211", 1)
+                .observer(|event| MetricsObserver.observe(event))
+                .build()
+                .unwrap();
+            client.set(b"test:metrics_observer", b"value", 0, 120).unwrap();
+            client.get(b"test:metrics_observer").unwrap();
+            client.delete(b"test:metrics_observer").unwrap();
+            client.get(b"test:metrics_observer").unwrap_err();
+        });
+
+        let server = "server=tcp://127.0.0.1:11211";
+        let values = recorder.values.lock().unwrap();
+        let value = |name: &str, labels: &str| values.get(&format!("{}{{{}}}", name, labels)).cloned();
+        assert_eq!(value("memcached_client_ops_total", &format!("op=get,{},status=ok", server)), Some(1));
+        assert_eq!(value("memcached_client_ops_total", &format!("op=get,{},status=not_found", server)), Some(1));
+        assert_eq!(value("memcached_client_ops_total", &format!("op=set,{},status=ok", server)), Some(1));
+        assert_eq!(value("memcached_client_errors_total", &format!("op=get,{},status=not_found", server)), Some(1));
+        assert_eq!(value("memcached_client_errors_total", &format!("op=set,{},status=ok", server)), None);
+        assert_eq!(value("memcached_client_op_duration_seconds", &format!("op=get,{}", server)), Some(2));
+        assert_eq!(value("memcached_client_op_duration_seconds", &format!("op=delete,{}", server)), Some(1));
+    }
+}
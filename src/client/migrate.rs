@@ -0,0 +1,107 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Copying keys from one cluster to another
+
+use super::Client;
+use crate::proto;
+
+/// Outcome of `migrate`
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Keys stored in the new cluster
+    pub copied: usize,
+    /// Keys the old cluster did not have
+    pub missing: Vec<Vec<u8>>,
+    /// Keys that could not be read or stored, with the error
+    pub failed: Vec<(Vec<u8>, proto::Error)>,
+}
+
+/// Copy the value and flags of each of `keys` from `from` to `to`
+///
+/// Keys are read with `get` and stored with `set`, so the value framers of both clients apply.
+/// Copies never expire: the remaining TTL of a key is only readable over the meta protocol, which
+/// this client does not speak. With `preserve_ttl` every key found is reported as failed instead,
+/// rather than stored without its expiration.
+pub fn migrate(from: &mut Client, to: &mut Client, keys: &[&[u8]], preserve_ttl: bool) -> MigrationReport {
+    let mut report = MigrationReport::default();
+    for &key in keys {
+        let (value, flags) = match from.get_opt(key) {
+            Ok(Some(item)) => item,
+            Ok(None) => {
+                report.missing.push(key.to_vec());
+                continue;
+            }
+            Err(err) => {
+                report.failed.push((key.to_vec(), err));
+                continue;
+            }
+        };
+        if preserve_ttl {
+            report.failed.push((
+                key.to_vec(),
+                proto::Error::OtherError {
+                    desc: "TTL is not readable over the binary protocol",
+                    detail: Some("migrate without preserve_ttl to copy the key without expiration".to_owned()),
+                },
+            ));
+            continue;
+        }
+        match to.set(key, &value, flags, 0) {
+            Ok(()) => report.copied += 1,
+            Err(err) => report.failed.push((key.to_vec(), err)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::migrate;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    fn cluster(mocks: &[MockServer]) -> Client {
+        mocks
+            .iter()
+            .fold(Client::builder(ProtoType::Binary), |builder, mock| builder.add_server(mock.url(), 1))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate() {
+        let old: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let new: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let (mut from, mut to) = (cluster(&old), cluster(&new));
+
+        let keys: Vec<Vec<u8>> = (0..5).map(|i| format!("test:migrate_{}", i).into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            from.set(key, format!("value{}", i).as_bytes(), i as u32, 0).unwrap();
+        }
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+
+        let report = migrate(&mut from, &mut to, &keys, true);
+        assert_eq!(report.copied, 0);
+        assert_eq!(report.missing, vec![keys[0].to_vec()]);
+        assert_eq!(report.failed.len(), 4);
+        assert!(to.get_opt(keys[1]).unwrap().is_none());
+
+        let report = migrate(&mut from, &mut to, &keys, false);
+        assert_eq!(report.copied, 4);
+        assert_eq!(report.missing, vec![keys[0].to_vec()]);
+        assert!(report.failed.is_empty());
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            assert_eq!(to.get(key).unwrap(), (format!("value{}", i).into_bytes(), i as u32));
+        }
+        assert!(to.get_opt(keys[0]).unwrap().is_none());
+        assert_eq!(new.iter().map(MockServer::item_count).sum::<usize>(), 4);
+    }
+}
@@ -9,24 +9,94 @@
 
 //! Memcached client
 
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{RefCell, RefMut};
 use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::ops::Deref;
 use std::path::Path;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use conhash::{ConsistentHash, Node};
+use conhash::Node;
+use log::debug;
+use socket2::SockRef;
 
 use bufstream::BufStream;
 
 #[cfg(unix)]
 use unix_socket::UnixStream;
 
-use crate::proto::{self, AuthResponse, MemCachedResult};
-use crate::proto::{CasOperation, MultiOperation, NoReplyOperation, Operation, Proto};
+use crate::flags;
+use crate::proto::{self, AuthResponse, BatchResult, Item, MemCachedResult, TouchMultiSummary};
+use crate::proto::{CasOperation, MultiOperation, NoReplyOperation, OpKind, Operation, Proto};
+
+pub use self::builder::{ClientBuilder, ConnectPreset, DEFAULT_BUSY_RETRY_AFTER, DEFAULT_MAX_VALUE_SIZE};
+pub use self::cas_update::{CasConflict, CasUpdateStats, DEFAULT_CAS_BACKOFF_BASE, DEFAULT_CAS_BACKOFF_CAP};
+#[cfg(feature = "crypto")]
+pub use self::cipher::AesGcmCipher;
+pub use self::cipher::ValueCipher;
+pub use self::clock::{Clock, SystemClock};
+pub use self::config::ClientConfig;
+pub use self::framer::{LengthPrefixedFramer, Meta, NoopFramer, ValueFramer};
+#[cfg(feature = "metrics")]
+pub use self::metrics::MetricsObserver;
+pub use self::migrate::{migrate, MigrationReport};
+pub use self::observer::OpEvent;
+#[cfg(feature = "percentiles")]
+pub use self::percentiles::Percentiles;
+pub use self::prefetch::{PrefetchStats, PREFETCH_MAX_AGE};
+pub use self::preflight::{PreflightFailure, PreflightReport, ServerPreflight, PREFLIGHT_MAX_PROBE_CANDIDATES};
+pub use self::quirks::Quirks;
+pub use self::rename::RenameOutcome;
+pub use self::scoped::{Purged, ScopedClient, SCOPE_MAX_TRACKED_KEYS};
+pub use self::set_stream::{
+    SetStreamSummary, SET_STREAM_MAX_PAUSE, SET_STREAM_MAX_RETRIES, SET_STREAM_MAX_WINDOW, SET_STREAM_PAUSE,
+};
+pub use self::settings::{SettingsWarning, GROWTH_FACTOR_RANGE, NO_EVICTIONS_MIN_MAXBYTES};
+pub use self::slab_reassign::ReassignStatus;
+pub use self::stats::{ClassStats, ClientStats, LatencyHistogram, OpStats, DEFAULT_CLASS, LATENCY_BUCKETS};
+pub use self::timeouts::{AdaptiveTimeouts, LatencyEstimate};
+pub use self::tombstone::{Checked, TOMBSTONE_MAX_ATTEMPTS};
+pub use self::watch::Watch;
+
+pub(crate) use self::ring::Ring;
+
+mod builder;
+mod cas_update;
+mod cipher;
+mod clock;
+mod config;
+mod framer;
+mod generic;
+mod keys;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod migrate;
+mod observer;
+#[cfg(feature = "percentiles")]
+mod percentiles;
+mod prefetch;
+mod preflight;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod quirks;
+mod rename;
+mod ring;
+mod scoped;
+mod server_clock;
+mod set_stream;
+mod settings;
+mod slab_reassign;
+#[cfg(feature = "socks")]
+mod socks;
+mod stats;
+mod stats_watch;
+mod timeouts;
+mod tombstone;
+mod touch_cache;
+mod watch;
 
 struct Sasl<'a> {
     username: &'a str,
@@ -37,11 +107,86 @@ struct ConnectOpts {
     connect_timeout: Option<Duration>,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
+    auth_timeout: Option<Duration>,
+    noreply_max_outstanding_bytes: Option<usize>,
+    handshake: bool,
+    nodelay: bool,
+    /// `SO_LINGER` of TCP connections, the system default for `None`
+    tcp_linger: Option<Option<Duration>>,
+    buffer_capacity: Option<usize>,
+    read_buffer_pool: Option<usize>,
+    max_pipeline_depth: Option<usize>,
+    value_copy_cutoff: Option<usize>,
+    coalesce_noreply: bool,
+    missing_flags: proto::MissingFlags,
+    strict: bool,
+    max_value_size: Option<usize>,
+    discover_max_value_size: bool,
+    detect_quirks: bool,
+    quirks: Option<Quirks>,
+    busy_backpressure: Option<Duration>,
+    client_label: Option<String>,
+    adaptive_timeouts: Option<AdaptiveTimeouts>,
+}
+
+impl Default for ConnectOpts {
+    /// What connections get without a `ClientBuilder`
+    fn default() -> ConnectOpts {
+        ConnectOpts {
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            auth_timeout: None,
+            noreply_max_outstanding_bytes: None,
+            handshake: true,
+            nodelay: true,
+            tcp_linger: None,
+            buffer_capacity: None,
+            read_buffer_pool: None,
+            max_pipeline_depth: None,
+            value_copy_cutoff: None,
+            coalesce_noreply: false,
+            missing_flags: proto::MissingFlags::default(),
+            strict: false,
+            max_value_size: None,
+            discover_max_value_size: false,
+            detect_quirks: false,
+            quirks: None,
+            busy_backpressure: None,
+            client_label: None,
+            adaptive_timeouts: None,
+        }
+    }
+}
+
+/// Read timeout for the handshake if the connection has none
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Deadline of the SASL exchange without `ClientBuilder::auth_timeout` or `connect_timeout`
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn handshake_enabled(connect_opts: &Option<ConnectOpts>) -> bool {
+    connect_opts.as_ref().is_none_or(|opts| opts.handshake)
 }
 
 struct Server {
     pub proto: Box<dyn Proto + Send>,
     addr: String,
+    /// Retry hint of the `Backpressure` errors replacing `Busy` ones, if enabled
+    busy_backpressure: Option<Duration>,
+    client_label: Option<String>,
+    /// Latency estimates setting the read timeout before each operation, if enabled
+    timeouts: Option<timeouts::Adaptive>,
+    /// How far the server's clock is ahead, see `Client::expiration_at`
+    clock_offset: Option<server_clock::ClockOffset>,
+    /// Latency of every operation together, see `ClientBuilder::fastest_replica_reads`
+    latency: timeouts::LatencyEstimate,
+    /// The error that left the connection out of step with the server, see `Client::reset_connection`
+    poisoned: Option<String>,
+    /// Longest value sent to the server, see `ClientBuilder::max_value_size`
+    max_value_size: Option<usize>,
+    /// Workarounds for the server's known bugs, see `ClientBuilder::detect_quirks`
+    quirks: Quirks,
 }
 
 impl Server {
@@ -51,7 +196,7 @@ impl Server {
         o_sasl: &Option<Sasl>,
         connect_opts: &Option<ConnectOpts>,
     ) -> io::Result<Server> {
-        let proto = {
+        let (proto, timeouts) = {
             let mut split = addr.split("://");
             match protocol {
                 proto::ProtoType::Binary => match (split.next(), split.next()) {
@@ -59,38 +204,34 @@ impl Server {
                         let stream = match connect_opts.as_ref().and_then(|opts| opts.connect_timeout) {
                             Some(timeout) => {
                                 let socket_addr: SocketAddr = addr.to_socket_addrs()?.next().unwrap();
-                                TcpStream::connect_timeout(&socket_addr, timeout)?
+                                TcpStream::connect_timeout(&socket_addr, timeout)
+                                    .map_err(|err| phase_timed_out("connect", timeout, err))?
                             }
                             None => TcpStream::connect(addr)?,
                         };
-                        if let Some(opts) = &connect_opts {
-                            stream.set_read_timeout(opts.read_timeout)?;
-                            stream.set_write_timeout(opts.write_timeout)?;
-                        }
-                        stream.set_nodelay(true)?;
-                        let mut proto =
-                            Box::new(proto::BinaryProto::new(BufStream::new(stream))) as Box<dyn Proto + Send>;
-                        if let Some(sasl) = o_sasl {
-                            let auth_str = format!("\x00{}\x00{}", sasl.username, sasl.password);
-                            match proto.auth_start("PLAIN", auth_str.as_bytes()) {
-                                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
-                                Ok(AuthResponse::Succeeded) => (),
-                                Ok(resp) => {
-                                    let msg = format!("SASL auth failed with AuthResponse: {:?}", resp);
-                                    return Err(io::Error::new(io::ErrorKind::Other, msg));
-                                }
-                            }
-                        }
-                        proto
+                        tcp_proto(stream, o_sasl, connect_opts)?
+                    }
+                    #[cfg(feature = "socks")]
+                    (Some("socks5"), Some(addr)) => {
+                        let stream = socks::connect(addr, connect_opts.as_ref().and_then(|opts| opts.connect_timeout))?;
+                        tcp_proto(stream, o_sasl, connect_opts)?
                     }
                     #[cfg(unix)]
                     (Some("unix"), Some(addr)) => {
-                        let stream = UnixStream::connect(&Path::new(addr))?;
+                        #[allow(clippy::needless_borrows_for_generic_args)]
+                        let mut stream = UnixStream::connect(&Path::new(addr))?;
                         if let Some(opts) = &connect_opts {
                             stream.set_read_timeout(opts.read_timeout)?;
                             stream.set_write_timeout(opts.write_timeout)?;
                         }
-                        Box::new(proto::BinaryProto::new(BufStream::new(stream))) as Box<dyn Proto + Send>
+                        if handshake_enabled(connect_opts) {
+                            let read_timeout = stream.read_timeout()?;
+                            stream.set_read_timeout(read_timeout.or(Some(HANDSHAKE_TIMEOUT)))?;
+                            proto::binary::handshake(&mut stream)?;
+                            stream.set_read_timeout(read_timeout)?;
+                        }
+                        let timeouts = adaptive_timeouts(connect_opts, || Ok(Box::new(stream.try_clone()?)))?;
+                        (Box::new(binary_proto(stream, connect_opts)) as Box<dyn Proto + Send>, timeouts)
                     }
                     (Some(prot), _) => {
                         panic!("Unsupported protocol: {}", prot);
@@ -99,16 +240,322 @@ impl Server {
                 },
             }
         };
-        Ok(Server { proto, addr })
+        let opts = connect_opts.as_ref();
+        let mut server = Server {
+            proto,
+            addr,
+            busy_backpressure: opts.and_then(|opts| opts.busy_backpressure),
+            client_label: opts.and_then(|opts| opts.client_label.clone()),
+            timeouts,
+            clock_offset: None,
+            latency: timeouts::LatencyEstimate::default(),
+            poisoned: None,
+            max_value_size: opts.and_then(|opts| opts.max_value_size),
+            quirks: opts.and_then(|opts| opts.quirks).unwrap_or_default(),
+        };
+        if opts.is_some_and(|opts| opts.detect_quirks && opts.quirks.is_none()) {
+            server.detect_quirks();
+        }
+        if opts.is_some_and(|opts| opts.discover_max_value_size) {
+            server.discover_max_value_size();
+        }
+        Ok(server)
+    }
+
+    /// Take `item_size_max` of the server's settings as its longest value
+    ///
+    /// A server that does not report it keeps the configured limit, `DEFAULT_MAX_VALUE_SIZE`
+    /// without one.
+    fn discover_max_value_size(&mut self) {
+        if self.quirks.stat_settings_missing {
+            self.max_value_size.get_or_insert(builder::DEFAULT_MAX_VALUE_SIZE);
+            return;
+        }
+        match self.call("stat", |proto| proto.stat_key("settings")) {
+            Ok(settings) => match settings.get("item_size_max").and_then(|value| value.parse().ok()) {
+                Some(item_size_max) => self.max_value_size = Some(item_size_max),
+                None => debug!("{} does not report item_size_max", self.addr),
+            },
+            Err(err) => debug!("Failed to read the settings of {}: {}", self.addr, err),
+        }
+        self.max_value_size.get_or_insert(builder::DEFAULT_MAX_VALUE_SIZE);
+    }
+
+    /// Derive the quirks of the server from the version it reports
+    ///
+    /// A server whose version cannot be read is taken to have none.
+    fn detect_quirks(&mut self) {
+        match self.call("version", |proto| proto.version()) {
+            Ok(version) => {
+                self.quirks = Quirks::detect(&version);
+                debug!("{} is version {} with {:?}", self.addr, version, self.quirks);
+            }
+            Err(err) => debug!("Failed to read the version of {}: {}", self.addr, err),
+        }
+    }
+
+    /// Fail with `ValueTooLarge` if `value` is longer than this server accepts
+    fn check_value_size(&self, op: &'static str, value: &[u8]) -> MemCachedResult<()> {
+        match self.max_value_size {
+            Some(max_len) if value.len() > max_len => Err(self.context(
+                op,
+                proto::Error::ValueTooLarge {
+                    len: value.len(),
+                    max_len,
+                },
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `f` on the connection, naming `op` and this server in its error
+    ///
+    /// Fails with `ConnectionPoisoned` without running `f` once an error left the connection out
+    /// of step with the server.
+    fn call<R, F>(&mut self, op: &'static str, f: F) -> MemCachedResult<R>
+    where
+        F: FnOnce(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        if let Some(ref cause) = self.poisoned {
+            let cause = cause.clone();
+            return Err(self.context(op, proto::Error::ConnectionPoisoned { cause }));
+        }
+        let started = match self.timeouts {
+            Some(ref mut timeouts) => {
+                if let Err(err) = timeouts.before(op) {
+                    return Err(self.context(op, err.into()));
+                }
+                Some(Instant::now())
+            }
+            None => None,
+        };
+        let result = f(&mut *self.proto).map_err(|err| self.context(op, self.quirks.adjust_error(op, err)));
+        if let Err(ref err) = result {
+            if err.is_timeout() {
+                // What is left of the abandoned response is skipped right away, only a connection
+                // that cannot be brought back in step is taken out of service. One that closed
+                // meanwhile has nothing left to misread.
+                match self.proto.resync() {
+                    Err(ref resync_err) if resync_err.is_timeout() || resync_err.poisons_connection() => {
+                        self.poisoned = Some(format!("{}, then resync failed: {}", err.root(), resync_err));
+                    }
+                    _ => {}
+                }
+            } else if err.poisons_connection() {
+                self.poisoned = Some(err.root().to_string());
+            }
+        }
+        if let (Some(started), Some(timeouts)) = (started, self.timeouts.as_mut()) {
+            timeouts.after(op, started.elapsed());
+        }
+        result
+    }
+
+    /// Bring the connection back in step with the server and use it again
+    fn reset(&mut self) -> MemCachedResult<()> {
+        self.proto.resync().map_err(|err| self.context("resync", err))?;
+        self.poisoned = None;
+        Ok(())
+    }
+
+    fn context(&self, op: &'static str, err: proto::Error) -> proto::Error {
+        let err = match self.busy_backpressure {
+            Some(retry_after) if err.status() == Some(proto::binary::Status::Busy) => proto::Error::Backpressure {
+                retry_after: Some(retry_after),
+            },
+            _ => err,
+        };
+        match err {
+            err @ proto::Error::WithContext { .. } => err,
+            err => proto::Error::WithContext {
+                op,
+                server: self.addr.clone(),
+                client: self.client_label.clone(),
+                source: Box::new(err),
+            },
+        }
+    }
+}
+
+impl Drop for Server {
+    /// Send what is left in the write buffer, so coalesced noreply requests are not lost when the
+    /// client goes away without calling `drain_errors`
+    fn drop(&mut self) {
+        if let Err(err) = self.proto.send_pending() {
+            debug!("Dropping buffered requests to {}: {}", self.addr, err);
+        }
+    }
+}
+
+/// Protocol over a connected TCP stream, direct or through a proxy
+fn tcp_proto(
+    mut stream: TcpStream,
+    o_sasl: &Option<Sasl>,
+    connect_opts: &Option<ConnectOpts>,
+) -> io::Result<(Box<dyn Proto + Send>, Option<timeouts::Adaptive>)> {
+    if let Some(opts) = &connect_opts {
+        stream.set_read_timeout(opts.read_timeout)?;
+        stream.set_write_timeout(opts.write_timeout)?;
+    }
+    stream.set_nodelay(connect_opts.as_ref().is_none_or(|opts| opts.nodelay))?;
+    if let Some(linger) = connect_opts.as_ref().and_then(|opts| opts.tcp_linger) {
+        SockRef::from(&stream).set_linger(linger)?;
+    }
+    if handshake_enabled(connect_opts) {
+        let read_timeout = stream.read_timeout()?;
+        stream.set_read_timeout(read_timeout.or(Some(HANDSHAKE_TIMEOUT)))?;
+        proto::binary::handshake(&mut stream)?;
+        stream.set_read_timeout(read_timeout)?;
+    }
+    let timeouts = adaptive_timeouts(connect_opts, || Ok(Box::new(stream.try_clone()?)))?;
+    let socket = stream.try_clone()?;
+    let mut proto = Box::new(binary_proto(stream, connect_opts)) as Box<dyn Proto + Send>;
+    if let Some(sasl) = o_sasl {
+        let opts = connect_opts.as_ref();
+        let timeout = opts
+            .and_then(|opts| opts.auth_timeout.or(opts.connect_timeout))
+            .unwrap_or(AUTH_TIMEOUT);
+        authenticate(&mut *proto, &socket, sasl, timeout)?;
+        socket.set_read_timeout(opts.and_then(|opts| opts.read_timeout))?;
+        socket.set_write_timeout(opts.and_then(|opts| opts.write_timeout))?;
+    }
+    Ok((proto, timeouts))
+}
+
+/// SASL `PLAIN` exchange finishing within `timeout`, whatever the timeouts of the connection
+///
+/// Every step runs with read and write timeouts of what is left of it, so a server that accepts
+/// connections but never answers cannot hold up connecting. The caller restores the configured
+/// timeouts of `socket`.
+fn authenticate(proto: &mut (dyn Proto + Send), socket: &TcpStream, sasl: &Sasl, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let auth_str = format!("\x00{}\x00{}", sasl.username, sasl.password);
+    let mut phase = "auth-start";
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(phase_timed_out(phase, timeout, io::ErrorKind::TimedOut.into()));
+        }
+        socket.set_read_timeout(Some(left))?;
+        socket.set_write_timeout(Some(left))?;
+        let resp = if phase == "auth-start" {
+            proto.auth_start("PLAIN", auth_str.as_bytes())
+        } else {
+            proto.auth_continue("PLAIN", auth_str.as_bytes())
+        };
+        match resp {
+            Ok(AuthResponse::Succeeded) => return Ok(()),
+            Ok(AuthResponse::Continue(..)) => phase = "auth-continue",
+            Ok(resp) => {
+                let msg = format!("SASL auth failed with AuthResponse: {:?}", resp);
+                return Err(io::Error::other(msg));
+            }
+            Err(proto::Error::IoError(err)) => return Err(phase_timed_out(phase, timeout, err)),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// `err` naming the step of connecting that ran past `timeout`, if it is a timeout
+fn phase_timed_out(phase: &str, timeout: Duration, err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out after {:?}", phase, timeout))
+        }
+        _ => err,
+    }
+}
+
+/// Latency tracking of a new connection, `socket` clones its stream to set read timeouts on
+fn adaptive_timeouts<F>(connect_opts: &Option<ConnectOpts>, socket: F) -> io::Result<Option<timeouts::Adaptive>>
+where
+    F: FnOnce() -> io::Result<Box<dyn timeouts::ReadTimeout>>,
+{
+    match connect_opts {
+        Some(ConnectOpts {
+            adaptive_timeouts: Some(config),
+            read_timeout,
+            ..
+        }) => Ok(Some(timeouts::Adaptive::new(*config, *read_timeout, socket()?))),
+        _ => Ok(None),
+    }
+}
+
+fn binary_proto<S: io::Read + io::Write + Send>(
+    stream: S,
+    connect_opts: &Option<ConnectOpts>,
+) -> proto::BinaryProto<BufStream<S>> {
+    let stream = match connect_opts.as_ref().and_then(|opts| opts.buffer_capacity) {
+        Some(capacity) => BufStream::with_capacities(capacity, capacity, stream),
+        None => BufStream::new(stream),
+    };
+    let mut proto = proto::BinaryProto::new(stream);
+    if let Some(opts) = connect_opts {
+        proto.set_noreply_max_outstanding_bytes(opts.noreply_max_outstanding_bytes);
+        proto.set_coalesce_noreply(opts.coalesce_noreply);
+        proto.set_missing_flags(opts.missing_flags);
+        proto.set_strict(opts.strict);
+        proto.set_read_buffer_pool(opts.read_buffer_pool.map(proto::ReadBufferPool::new));
+        proto.set_max_pipeline_depth(opts.max_pipeline_depth);
+        if let Some(cutoff) = opts.value_copy_cutoff {
+            proto.set_value_copy_cutoff(cutoff);
+        }
     }
+    proto
+}
+
+/// Number of points a server with `weight` gets on the consistent hash ring
+pub(crate) fn ring_points(weight: usize, replicas_per_node: usize) -> usize {
+    weight * replicas_per_node
 }
 
 #[derive(Clone)]
-struct ServerRef(Rc<RefCell<Server>>);
+struct ServerRef {
+    server: Rc<RefCell<Server>>,
+    /// Copy of `Server::addr`, readable while an operation holds the server
+    addr: Rc<str>,
+}
+
+impl ServerRef {
+    fn new(server: Server) -> ServerRef {
+        ServerRef {
+            addr: server.addr.as_str().into(),
+            server: Rc::new(RefCell::new(server)),
+        }
+    }
+
+    fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The server, for running an operation on it
+    ///
+    /// Fails with `ReentrantUse` instead of panicking while another operation holds the server.
+    fn lock(&self) -> MemCachedResult<RefMut<'_, Server>> {
+        self.server.try_borrow_mut().map_err(|_| proto::Error::ReentrantUse {
+            server: self.addr.to_string(),
+        })
+    }
+
+    /// Fail with `ValueTooLarge` if `value` is longer than the server accepts
+    ///
+    /// Passes while another operation holds the server, running this one then fails with
+    /// `ReentrantUse`.
+    fn check_value_size(&self, op: &'static str, value: &[u8]) -> MemCachedResult<()> {
+        match self.server.try_borrow() {
+            Ok(server) => server.check_value_size(op, value),
+            Err(_) => Ok(()),
+        }
+    }
+}
 
+/// The ring name is the address exactly as configured, never the one it resolved to
+///
+/// A hostname keeps its keys whatever DNS answers, and `tcp://[::1]:11211` is not rewritten into
+/// another spelling of the same address. Two spellings of one address are two different names.
 impl Node for ServerRef {
     fn name(&self) -> String {
-        self.0.borrow().addr.clone()
+        self.addr.to_string()
     }
 }
 
@@ -116,7 +563,7 @@ impl Deref for ServerRef {
     type Target = Rc<RefCell<Server>>;
 
     fn deref(&self) -> &Rc<RefCell<Server>> {
-        &self.0
+        &self.server
     }
 }
 
@@ -145,7 +592,29 @@ impl Deref for ServerRef {
 /// client.increment_cas(b"key:numerical", 1, 1, 20, cas_val).unwrap();
 /// ```
 pub struct Client {
-    servers: ConsistentHash<ServerRef>,
+    servers: ring::Ring<ServerRef>,
+    nodes: Vec<ServerRef>,
+    config: ClientConfig,
+    default_expiration: u32,
+    default_flags: u32,
+    observer: Option<observer::Observer>,
+    classifier: Option<stats::Classifier>,
+    stats: ClientStats,
+    #[cfg(feature = "percentiles")]
+    latencies: percentiles::OpLatencies,
+    framer: Option<Box<dyn ValueFramer>>,
+    encryption: Option<cipher::Encryption>,
+    replication_factor: usize,
+    prefetcher: Option<prefetch::Prefetcher>,
+    auto_hash_long_keys: bool,
+    cas_backoff: (Duration, Duration),
+    on_cas_conflict: Option<Box<dyn Fn(&CasConflict)>>,
+    cas_update_stats: CasUpdateStats,
+    clock: Box<dyn Clock>,
+    server_clock_refresh: Option<Duration>,
+    fastest_replica_window: Option<u32>,
+    touch_cache: Option<touch_cache::TouchCache>,
+    stats_watch: Option<stats_watch::StatsWatch>,
 }
 
 impl Client {
@@ -155,8 +624,11 @@ impl Client {
     /// as a array of tuples in this form
     ///
     /// `(address, weight)`.
+    ///
+    /// Addresses are `tcp://host:port`, `unix:///path/to/socket` on unix, or with the `socks`
+    /// feature `socks5://proxyhost:port/targethost:targetport` to go through a SOCKS5 proxy.
     pub fn connect<S: ToString>(svrs: &[(S, usize)], p: proto::ProtoType) -> io::Result<Client> {
-        Client::conn(svrs, p, None, None)
+        Client::conn(svrs, p, None, None, builder::DEFAULT_REPLICAS_PER_NODE)
     }
 
     /// Connect to Memcached servers with connect and/or IO timeouts
@@ -180,7 +652,9 @@ impl Client {
                 connect_timeout,
                 read_timeout,
                 write_timeout,
+                ..ConnectOpts::default()
             }),
+            builder::DEFAULT_REPLICAS_PER_NODE,
         )
     }
 
@@ -196,7 +670,7 @@ impl Client {
         username: &str,
         password: &str,
     ) -> io::Result<Client> {
-        Client::conn(svrs, p, Some(Sasl { username, password }), None)
+        Client::conn(svrs, p, Some(Sasl { username, password }), None, builder::DEFAULT_REPLICAS_PER_NODE)
     }
 
     /// Connect to Memcached servers that require SASL authentication with connect and/or I/O timeouts
@@ -222,278 +696,1421 @@ impl Client {
                 connect_timeout,
                 read_timeout,
                 write_timeout,
+                ..ConnectOpts::default()
             }),
+            builder::DEFAULT_REPLICAS_PER_NODE,
         )
     }
 
+    /// Create a `ClientBuilder` for more connection options
+    pub fn builder(p: proto::ProtoType) -> ClientBuilder {
+        ClientBuilder::new(p)
+    }
+
     fn conn<S: ToString>(
         svrs: &[(S, usize)],
         p: proto::ProtoType,
         sasl: Option<Sasl>,
         opts: Option<ConnectOpts>,
+        replicas_per_node: usize,
     ) -> io::Result<Client> {
         assert!(!svrs.is_empty(), "Server list should not be empty");
 
-        let mut servers = ConsistentHash::new();
+        let mut servers = ring::Ring::new();
+        let mut nodes = Vec::with_capacity(svrs.len());
         for (addr, weight) in svrs.iter() {
-            let svr = Server::connect(addr.to_string(), p, &sasl, &opts)?;
-            servers.add(&ServerRef(Rc::new(RefCell::new(svr))), *weight);
+            let svr = ServerRef::new(Server::connect(addr.to_string(), p, &sasl, &opts)?);
+            servers.add(svr.clone(), ring_points(*weight, replicas_per_node));
+            nodes.push(svr);
         }
 
-        Ok(Client { servers })
+        let config = ClientConfig {
+            servers: svrs.iter().map(|(addr, weight)| (addr.to_string(), *weight)).collect(),
+            protocol: p,
+            replicas_per_node,
+            replication_factor: 1,
+            connect_timeout: opts.as_ref().and_then(|opts| opts.connect_timeout),
+            read_timeout: opts.as_ref().and_then(|opts| opts.read_timeout),
+            write_timeout: opts.as_ref().and_then(|opts| opts.write_timeout),
+            auth_timeout: opts.as_ref().and_then(|opts| opts.auth_timeout),
+            noreply_max_outstanding_bytes: opts.as_ref().and_then(|opts| opts.noreply_max_outstanding_bytes),
+            handshake: handshake_enabled(&opts),
+            nodelay: opts.as_ref().is_none_or(|opts| opts.nodelay),
+            tcp_linger: opts.as_ref().and_then(|opts| opts.tcp_linger),
+            buffer_capacity: opts.as_ref().and_then(|opts| opts.buffer_capacity),
+            read_buffer_pool: opts.as_ref().and_then(|opts| opts.read_buffer_pool),
+            max_pipeline_depth: opts.as_ref().and_then(|opts| opts.max_pipeline_depth),
+            value_copy_cutoff: opts.as_ref().and_then(|opts| opts.value_copy_cutoff),
+            coalesce_noreply: opts.as_ref().is_some_and(|opts| opts.coalesce_noreply),
+            missing_flags: opts
+                .as_ref()
+                .map_or_else(proto::MissingFlags::default, |opts| opts.missing_flags),
+            strict: opts.as_ref().is_some_and(|opts| opts.strict),
+            max_value_size: opts.as_ref().and_then(|opts| opts.max_value_size),
+            discover_max_value_size: opts.as_ref().is_some_and(|opts| opts.discover_max_value_size),
+            detect_quirks: opts.as_ref().is_some_and(|opts| opts.detect_quirks),
+            quirks: opts.as_ref().and_then(|opts| opts.quirks),
+            busy_backpressure: opts.as_ref().and_then(|opts| opts.busy_backpressure),
+            adaptive_timeouts: opts.as_ref().and_then(|opts| opts.adaptive_timeouts),
+            client_label: opts.and_then(|opts| opts.client_label),
+            sasl_username: sasl.map(|sasl| sasl.username.to_owned()),
+            default_expiration: 0,
+            default_flags: 0,
+            auto_hash_long_keys: false,
+            cas_backoff_base: DEFAULT_CAS_BACKOFF_BASE,
+            cas_backoff_cap: DEFAULT_CAS_BACKOFF_CAP,
+            server_clock_refresh: None,
+            route_cache: None,
+            fastest_replica_window: None,
+            touch_cache: None,
+        };
+
+        Ok(Client {
+            servers,
+            nodes,
+            config,
+            default_expiration: 0,
+            default_flags: 0,
+            observer: None,
+            classifier: None,
+            stats: ClientStats::default(),
+            #[cfg(feature = "percentiles")]
+            latencies: percentiles::OpLatencies::default(),
+            framer: None,
+            encryption: None,
+            replication_factor: 1,
+            prefetcher: None,
+            auto_hash_long_keys: false,
+            cas_backoff: (DEFAULT_CAS_BACKOFF_BASE, DEFAULT_CAS_BACKOFF_CAP),
+            on_cas_conflict: None,
+            cas_update_stats: CasUpdateStats::default(),
+            clock: Box::new(SystemClock),
+            server_clock_refresh: None,
+            fastest_replica_window: None,
+            touch_cache: None,
+            stats_watch: None,
+        })
     }
 
-    fn find_server_by_key(&mut self, key: &[u8]) -> &mut ServerRef {
-        self.servers.get_mut(key).expect("No valid server found")
+    /// `key` as it is sent to the servers, see `ClientBuilder::auto_hash_long_keys`
+    fn wire_key<'k>(&self, key: &'k [u8]) -> MemCachedResult<Cow<'k, [u8]>> {
+        keys::wire_key(key, self.auto_hash_long_keys)
     }
-}
 
-impl Operation for Client {
-    fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.set(key, value, flags, expiration)
+    fn wire_keys<'k>(&self, keys: &[&'k [u8]]) -> MemCachedResult<Vec<Cow<'k, [u8]>>> {
+        keys.iter().map(|key| self.wire_key(key)).collect()
     }
 
-    fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.add(key, value, flags, expiration)
+    /// `value` and `flags` as stored under `key`, framed by the `ValueFramer` then sealed by the
+    /// `ValueCipher`
+    ///
+    /// Every operation storing a whole value goes through here, every read through `decode`.
+    fn encode<'v>(&self, key: &[u8], value: &'v [u8], flags: u32) -> (Cow<'v, [u8]>, u32) {
+        let value = match self.framer {
+            Some(ref framer) => Cow::Owned(framer.frame(value)),
+            None => Cow::Borrowed(value),
+        };
+        match self.encryption {
+            Some(ref encryption) => encryption.seal(key, value, flags),
+            None => (value, flags),
+        }
     }
 
-    fn delete(&mut self, key: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.delete(key)
+    /// Reverses `encode` on a value read from `key`, with the metadata its framer stored in it
+    fn decode(&self, key: &[u8], value: Vec<u8>, flags: u32) -> MemCachedResult<(Vec<u8>, u32, Meta)> {
+        let (value, flags) = cipher::open(self.encryption.as_ref(), key, value, flags)?;
+        match self.framer {
+            Some(ref framer) => {
+                let (value, meta) = framer.unframe(&value)?;
+                Ok((value, flags, meta))
+            }
+            None => Ok((value, flags, Meta::default())),
+        }
     }
 
-    fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.replace(key, value, flags, expiration)
+    /// `decode` for a value `op` read from `key`, failing with the context of the server `key` maps to
+    fn decode_read(
+        &self,
+        op: &'static str,
+        key: &[u8],
+        value: Vec<u8>,
+        flags: u32,
+    ) -> MemCachedResult<(Vec<u8>, u32, Meta)> {
+        self.decode(key, value, flags).map_err(|err| match self.wire_key(key) {
+            Ok(wire) => self.find_server_by_key(&wire).borrow().context(op, err),
+            Err(..) => err,
+        })
     }
 
-    fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.get(key)
+    /// Fail for an operation changing the value of `key` in place if the values of `key` are
+    /// encrypted, a ciphertext cannot be appended to or incremented
+    fn check_in_place(&self, op: &'static str, key: &[u8]) -> MemCachedResult<()> {
+        match self.encryption {
+            Some(ref encryption) if encryption.applies_to(key) => Err(proto::Error::OtherError {
+                desc: "Cannot change an encrypted value in place",
+                detail: Some(format!("`{}` on a key of `ClientBuilder::value_cipher`", op)),
+            }),
+            _ => Ok(()),
+        }
     }
 
-    fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.getk(key)
+    /// Fail with `Error::ValidationFailed` listing every item of a multi store that cannot be sent,
+    /// before any server gets a part of the batch
+    ///
+    /// `items` are the keys as the caller gave them with the values as they will be sent.
+    fn validate_stores<'a>(
+        &self,
+        op: &'static str,
+        items: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+    ) -> MemCachedResult<()> {
+        let invalid = items
+            .filter_map(|(key, value)| {
+                let err = match self.wire_key(key) {
+                    Ok(wire) => match proto::check_store(&wire, value.len()) {
+                        Some(err) => err,
+                        None => self.find_server_by_key(&wire).check_value_size(op, value).err()?,
+                    },
+                    Err(err) => err,
+                };
+                Some((key.to_vec(), err))
+            })
+            .collect();
+        proto::validated(invalid)
     }
 
-    fn increment(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.increment(key, amount, initial, expiration)
+    fn find_server_by_key(&self, key: &[u8]) -> &ServerRef {
+        self.servers.get(key).expect("No valid server found")
     }
 
-    fn decrement(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.increment(key, amount, initial, expiration)
+    /// The servers holding `key`: its owner, then the next `replication_factor - 1` servers on the ring
+    fn replicas_of(&self, key: &[u8]) -> Vec<ServerRef> {
+        self.servers
+            .successors(key)
+            .take(self.replication_factor)
+            .cloned()
+            .collect()
     }
 
-    fn append(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.append(key, value)
+    /// Run `f` on the connection of `server`, reporting it to the observer
+    fn observe<R, F>(&mut self, op: &'static str, key: &[u8], server: &ServerRef, f: F) -> MemCachedResult<R>
+    where
+        F: FnOnce(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        let sampled = self.observer.as_mut().is_some_and(|observer| observer.sample());
+        let started = if sampled || self.fastest_replica_window.is_some() {
+            Some(Instant::now())
+        } else {
+            None
+        };
+
+        let result = server.lock().and_then(|mut server| server.call(op, f));
+
+        if let (Some(started), Some(window)) = (started, self.fastest_replica_window) {
+            if let Ok(mut server) = server.try_borrow_mut() {
+                server.latency.observe(started.elapsed(), window);
+            }
+        }
+        let started = started.filter(|_| sampled);
+        if let (Some(started), Some(observer)) = (started, self.observer.as_ref()) {
+            observer.report(&OpEvent {
+                op,
+                key,
+                server: server.addr(),
+                client: self.config.client_label.as_deref(),
+                elapsed: started.elapsed(),
+                ok: result.is_ok(),
+                status: stats::outcome_of(&result),
+            });
+        }
+        result
     }
 
-    fn prepend(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.prepend(key, value)
+    fn record<R: stats::Payload>(
+        &mut self,
+        op: &'static str,
+        key: &[u8],
+        value: &[u8],
+        started: Instant,
+        result: &MemCachedResult<R>,
+    ) {
+        let elapsed = started.elapsed();
+        self.stats.record_op(op, value, elapsed, result);
+        #[cfg(feature = "percentiles")]
+        self.latencies.record(op, elapsed);
+        if let Some(ref classifier) = self.classifier {
+            self.stats.class_mut(classifier.classify(key)).record(op, value, result);
+        }
+        self.poll_stats_watch_if_due();
     }
 
-    fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.touch(key, expiration)
+    /// Drop the last touch of `key` from the touch cache, before a write that may change or remove it
+    ///
+    /// Every write goes through here whether it succeeds or not, a failed write may still have
+    /// reached the server.
+    fn forget_touched(&mut self, key: &[u8]) {
+        if let Some(cache) = self.touch_cache.as_mut() {
+            cache.forget(key);
+        }
     }
-}
 
-impl NoReplyOperation for Client {
-    fn set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.set_noreply(key, value, flags, expiration)
+    /// Run `f` on the connection of the server `key` maps to, reporting it to the observer and stats
+    ///
+    /// `value` is what the operation stores, empty for operations without a value.
+    fn dispatch<R, F>(&mut self, op: &'static str, key: &[u8], value: &[u8], f: F) -> MemCachedResult<R>
+    where
+        R: stats::Payload,
+        F: FnOnce(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        let started = Instant::now();
+        if let Some(ref mut prefetcher) = self.prefetcher {
+            prefetcher.forget(key);
+        }
+        let server = self.find_server_by_key(key).clone();
+        let result = server
+            .check_value_size(op, value)
+            .and_then(|()| self.observe(op, key, &server, f));
+        self.record(op, key, value, started, &result);
+        result
+    }
+
+    /// Like `dispatch`, but runs `f` on every replica of `key`
+    ///
+    /// The answer of the owner decides, so a conditional store the owner refused fails even where
+    /// another replica accepted it. Only if the owner gave no answer, e.g. it is down, is the first
+    /// successful result of the other replicas in ring order returned, or else the error of the owner.
+    fn dispatch_write<R, F>(&mut self, op: &'static str, key: &[u8], value: &[u8], mut f: F) -> MemCachedResult<R>
+    where
+        R: stats::Payload,
+        F: FnMut(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        self.forget_touched(key);
+        if self.replication_factor == 1 {
+            return self.dispatch(op, key, value, f);
+        }
+
+        let started = Instant::now();
+        if let Some(ref mut prefetcher) = self.prefetcher {
+            prefetcher.forget(key);
+        }
+        let mut outcome: Option<MemCachedResult<R>> = None;
+        for server in self.replicas_of(key) {
+            let result = server
+                .check_value_size(op, value)
+                .and_then(|()| self.observe(op, key, &server, &mut f));
+            let answered = outcome.as_ref().map(|outcome| match *outcome {
+                Ok(_) => true,
+                Err(ref err) => err.status().is_some(),
+            });
+            if answered.is_none() || (answered == Some(false) && result.is_ok()) {
+                outcome = Some(result);
+            }
+        }
+        let result = outcome.expect("No valid server found");
+        self.record(op, key, value, started, &result);
+        result
     }
 
-    fn add_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.add_noreply(key, value, flags, expiration)
+    /// Like `dispatch`, but tries the replicas of `key` in ring order until one succeeds
+    ///
+    /// With `ClientBuilder::fastest_replica_reads`, the replicas are tried from the lowest latency
+    /// estimate up instead. A miss or an error falls back to the next replica. If every replica
+    /// failed, returns the error of the first one tried.
+    fn dispatch_read<R, F>(&mut self, op: &'static str, key: &[u8], mut f: F) -> MemCachedResult<R>
+    where
+        R: stats::Payload,
+        F: FnMut(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        if self.replication_factor == 1 {
+            return self.dispatch(op, key, &[], f);
+        }
+
+        let started = Instant::now();
+        let mut replicas = self.replicas_of(key);
+        if self.fastest_replica_window.is_some() {
+            // Stable, so replicas without a latency yet come first in ring order and get measured
+            replicas.sort_by_key(|server| {
+                server
+                    .try_borrow()
+                    .map_or(Duration::MAX, |server| server.latency.mean())
+            });
+        }
+        let mut result = None;
+        for server in replicas {
+            match self.observe(op, key, &server, &mut f) {
+                Ok(found) => {
+                    result = Some(Ok(found));
+                    break;
+                }
+                Err(err) => {
+                    if result.is_none() {
+                        result = Some(Err(err));
+                    }
+                }
+            }
+        }
+        let result = result.expect("No valid server found");
+        self.record(op, key, &[], started, &result);
+        result
     }
 
-    fn delete_noreply(&mut self, key: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.delete_noreply(key)
+    /// Run the CAS operation `f` on the owner of `key` only, then `propagate` its effect to the other
+    /// replicas without a CAS check
+    ///
+    /// CAS tokens are per server, so only the token of the owner is meaningful. Errors of the other
+    /// replicas are ignored, they only lower redundancy.
+    fn dispatch_cas<R, F, P>(
+        &mut self,
+        op: &'static str,
+        key: &[u8],
+        value: &[u8],
+        f: F,
+        mut propagate: P,
+    ) -> MemCachedResult<R>
+    where
+        R: stats::Payload,
+        F: FnOnce(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+        P: FnMut(&mut (dyn Proto + Send)) -> MemCachedResult<()>,
+    {
+        let started = Instant::now();
+        self.forget_touched(key);
+        if let Some(ref mut prefetcher) = self.prefetcher {
+            prefetcher.forget(key);
+        }
+        let owner = self.find_server_by_key(key).clone();
+        let result = owner
+            .check_value_size(op, value)
+            .and_then(|()| self.observe(op, key, &owner, f));
+        if result.is_ok() && self.replication_factor > 1 {
+            for server in self.replicas_of(key).iter().skip(1) {
+                if let Err(err) = self.observe(op, key, server, &mut propagate) {
+                    debug!("Failed to replicate {} to {}: {}", op, server.addr(), err);
+                }
+            }
+        }
+        self.record(op, key, value, started, &result);
+        result
     }
 
-    fn replace_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.replace_noreply(key, value, flags, expiration)
+    /// Run the single key operation `op` with `f` on the servers of `key`
+    ///
+    /// Every single key operation of the traits goes through here. `key` is sent as `wire_key`
+    /// makes it, `value` is encoded or checked as it says, and `route` picks the `dispatch_*`
+    /// helper that involves the replicas, looks up and locks their connections, and reports to
+    /// the stats and the observer. `f` makes the `Request` on the connection of each server it
+    /// runs on. Values read are returned as they are, decoding them is left to the caller.
+    fn call<R, F>(&mut self, op: &'static str, route: Route, key: &[u8], value: Value, mut f: F) -> MemCachedResult<R>
+    where
+        R: stats::Payload,
+        F: FnMut(&mut (dyn Proto + Send), &Request) -> MemCachedResult<R>,
+    {
+        if let Value::InPlace(..) = value {
+            self.check_in_place(op, key)?;
+        }
+        let wire = self.wire_key(key)?;
+        let (value, flags) = match value {
+            Value::Empty => (Cow::Borrowed(&[][..]), 0),
+            Value::Stored(value, flags) => self.encode(key, value, flags),
+            Value::InPlace(value) => (Cow::Borrowed(value), 0),
+        };
+        let req = Request {
+            key: &wire,
+            value: &value,
+            flags,
+        };
+        match route {
+            Route::Read => self.dispatch_read(op, req.key, |proto| f(proto, &req)),
+            Route::Write => self.dispatch_write(op, req.key, req.value, |proto| f(proto, &req)),
+            Route::Cas(copy) => {
+                self.dispatch_cas(op, req.key, req.value, |proto| f(proto, &req), |proto| copy(proto, &req))
+            }
+        }
     }
 
-    fn increment_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server
-            .borrow_mut()
-            .proto
-            .increment_noreply(key, amount, initial, expiration)
+    /// Get a value together with the metadata its `ValueFramer` stored in it
+    ///
+    /// Without a framer, the metadata is always empty.
+    pub fn get_with_meta(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, Meta)> {
+        let wire = &*self.wire_key(key)?;
+        let prefetched = self.prefetcher.as_mut().and_then(|prefetcher| prefetcher.take(wire));
+        let (value, flags) = match prefetched {
+            Some(found) => found,
+            None => self.call("get", Route::Read, key, Value::Empty, |proto, req| proto.get(req.key))?,
+        };
+        self.prefetch_related(key);
+        self.decode_read("get", key, value, flags)
     }
 
-    fn decrement_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server
-            .borrow_mut()
-            .proto
-            .decrement_noreply(key, amount, initial, expiration)
+    /// Fetch the keys related to `key` into the prefetch buffer, in one pipelined batch per server
+    ///
+    /// Prefetching is best effort, errors are only logged.
+    fn prefetch_related(&mut self, key: &[u8]) {
+        let keys = match self.prefetcher {
+            Some(ref mut prefetcher) => prefetcher.plan(key),
+            None => return,
+        };
+        // Values are kept under the keys as sent, which is what `get_with_meta` looks up
+        let wire: Vec<Cow<[u8]>> = keys.iter().filter_map(|key| self.wire_key(key).ok()).collect();
+        for (server, batch) in self.batch_by_server(wire.iter().map(|key| &key[..]), |key| key) {
+            let result = server
+                .lock()
+                .and_then(|mut server| server.call("get_multi", |proto| proto.get_multi(&batch)));
+            match (result, self.prefetcher.as_mut()) {
+                (Ok(found), Some(prefetcher)) => {
+                    for (key, (value, flags)) in found {
+                        prefetcher.store(key, value, flags);
+                    }
+                }
+                (Err(err), _) => debug!("Failed to prefetch: {}", err),
+                (Ok(..), None) => {}
+            }
+        }
     }
 
-    fn append_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.append_noreply(key, value)
+    /// Counters of the prefetcher, all zero unless `ClientBuilder::prefetch` is set
+    pub fn prefetch_stats(&self) -> PrefetchStats {
+        self.prefetcher
+            .as_ref()
+            .map_or_else(PrefetchStats::default, |prefetcher| prefetcher.stats)
     }
 
-    fn prepend_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.prepend_noreply(key, value)
+    /// The settings this client was created with
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
     }
-}
 
-impl CasOperation for Client {
-    fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.set_cas(key, value, flags, expiration, cas)
+    /// Counters collected by this client
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
     }
 
-    fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.add_cas(key, value, flags, expiration)
+    /// Append `stats` and the points of each server on the ring to `buf`, in the Prometheus text
+    /// exposition format
+    ///
+    /// Metric names start with `memcached_client_`, and are labelled by `op` and `status`, by key
+    /// `class`, or by `server` address. The label set with `ClientBuilder::client_label` is the
+    /// `client` label of `memcached_client_info`.
+    #[cfg(feature = "prometheus")]
+    pub fn render_prometheus(&self, buf: &mut String) {
+        let servers: Vec<(String, usize)> = self
+            .servers
+            .points_per_node()
+            .map(|(server, points)| (server.addr().to_owned(), points))
+            .collect();
+        prometheus::render(&self.stats, &servers, self.config.client_label.as_deref(), buf);
     }
 
-    fn replace_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server
-            .borrow_mut()
-            .proto
-            .replace_cas(key, value, flags, expiration, cas)
+    /// Split `items` into one batch per server, keeping the order of items within each batch
+    fn batch_by_server<T, F>(&mut self, items: impl IntoIterator<Item = T>, key_of: F) -> Vec<(ServerRef, Vec<T>)>
+    where
+        F: Fn(&T) -> &[u8],
+    {
+        let mut batches: Vec<(ServerRef, Vec<T>)> = Vec::new();
+        for item in items {
+            let server = self.find_server_by_key(key_of(&item)).clone();
+            match batches.iter_mut().find(|(svr, _)| Rc::ptr_eq(svr, &server)) {
+                Some((_, batch)) => batch.push(item),
+                None => batches.push((server, vec![item])),
+            }
+        }
+        batches
     }
 
-    fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.get_cas(key)
+    /// Split `items` into one batch per server for the copies the replicas other than the owner keep,
+    /// no batch at all without replication
+    fn batch_by_copy<T, F>(&mut self, items: impl IntoIterator<Item = T>, key_of: F) -> Vec<(ServerRef, Vec<T>)>
+    where
+        T: Clone,
+        F: Fn(&T) -> &[u8],
+    {
+        let mut batches: Vec<(ServerRef, Vec<T>)> = Vec::new();
+        if self.replication_factor == 1 {
+            return batches;
+        }
+        for item in items {
+            for server in self.replicas_of(key_of(&item)).into_iter().skip(1) {
+                match batches.iter_mut().find(|(svr, _)| Rc::ptr_eq(svr, &server)) {
+                    Some((_, batch)) => batch.push(item.clone()),
+                    None => batches.push((server, vec![item.clone()])),
+                }
+            }
+        }
+        batches
     }
 
-    fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.getk_cas(key)
+    /// Run `f` on each batch of `batch_by_copy`
+    ///
+    /// Like in `dispatch_cas`, errors of the copies are ignored, they only lower redundancy.
+    fn copy_to_replicas<T, R, F>(&mut self, op: &'static str, batches: Vec<(ServerRef, Vec<T>)>, mut f: F)
+    where
+        F: FnMut(&mut (dyn Proto + Send), Vec<T>) -> MemCachedResult<R>,
+    {
+        for (server, batch) in batches {
+            let result = server
+                .lock()
+                .and_then(|mut server| server.call(op, |proto| f(proto, batch)));
+            if let Err(err) = result {
+                debug!("Failed to replicate {} to {}: {}", op, server.addr(), err);
+            }
+        }
     }
 
-    fn increment_cas(
+    /// Move the value of `old_key` to `new_key`, keeping its flags
+    ///
+    /// This is a `get_cas` of the old key, an `add` (or `set` with `overwrite`) of the new key with
+    /// `expiration`, and a `delete_cas` of the old key. It is not atomic: the keys may live on
+    /// different servers, and other clients can briefly observe both keys. If the old key is modified
+    /// in between, the copy is deleted again instead of dropping the newer value. The returned
+    /// `RenameOutcome` tells exactly where the sequence stopped.
+    pub fn rename(
         &mut self,
-        key: &[u8],
-        amount: u64,
-        initial: u64,
+        old_key: &[u8],
+        new_key: &[u8],
+        overwrite: bool,
         expiration: u32,
-        cas: u64,
-    ) -> MemCachedResult<(u64, u64)> {
-        let server = self.find_server_by_key(key);
-        server
-            .borrow_mut()
-            .proto
-            .increment_cas(key, amount, initial, expiration, cas)
+    ) -> MemCachedResult<RenameOutcome> {
+        rename::rename(self, old_key, new_key, overwrite, expiration)
+    }
+
+    /// The operations of this client with every key under `prefix`, for tests and canaries sharing
+    /// a cluster
+    ///
+    /// The returned `ScopedClient` tracks the keys it writes, so `ScopedClient::purge` can delete
+    /// them again. Writes of new keys fail once `ScopedClient::max_tracked` keys are tracked.
+    pub fn scoped(&mut self, prefix: &[u8]) -> ScopedClient<'_> {
+        ScopedClient::new(self, prefix)
     }
 
-    fn decrement_cas(
+    /// Delete `key` by replacing its value with a tombstone that lives for `ttl` seconds
+    ///
+    /// The tombstone is an empty value with the `flags::reserved::TOMBSTONE` flags. While it lives,
+    /// `get_checked` reports the key as `Checked::Tombstoned` and `set_checked` and `add_checked`
+    /// refuse to bring it back, so a slow writer holding an old value cannot repopulate a key that
+    /// is being deleted. Remove it earlier with `purge`.
+    ///
+    /// Like the `_cas` operations, the tombstone aware methods bypass the `ValueFramer`.
+    pub fn tombstone(&mut self, key: &[u8], ttl: u32) -> MemCachedResult<()> {
+        tombstone::tombstone(self, key, ttl)
+    }
+
+    /// Get `key`, telling a tombstone apart from a value
+    pub fn get_checked(&mut self, key: &[u8]) -> MemCachedResult<Checked> {
+        tombstone::get_checked(self, key)
+    }
+
+    /// Set `key` unless it holds a live tombstone, returns whether the value was written
+    ///
+    /// Without `force` the write is an `add` or a CAS checked `set`, retried up to
+    /// `TOMBSTONE_MAX_ATTEMPTS` times while other clients keep changing the key. With `force` this
+    /// is a plain `set`.
+    pub fn set_checked(
         &mut self,
         key: &[u8],
-        amount: u64,
-        initial: u64,
+        value: &[u8],
+        flags: u32,
         expiration: u32,
-        cas: u64,
-    ) -> MemCachedResult<(u64, u64)> {
-        let server = self.find_server_by_key(key);
-        server
-            .borrow_mut()
-            .proto
-            .decrement_cas(key, amount, initial, expiration, cas)
+        force: bool,
+    ) -> MemCachedResult<bool> {
+        tombstone::set_checked(self, key, value, flags, expiration, force)
     }
 
-    fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.append_cas(key, value, cas)
+    /// Add `key` unless it holds a live tombstone, returns whether the value was written
+    ///
+    /// A live value fails with `KeyExists` like `add` does. With `force` a tombstone is
+    /// overwritten.
+    pub fn add_checked(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        force: bool,
+    ) -> MemCachedResult<bool> {
+        tombstone::add_checked(self, key, value, flags, expiration, force)
     }
 
-    fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.prepend_cas(key, value, cas)
+    /// Remove the tombstone of `key` before it expires, returns whether there was one
+    ///
+    /// A live value under `key` is left alone.
+    pub fn purge(&mut self, key: &[u8]) -> MemCachedResult<bool> {
+        tombstone::purge(self, key)
     }
 
-    fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let server = self.find_server_by_key(key);
-        server.borrow_mut().proto.touch_cas(key, expiration, cas)
+    /// `set` with the default flags and expiration configured on the `ClientBuilder`
+    ///
+    /// ```no_run
+    /// use memcached::proto::{Operation, ProtoType};
+    /// use memcached::Client;
+    ///
+    /// // One place decides how long everything stays cached
+    /// let mut client = Client::builder(ProtoType::Binary)
+    ///     .add_server("tcp://127.0.0.1:11211", 1)
+    ///     .default_expiration(300)
+    ///     .default_flags(0x1)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// client.set_default(b"session:42", b"data").unwrap();
+    /// client.add_default(b"session:43", b"data").unwrap();
+    /// assert_eq!(client.get(b"session:42").unwrap(), (b"data".to_vec(), 0x1));
+    /// ```
+    pub fn set_default(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        let (flags, expiration) = (self.default_flags, self.default_expiration);
+        self.set(key, value, flags, expiration)
     }
-}
 
-impl MultiOperation for Client {
-    fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
-        assert!(kv.keys().len() > 1);
-        assert_eq!(self.servers.len(), 1);
-        let server = self.find_server_by_key(kv.keys().next().unwrap());
-        server.borrow_mut().proto.set_multi(kv)
+    /// `add` with the default flags and expiration configured on the `ClientBuilder`
+    pub fn add_default(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        let (flags, expiration) = (self.default_flags, self.default_expiration);
+        self.add(key, value, flags, expiration)
     }
-    fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
-        assert!(keys.len() > 1);
-        assert_eq!(self.servers.len(), 1);
-        let server = self.find_server_by_key(keys[0]);
-        server.borrow_mut().proto.delete_multi(keys)
+
+    /// Number of bytes a request for `op` with `key` and a value of `value_len` bytes occupies on the wire
+    ///
+    /// Computed from the protocol layout without building the packet, see `proto::binary::request_size`.
+    pub fn estimate_request_size(&self, op: OpKind, key: &[u8], value_len: usize) -> usize {
+        proto::binary::request_size(op, key.len(), value_len)
     }
-    fn increment_multi<'a>(
+
+    /// `get_multi` across servers, with the found items grouped by the address of their server
+    ///
+    /// Only servers that `keys` route to show up in the result, each with the keys it had.
+    pub fn get_multi_by_server(
         &mut self,
-        kv: HashMap<&'a [u8], (u64, u64, u32)>,
-    ) -> MemCachedResult<HashMap<&'a [u8], u64>> {
-        assert!(kv.keys().len() > 1);
-        assert_eq!(self.servers.len(), 1);
-        let server = self.find_server_by_key(kv.keys().next().unwrap());
-        server.borrow_mut().proto.increment_multi(kv)
+        keys: &[&[u8]],
+    ) -> MemCachedResult<BTreeMap<String, HashMap<Vec<u8>, (Vec<u8>, u32)>>> {
+        let wire = self.wire_keys(keys)?;
+        let mut result = BTreeMap::new();
+        for (server, batch) in self.batch_by_server(wire.iter().map(|key| &key[..]), |key| key) {
+            let mut server = server.lock()?;
+            let found = server.call("get_multi", |proto| proto.get_multi(&batch))?;
+            let originals = keys::Originals::new(keys.iter().cloned(), &wire);
+            let found = originals
+                .restore_map(found)
+                .into_iter()
+                .map(|(key, (value, flags))| {
+                    let (value, flags, _) = self
+                        .decode(&key, value, flags)
+                        .map_err(|err| server.context("get_multi", err))?;
+                    Ok((key, (value, flags)))
+                })
+                .collect::<MemCachedResult<_>>()?;
+            result.insert(server.addr.clone(), found);
+        }
+        Ok(result)
     }
-    fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
-        assert!(keys.len() > 1);
-        assert_eq!(self.servers.len(), 1);
-        let server = self.find_server_by_key(keys[0]);
-        server.borrow_mut().proto.get_multi(keys)
+
+    /// Get `keys` from the servers they route to, with the value and flags of each key at its
+    /// position in `keys`, `None` for a miss
+    ///
+    /// Each server gets its keys in one pipelined `get_multi`, split at
+    /// `ClientBuilder::max_pipeline_depth`, and is reported to the observer as one `"multi_get"`
+    /// event carrying the first of its keys. A key given twice is fetched once and returned twice.
+    /// Fails with the error of the first server that failed.
+    pub fn multi_get(&mut self, keys: &[&[u8]]) -> MemCachedResult<Vec<Option<(Vec<u8>, u32)>>> {
+        let wire = self.wire_keys(keys)?;
+        let mut found = vec![None; keys.len()];
+        for (server, batch) in self.batch_by_server(wire.iter().enumerate(), |&(_, key)| key) {
+            let batch_keys: Vec<&[u8]> = batch.iter().map(|&(_, key)| &key[..]).collect();
+            let hits = self.observe("multi_get", batch_keys[0], &server, |proto| proto.get_multi(&batch_keys))?;
+            for (i, key) in batch {
+                if let Some((value, flags)) = hits.get(&key[..]) {
+                    let (value, flags, _) = self.decode_read("multi_get", keys[i], value.clone(), *flags)?;
+                    found[i] = Some((value, flags));
+                }
+            }
+        }
+        self.poll_stats_watch_if_due();
+        Ok(found)
     }
-}
 
-#[cfg(all(test, feature = "nightly"))]
-mod bench_test {
-    use super::Client;
-    use crate::proto::{NoReplyOperation, Operation, ProtoType};
-    use test::Bencher;
+    /// Addresses of the server owning `key`, then of the next `n` distinct servers on the ring
+    ///
+    /// The servers after the owner are where its keys go if it is drained, in that order, and the
+    /// ones holding the replicas with `ClientBuilder::replication_factor`. The chain stops short
+    /// when the ring has fewer servers.
+    pub fn route_chain(&self, key: &[u8], n: usize) -> Vec<String> {
+        // A key too long to send is still placed on the ring, operations on it fail later
+        let key = self.wire_key(key).unwrap_or(Cow::Borrowed(key));
+        self.servers
+            .successors(&key)
+            .take(n.saturating_add(1))
+            .map(|server| server.addr().to_owned())
+            .collect()
+    }
 
-    fn generate_data(len: usize) -> Vec<u8> {
-        (0..len).map(|_| fastrand::u8(..)).collect()
+    /// Re-establish a clean request/response boundary on every server connection
+    ///
+    /// An operation that timed out is resynced right away. Call this after any other error that
+    /// left a connection out of step with the server (e.g. an "Invalid magic" error), or after a
+    /// resync that failed. Such errors poison the connection until then, see `reset_connection`.
+    pub fn resync(&mut self) -> MemCachedResult<()> {
+        for server in self.nodes.iter() {
+            server.lock()?.reset()?;
+        }
+        Ok(())
     }
 
-    #[bench]
-    fn bench_set_64(b: &mut Bencher) {
-        let key = b"test:test_bench";
-        let val = generate_data(64);
+    /// Resync the connection to the server added as `addr` and use it again
+    ///
+    /// Bytes that are not the expected response, or a timed out response that the automatic resync
+    /// could not skip, leave the connection out of step with the server. Every operation on it then
+    /// fails with `ConnectionPoisoned` instead of reading the leftovers as its answer, until the
+    /// connection is reset with this or `resync`. Other errors, a closed connection included, do
+    /// not poison it.
+    pub fn reset_connection(&mut self, addr: &str) -> MemCachedResult<()> {
+        let server = self.server_by_addr(addr)?;
+        let mut server = server.lock()?;
+        server.reset()
+    }
 
-        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+    /// Latency estimate of `op` on the server added as `addr`, see `ClientBuilder::adaptive_timeouts`
+    ///
+    /// `None` unless adaptive timeouts are enabled and `op` ran on that server.
+    pub fn latency_estimate(&self, addr: &str, op: &str) -> Option<LatencyEstimate> {
+        let server = self.server_by_addr(addr).ok()?;
+        let server = server.try_borrow().ok()?;
+        server.timeouts.as_ref()?.estimate(op)
+    }
 
-        b.iter(|| client.set(key, &val[..], 0, 2));
+    /// Workarounds in use for the server added as `addr`, see `ClientBuilder::detect_quirks`
+    pub fn quirks(&self, addr: &str) -> Option<Quirks> {
+        let server = self.server_by_addr(addr).ok()?;
+        let server = server.try_borrow().ok()?;
+        Some(server.quirks)
     }
 
-    #[bench]
-    fn bench_set_noreply_64(b: &mut Bencher) {
-        let key = b"test:test_bench";
-        let val = generate_data(64);
+    fn server_by_addr(&self, addr: &str) -> MemCachedResult<ServerRef> {
+        match self.nodes.iter().find(|server| server.addr() == addr) {
+            Some(server) => Ok(server.clone()),
+            None => Err(proto::Error::OtherError {
+                desc: "Unknown server address",
+                detail: Some(format!("{} is not one of the servers of this client", addr)),
+            }),
+        }
+    }
 
-        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+    /// Run `f` on the connection of the server added as `addr`
+    fn on_server<R, F>(&mut self, addr: &str, op: &'static str, f: F) -> MemCachedResult<R>
+    where
+        F: FnOnce(&mut (dyn Proto + Send)) -> MemCachedResult<R>,
+    {
+        let server = self.server_by_addr(addr)?;
+        let mut server = server.lock()?;
+        server.call(op, f)
+    }
 
-        b.iter(|| client.set_noreply(key, &val[..], 0, 2));
+    /// General statistics of the server added as `addr`
+    pub fn server_stats(&mut self, addr: &str) -> MemCachedResult<BTreeMap<String, String>> {
+        self.on_server(addr, "stat", |proto| proto.stat())
     }
 
-    #[bench]
-    fn bench_set_512(b: &mut Bencher) {
-        let key = b"test:test_bench";
-        let val = generate_data(512);
+    /// Reset the statistics counters of the server added as `addr`
+    pub fn reset_stats(&mut self, addr: &str) -> MemCachedResult<()> {
+        self.on_server(addr, "reset_stats", |proto| proto.stat_key("reset").map(|_| ()))
+    }
 
-        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+    /// Make the server added as `addr` collect per key prefix statistics
+    pub fn detailed_stats_on(&mut self, addr: &str) -> MemCachedResult<()> {
+        self.on_server(addr, "detailed_stats_on", |proto| proto.stat_key("detail on").map(|_| ()))
+    }
+
+    /// Stop collecting the statistics enabled by `detailed_stats_on`
+    pub fn detailed_stats_off(&mut self, addr: &str) -> MemCachedResult<()> {
+        self.on_server(addr, "detailed_stats_off", |proto| proto.stat_key("detail off").map(|_| ()))
+    }
+
+    /// Set the logging verbosity of the server added as `addr`
+    pub fn verbosity_on(&mut self, addr: &str, level: u32) -> MemCachedResult<()> {
+        self.on_server(addr, "verbosity", |proto| proto.verbosity(level))
+    }
+
+    /// Invalidate every item of the server added as `addr`, in `expiration` seconds or right away
+    /// for 0
+    pub fn flush_server(&mut self, addr: &str, expiration: u32) -> MemCachedResult<()> {
+        self.on_server(addr, "flush", |proto| proto.flush(expiration))
+    }
+
+    /// Fill the cache of the server added as `addr`, e.g. after it rejoined, from `source`
+    ///
+    /// Only the `keys` that route to `addr` and that it is missing are fetched from `source`, then
+    /// stored with `add_default`, so a value written in the meantime is kept. Keys `source` returns
+    /// `None` for are skipped. Returns the number of keys stored.
+    pub fn rewarm_server(
+        &mut self,
+        addr: &str,
+        source: &mut dyn FnMut(&[u8]) -> Option<Vec<u8>>,
+        keys: &[&[u8]],
+    ) -> MemCachedResult<usize> {
+        let server = self.server_by_addr(addr)?;
+        let mut stored = 0;
+        for &key in keys {
+            let wire = &*self.wire_key(key)?;
+            if !Rc::ptr_eq(self.find_server_by_key(wire), &server) {
+                continue;
+            }
+            if server
+                .lock()?
+                .call("get", |proto| proto::miss_as_none(proto.get(wire)))?
+                .is_some()
+            {
+                continue;
+            }
+            let value = match source(key) {
+                Some(value) => value,
+                None => continue,
+            };
+            match self.add_default(key, &value) {
+                Ok(()) => stored += 1,
+                Err(ref err) if err.status() == Some(proto::binary::Status::KeyExists) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Take the server added as `addr` out of rotation, then close its connection with a Quit
+    ///
+    /// From then on its keys route to the other servers as if it had never been added, and it is
+    /// gone from `config().servers`. Operations are synchronous, so none is in flight on it;
+    /// noreply requests still buffered for it are sent before the Quit. Fails with `NotFound` for
+    /// an unknown address and with `InvalidInput` for the last server.
+    pub fn drain_server(&mut self, addr: &str) -> io::Result<()> {
+        let server = self
+            .server_by_addr(addr)
+            .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+        if self.nodes.len() == 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot drain the last server"));
+        }
+
+        self.servers.remove(addr);
+        self.nodes.retain(|node| !Rc::ptr_eq(node, &server));
+        self.config.servers.retain(|(server_addr, _)| server_addr != addr);
+        let quit = server
+            .lock()
+            .and_then(|mut server| server.call("quit", |proto| proto.quit()));
+        quit.map_err(io::Error::other)
+    }
+}
+
+/// How `Client::call` involves the replicas of a key
+enum Route<'f> {
+    /// Try the replicas until one succeeds, see `Client::dispatch_read`
+    Read,
+    /// Run on every replica with the answer of the owner deciding, see `Client::dispatch_write`
+    Write,
+    /// Check the CAS token on the owner only, then make the same change with this on the other
+    /// replicas, see `Client::dispatch_cas`
+    Cas(&'f mut dyn FnMut(&mut (dyn Proto + Send), &Request) -> MemCachedResult<()>),
+}
+
+/// What `Client::call` sends as the value of an operation
+enum Value<'v> {
+    /// Nothing, e.g. for a get or a delete
+    Empty,
+    /// A whole value with its flags, sent as `Client::encode` makes them
+    Stored(&'v [u8], u32),
+    /// A change of the value in place, e.g. an append or an increment, which cannot work on a
+    /// ciphertext and is sent as it is
+    InPlace(&'v [u8]),
+}
+
+/// A single key operation of `Client::call` as it is sent to each server
+struct Request<'a> {
+    /// The key as sent, see `Client::wire_key`
+    key: &'a [u8],
+    /// The value as sent, empty for `Value::Empty`
+    value: &'a [u8],
+    /// The flags as sent, 0 unless the value is `Value::Stored`
+    flags: u32,
+}
+
+/// Trait methods of `Client` that are nothing but a `Client::call` with the method name as `op`
+///
+/// Each entry gives the arguments after `key`, the return type, then the `Route`, the `Value` and
+/// the request to make on each server.
+macro_rules! forward {
+    ($(fn $name:ident(key $(, $arg:ident: $ty:ty)*) -> $ret:ty = $route:expr, $value:expr, |$proto:ident, $req:ident| $body:expr;)*) => {
+        $(
+            fn $name(&mut self, key: &[u8] $(, $arg: $ty)*) -> MemCachedResult<$ret> {
+                self.call(stringify!($name), $route, key, $value, |$proto, $req| $body)
+            }
+        )*
+    };
+}
+
+impl Operation for Client {
+    forward! {
+        fn set(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.set(req.key, req.value, req.flags, expiration);
+        fn add(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.add(req.key, req.value, req.flags, expiration);
+        fn delete(key) -> () = Route::Write, Value::Empty, |proto, req| proto.delete(req.key);
+        fn replace(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.replace(req.key, req.value, req.flags, expiration);
+        fn increment(key, amount: u64, initial: u64, expiration: u32) -> u64 = Route::Write, Value::InPlace(&[]),
+            |proto, req| proto.increment(req.key, amount, initial, expiration);
+        fn decrement(key, amount: u64, initial: u64, expiration: u32) -> u64 = Route::Write, Value::InPlace(&[]),
+            |proto, req| proto.decrement(req.key, amount, initial, expiration);
+        fn append(key, value: &[u8]) -> () = Route::Write, Value::InPlace(value),
+            |proto, req| proto.append(req.key, req.value);
+        fn prepend(key, value: &[u8]) -> () = Route::Write, Value::InPlace(value),
+            |proto, req| proto.prepend(req.key, req.value);
+        fn exists(key) -> bool = Route::Read, Value::Empty, |proto, req| proto.exists(req.key);
+    }
+
+    fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
+        self.get_with_meta(key).map(|(value, flags, _)| (value, flags))
+    }
+
+    fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
+        let (found, value, flags) =
+            self.call("getk", Route::Read, key, Value::Empty, |proto, req| proto.getk(req.key))?;
+        let (value, flags, _) = self.decode_read("getk", key, value, flags)?;
+        Ok((found, value, flags))
+    }
+
+    fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()> {
+        let wire = self.wire_key(key)?;
+        if self
+            .touch_cache
+            .as_ref()
+            .is_some_and(|cache| cache.is_fresh(&wire, expiration))
+        {
+            return Ok(());
+        }
+        self.call("touch", Route::Write, key, Value::Empty, |proto, req| proto.touch(req.key, expiration))?;
+        if let Some(cache) = self.touch_cache.as_mut() {
+            cache.insert(&wire, expiration);
+        }
+        Ok(())
+    }
+}
+
+impl NoReplyOperation for Client {
+    forward! {
+        fn set_noreply(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.set_noreply(req.key, req.value, req.flags, expiration);
+        fn add_noreply(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.add_noreply(req.key, req.value, req.flags, expiration);
+        fn delete_noreply(key) -> () = Route::Write, Value::Empty, |proto, req| proto.delete_noreply(req.key);
+        fn replace_noreply(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.replace_noreply(req.key, req.value, req.flags, expiration);
+        fn increment_noreply(key, amount: u64, initial: u64, expiration: u32) -> () = Route::Write, Value::InPlace(&[]),
+            |proto, req| proto.increment_noreply(req.key, amount, initial, expiration);
+        fn decrement_noreply(key, amount: u64, initial: u64, expiration: u32) -> () = Route::Write, Value::InPlace(&[]),
+            |proto, req| proto.decrement_noreply(req.key, amount, initial, expiration);
+        fn append_noreply(key, value: &[u8]) -> () = Route::Write, Value::InPlace(value),
+            |proto, req| proto.append_noreply(req.key, req.value);
+        fn prepend_noreply(key, value: &[u8]) -> () = Route::Write, Value::InPlace(value),
+            |proto, req| proto.prepend_noreply(req.key, req.value);
+        fn try_set_noreply(key, value: &[u8], flags: u32, expiration: u32) -> () = Route::Write, Value::Stored(value, flags),
+            |proto, req| proto.try_set_noreply(req.key, req.value, req.flags, expiration);
+    }
+
+    fn send_pending(&mut self) -> MemCachedResult<()> {
+        for server in self.nodes.iter() {
+            server.lock()?.call("send_pending", |proto| proto.send_pending())?;
+        }
+        Ok(())
+    }
+
+    fn drain_errors(&mut self) -> MemCachedResult<Vec<proto::Error>> {
+        let mut errors = Vec::new();
+        for server in self.nodes.iter() {
+            let mut server = server.lock()?;
+            let drained = server.call("drain_errors", |proto| proto.drain_errors())?;
+            errors.extend(drained.into_iter().map(|err| server.context("noreply", err)));
+        }
+        Ok(errors)
+    }
+}
+
+impl CasOperation for Client {
+    forward! {
+        fn set_cas(key, value: &[u8], flags: u32, expiration: u32, cas: u64) -> u64
+            = Route::Cas(&mut |proto, req| proto.set(req.key, req.value, req.flags, expiration)),
+            Value::Stored(value, flags),
+            |proto, req| proto.set_cas(req.key, req.value, req.flags, expiration, cas);
+        fn add_cas(key, value: &[u8], flags: u32, expiration: u32) -> u64
+            = Route::Cas(&mut |proto, req| proto.set(req.key, req.value, req.flags, expiration)),
+            Value::Stored(value, flags),
+            |proto, req| proto.add_cas(req.key, req.value, req.flags, expiration);
+        fn replace_cas(key, value: &[u8], flags: u32, expiration: u32, cas: u64) -> u64
+            = Route::Cas(&mut |proto, req| proto.set(req.key, req.value, req.flags, expiration)),
+            Value::Stored(value, flags),
+            |proto, req| proto.replace_cas(req.key, req.value, req.flags, expiration, cas);
+        fn increment_cas(key, amount: u64, initial: u64, expiration: u32, cas: u64) -> (u64, u64)
+            = Route::Cas(&mut |proto, req| proto.increment(req.key, amount, initial, expiration).map(|_| ())),
+            Value::InPlace(&[]),
+            |proto, req| proto.increment_cas(req.key, amount, initial, expiration, cas);
+        fn decrement_cas(key, amount: u64, initial: u64, expiration: u32, cas: u64) -> (u64, u64)
+            = Route::Cas(&mut |proto, req| proto.decrement(req.key, amount, initial, expiration).map(|_| ())),
+            Value::InPlace(&[]),
+            |proto, req| proto.decrement_cas(req.key, amount, initial, expiration, cas);
+        fn append_cas(key, value: &[u8], cas: u64) -> u64
+            = Route::Cas(&mut |proto, req| proto.append(req.key, req.value)),
+            Value::InPlace(value),
+            |proto, req| proto.append_cas(req.key, req.value, cas);
+        fn prepend_cas(key, value: &[u8], cas: u64) -> u64
+            = Route::Cas(&mut |proto, req| proto.prepend(req.key, req.value)),
+            Value::InPlace(value),
+            |proto, req| proto.prepend_cas(req.key, req.value, cas);
+        fn touch_cas(key, expiration: u32, cas: u64) -> u64
+            = Route::Cas(&mut |proto, req| proto.touch(req.key, expiration)),
+            Value::Empty,
+            |proto, req| proto.touch_cas(req.key, expiration, cas);
+        fn delete_cas(key, cas: u64) -> ()
+            = Route::Cas(&mut |proto, req| proto.delete(req.key)),
+            Value::Empty,
+            |proto, req| proto.delete_cas(req.key, cas);
+        fn delete_returning_cas(key) -> Option<u64> = Route::Write, Value::Empty,
+            |proto, req| proto.delete_returning_cas(req.key);
+        fn append_bounded(key, value: &[u8], max_len: usize) -> u64
+            = Route::Cas(&mut |proto, req| proto.append(req.key, req.value)),
+            Value::InPlace(value),
+            |proto, req| proto.append_bounded(req.key, req.value, max_len);
+    }
+
+    fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+        let (value, flags, cas) =
+            self.call("get_cas", Route::Read, key, Value::Empty, |proto, req| proto.get_cas(req.key))?;
+        let (value, flags, _) = self.decode_read("get_cas", key, value, flags)?;
+        Ok((value, flags, cas))
+    }
+
+    fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
+        let (found, value, flags, cas) =
+            self.call("getk_cas", Route::Read, key, Value::Empty, |proto, req| proto.getk_cas(req.key))?;
+        let (value, flags, _) = self.decode_read("getk_cas", key, value, flags)?;
+        Ok((found, value, flags, cas))
+    }
+
+    fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+        let no_gat = self
+            .find_server_by_key(&self.wire_key(key)?)
+            .try_borrow()
+            .is_ok_and(|server| server.quirks.no_gat);
+        let mut item = self.call("gat", Route::Write, key, Value::Empty, |proto, req| {
+            if no_gat {
+                proto::gat_by_cas(proto, req.key, expiration)
+            } else {
+                proto.gat_item(req.key, expiration)
+            }
+        })?;
+        let (value, flags, _) = self.decode_read("gat", key, item.value.to_vec(), item.flags)?;
+        item.value = value.into();
+        item.flags = flags;
+        Ok(item)
+    }
+}
+
+impl MultiOperation for Client {
+    fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
+        assert!(kv.keys().len() > 1);
+        assert_eq!(self.nodes.len(), 1);
+        let sealed: Vec<_> = kv
+            .iter()
+            .map(|(key, &(value, flags, expiration))| (*key, self.encode(key, value, flags), expiration))
+            .collect();
+        self.validate_stores("set_multi", sealed.iter().map(|(key, (value, _), _)| (*key, &value[..])))?;
+        let wire = sealed
+            .iter()
+            .map(|(key, (value, flags), expiration)| Ok((self.wire_key(key)?, (&value[..], *flags, *expiration))))
+            .collect::<MemCachedResult<Vec<_>>>()?;
+        for (key, _) in wire.iter() {
+            self.forget_touched(key);
+        }
+        let kv = wire.iter().map(|(key, item)| (&key[..], *item)).collect();
+        let server = self.find_server_by_key(&wire[0].0);
+        server.lock()?.call("set_multi", |proto| proto.set_multi(kv))
+    }
+    fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
+        assert!(keys.len() > 1);
+        assert_eq!(self.nodes.len(), 1);
+        let wire = self.wire_keys(keys)?;
+        for key in wire.iter() {
+            self.forget_touched(key);
+        }
+        let keys: Vec<&[u8]> = wire.iter().map(|key| &key[..]).collect();
+        let server = self.find_server_by_key(keys[0]);
+        server.lock()?.call("delete_multi", |proto| proto.delete_multi(&keys))
+    }
+    fn increment_multi(&mut self, kv: HashMap<&[u8], (u64, u64, u32)>) -> MemCachedResult<HashMap<Vec<u8>, u64>> {
+        assert!(kv.keys().len() > 1);
+        assert_eq!(self.nodes.len(), 1);
+        let (keys, items): (Vec<&[u8]>, Vec<_>) = kv.into_iter().unzip();
+        for key in keys.iter() {
+            self.check_in_place("increment_multi", key)?;
+        }
+        let wire = self.wire_keys(&keys)?;
+        for key in wire.iter() {
+            self.forget_touched(key);
+        }
+        let server = self.find_server_by_key(&wire[0]);
+        let incremented = server.lock()?.call("increment_multi", |proto| {
+            proto.increment_multi(wire.iter().map(|key| &key[..]).zip(items.iter().cloned()).collect())
+        })?;
+        Ok(keys::Originals::new(keys.iter().cloned(), &wire).restore_map(incremented))
+    }
+    fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
+        assert!(keys.len() > 1);
+        assert_eq!(self.nodes.len(), 1);
+        let wire = self.wire_keys(keys)?;
+        let batch: Vec<&[u8]> = wire.iter().map(|key| &key[..]).collect();
+        let server = self.find_server_by_key(batch[0]);
+        let found = server.lock()?.call("get_multi", |proto| proto.get_multi(&batch))?;
+        keys::Originals::new(keys.iter().cloned(), &wire)
+            .restore_map(found)
+            .into_iter()
+            .map(|(key, (value, flags))| {
+                let (value, flags, _) = self.decode_read("get_multi", &key, value, flags)?;
+                Ok((key, (value, flags)))
+            })
+            .collect()
+    }
+    fn get_multi_foreach(&mut self, keys: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8], u32)) -> MemCachedResult<usize> {
+        let wire = self.wire_keys(keys)?;
+        let originals = keys::Originals::new(keys.iter().cloned(), &wire);
+        let mut hits = 0;
+        // The first value that fails to decrypt, the others are still read to keep the connection in sync
+        let mut failed = None;
+        for (server, batch) in self.batch_by_server(wire.iter().map(|key| &key[..]), |key| key) {
+            let mut server = server.lock()?;
+            hits += server.call("get_multi_foreach", |proto| {
+                proto.get_multi_foreach(&batch, &mut |key, value, flags| {
+                    let key = originals.original(key);
+                    if self.framer.is_none()
+                        && self.encryption.is_none()
+                        && !flags::has(flags, flags::reserved::ENCRYPTED)
+                    {
+                        return f(key, value, flags);
+                    }
+                    match self.decode(key, value.to_vec(), flags) {
+                        Ok((value, flags, _)) => f(key, &value, flags),
+                        Err(err) => {
+                            failed.get_or_insert(err);
+                        }
+                    }
+                })
+            })?;
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(hits),
+        }
+    }
+    fn touch_multi(&mut self, keys: &[(&[u8], u32)], dry_run: bool) -> MemCachedResult<TouchMultiSummary> {
+        let wire = keys
+            .iter()
+            .map(|&(key, _)| self.wire_key(key))
+            .collect::<MemCachedResult<Vec<_>>>()?;
+        if !dry_run {
+            for key in wire.iter() {
+                self.forget_touched(key);
+            }
+        }
+        let mut summary = TouchMultiSummary::default();
+        let items = wire
+            .iter()
+            .zip(keys)
+            .map(|(key, &(_, expiration))| (&key[..], expiration));
+        if !dry_run {
+            // The copies go out first, so that they are touched even if a batch of the owners fails
+            let copies = self.batch_by_copy(items.clone(), |&(key, _)| key);
+            self.copy_to_replicas("touch_multi", copies, |proto, batch| proto.touch_multi(&batch, false));
+        }
+        for (server, batch) in self.batch_by_server(items, |&(key, _)| key) {
+            summary.merge(
+                server
+                    .lock()?
+                    .call("touch_multi", |proto| proto.touch_multi(&batch, dry_run))?,
+            );
+        }
+        let originals = keys::Originals::new(keys.iter().map(|&(key, _)| key), &wire);
+        Ok(TouchMultiSummary {
+            touched: summary.touched.into_iter().map(|key| originals.restore(key)).collect(),
+            missing: summary.missing.into_iter().map(|key| originals.restore(key)).collect(),
+            errors: summary
+                .errors
+                .into_iter()
+                .map(|(key, err)| (originals.restore(key), err))
+                .collect(),
+            duplicates: summary.duplicates,
+        })
+    }
+    fn gets_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        let wire = self.wire_keys(keys)?;
+        let originals = keys::Originals::new(keys.iter().cloned(), &wire);
+        let mut result = HashMap::with_capacity(keys.len());
+        for (server, batch) in self.batch_by_server(wire.iter().map(|key| &key[..]), |key| *key) {
+            let mut server = server.lock()?;
+            for (key, (value, flags, cas)) in server.call("gets_multi", |proto| proto.gets_multi(&batch))? {
+                let original = originals.restore(key);
+                let (value, flags, _) = self
+                    .decode(&original, value, flags)
+                    .map_err(|err| server.context("gets_multi", err))?;
+                result.insert(original, (value, flags, cas));
+            }
+        }
+        Ok(result)
+    }
+    fn set_cas_multi(&mut self, items: &[(&[u8], &[u8], u32, u32, u64)]) -> MemCachedResult<Vec<MemCachedResult<u64>>> {
+        let encoded: Vec<_> = items
+            .iter()
+            .map(|&(key, value, flags, expiration, cas)| (key, self.encode(key, value, flags), expiration, cas))
+            .collect();
+        self.validate_stores("set_cas_multi", encoded.iter().map(|(key, (value, _), ..)| (*key, &value[..])))?;
+        let wire = encoded
+            .iter()
+            .map(|(key, (value, flags), expiration, cas)| {
+                Ok((self.wire_key(key)?, &value[..], *flags, *expiration, *cas))
+            })
+            .collect::<MemCachedResult<Vec<_>>>()?;
+        for (key, ..) in wire.iter() {
+            self.forget_touched(key);
+        }
+        let items = wire
+            .iter()
+            .map(|(key, value, flags, expiration, cas)| (&key[..], *value, *flags, *expiration, *cas))
+            .enumerate();
+        let mut results: Vec<Option<MemCachedResult<u64>>> = wire.iter().map(|_| None).collect();
+        for (server, batch) in self.batch_by_server(items, |(_, item)| item.0) {
+            let (indices, batch): (Vec<usize>, Vec<_>) = batch.into_iter().unzip();
+            let mut server = server.lock()?;
+            let batch_results = server.call("set_cas_multi", |proto| proto.set_cas_multi(&batch))?;
+            for (index, result) in indices.into_iter().zip(batch_results) {
+                results[index] = Some(result.map_err(|err| server.context("set_cas", err)));
+            }
+        }
+        // CAS tokens are per server: the owners checked them, the copies are stored without a check
+        let stored = wire
+            .iter()
+            .zip(results.iter())
+            .filter(|(_, result)| matches!(result, Some(Ok(..))))
+            .map(|((key, value, flags, expiration, _), _)| (&key[..], (*value, *flags, *expiration)));
+        let copies = self.batch_by_copy(stored, |&(key, _)| key);
+        self.copy_to_replicas("set_cas_multi", copies, |proto, batch| {
+            proto.set_multi_collect(batch.into_iter().collect())
+        });
+        Ok(results
+            .into_iter()
+            .map(|result| result.expect("every item is in one batch"))
+            .collect())
+    }
+    fn set_multi_collect(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<BatchResult> {
+        let keys: Vec<&[u8]> = kv.keys().cloned().collect();
+        let sealed: Vec<_> = kv
+            .iter()
+            .map(|(key, &(value, flags, expiration))| (self.encode(key, value, flags), expiration))
+            .collect();
+        self.validate_stores(
+            "set_multi_collect",
+            keys.iter()
+                .zip(&sealed)
+                .map(|(key, ((value, _), _))| (*key, &value[..])),
+        )?;
+        let wire = self.wire_keys(&keys)?;
+        for key in wire.iter() {
+            self.forget_touched(key);
+        }
+        let items = wire.iter().map(|key| &key[..]).zip(
+            sealed
+                .iter()
+                .map(|((value, flags), expiration)| (&value[..], *flags, *expiration)),
+        );
+        // The copies go out first, so that they are written even if a batch of the owners fails
+        let copies = self.batch_by_copy(items.clone(), |&(key, _)| key);
+        self.copy_to_replicas("set_multi_collect", copies, |proto, batch| {
+            proto.set_multi_collect(batch.into_iter().collect())
+        });
+        let mut result = BatchResult::default();
+        for (server, batch) in self.batch_by_server(items, |&(key, _)| key) {
+            let mut server = server.lock()?;
+            let batch =
+                server.call("set_multi_collect", |proto| proto.set_multi_collect(batch.into_iter().collect()))?;
+            result.succeeded += batch.succeeded;
+            result.failures.extend(
+                batch
+                    .failures
+                    .into_iter()
+                    .map(|(key, err)| (key, server.context("set", err))),
+            );
+        }
+        Ok(restore_failures(result, &keys, &wire))
+    }
+    fn delete_multi_collect(&mut self, keys: &[&[u8]]) -> MemCachedResult<BatchResult> {
+        let wire = self.wire_keys(keys)?;
+        for key in wire.iter() {
+            self.forget_touched(key);
+        }
+        // The copies go out first, so that they are deleted even if a batch of the owners fails
+        let copies = self.batch_by_copy(wire.iter().map(|key| &key[..]), |key| *key);
+        self.copy_to_replicas("delete_multi_collect", copies, |proto, batch| proto.delete_multi_collect(&batch));
+        let mut result = BatchResult::default();
+        for (server, batch) in self.batch_by_server(wire.iter().map(|key| &key[..]), |key| *key) {
+            let mut server = server.lock()?;
+            let batch = server.call("delete_multi_collect", |proto| proto.delete_multi_collect(&batch))?;
+            result.succeeded += batch.succeeded;
+            result.failures.extend(
+                batch
+                    .failures
+                    .into_iter()
+                    .map(|(key, err)| (key, server.context("delete", err))),
+            );
+        }
+        Ok(restore_failures(result, keys, &wire))
+    }
+}
+
+/// `result` with the keys the caller asked for, `keys` were sent as `wire`
+fn restore_failures(result: BatchResult, keys: &[&[u8]], wire: &[Cow<[u8]>]) -> BatchResult {
+    let originals = keys::Originals::new(keys.iter().cloned(), wire);
+    BatchResult {
+        succeeded: result.succeeded,
+        failures: result
+            .failures
+            .into_iter()
+            .map(|(key, err)| (originals.restore(key), err))
+            .collect(),
+    }
+}
+
+#[cfg(all(test, feature = "nightly"))]
+mod bench_test {
+    use super::{Client, ConnectPreset};
+    use crate::proto::{MultiOperation, NoReplyOperation, ProtoType};
+    use test::Bencher;
+
+    fn generate_data(len: usize) -> Vec<u8> {
+        (0..len).map(|_| fastrand::u8(..)).collect()
+    }
+
+    #[bench]
+    fn bench_set_64(b: &mut Bencher) {
+        let key = b"test:test_bench";
+        let val = generate_data(64);
+
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+
+        b.iter(|| client.set(key, &val[..], 0, 2));
+    }
+
+    #[bench]
+    fn bench_set_noreply_64(b: &mut Bencher) {
+        let key = b"test:test_bench";
+        let val = generate_data(64);
+
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+
+        b.iter(|| client.set_noreply(key, &val[..], 0, 2));
+    }
+
+    #[bench]
+    fn bench_set_512(b: &mut Bencher) {
+        let key = b"test:test_bench";
+        let val = generate_data(512);
+
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
 
         b.iter(|| client.set(key, &val[..], 0, 2));
     }
@@ -567,13 +2184,228 @@ mod bench_test {
 
         b.iter(|| client.set_noreply(key, &val[..], 0, 2));
     }
+
+    fn bench_get(b: &mut Bencher, len: usize, read_buffer_pool: Option<usize>) {
+        let key = b"test:test_bench_get";
+        let val = generate_data(len);
+
+        let mut builder = Client::builder(ProtoType::Binary).add_server("tcp://127.0.0.1:11211", 1);
+        if let Some(capacity) = read_buffer_pool {
+            builder = builder.read_buffer_pool(capacity);
+        }
+        let mut client = builder.build().unwrap();
+        client.set(key, &val[..], 0, 0).unwrap();
+
+        b.iter(|| client.get(key).unwrap());
+    }
+
+    #[bench]
+    fn bench_get_512(b: &mut Bencher) {
+        bench_get(b, 512, None);
+    }
+
+    #[bench]
+    fn bench_get_pooled_512(b: &mut Bencher) {
+        bench_get(b, 512, Some(64 * 1024));
+    }
+
+    #[bench]
+    fn bench_get_4096(b: &mut Bencher) {
+        bench_get(b, 4096, None);
+    }
+
+    #[bench]
+    fn bench_get_pooled_4096(b: &mut Bencher) {
+        bench_get(b, 4096, Some(64 * 1024));
+    }
+
+    /// Owners of 1000 keys of 240 bytes
+    fn bench_route_long_keys(b: &mut Bencher, route_cache: Option<usize>) {
+        let keys: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("test:bench_route_{:0>222}", i).into_bytes())
+            .collect();
+
+        let mut builder = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .add_server("tcp://localhost:11211", 1);
+        if let Some(entries) = route_cache {
+            builder = builder.route_cache(entries);
+        }
+        let client = builder.build().unwrap();
+
+        b.iter(|| {
+            for key in keys.iter() {
+                test::black_box(client.find_server_by_key(key));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_route_long_keys_uncached(b: &mut Bencher) {
+        bench_route_long_keys(b, None);
+    }
+
+    #[bench]
+    fn bench_route_long_keys_cached(b: &mut Bencher) {
+        bench_route_long_keys(b, Some(4096));
+    }
+
+    /// 10k noreply sets of 512 bytes, then a round-trip to make sure the server got all of them
+    fn bench_set_noreply_10k(b: &mut Bencher, preset: ConnectPreset) {
+        let keys: Vec<String> = (0..10_000).map(|i| format!("test:bench_bulk_{}", i)).collect();
+        let val = generate_data(512);
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .preset(preset)
+            .build()
+            .unwrap();
+
+        b.iter(|| {
+            for key in keys.iter() {
+                client.set_noreply(key.as_bytes(), &val[..], 0, 2).unwrap();
+            }
+            client.drain_errors().unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_set_noreply_10k_default_preset(b: &mut Bencher) {
+        bench_set_noreply_10k(b, ConnectPreset::Default);
+    }
+
+    #[bench]
+    fn bench_set_noreply_10k_bulk_transfer_preset(b: &mut Bencher) {
+        bench_set_noreply_10k(b, ConnectPreset::BulkTransfer);
+    }
+
+    /// A client with 100k values of 64 bytes stored, and their keys
+    ///
+    /// The gets of the two benches below only differ by the `HashMap` of `get_multi`, run them
+    /// under a heap profiler to compare their peak memory.
+    fn stored_100k() -> (Client, Vec<String>) {
+        let keys: Vec<String> = (0..100_000).map(|i| format!("test:bench_multi_{}", i)).collect();
+        let val = generate_data(64);
+
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+        for key in keys.iter() {
+            client.set_noreply(key.as_bytes(), &val[..], 0, 60).unwrap();
+        }
+        client.drain_errors().unwrap();
+        (client, keys)
+    }
+
+    #[bench]
+    fn bench_get_multi_100k(b: &mut Bencher) {
+        let (mut client, keys) = stored_100k();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+
+        b.iter(|| {
+            client
+                .get_multi(&keys)
+                .unwrap()
+                .values()
+                .map(|(value, _)| value.len())
+                .sum::<usize>()
+        });
+    }
+
+    #[bench]
+    fn bench_get_multi_foreach_100k(b: &mut Bencher) {
+        let (mut client, keys) = stored_100k();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+
+        b.iter(|| {
+            let mut total = 0;
+            client
+                .get_multi_foreach(&keys, &mut |_, value, _| total += value.len())
+                .unwrap();
+            total
+        });
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Client;
-    use crate::proto::{ProtoType, MultiOperation};
+    use super::{
+        ring_points, ClassStats, Client, ClientBuilder, ConnectPreset, LengthPrefixedFramer, Quirks,
+        DEFAULT_BUSY_RETRY_AFTER, DEFAULT_CLASS, DEFAULT_MAX_VALUE_SIZE,
+    };
+    use crate::proto::binary::{self, Status};
+    use crate::proto::{self, CasOperation, MultiOperation, NoReplyOperation, Operation, ProtoType};
+    use crate::test_support::{check_arithmetic, MockServer};
+    use conhash::{ConsistentHash, Node};
+    use std::cell::RefCell;
     use std::collections::{BTreeMap, HashMap};
+    use std::error;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone)]
+    struct NamedNode(String);
+
+    impl Node for NamedNode {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    /// Standard deviation of the number of keys each of 8 equally weighted servers receives
+    fn key_distribution_stddev(replicas_per_node: usize) -> f64 {
+        const SERVERS: usize = 8;
+        const KEYS: usize = 20_000;
+
+        let mut ring = ConsistentHash::new();
+        for i in 0..SERVERS {
+            ring.add(&NamedNode(format!("tcp://10.0.0.{}:11211", i)), ring_points(1, replicas_per_node));
+        }
+
+        let mut counts = HashMap::new();
+        for i in 0..KEYS {
+            let node = ring.get(format!("test:distribution_{}", i).as_bytes()).unwrap();
+            *counts.entry(node.0.clone()).or_insert(0usize) += 1;
+        }
+
+        let mean = KEYS as f64 / SERVERS as f64;
+        let variance = (0..SERVERS)
+            .map(|i| {
+                let count = counts.get(&format!("tcp://10.0.0.{}:11211", i)).cloned().unwrap_or(0);
+                (count as f64 - mean).powi(2)
+            })
+            .sum::<f64>()
+            / SERVERS as f64;
+        variance.sqrt()
+    }
+
+    #[test]
+    fn test_replicas_per_node_distribution() {
+        let coarse = key_distribution_stddev(1);
+        let medium = key_distribution_stddev(16);
+        let fine = key_distribution_stddev(160);
+
+        assert!(medium < coarse, "{} < {}", medium, coarse);
+        assert!(fine < medium, "{} < {}", fine, medium);
+    }
+
+    #[test]
+    fn test_builder_replicas_per_node() {
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .replicas_per_node(160)
+            .build()
+            .unwrap();
+
+        let mut data = BTreeMap::new();
+        data.insert(&b"test:builder_replicas1"[..], (&b"world1"[..], 0, 120));
+        data.insert(&b"test:builder_replicas2"[..], (&b"world2"[..], 0, 120));
+        client.set_multi(data).unwrap();
+        client
+            .delete_multi(&[b"test:builder_replicas1", b"test:builder_replicas2"])
+            .unwrap();
+    }
 
     #[test]
     fn test_set_multi() {
@@ -618,7 +2450,11 @@ mod test {
         data.insert(&b"test:increment_multi_num1"[..], (10, 50, 120));
         data.insert(&b"test:increment_multi_num2"[..], (20, 50, 120));
 
-        client.increment_multi(data).unwrap();
+        let incremented = client.increment_multi(data.clone()).unwrap();
+        assert_eq!(incremented.len(), 2);
+        let again = client.increment_multi(data).unwrap();
+        assert_eq!(again[&b"test:increment_multi_num1"[..]], incremented[&b"test:increment_multi_num1"[..]] + 10);
+        assert_eq!(again[&b"test:increment_multi_num2"[..]], incremented[&b"test:increment_multi_num2"[..]] + 20);
     }
 
     #[test]
@@ -629,6 +2465,1643 @@ mod test {
         client.increment_multi(HashMap::new()).unwrap();
     }
 
+    /// Accepts one connection and answers whatever arrives like an HTTP server would
+    fn http_listener() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 24];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+        });
+        format!("tcp://{}", addr)
+    }
+
+    /// Server answering every request with `Busy`
+    fn busy_listener() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 24];
+            while stream.read_exact(&mut header).is_ok() {
+                let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+                let mut body = vec![0u8; body_len as usize];
+                stream.read_exact(&mut body).unwrap();
+
+                let mut resp = [0u8; 24];
+                resp[0] = 0x81;
+                resp[1] = header[1];
+                resp[6..8].copy_from_slice(&0x0085u16.to_be_bytes());
+                resp[12..16].copy_from_slice(&header[12..16]);
+                stream.write_all(&resp).unwrap();
+            }
+        });
+        format!("tcp://{}", addr)
+    }
+
+    /// Server reading requests without answering them, after answering the first `answered` SASL
+    /// requests with `AuthenticationFurtherStepRequired`
+    fn silent_listener(answered: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 24];
+            let mut answered = answered;
+            while stream.read_exact(&mut header).is_ok() {
+                let body_len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+                let mut body = vec![0u8; body_len as usize];
+                stream.read_exact(&mut body).unwrap();
+                if answered == 0 {
+                    continue;
+                }
+                answered -= 1;
+
+                let mut resp = [0u8; 24];
+                resp[0] = 0x81;
+                resp[1] = header[1];
+                resp[6..8].copy_from_slice(&0x0021u16.to_be_bytes());
+                resp[12..16].copy_from_slice(&header[12..16]);
+                stream.write_all(&resp).unwrap();
+            }
+        });
+        format!("tcp://{}", addr)
+    }
+
+    #[test]
+    fn test_auth_timeout() {
+        for (answered, phase) in [(0, "auth-start"), (1, "auth-continue")] {
+            let started = Instant::now();
+            let err = match Client::builder(ProtoType::Binary)
+                .add_server(silent_listener(answered), 1)
+                .handshake(false)
+                .read_timeout(Duration::from_secs(30))
+                .auth_timeout(Duration::from_millis(200))
+                .sasl("user", "hunter2")
+                .build()
+            {
+                Ok(..) => panic!("{} of a silent server succeeded", phase),
+                Err(err) => err,
+            };
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert!(err.to_string().contains(phase), "{}", err);
+        }
+
+        let started = Instant::now();
+        let err = match Client::builder(ProtoType::Binary)
+            .add_server(silent_listener(0), 1)
+            .handshake(false)
+            .connect_timeout(Duration::from_millis(200))
+            .sasl("user", "hunter2")
+            .build()
+        {
+            Ok(..) => panic!("auth-start of a silent server succeeded"),
+            Err(err) => err,
+        };
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(err.to_string().contains("auth-start"), "{}", err);
+    }
+
+    #[test]
+    fn test_busy_backpressure() {
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(busy_listener(), 1)
+            .handshake(false)
+            .build()
+            .unwrap();
+        let err = client.get(b"test:busy").unwrap_err();
+        assert!(matches!(*err.root(), proto::Error::BinaryProtoError(..)));
+        assert_eq!(err.status(), Some(Status::Busy));
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(busy_listener(), 1)
+            .handshake(false)
+            .busy_backpressure(DEFAULT_BUSY_RETRY_AFTER)
+            .build()
+            .unwrap();
+        let err = client.get(b"test:busy").unwrap_err();
+        assert!(matches!(
+            *err.root(),
+            proto::Error::Backpressure {
+                retry_after: Some(DEFAULT_BUSY_RETRY_AFTER)
+            }
+        ));
+        assert_eq!(err.status(), Some(Status::Busy));
+        let err = client.set(b"test:busy", b"value", 0, 60).unwrap_err();
+        assert!(matches!(*err.root(), proto::Error::Backpressure { .. }));
+        assert_eq!(client.stats().by_op()["set"].outcomes["busy"], 1);
+    }
+
+    #[test]
+    fn test_handshake_rejects_http() {
+        let err = match Client::connect(&[(http_listener(), 1)], ProtoType::Binary) {
+            Ok(..) => panic!("connected to an HTTP server"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let msg = err.to_string();
+        assert!(msg.contains("does not speak the memcached binary protocol"), "{}", msg);
+        assert!(msg.contains("HTTP/1.1 400"), "{}", msg);
+
+        // Without the handshake the mistake only shows up on the first operation
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(http_listener(), 1)
+            .handshake(false)
+            .build()
+            .unwrap();
+        client.get(b"test:handshake").unwrap_err();
+    }
+
+    #[test]
+    fn test_handshake_before_sasl() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        mock.require_sasl("user", "hunter2");
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .sasl("user", "hunter2")
+            .build()
+            .unwrap();
+        client.set(b"test:sasl", b"value", 0, 0).unwrap();
+        assert_eq!(client.get(b"test:sasl").unwrap(), (b"value".to_vec(), 0));
+
+        let err = match Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .sasl("user", "wrong")
+            .build()
+        {
+            Ok(..) => panic!("authenticated with a wrong password"),
+            Err(err) => err,
+        };
+        assert!(!err.to_string().contains("does not speak"), "{}", err);
+
+        // The handshake passes without credentials, the operations are refused
+        let mut client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+        let err = client.get(b"test:sasl").unwrap_err();
+        assert_eq!(err.status(), Some(Status::AuthenticationError));
+    }
+
+    #[test]
+    fn test_observer_sampling() {
+        let events: Rc<RefCell<Vec<(&'static str, bool)>>> = Rc::new(RefCell::new(Vec::new()));
+        let observed = events.clone();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .observer(move |event| observed.borrow_mut().push((event.op, event.ok)))
+            .observer_sampling_rate(10)
+            .build()
+            .unwrap();
+
+        client.set(b"test:observer", b"value", 0, 120).unwrap();
+        for _ in 0..99 {
+            client.get(b"test:observer").unwrap();
+        }
+        assert_eq!(events.borrow().len(), 10);
+        assert_eq!(events.borrow()[0], ("set", true));
+        assert!(events.borrow()[1..].iter().all(|&event| event == ("get", true)));
+
+        let observed = events.clone();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .observer(move |event| observed.borrow_mut().push((event.op, event.ok)))
+            .build()
+            .unwrap();
+        events.borrow_mut().clear();
+        client.delete(b"test:observer").unwrap();
+        client.decrement(b"test:observer", 1, 10, 120).unwrap();
+        client.delete(b"test:observer").unwrap();
+        client.get(b"test:observer").unwrap_err();
+        assert_eq!(*events.borrow(), vec![("delete", true), ("decrement", true), ("delete", true), ("get", false)]);
+    }
+
+    #[test]
+    fn test_reentrant_use() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let events: Rc<RefCell<Vec<(&'static str, bool)>>> = Rc::new(RefCell::new(Vec::new()));
+        let observed = events.clone();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .observer(move |event| observed.borrow_mut().push((event.op, event.ok)))
+            .build()
+            .unwrap();
+        client.set(b"test:reentrant", b"value", 0, 120).unwrap();
+
+        // What a callback that re-entered the client in the middle of an operation runs into
+        let server = client.nodes[0].clone();
+        let running = server.lock().unwrap();
+        match client.get(b"test:reentrant").unwrap_err() {
+            proto::Error::ReentrantUse { server } => assert_eq!(server, mock.url()),
+            err => panic!("unexpected error {}", err),
+        }
+        assert!(client.server_stats(&mock.url()).is_err());
+        assert!(client.resync().is_err());
+        assert!(client.latency_estimate(&mock.url(), "get").is_none());
+        drop(running);
+
+        assert_eq!(client.get(b"test:reentrant").unwrap().0, b"value");
+        assert_eq!(*events.borrow(), vec![("set", true), ("get", false), ("get", true)]);
+    }
+
+    /// A forwarding method, the op it reports, the key it works on and the value left there
+    type Forwarding =
+        (&'static str, fn(&mut Client) -> proto::MemCachedResult<()>, &'static [u8], Option<&'static [u8]>);
+
+    #[test]
+    fn test_forwarding() {
+        // Every single key method should reach the operation it is named after, not a neighbour
+        // it was copied from, and report under its own name
+        const KEY: &[u8] = b"test:forwarding";
+        const COUNTER: &[u8] = b"test:forwarding_counter";
+        let steps: &[Forwarding] = &[
+            ("set", |c| c.set(KEY, b"a", 0, 0), KEY, Some(b"a")),
+            ("append", |c| c.append(KEY, b"b"), KEY, Some(b"ab")),
+            ("prepend", |c| c.prepend(KEY, b"c"), KEY, Some(b"cab")),
+            ("replace", |c| c.replace(KEY, b"d", 0, 0), KEY, Some(b"d")),
+            ("getk", |c| c.getk(KEY).map(|_| ()), KEY, Some(b"d")),
+            ("touch", |c| c.touch(KEY, 60), KEY, Some(b"d")),
+            ("exists", |c| c.exists(KEY).map(|_| ()), KEY, Some(b"d")),
+            ("delete", |c| c.delete(KEY), KEY, None),
+            ("add", |c| c.add(KEY, b"e", 0, 0), KEY, Some(b"e")),
+            ("increment", |c| c.increment(COUNTER, 5, 10, 0).map(|_| ()), COUNTER, Some(b"10")),
+            ("increment", |c| c.increment(COUNTER, 5, 10, 0).map(|_| ()), COUNTER, Some(b"15")),
+            ("decrement", |c| c.decrement(COUNTER, 3, 0, 0).map(|_| ()), COUNTER, Some(b"12")),
+            ("increment_noreply", |c| c.increment_noreply(COUNTER, 2, 0, 0), COUNTER, Some(b"14")),
+            ("decrement_noreply", |c| c.decrement_noreply(COUNTER, 4, 0, 0), COUNTER, Some(b"10")),
+            ("increment_cas", |c| c.increment_cas(COUNTER, 1, 0, 0, 0).map(|_| ()), COUNTER, Some(b"11")),
+            ("decrement_cas", |c| c.decrement_cas(COUNTER, 2, 0, 0, 0).map(|_| ()), COUNTER, Some(b"9")),
+            ("set_noreply", |c| c.set_noreply(KEY, b"f", 0, 0), KEY, Some(b"f")),
+            ("append_noreply", |c| c.append_noreply(KEY, b"g"), KEY, Some(b"fg")),
+            ("prepend_noreply", |c| c.prepend_noreply(KEY, b"h"), KEY, Some(b"hfg")),
+            ("replace_noreply", |c| c.replace_noreply(KEY, b"i", 0, 0), KEY, Some(b"i")),
+            ("delete_noreply", |c| c.delete_noreply(KEY), KEY, None),
+            ("add_noreply", |c| c.add_noreply(KEY, b"j", 0, 0), KEY, Some(b"j")),
+            ("try_set_noreply", |c| c.try_set_noreply(KEY, b"k", 0, 0), KEY, Some(b"k")),
+            ("set_cas", |c| c.set_cas(KEY, b"l", 0, 0, 0).map(|_| ()), KEY, Some(b"l")),
+            ("append_cas", |c| c.append_cas(KEY, b"m", 0).map(|_| ()), KEY, Some(b"lm")),
+            ("prepend_cas", |c| c.prepend_cas(KEY, b"n", 0).map(|_| ()), KEY, Some(b"nlm")),
+            ("replace_cas", |c| c.replace_cas(KEY, b"o", 0, 0, 0).map(|_| ()), KEY, Some(b"o")),
+            ("get_cas", |c| c.get_cas(KEY).map(|_| ()), KEY, Some(b"o")),
+            ("getk_cas", |c| c.getk_cas(KEY).map(|_| ()), KEY, Some(b"o")),
+            ("gat", |c| c.gat_item(KEY, 60).map(|_| ()), KEY, Some(b"o")),
+            ("touch_cas", |c| c.touch_cas(KEY, 60, 0).map(|_| ()), KEY, Some(b"o")),
+            ("append_bounded", |c| c.append_bounded(KEY, b"p", 100).map(|_| ()), KEY, Some(b"op")),
+            ("delete_cas", |c| c.delete_cas(KEY, 0), KEY, None),
+            ("add_cas", |c| c.add_cas(KEY, b"q", 0, 0).map(|_| ()), KEY, Some(b"q")),
+            ("delete_returning_cas", |c| c.delete_returning_cas(KEY).map(|_| ()), KEY, None),
+        ];
+
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        let mut checker = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        let calls = |client: &Client, op: &str| client.stats().by_op().get(op).map_or(0, |stats| stats.latency.count());
+        for &(op, forward, key, expected) in steps {
+            let before = calls(&client, op);
+            if let Err(err) = forward(&mut client) {
+                panic!("{} failed: {}", op, err);
+            }
+            // Waits for the noreply requests to be processed
+            assert!(client.drain_errors().unwrap().is_empty(), "{}", op);
+            assert_eq!(calls(&client, op), before + 1, "{} reported under another name", op);
+            let found = proto::miss_as_none(checker.get(key)).unwrap().map(|(value, _)| value);
+            assert_eq!(found.as_deref(), expected, "{} did something else", op);
+        }
+    }
+
+    #[test]
+    fn test_poisoned_connection() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .read_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        client.set(b"test:poisoned_a", b"a", 0, 0).unwrap();
+        client.set(b"test:poisoned_b", b"b", 0, 0).unwrap();
+
+        // The answer to this get is still on its way when it gives up, and when the resync does
+        mock.set_latency(Duration::from_millis(200));
+        let err = client.get(b"test:poisoned_a").unwrap_err();
+        assert!(err.is_timeout() && !err.poisons_connection(), "{}", err);
+        mock.set_latency(Duration::ZERO);
+        thread::sleep(Duration::from_millis(250));
+
+        // Instead of taking it for the answer to the next one
+        for _ in 0..2 {
+            match client.get(b"test:poisoned_b").unwrap_err().into_root() {
+                proto::Error::ConnectionPoisoned { .. } => {}
+                err => panic!("unexpected error {}", err),
+            }
+        }
+        client.reset_connection(&mock.url()).unwrap();
+        assert_eq!(client.get(b"test:poisoned_b").unwrap().0, b"b");
+        assert_eq!(client.get(b"test:poisoned_a").unwrap().0, b"a");
+
+        // A miss or any other answer of the server does not poison it
+        client.get(b"test:poisoned_missing").unwrap_err();
+        assert_eq!(client.get(b"test:poisoned_b").unwrap().0, b"b");
+
+        // Nor does a closed connection, which has nothing left to misread
+        let mut mock = mock;
+        mock.stop();
+        for _ in 0..2 {
+            let err = client.get(b"test:poisoned_b").unwrap_err();
+            assert!(matches!(err.root(), proto::Error::IoError(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_timeout_resyncs() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .read_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        client.set(b"test:timeout_resync_a", b"a", 0, 0).unwrap();
+        client.set(b"test:timeout_resync_b", b"b", 0, 0).unwrap();
+
+        // The get gives up before its answer arrives, which the resync then skips
+        mock.set_latency(Duration::from_millis(150));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(30));
+                mock.set_latency(Duration::ZERO);
+            });
+            let err = client.get(b"test:timeout_resync_a").unwrap_err();
+            assert!(err.is_timeout(), "{}", err);
+        });
+        assert_eq!(client.get(b"test:timeout_resync_b").unwrap().0, b"b");
+        assert_eq!(client.get(b"test:timeout_resync_a").unwrap().0, b"a");
+    }
+
+    #[test]
+    fn test_strict_poisons_connection() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let keys: &[&[u8]] = &[b"test:strict_a", b"test:strict_b", b"test:strict_a"];
+        let mut lenient = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        lenient.delete_multi(keys).unwrap();
+
+        let mut strict = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .strict(true)
+            .build()
+            .unwrap();
+        match strict.delete_multi(keys).unwrap_err().into_root() {
+            proto::Error::ProtocolAnomaly(proto::Anomaly::DuplicateKey { key }) => assert_eq!(key, b"test:strict_a"),
+            err => panic!("unexpected error {}", err),
+        }
+        match strict.get(b"test:strict_a").unwrap_err().into_root() {
+            proto::Error::ConnectionPoisoned { .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        strict.reset_connection(&mock.url()).unwrap();
+        strict.delete_multi(&keys[..2]).unwrap();
+    }
+
+    #[test]
+    fn test_discover_max_value_size() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        mock.set_setting("item_size_max", "4194304");
+        let large = vec![b'x'; 2 * 1024 * 1024];
+
+        let mut capped = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .max_value_size(DEFAULT_MAX_VALUE_SIZE)
+            .build()
+            .unwrap();
+        match capped
+            .set(b"test:max_value_size", &large, 0, 0)
+            .unwrap_err()
+            .into_root()
+        {
+            proto::Error::ValueTooLarge { len, max_len } => {
+                assert_eq!((len, max_len), (large.len(), DEFAULT_MAX_VALUE_SIZE))
+            }
+            err => panic!("unexpected error {}", err),
+        }
+        assert_eq!(mock.item_count(), 0);
+
+        let mut discovered = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .max_value_size(DEFAULT_MAX_VALUE_SIZE)
+            .discover_max_value_size(true)
+            .build()
+            .unwrap();
+        discovered.set(b"test:max_value_size", &large, 0, 0).unwrap();
+        assert_eq!(discovered.get(b"test:max_value_size").unwrap().0.len(), large.len());
+        let mut kv: BTreeMap<&[u8], (&[u8], u32, u32)> = BTreeMap::new();
+        kv.insert(b"test:max_value_size_a", (b"a", 0, 0));
+        kv.insert(b"test:max_value_size_b", (&large[..], 0, 0));
+        capped.set_multi(kv.clone()).unwrap_err();
+        discovered.set_multi(kv).unwrap();
+
+        // Past the discovered limit it is refused before reaching the server
+        let huge = vec![b'x'; 4 * 1024 * 1024 + 1];
+        match discovered
+            .set(b"test:max_value_size", &huge, 0, 0)
+            .unwrap_err()
+            .into_root()
+        {
+            proto::Error::ValueTooLarge { max_len, .. } => assert_eq!(max_len, 4 * 1024 * 1024),
+            err => panic!("unexpected error {}", err),
+        }
+        assert_eq!(discovered.get(b"test:max_value_size").unwrap().0.len(), large.len());
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let detected = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .detect_quirks(true)
+            .build()
+            .unwrap();
+        assert_eq!(detected.quirks(&mock.url()), Some(Quirks::default()));
+        assert_eq!(detected.quirks("tcp://127.0.0.1:1"), None);
+
+        mock.set_setting("item_size_max", "4194304");
+        mock.set_setting("inter", "NULL");
+        let quirks = Quirks {
+            no_gat: true,
+            stat_settings_missing: true,
+            ..Quirks::default()
+        };
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .quirks(quirks)
+            .detect_quirks(true)
+            .discover_max_value_size(true)
+            .build()
+            .unwrap();
+        assert_eq!(client.quirks(&mock.url()), Some(quirks));
+
+        client.set(b"test:quirks", b"value", 0xcafe, 0).unwrap();
+        let item = client.gat_item(b"test:quirks", 60).unwrap();
+        assert_eq!((&item.value[..], item.flags), (&b"value"[..], 0xcafe));
+        assert_eq!(mock.touch_count(), 0);
+
+        // The settings are never read, so the default limit stays
+        let large = vec![b'x'; 2 * 1024 * 1024];
+        match client.set(b"test:quirks", &large, 0, 0).unwrap_err().into_root() {
+            proto::Error::ValueTooLarge { max_len, .. } => assert_eq!(max_len, DEFAULT_MAX_VALUE_SIZE),
+            err => panic!("unexpected error {}", err),
+        }
+        assert!(client.check_settings().is_empty());
+    }
+
+    #[test]
+    fn test_stats_by_class() {
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .key_classes(&[(b"test:stats:user:", "user"), (b"test:stats:user:session:", "session")])
+            .build()
+            .unwrap();
+
+        client.set(b"test:stats:user:1", b"12345", 0, 120).unwrap();
+        client.get(b"test:stats:user:1").unwrap();
+        let _ = client.delete(b"test:stats:user:2");
+        client.get(b"test:stats:user:2").unwrap_err();
+        client.set(b"test:stats:user:session:1", b"123", 0, 120).unwrap();
+        client.append(b"test:stats:user:session:1", b"45").unwrap();
+        client.get_cas(b"test:stats:user:session:1").unwrap();
+        client.set(b"test:stats:other", b"1", 0, 120).unwrap();
+
+        let by_class = client.stats().by_class();
+        assert_eq!(
+            by_class["user"],
+            ClassStats {
+                hits: 1,
+                misses: 1,
+                sets: 1,
+                bytes: 10,
+            }
+        );
+        assert_eq!(
+            by_class["session"],
+            ClassStats {
+                hits: 1,
+                misses: 0,
+                sets: 2,
+                bytes: 10,
+            }
+        );
+        assert_eq!(by_class[DEFAULT_CLASS].sets, 1);
+        assert_eq!(by_class.len(), 3);
+
+        fn classify(key: &[u8]) -> &'static str {
+            if key.ends_with(b"1") {
+                "odd"
+            } else {
+                "even"
+            }
+        }
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .key_classifier(classify)
+            .build()
+            .unwrap();
+        client.get(b"test:stats:user:1").unwrap();
+        client.delete(b"test:stats:user:1").unwrap();
+        assert_eq!(client.stats().by_class()["odd"].hits, 1);
+        assert_eq!(client.stats().by_class().len(), 1);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .default_expiration(1)
+            .default_flags(0xcafe)
+            .build()
+            .unwrap();
+
+        let _ = client.delete(b"test:defaults_add");
+        client.set_default(b"test:defaults_set", b"value").unwrap();
+        client.add_default(b"test:defaults_add", b"value").unwrap();
+        client.add_default(b"test:defaults_add", b"value").unwrap_err();
+        assert_eq!(client.get(b"test:defaults_set").unwrap(), (b"value".to_vec(), 0xcafe));
+        assert_eq!(client.get(b"test:defaults_add").unwrap(), (b"value".to_vec(), 0xcafe));
+
+        thread::sleep(Duration::from_secs(2));
+        client.get(b"test:defaults_set").unwrap_err();
+        client.get(b"test:defaults_add").unwrap_err();
+    }
+
+    #[test]
+    fn test_touch_multi_fans_out() {
+        // Two names for the same memcached, so keys are split across two connections
+        let mut client =
+            Client::connect(&[("tcp://127.0.0.1:11211", 1), ("tcp://localhost:11211", 1)], ProtoType::Binary).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..8)
+            .map(|i| format!("test:touch_multi_fan_out{}", i).into_bytes())
+            .collect();
+        for key in keys.iter().skip(4) {
+            client.set(key, b"value", 0, 120).unwrap();
+        }
+        for key in keys.iter().take(4) {
+            let _ = client.delete(key);
+        }
+
+        let req: Vec<(&[u8], u32)> = keys.iter().map(|key| (&key[..], 120)).collect();
+        let mut summary = client.touch_multi(&req, false).unwrap();
+        summary.touched.sort();
+        summary.missing.sort();
+        assert_eq!(summary.touched, keys[4..].to_vec());
+        assert_eq!(summary.missing, keys[..4].to_vec());
+        assert!(summary.errors.is_empty());
+
+        for key in keys.iter().skip(4) {
+            client.delete(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replication_factor() {
+        let mut mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let urls: Vec<String> = mocks.iter().map(|mock| mock.url()).collect();
+        let mut builder = Client::builder(ProtoType::Binary).replication_factor(2);
+        for url in urls.iter() {
+            builder = builder.add_server(url, 1);
+        }
+        let mut client = builder.build().unwrap();
+        let mut direct: Vec<Client> = urls
+            .iter()
+            .map(|url| Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap())
+            .collect();
+
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("test:replicated{}", i).into_bytes()).collect();
+        for key in keys.iter() {
+            client.set(key, b"value", 0, 60).unwrap();
+            let copies = direct
+                .iter_mut()
+                .map(|server| server.get(key))
+                .filter(Result::is_ok)
+                .count();
+            assert_eq!(copies, 2);
+        }
+
+        // CAS writes check the owner's token and are copied to the other replica
+        let (_, _, cas) = client.get_cas(&keys[0]).unwrap();
+        client.set_cas(&keys[0], b"other", 0, 60, cas).unwrap();
+        let copies = direct
+            .iter_mut()
+            .map(|server| server.get(&keys[0]))
+            .filter(|found| found.as_ref().is_ok_and(|(value, _)| value == b"other"))
+            .count();
+        assert_eq!(copies, 2);
+
+        client.delete(&keys[1]).unwrap();
+        assert!(direct.iter_mut().all(|server| server.get(&keys[1]).is_err()));
+
+        // The owner decides conditional stores, even where the other replica accepts them
+        let chain: Vec<usize> = client
+            .route_chain(&keys[1], 1)
+            .iter()
+            .map(|addr| urls.iter().position(|url| url == addr).unwrap())
+            .collect();
+        direct[chain[0]].set(&keys[1], b"owner", 0, 60).unwrap();
+        let err = client.add(&keys[1], b"value", 0, 60).unwrap_err();
+        assert_eq!(err.status(), Some(Status::KeyExists));
+        assert_eq!(client.get(&keys[1]).unwrap().0, b"owner");
+
+        // Arithmetic and concatenation reach every replica, so a fallback read sees them
+        client.set(&keys[1], b"1", 0, 60).unwrap();
+        assert_eq!(client.increment(&keys[1], 1, 0, 60).unwrap(), 2);
+        client.append(&keys[1], b"0").unwrap();
+        client.prepend_noreply(&keys[1], b"1").unwrap();
+        client.send_pending().unwrap();
+        for &i in chain.iter() {
+            assert_eq!(direct[i].get(&keys[1]).unwrap().0, b"120");
+        }
+
+        // Every key survives losing its owner
+        let owner = client.find_server_by_key(&keys[2]).borrow().addr.clone();
+        mocks.iter_mut().find(|mock| mock.url() == owner).unwrap().stop();
+        assert_eq!(client.get(&keys[2]).unwrap(), (b"value".to_vec(), 0));
+        for key in keys.iter().skip(2) {
+            assert!(client.get(key).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_replicated_multi_writes() {
+        let mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut builder = Client::builder(ProtoType::Binary).replication_factor(2);
+        for mock in mocks.iter() {
+            builder = builder.add_server(mock.url(), 1);
+        }
+        let mut client = builder.build().unwrap();
+        let mut direct: Vec<Client> = mocks
+            .iter()
+            .map(|mock| Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap())
+            .collect();
+        let keys: Vec<Vec<u8>> = (0..10)
+            .map(|i| format!("test:replicated_multi{}", i).into_bytes())
+            .collect();
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let copies = |direct: &mut Vec<Client>, key: &[u8], value: &[u8]| {
+            direct
+                .iter_mut()
+                .map(|server| server.get(key))
+                .filter(|found| found.as_ref().is_ok_and(|(found, _)| found == value))
+                .count()
+        };
+
+        let result = client
+            .set_multi_collect(refs.iter().map(|&key| (key, (&b"value"[..], 0, 60))).collect())
+            .unwrap();
+        assert_eq!(result.succeeded, keys.len());
+        for key in keys.iter() {
+            assert_eq!(copies(&mut direct, key, b"value"), 2);
+        }
+
+        // Only the owner checks the token, the copy is stored without it
+        let (_, _, cas) = client.get_cas(&keys[0]).unwrap();
+        let results = client
+            .set_cas_multi(&[
+                (&keys[0], b"other", 0, 60, cas),
+                (&keys[1], b"other", 0, 60, cas + 1000),
+            ])
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().status(), Some(Status::KeyExists));
+        assert_eq!(copies(&mut direct, &keys[0], b"other"), 2);
+        assert_eq!(copies(&mut direct, &keys[1], b"value"), 2);
+
+        // A fallback read cannot bring back a deleted value
+        let result = client.delete_multi_collect(&refs).unwrap();
+        assert!(result.is_complete());
+        assert_eq!(mocks.iter().map(|mock| mock.item_count()).sum::<usize>(), 0);
+        assert_eq!(client.get_opt(&keys[0]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_error_context() {
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+        let _ = client.delete(b"test:error_context_missing");
+        client.set(b"test:error_context", b"value", 0, 120).unwrap();
+
+        let err = client.get(b"test:error_context_missing").unwrap_err();
+        assert_eq!(err.to_string(), format!("get on tcp://127.0.0.1:11211 failed: {}", err.root()));
+        assert_eq!(err.status(), Some(Status::KeyNotFound));
+        assert!(matches!(*err.root(), proto::Error::BinaryProtoError(..)));
+        assert_eq!(error::Error::source(&err).unwrap().to_string(), err.root().to_string());
+
+        let err = client.get_cas(b"test:error_context_missing").unwrap_err();
+        assert!(matches!(err, proto::Error::WithContext { op: "get_cas", .. }));
+        assert_eq!(err.status(), Some(Status::KeyNotFound));
+
+        client.add_noreply(b"test:error_context", b"value", 0, 120).unwrap();
+        let errors = client.drain_errors().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .to_string()
+            .starts_with("noreply on tcp://127.0.0.1:11211 failed: "));
+        assert_eq!(errors[0].status(), Some(Status::KeyExists));
+
+        let (_, _, cas) = client.get_cas(b"test:error_context").unwrap();
+        let results = client
+            .set_cas_multi(&[(b"test:error_context", b"other", 0, 120, cas + 1)])
+            .unwrap();
+        let err = results.into_iter().next().unwrap().unwrap_err();
+        assert!(
+            matches!(err, proto::Error::WithContext { op: "set_cas", ref server, .. } if server == "tcp://127.0.0.1:11211")
+        );
+        assert_eq!(err.into_root().to_string(), binary::Error::from_status(Status::KeyExists, None).to_string());
+
+        client.delete(b"test:error_context").unwrap();
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .client_label("checkout-7f9".to_owned())
+            .build()
+            .unwrap();
+        let err = client.get(b"test:error_context_missing").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("get on tcp://127.0.0.1:11211 by client checkout-7f9 failed: {}", err.root())
+        );
+    }
+
+    #[test]
+    fn test_admin_commands() {
+        let addr = "tcp://127.0.0.1:11211";
+        let mut client = Client::connect(&[(addr, 1)], ProtoType::Binary).unwrap();
+
+        // Other tests share the server, so only check that the counter went down
+        let cmd_get = |client: &mut Client| client.server_stats(addr).unwrap()["cmd_get"].parse::<u64>().unwrap();
+        for _ in 0..100 {
+            client.get(b"test:admin_commands").unwrap_err();
+        }
+        let before = cmd_get(&mut client);
+        client.reset_stats(addr).unwrap();
+        assert!(cmd_get(&mut client) < before);
+
+        client.detailed_stats_on(addr).unwrap();
+        client.detailed_stats_off(addr).unwrap();
+        client.verbosity_on(addr, 1).unwrap();
+        client.verbosity_on(addr, 0).unwrap();
+
+        let err = client.reset_stats("tcp://127.0.0.1:11212").unwrap_err();
+        assert!(matches!(
+            err,
+            proto::Error::OtherError {
+                desc: "Unknown server address",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_rewarm_server() {
+        // Two names for the same memcached, so keys are split across two connections
+        let target = "tcp://localhost:11211";
+        let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1), (target, 1)], ProtoType::Binary).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("test:rewarm{}", i).into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        for key in keys.iter() {
+            let _ = client.delete(key);
+        }
+        let routed: Vec<&[u8]> = keys
+            .iter()
+            .cloned()
+            .filter(|key| client.find_server_by_key(key).borrow().addr == target)
+            .collect();
+        assert!(!routed.is_empty() && routed.len() < keys.len());
+        // Already warm, must not be asked for
+        client.set(routed[0], b"fresh", 0, 120).unwrap();
+
+        let mut asked = Vec::new();
+        let mut source = |key: &[u8]| {
+            asked.push(key.to_vec());
+            Some(b"warm".to_vec())
+        };
+        let stored = client.rewarm_server(target, &mut source, &keys).unwrap();
+        assert_eq!(stored, routed.len() - 1);
+        assert_eq!(asked, routed[1..].iter().map(|key| key.to_vec()).collect::<Vec<_>>());
+
+        assert_eq!(client.get(routed[0]).unwrap().0, b"fresh");
+        for key in keys.iter() {
+            match client.get(key) {
+                Ok(..) => assert!(routed.contains(key)),
+                Err(..) => assert!(!routed.contains(key)),
+            }
+            let _ = client.delete(key);
+        }
+
+        client
+            .rewarm_server("tcp://127.0.0.1:11212", &mut |_| None, &keys)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_rewarm_server_long_keys() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .auto_hash_long_keys(true)
+            .build()
+            .unwrap();
+        let target = mocks[0].url();
+
+        let keys: Vec<Vec<u8>> = (0..20)
+            .map(|i| format!("test:rewarm_long{}:{}", i, "x".repeat(proto::MAX_KEY_LEN)).into_bytes())
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let routed: Vec<&[u8]> = keys
+            .iter()
+            .cloned()
+            .filter(|key| client.find_server_by_key(&client.wire_key(key).unwrap()).borrow().addr == target)
+            .collect();
+        assert!(!routed.is_empty() && routed.len() < keys.len());
+        client.set(routed[0], b"fresh", 0, 120).unwrap();
+
+        let stored = client
+            .rewarm_server(&target, &mut |_| Some(b"warm".to_vec()), &keys)
+            .unwrap();
+        assert_eq!(stored, routed.len() - 1);
+        assert_eq!(mocks[0].item_count(), routed.len());
+        assert_eq!(mocks[1].item_count(), 0);
+        assert_eq!(client.get(routed[0]).unwrap().0, b"fresh");
+    }
+
+    #[test]
+    fn test_drain_server() {
+        let mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = mocks
+            .iter()
+            .fold(Client::builder(ProtoType::Binary), |builder, mock| builder.add_server(mock.url(), 1))
+            .route_cache(1024)
+            .build()
+            .unwrap();
+        assert_eq!(client.config().route_cache, Some(1024));
+
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("test:drain_server_{}", i).into_bytes())
+            .collect();
+        let owner = |client: &Client, key: &[u8]| client.find_server_by_key(key).borrow().addr.clone();
+        let before: Vec<String> = keys.iter().map(|key| owner(&client, key)).collect();
+        let drained = before[0].clone();
+        let (drained_mock, others): (Vec<&MockServer>, Vec<&MockServer>) =
+            mocks.iter().partition(|mock| mock.url() == drained);
+
+        // Still buffered when the server is drained
+        client.set_noreply(&keys[0], b"value", 0, 0).unwrap();
+        client.drain_server(&drained).unwrap();
+        assert_eq!(client.config().servers.len(), 2);
+        for (key, before) in keys.iter().zip(before) {
+            let after = owner(&client, key);
+            assert_ne!(after, drained);
+            assert_eq!(after, client.servers.successors(key).next().unwrap().borrow().addr);
+            // Only the keys of the drained server move
+            if before != drained {
+                assert_eq!(after, before);
+            }
+            client.set(key, b"value", 0, 0).unwrap();
+        }
+        assert_eq!(drained_mock[0].item_count(), 1);
+        assert_eq!(others[0].item_count() + others[1].item_count(), 100);
+
+        // The mock closes its side once it answered the Quit
+        let mut waited = Duration::ZERO;
+        while drained_mock[0].connection_count() > 0 && waited < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(5));
+            waited += Duration::from_millis(5);
+        }
+        assert_eq!(drained_mock[0].connection_count(), 0);
+        assert_eq!(others[0].connection_count(), 1);
+
+        let err = client.drain_server(&drained).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        client.drain_server(&others[0].url()).unwrap();
+        let err = client.drain_server(&others[1].url()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(keys.iter().all(|key| owner(&client, key) == others[1].url()));
+    }
+
+    #[test]
+    fn test_route_chain() {
+        let mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = mocks
+            .iter()
+            .fold(Client::builder(ProtoType::Binary), |builder, mock| builder.add_server(mock.url(), 1))
+            .build()
+            .unwrap();
+        let mut conhash = ConsistentHash::new();
+        for mock in mocks.iter() {
+            conhash.add(&NamedNode(mock.url()), ring_points(1, super::builder::DEFAULT_REPLICAS_PER_NODE));
+        }
+
+        let key = b"test:route_chain";
+        let chain = client.route_chain(key, 2);
+        assert_eq!(chain.len(), 3);
+        // Each next server is the owner once the ones before it are gone
+        for addr in chain.iter() {
+            assert_eq!(&conhash.get(key).unwrap().0, addr);
+            conhash.remove(&NamedNode(addr.clone()));
+        }
+        assert_eq!(client.route_chain(key, 0), &chain[..1]);
+        assert_eq!(client.route_chain(key, usize::MAX), chain);
+
+        client.drain_server(&chain[0]).unwrap();
+        assert_eq!(client.route_chain(key, 1), &chain[1..]);
+    }
+
+    #[test]
+    fn test_arithmetic_conformance() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .build()
+            .unwrap();
+        check_arithmetic(&mut client, "test:arithmetic_").unwrap();
+    }
+
+    #[test]
+    fn test_items() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .build()
+            .unwrap();
+
+        let keys: Vec<String> = (0..20).map(|i| format!("test:items_{}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            client.set(key.as_bytes(), b"value", i as u32, 3600).unwrap();
+        }
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| key.as_bytes()).collect();
+        let items = client.get_multi_items(&key_refs).unwrap();
+        assert_eq!(items.len(), 20);
+        for (i, key) in keys.iter().enumerate() {
+            let item = &items[key.as_bytes()];
+            assert_eq!((&item.value[..], item.flags), (&b"value"[..], i as u32));
+            assert_eq!(client.get_item(key.as_bytes()).unwrap().cas, item.cas);
+        }
+
+        let item = client.gat_item(key_refs[0], 10).unwrap();
+        assert_eq!(<(Vec<u8>, u32)>::from(item), (b"value".to_vec(), 0));
+        for mock in mocks.iter() {
+            mock.advance_clock(Duration::from_secs(60));
+        }
+        assert_eq!(client.get_item(key_refs[0]).unwrap_err().status(), Some(Status::KeyNotFound));
+        assert_eq!(client.getk_item(key_refs[1]).unwrap().key.as_deref(), Some(key_refs[1]));
+    }
+
+    #[test]
+    fn test_ring_name_is_configured_address() {
+        let mut v4 = MockServer::start("127.0.0.1:0").unwrap();
+        let mut v6 = MockServer::start("[::1]:0").unwrap();
+        let hostname = format!("tcp://localhost:{}", v4.addr().port());
+        assert_eq!(v6.url(), format!("tcp://[::1]:{}", v6.addr().port()));
+        let builder = Client::builder(ProtoType::Binary)
+            .add_server(&hostname, 1)
+            .add_server(v6.url(), 1);
+        let mut client = builder.build().unwrap();
+
+        let names: Vec<String> = client.nodes.iter().map(|node| node.name()).collect();
+        assert_eq!(names, [hostname.clone(), v6.url()]);
+
+        let keys: Vec<Vec<u8>> = (0..50).map(|i| format!("test:ring_name_{}", i).into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        for key in keys.iter() {
+            client.set(key, b"value", 0, 0).unwrap();
+        }
+        let routed = |found: BTreeMap<String, HashMap<Vec<u8>, (Vec<u8>, u32)>>| {
+            found
+                .into_iter()
+                .map(|(addr, items)| {
+                    let mut keys: Vec<Vec<u8>> = items.into_keys().collect();
+                    keys.sort();
+                    (addr, keys)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let before = routed(client.get_multi_by_server(&keys).unwrap());
+        assert!(before.contains_key(&hostname) && before.contains_key(&v6.url()));
+
+        // Connecting again with the same configuration routes every key to the same server
+        v4.restart().unwrap();
+        v6.restart().unwrap();
+        let mut client = ClientBuilder::from_config(client.config()).build().unwrap();
+        for key in keys.iter() {
+            client.set(key, b"value", 0, 0).unwrap();
+        }
+        assert_eq!(routed(client.get_multi_by_server(&keys).unwrap()), before);
+        assert_eq!(v4.item_count() + v6.item_count(), 50);
+    }
+
+    #[test]
+    fn test_fastest_replica_reads() {
+        let fast = MockServer::start("127.0.0.1:0").unwrap();
+        let slow = MockServer::start("127.0.0.1:0").unwrap();
+        let served: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let observed = served.clone();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(fast.url(), 1)
+            .add_server(slow.url(), 1)
+            .replication_factor(2)
+            .fastest_replica_reads(10)
+            .observer(move |event| {
+                if event.op == "get" && event.ok {
+                    observed.borrow_mut().push(event.server.to_owned());
+                }
+            })
+            .build()
+            .unwrap();
+        slow.set_latency(Duration::from_millis(20));
+
+        client.set(b"test:fastest_replica", b"value", 0, 120).unwrap();
+        for _ in 0..20 {
+            client.get(b"test:fastest_replica").unwrap();
+        }
+        let from_fast = served.borrow().iter().filter(|&server| *server == fast.url()).count();
+        assert_eq!(from_fast, 20);
+
+        // A miss on the fast replica still falls back to the slow one
+        let mut direct = Client::connect(&[(&slow.url()[..], 1)], ProtoType::Binary).unwrap();
+        direct.set(b"test:fastest_replica_slow", b"value", 0, 120).unwrap();
+        served.borrow_mut().clear();
+        assert_eq!(client.get(b"test:fastest_replica_slow").unwrap().0, b"value");
+        assert_eq!(*served.borrow(), vec![slow.url()]);
+    }
+
+    #[test]
+    fn test_multi_collect() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .auto_hash_long_keys(true)
+            .build()
+            .unwrap();
+        let long_key = vec![b'k'; 300];
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("test:collect_{}", i).into_bytes()).collect();
+        let large = vec![b'x'; 2 * 1024 * 1024];
+        let mut kv: BTreeMap<&[u8], (&[u8], u32, u32)> = keys.iter().map(|key| (&key[..], (&b"v"[..], 0, 0))).collect();
+        kv.insert(&long_key, (&large, 0, 0));
+
+        let result = client.set_multi_collect(kv).unwrap();
+        assert_eq!(result.succeeded, 20);
+        assert_eq!(result.failures.len(), 1);
+        let (key, err) = &result.failures[0];
+        assert_eq!(*key, long_key);
+        assert_eq!(err.status(), Some(Status::ValueTooLarge));
+        match err {
+            proto::Error::WithContext { op, server, .. } => {
+                assert_eq!(*op, "set");
+                assert_eq!(
+                    *server,
+                    client
+                        .find_server_by_key(&super::keys::wire_key(key, true).unwrap())
+                        .addr()
+                );
+            }
+            err => panic!("unexpected error {}", err),
+        }
+        assert_eq!(mocks.iter().map(|mock| mock.item_count()).sum::<usize>(), 20);
+
+        let mut keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        keys.push(&long_key);
+        let result = client.delete_multi_collect(&keys).unwrap();
+        assert!(result.is_complete());
+        assert_eq!(result.succeeded, 21);
+        assert!(mocks.iter().all(|mock| mock.item_count() == 0));
+    }
+
+    #[test]
+    fn test_multi_store_validation() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .max_value_size(1024)
+            .build()
+            .unwrap();
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("test:validation_{}", i).into_bytes()).collect();
+        let too_long = vec![b'k'; proto::MAX_KEY_LEN + 1];
+        let large = vec![b'x'; 2048];
+        let mut kv: BTreeMap<&[u8], (&[u8], u32, u32)> = keys.iter().map(|key| (&key[..], (&b"v"[..], 0, 0))).collect();
+        kv.insert(&keys[3], (&large, 0, 0));
+        kv.insert(&too_long, (b"v", 0, 0));
+        let items: Vec<_> = kv.iter().map(|(&key, &(value, ..))| (key, value, 0, 0, 0)).collect();
+
+        let check = |err: proto::Error, op: &str| {
+            let invalid = match err {
+                proto::Error::ValidationFailed { invalid } => invalid,
+                err => panic!("unexpected error {}", err),
+            };
+            assert_eq!(invalid.len(), 2);
+            for (key, err) in invalid {
+                if key == too_long {
+                    assert!(matches!(err, proto::Error::KeyTooLong { .. }));
+                } else {
+                    assert_eq!(key, keys[3]);
+                    assert!(matches!(err, proto::Error::WithContext { op: failed, .. } if failed == op));
+                    assert!(matches!(
+                        err.root(),
+                        proto::Error::ValueTooLarge {
+                            len: 2048,
+                            max_len: 1024
+                        }
+                    ));
+                }
+            }
+        };
+        check(client.set_multi_collect(kv.clone()).unwrap_err(), "set_multi_collect");
+        check(client.set_cas_multi(&items).unwrap_err(), "set_cas_multi");
+        // Not a key of the batch reached either server
+        assert!(mocks.iter().all(|mock| mock.item_count() == 0));
+
+        kv.remove(&too_long[..]);
+        kv.remove(&keys[3][..]);
+        assert!(client.set_multi_collect(kv).unwrap().is_complete());
+        assert_eq!(mocks.iter().map(|mock| mock.item_count()).sum::<usize>(), 19);
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 2)
+            .add_server("tcp://localhost:11211", 1)
+            .replicas_per_node(40)
+            .replication_factor(2)
+            .fastest_replica_reads(50)
+            .touch_cache(Duration::from_secs(1), 1024)
+            .read_timeout(Duration::from_secs(3))
+            .auth_timeout(Duration::from_secs(2))
+            .preset(ConnectPreset::BulkTransfer)
+            .read_buffer_pool(64 * 1024)
+            .max_pipeline_depth(1000)
+            .value_copy_cutoff(256)
+            .tcp_linger(None)
+            .busy_backpressure(DEFAULT_BUSY_RETRY_AFTER)
+            .client_label("checkout-7f9".to_owned())
+            .strict(true)
+            .max_value_size(512 * 1024)
+            .discover_max_value_size(true)
+            .quirks(Quirks {
+                no_gat: true,
+                ..Quirks::default()
+            })
+            .sasl("user", "hunter2")
+            .default_expiration(60)
+            .default_flags(0xcafe)
+            .build()
+            .unwrap();
+
+        let config = client.config().clone();
+        assert_eq!(
+            config.servers,
+            vec![
+                ("tcp://127.0.0.1:11211".to_owned(), 2),
+                ("tcp://localhost:11211".to_owned(), 1)
+            ]
+        );
+        assert_eq!(config.replicas_per_node, 40);
+        assert_eq!(config.replication_factor, 2);
+        assert_eq!(config.fastest_replica_window, Some(50));
+        assert_eq!(config.touch_cache, Some((Duration::from_secs(1), 1024)));
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(config.connect_timeout, None);
+        assert_eq!(config.read_buffer_pool, Some(64 * 1024));
+        assert_eq!(config.max_pipeline_depth, Some(1000));
+        assert_eq!(config.value_copy_cutoff, Some(256));
+        assert!(!config.nodelay && config.coalesce_noreply);
+        assert_eq!(config.tcp_linger, Some(None));
+        assert!(config.strict);
+        assert_eq!(config.max_value_size, Some(512 * 1024));
+        assert!(config.discover_max_value_size);
+        assert_eq!(config.quirks.map(|quirks| quirks.no_gat), Some(true));
+        assert_eq!(config.sasl_username.as_deref(), Some("user"));
+        assert_eq!((config.default_expiration, config.default_flags), (60, 0xcafe));
+        assert!(!format!("{:?}", config).contains("hunter2"));
+
+        let rebuilt = ClientBuilder::from_config(&config).build().unwrap();
+        let mut expected = config.clone();
+        expected.sasl_username = None;
+        assert_eq!(*rebuilt.config(), expected);
+
+        let plain = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+        assert_eq!(
+            *plain.config(),
+            *Client::builder(ProtoType::Binary)
+                .add_server("tcp://127.0.0.1:11211", 1)
+                .build()
+                .unwrap()
+                .config()
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&config).unwrap();
+            assert!(!json.contains("hunter2"));
+            assert_eq!(serde_json::from_str::<super::ClientConfig>(&json).unwrap(), config);
+        }
+    }
+
+    fn related_user_keys(key: &[u8]) -> Vec<Vec<u8>> {
+        if key.starts_with(b"user:") && !key[5..].contains(&b':') {
+            vec![[key, b":prefs"].concat(), [key, b":avatar"].concat()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(&url, 1)
+            .prefetch(related_user_keys, 3)
+            .build()
+            .unwrap();
+        let mut other = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        for key in [
+            "user:1",
+            "user:1:prefs",
+            "user:1:avatar",
+            "user:2",
+            "user:2:prefs",
+            "user:2:avatar",
+        ] {
+            other.set(key.as_bytes(), key.as_bytes(), 0, 60).unwrap();
+        }
+
+        assert_eq!(client.get(b"user:1").unwrap().0, b"user:1");
+        assert_eq!(client.prefetch_stats().requested, 2);
+        assert_eq!(client.prefetch_stats().fetched, 2);
+
+        // Served from the prefetched values, even though they are gone from the server
+        other.delete(b"user:1:prefs").unwrap();
+        assert_eq!(client.get(b"user:1:prefs").unwrap().0, b"user:1:prefs");
+        assert_eq!(client.prefetch_stats().hits, 1);
+        assert!(client.get(b"user:1:prefs").is_err());
+
+        // A write through the client drops the prefetched value
+        client.set(b"user:1:avatar", b"new", 0, 60).unwrap();
+        assert_eq!(client.get(b"user:1:avatar").unwrap().0, b"new");
+        assert_eq!(client.prefetch_stats().hits, 1);
+
+        // Only one key of user:2 fits in what is left of this second's budget
+        client.get(b"user:2").unwrap();
+        let stats = client.prefetch_stats();
+        assert_eq!((stats.requested, stats.fetched, stats.over_budget), (3, 3, 1));
+    }
+
+    #[test]
+    fn test_bulk_transfer_preset() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        let mut bulk = Client::builder(ProtoType::Binary)
+            .add_server(&url, 1)
+            .preset(ConnectPreset::BulkTransfer)
+            .build()
+            .unwrap();
+        let mut other = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+
+        for i in 0..100 {
+            bulk.set_noreply(format!("test:bulk{}", i).as_bytes(), b"value", 0, 60)
+                .unwrap();
+        }
+        // Still coalescing in the write buffer
+        assert!(other.get(b"test:bulk0").is_err());
+
+        assert!(bulk.drain_errors().unwrap().is_empty());
+        for i in 0..100 {
+            assert_eq!(other.get(format!("test:bulk{}", i).as_bytes()).unwrap(), (b"value".to_vec(), 0));
+        }
+        bulk.set(b"test:bulk_reply", b"value", 0, 60).unwrap();
+        assert!(other.get(b"test:bulk_reply").is_ok());
+    }
+
+    #[test]
+    fn test_tcp_linger() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        for linger in [None, Some(Duration::ZERO), Some(Duration::from_secs(1))] {
+            let mut client = Client::builder(ProtoType::Binary)
+                .add_server(mock.url(), 1)
+                .tcp_linger(linger)
+                .build()
+                .unwrap();
+            assert_eq!(client.config().tcp_linger, Some(linger));
+            client.set(b"test:tcp_linger", b"value", 0, 60).unwrap();
+            assert_eq!(client.get(b"test:tcp_linger").unwrap(), (b"value".to_vec(), 0));
+        }
+    }
+
+    #[test]
+    fn test_drop_sends_pending_noreply() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        let bulk = || {
+            Client::builder(ProtoType::Binary)
+                .add_server(&url, 1)
+                .preset(ConnectPreset::BulkTransfer)
+                .build()
+                .unwrap()
+        };
+        let mut other = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        // The requests reach the server, which processes them on its own time
+        let mut eventually_get = |key: &[u8]| {
+            for _ in 0..100 {
+                if let Ok(item) = other.get(key) {
+                    return item;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{:?} never showed up", key);
+        };
+
+        let mut client = bulk();
+        client.set_noreply(b"test:pending_sent", b"sent", 1, 60).unwrap();
+        client.send_pending().unwrap();
+        assert_eq!(eventually_get(b"test:pending_sent"), (b"sent".to_vec(), 1));
+
+        let mut client = bulk();
+        for i in 0..10 {
+            client
+                .set_noreply(format!("test:pending_drop{}", i).as_bytes(), b"dropped", 2, 60)
+                .unwrap();
+        }
+        drop(client);
+        for i in 0..10 {
+            assert_eq!(eventually_get(format!("test:pending_drop{}", i).as_bytes()), (b"dropped".to_vec(), 2));
+        }
+    }
+
+    #[test]
+    fn test_value_framer() {
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server("tcp://127.0.0.1:11211", 1)
+            .value_framer(LengthPrefixedFramer::new(|| vec![1, 0xff]))
+            .build()
+            .unwrap();
+        let mut raw = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+
+        client.set(b"test:value_framer", b"payload", 3, 120).unwrap();
+        assert_eq!(raw.get(b"test:value_framer").unwrap(), (b"\x00\x02\x01\xffpayload".to_vec(), 3));
+        assert_eq!(client.get(b"test:value_framer").unwrap(), (b"payload".to_vec(), 3));
+        let (value, flags, meta) = client.get_with_meta(b"test:value_framer").unwrap();
+        assert_eq!((&value[..], flags, &meta.header[..]), (&b"payload"[..], 3, &[1, 0xff][..]));
+
+        // Written without the framer, too short for the header it claims
+        raw.set(b"test:value_framer", b"\x00\x09", 0, 120).unwrap();
+        assert!(client.get(b"test:value_framer").is_err());
+        assert!(raw.get_with_meta(b"test:value_framer").unwrap().2.header.is_empty());
+
+        raw.delete(b"test:value_framer").unwrap();
+    }
+
+    #[test]
+    fn test_value_framer_every_path() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .value_framer(LengthPrefixedFramer::new(|| vec![7]))
+            .build()
+            .unwrap();
+        let mut raw = Client::connect(&[(mock.url(), 1)], ProtoType::Binary).unwrap();
+        let framed = |value: &[u8]| [&b"\x00\x01\x07"[..], value].concat();
+
+        client.add(b"add", b"a", 1, 0).unwrap();
+        raw.set(b"replace", b"", 0, 0).unwrap();
+        client.replace(b"replace", b"r", 1, 0).unwrap();
+        client.set_noreply(b"set_noreply", b"s", 1, 0).unwrap();
+        client.add_noreply(b"add_noreply", b"a", 1, 0).unwrap();
+        client.try_set_noreply(b"try_set_noreply", b"t", 1, 0).unwrap();
+        client.send_pending().unwrap();
+        let cas = client.add_cas(b"add_cas", b"a", 1, 0).unwrap();
+        let cas = client.set_cas(b"add_cas", b"s", 1, 0, cas).unwrap();
+        client.replace_cas(b"add_cas", b"r", 1, 0, cas).unwrap();
+        client.set_cas_multi(&[(b"set_cas_multi", b"m", 1, 0, 0)]).unwrap()[0]
+            .as_ref()
+            .unwrap();
+        for (key, value) in [
+            (&b"add"[..], &b"a"[..]),
+            (b"replace", b"r"),
+            (b"set_noreply", b"s"),
+            (b"add_noreply", b"a"),
+            (b"try_set_noreply", b"t"),
+            (b"add_cas", b"r"),
+            (b"set_cas_multi", b"m"),
+        ] {
+            assert_eq!(raw.get(key).unwrap(), (framed(value), 1), "{}", String::from_utf8_lossy(key));
+        }
+
+        assert_eq!(client.getk(b"add").unwrap().1, b"a");
+        assert_eq!(client.get_cas(b"add").unwrap().0, b"a");
+        assert_eq!(client.getk_cas(b"add").unwrap().1, b"a");
+        assert_eq!(&client.gat_item(b"add", 0).unwrap().value[..], b"a");
+        let keys: Vec<&[u8]> = vec![b"add", b"replace"];
+        assert_eq!(client.get_multi(&keys).unwrap()[&b"add"[..]], (b"a".to_vec(), 1));
+        assert_eq!(client.gets_multi(&keys).unwrap()[&b"replace"[..]].0, b"r");
+        assert_eq!(client.multi_get(&keys).unwrap()[1], Some((b"r".to_vec(), 1)));
+        assert_eq!(client.get_multi_by_server(&keys).unwrap()[&mock.url()][&b"add"[..]], (b"a".to_vec(), 1));
+        let mut seen = Vec::new();
+        client
+            .get_multi_foreach(&keys, &mut |_, value, _| seen.push(value.to_vec()))
+            .unwrap();
+        seen.sort();
+        assert_eq!(seen, vec![b"a".to_vec(), b"r".to_vec()]);
+    }
+
+    #[test]
+    fn test_cas_update_multi_retries_conflicts() {
+        let mut client =
+            Client::connect(&[("tcp://127.0.0.1:11211", 1), ("tcp://localhost:11211", 1)], ProtoType::Binary).unwrap();
+        let mut other = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..6)
+            .map(|i| format!("test:cas_update_multi{}", i).into_bytes())
+            .collect();
+        for key in keys.iter().take(5) {
+            client.set(key, b"a", 7, 120).unwrap();
+        }
+        let _ = client.delete(&keys[5]);
+
+        // Overwrite the first two keys between the gets and the stores of the first round
+        let mut calls = 0;
+        let mut f = |key: &[u8], value: &[u8]| {
+            calls += 1;
+            if calls <= 5 && (key == &keys[0][..] || key == &keys[1][..]) {
+                other.set(key, b"b", 7, 120).unwrap();
+            }
+            [value, b"+"].concat()
+        };
+        let req: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let mut summary = client.cas_update_multi(&req, 120, 3, &mut f).unwrap();
+        summary.updated.sort();
+        assert_eq!(summary.rounds, 2);
+        assert_eq!(calls, 7);
+        assert_eq!(summary.updated, keys[..5].to_vec());
+        assert_eq!(summary.missing, vec![keys[5].clone()]);
+        assert!(summary.conflicted.is_empty());
+        assert!(summary.errors.is_empty());
+
+        assert_eq!(client.get(&keys[0]).unwrap(), (b"b+".to_vec(), 7));
+        assert_eq!(client.get(&keys[2]).unwrap(), (b"a+".to_vec(), 7));
+
+        // A single round leaves the conflicting keys unchanged
+        let mut f = |key: &[u8], value: &[u8]| {
+            if key == &keys[3][..] {
+                other.set(key, b"c", 7, 120).unwrap();
+            }
+            [value, b"+"].concat()
+        };
+        let summary = client.cas_update_multi(&req, 120, 1, &mut f).unwrap();
+        assert_eq!(summary.conflicted, vec![keys[3].clone()]);
+        assert_eq!(client.get(&keys[3]).unwrap(), (b"c".to_vec(), 7));
+
+        for key in keys.iter().take(5) {
+            client.delete(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_get_multi_by_server() {
+        const LOCAL: &str = "tcp://127.0.0.1:11211";
+        const LOCALHOST: &str = "tcp://localhost:11211";
+        let mut client = Client::connect(&[(LOCAL, 1), (LOCALHOST, 1)], ProtoType::Binary).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..16)
+            .map(|i| format!("test:get_multi_by_server{}", i).into_bytes())
+            .collect();
+        for key in keys.iter() {
+            client.set(key, key, 0, 120).unwrap();
+        }
+        let _ = client.delete(b"test:get_multi_by_server_missing");
+
+        let mut req: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        req.push(b"test:get_multi_by_server_missing");
+        let found = client.get_multi_by_server(&req).unwrap();
+
+        assert_eq!(found.keys().collect::<Vec<_>>(), vec![LOCAL, LOCALHOST]);
+        assert_eq!(found.values().map(|items| items.len()).sum::<usize>(), keys.len());
+        for (addr, items) in found.iter() {
+            for (key, (value, _)) in items.iter() {
+                assert_eq!(&client.find_server_by_key(key).borrow().addr, addr);
+                assert_eq!(value, key);
+            }
+        }
+
+        for key in keys.iter() {
+            client.delete(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observed = events.clone();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .max_pipeline_depth(3)
+            .observer(move |event| {
+                observed
+                    .borrow_mut()
+                    .push((event.op, event.server.to_owned(), event.ok))
+            })
+            .build()
+            .unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..16).map(|i| format!("test:multi_get{}", i).into_bytes()).collect();
+        for key in keys.iter().step_by(2) {
+            client.set(key, key, 7, 0).unwrap();
+        }
+        let owners: Vec<String> = keys
+            .iter()
+            .map(|key| client.find_server_by_key(key).addr().to_owned())
+            .collect();
+        assert!(owners.contains(&mocks[0].url()) && owners.contains(&mocks[1].url()));
+        assert_eq!(mocks[0].item_count() + mocks[1].item_count(), 8);
+
+        let mut req: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        req.push(&keys[0]);
+        events.borrow_mut().clear();
+        let found = client.multi_get(&req).unwrap();
+
+        assert_eq!(found.len(), req.len());
+        for (i, (key, found)) in req.iter().zip(found.iter()).enumerate() {
+            match found {
+                Some((value, flags)) => {
+                    assert!(i % 2 == 0 || i == keys.len());
+                    assert_eq!((&value[..], *flags), (*key, 7));
+                }
+                None => assert!(i % 2 == 1),
+            }
+        }
+        let mut servers: Vec<String> = events
+            .borrow()
+            .iter()
+            .map(|(op, server, ok)| {
+                assert_eq!((*op, *ok), ("multi_get", true));
+                server.clone()
+            })
+            .collect();
+        servers.sort();
+        let mut expected = vec![mocks[0].url(), mocks[1].url()];
+        expected.sort();
+        assert_eq!(servers, expected);
+
+        assert_eq!(client.multi_get(&[]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_get_multi_foreach() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .replicas_per_node(40)
+            .auto_hash_long_keys(true)
+            .build()
+            .unwrap();
+        let mut keys: Vec<Vec<u8>> = (0..20).map(|i| format!("test:foreach_{}", i).into_bytes()).collect();
+        keys.push(vec![b'k'; 300]);
+        for key in keys.iter().step_by(2) {
+            client.set(key, b"value", 3, 0).unwrap();
+        }
+
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let mut found = HashMap::new();
+        let hits = client
+            .get_multi_foreach(&keys, &mut |key, value, flags| {
+                found.insert(key.to_vec(), (value.to_vec(), flags));
+            })
+            .unwrap();
+        assert_eq!(hits, 11);
+        assert!(found.contains_key(&vec![b'k'; 300]));
+        assert_eq!(
+            found,
+            client
+                .get_multi_by_server(&keys)
+                .unwrap()
+                .into_values()
+                .flatten()
+                .collect()
+        );
+    }
+
     #[test]
     fn test_get_multi() {
         let mut client = Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap();
@@ -0,0 +1,62 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Hook for timing operations
+
+use std::time::Duration;
+
+/// An operation reported to the observer set with `ClientBuilder::observer`
+#[derive(Debug)]
+pub struct OpEvent<'a> {
+    /// Name of the operation, like `"get"` or `"set_cas"`
+    pub op: &'static str,
+    pub key: &'a [u8],
+    /// Address of the server that handled the operation
+    pub server: &'a str,
+    /// Label set with `ClientBuilder::client_label`
+    pub client: Option<&'a str>,
+    pub elapsed: Duration,
+    /// Whether the operation returned `Ok`
+    pub ok: bool,
+    /// Short name of the outcome, `"ok"`, `"not_found"`, `"exists"`, `"not_stored"`,
+    /// `"server_error"`, `"io_error"`, `"timeout"`, `"busy"` or `"error"`
+    pub status: &'static str,
+}
+
+pub(crate) struct Observer {
+    callback: Box<dyn Fn(&OpEvent)>,
+    sampling_rate: u32,
+    countdown: u32,
+}
+
+impl Observer {
+    pub(crate) fn new(callback: Box<dyn Fn(&OpEvent)>, sampling_rate: u32) -> Observer {
+        assert!(sampling_rate > 0, "sampling_rate should be positive");
+        Observer {
+            callback,
+            sampling_rate,
+            countdown: 0,
+        }
+    }
+
+    /// Whether the next operation should be timed, true for 1 in every `sampling_rate` calls
+    pub(crate) fn sample(&mut self) -> bool {
+        if self.countdown == 0 {
+            self.countdown = self.sampling_rate - 1;
+            true
+        } else {
+            self.countdown -= 1;
+            false
+        }
+    }
+
+    pub(crate) fn report(&self, event: &OpEvent) {
+        (self.callback)(event)
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Latency percentiles of each kind of operation
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+use super::Client;
+
+/// Latencies are recorded in microseconds up to this, longer ones count as this
+const MAX_LATENCY_MICROS: u64 = 60_000_000;
+
+/// Significant decimal digits kept of each latency
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// Latency percentiles of one kind of operation, returned by `Client::latency_percentiles`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// Number of operations the percentiles are computed from
+    pub count: u64,
+}
+
+/// A latency histogram per operation, fed with the latencies of `ClientStats`
+#[derive(Debug, Default)]
+pub(crate) struct OpLatencies {
+    by_op: BTreeMap<&'static str, Histogram<u64>>,
+}
+
+impl OpLatencies {
+    pub(crate) fn record(&mut self, op: &'static str, elapsed: Duration) {
+        let histogram = self.by_op.entry(op).or_insert_with(|| {
+            Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, SIGNIFICANT_DIGITS)
+                .expect("latency histogram bounds are valid")
+        });
+        histogram.saturating_record((elapsed.as_micros() as u64).clamp(1, MAX_LATENCY_MICROS));
+    }
+
+    fn percentiles(&self, op: &str) -> Option<Percentiles> {
+        let histogram = self.by_op.get(op).filter(|histogram| !histogram.is_empty())?;
+        let at = |quantile| Duration::from_micros(histogram.value_at_quantile(quantile));
+        Some(Percentiles {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            count: histogram.len(),
+        })
+    }
+}
+
+impl Client {
+    /// Median, 90th and 99th percentile of the latencies of `op`, e.g. `"get"`, since the client
+    /// was created
+    ///
+    /// `op` is a single key operation as named in `ClientStats::by_op`. Latencies are kept to 3
+    /// significant digits of a microsecond, up to a minute. Returns `None` for an operation that
+    /// never ran.
+    pub fn latency_percentiles(&self, op: &str) -> Option<Percentiles> {
+        self.latencies.percentiles(op)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::OpLatencies;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    #[test]
+    fn test_percentiles() {
+        let mut latencies = OpLatencies::default();
+        for millis in 1..=100 {
+            latencies.record("get", Duration::from_millis(millis));
+        }
+        latencies.record("set", Duration::from_secs(3600));
+        latencies.record("set", Duration::ZERO);
+
+        let get = latencies.percentiles("get").unwrap();
+        assert_eq!(get.count, 100);
+        let close =
+            |actual: Duration, millis: u64| actual.abs_diff(Duration::from_millis(millis)) < Duration::from_millis(1);
+        assert!(close(get.p50, 50) && close(get.p90, 90) && close(get.p99, 99), "{:?}", get);
+
+        // Out of range latencies are clamped instead of lost
+        let set = latencies.percentiles("set").unwrap();
+        assert_eq!((set.count, set.p50), (2, Duration::from_micros(1)));
+        assert!(set.p99 >= Duration::from_secs(59), "{:?}", set);
+        assert!(latencies.percentiles("delete").is_none());
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        client.set(b"test:percentiles", b"value", 0, 0).unwrap();
+
+        mock.set_latency(Duration::from_millis(20));
+        for _ in 0..10 {
+            client.get(b"test:percentiles").unwrap();
+        }
+        let get = client.latency_percentiles("get").unwrap();
+        assert_eq!(get.count, 10);
+        assert!(get.p50 >= Duration::from_millis(20) && get.p50 < Duration::from_secs(1), "{:?}", get);
+        assert!(get.p50 <= get.p90 && get.p90 <= get.p99, "{:?}", get);
+        assert_eq!(client.latency_percentiles("set").unwrap().count, 1);
+        assert!(client.latency_percentiles("delete").is_none());
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Prefetching keys related to the ones fetched
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a prefetched value may be served
+pub const PREFETCH_MAX_AGE: Duration = Duration::from_secs(1);
+
+/// Counters of the prefetcher set with `ClientBuilder::prefetch`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PrefetchStats {
+    /// Related keys requested from the servers
+    pub requested: u64,
+    /// Related keys the servers had
+    pub fetched: u64,
+    /// Gets served from a prefetched value
+    pub hits: u64,
+    /// Related keys not requested because the budget was used up
+    pub over_budget: u64,
+}
+
+pub(crate) struct Prefetcher {
+    related: fn(&[u8]) -> Vec<Vec<u8>>,
+    budget: u32,
+    window_start: Instant,
+    spent: u32,
+    values: HashMap<Vec<u8>, (Vec<u8>, u32, Instant)>,
+    pub(crate) stats: PrefetchStats,
+}
+
+impl Prefetcher {
+    pub(crate) fn new(related: fn(&[u8]) -> Vec<Vec<u8>>, budget: u32) -> Prefetcher {
+        Prefetcher {
+            related,
+            budget,
+            window_start: Instant::now(),
+            spent: 0,
+            values: HashMap::new(),
+            stats: PrefetchStats::default(),
+        }
+    }
+
+    /// Keys related to `key` worth fetching now, at most what is left of this second's budget
+    pub(crate) fn plan(&mut self, key: &[u8]) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.spent = 0;
+        }
+        self.values
+            .retain(|_, &mut (_, _, fetched)| now.duration_since(fetched) < PREFETCH_MAX_AGE);
+
+        let mut keys = (self.related)(key);
+        keys.retain(|related| !self.values.contains_key(related));
+        keys.sort();
+        keys.dedup();
+        let allowed = keys.len().min((self.budget - self.spent) as usize);
+        self.stats.over_budget += (keys.len() - allowed) as u64;
+        keys.truncate(allowed);
+        self.spent += allowed as u32;
+        self.stats.requested += allowed as u64;
+        keys
+    }
+
+    pub(crate) fn store(&mut self, key: Vec<u8>, value: Vec<u8>, flags: u32) {
+        self.stats.fetched += 1;
+        self.values.insert(key, (value, flags, Instant::now()));
+    }
+
+    /// Take the prefetched value of `key` if it is fresh enough
+    pub(crate) fn take(&mut self, key: &[u8]) -> Option<(Vec<u8>, u32)> {
+        match self.values.remove(key) {
+            Some((value, flags, fetched)) if fetched.elapsed() < PREFETCH_MAX_AGE => {
+                self.stats.hits += 1;
+                Some((value, flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// Drop the prefetched value of `key`, which is being modified
+    pub(crate) fn forget(&mut self, key: &[u8]) {
+        self.values.remove(key);
+    }
+}
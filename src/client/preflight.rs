@@ -0,0 +1,241 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Checking every server before taking traffic
+
+use std::process;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use semver::Version;
+
+use super::{Client, ServerRef};
+use crate::proto::{self, ServerVersion};
+use crate::random;
+
+/// Maximum number of candidate keys tried when looking for a probe key owned by a server
+pub const PREFLIGHT_MAX_PROBE_CANDIDATES: usize = 100_000;
+
+const PROBE_VALUE: &[u8] = b"preflight";
+
+/// Outcome of `Client::preflight`, one entry per server in the order they were added
+#[derive(Debug)]
+pub struct PreflightReport {
+    pub servers: Vec<ServerPreflight>,
+}
+
+impl PreflightReport {
+    /// Whether every server passed every check
+    pub fn is_ok(&self) -> bool {
+        self.servers.iter().all(ServerPreflight::is_ok)
+    }
+
+    /// The servers that failed a check
+    pub fn failed(&self) -> impl Iterator<Item = &ServerPreflight> {
+        self.servers.iter().filter(|server| !server.is_ok())
+    }
+}
+
+/// Preflight checks of one server
+#[derive(Debug)]
+pub struct ServerPreflight {
+    pub addr: String,
+    /// Round-trip time of the version request, `None` if it failed
+    pub latency: Option<Duration>,
+    pub version: Option<ServerVersion>,
+    pub failures: Vec<PreflightFailure>,
+}
+
+impl ServerPreflight {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A check of `Client::preflight` that did not pass
+#[derive(Debug)]
+pub enum PreflightFailure {
+    /// The version request failed
+    Version(proto::Error),
+    /// The server is older than the required minimum
+    VersionTooOld { minimum: Version },
+    /// The reported version is not semver, so it cannot be compared with the minimum
+    VersionUnknown,
+    /// No key the ring places on this server was found, e.g. because its weight is 0
+    NoProbeKey,
+    /// A step of the set/get/delete round-trip failed, `step` is `"set"`, `"get"` or `"delete"`
+    RoundTrip { step: &'static str, error: proto::Error },
+    /// The probe key was read back with another value
+    ValueMismatch,
+}
+
+impl Client {
+    /// Check that every server is ready for traffic
+    ///
+    /// The existing connection of each server asks for its version, which must be at least
+    /// `min_version` if given, then stores, reads back and deletes a probe key the ring places on
+    /// that server. Connections authenticated with SASL when they were opened, so the round-trip
+    /// also fails on a server that does not accept those credentials. The probe key is deleted
+    /// whenever it was stored, and every server is checked even after another one failed.
+    pub fn preflight(&mut self, min_version: Option<&Version>) -> PreflightReport {
+        let nonce = probe_nonce();
+        let servers = self
+            .nodes
+            .iter()
+            .map(|server| self.preflight_server(server, min_version, &nonce))
+            .collect();
+        PreflightReport { servers }
+    }
+
+    fn preflight_server(&self, server: &ServerRef, min_version: Option<&Version>, nonce: &str) -> ServerPreflight {
+        let mut report = ServerPreflight {
+            addr: server.addr().to_owned(),
+            latency: None,
+            version: None,
+            failures: Vec::new(),
+        };
+
+        let started = Instant::now();
+        match server
+            .lock()
+            .and_then(|mut server| server.call("version", |proto| proto.version()))
+        {
+            Ok(version) => {
+                report.latency = Some(started.elapsed());
+                match (min_version, &version.semver) {
+                    (Some(minimum), Some(semver)) if semver < minimum => {
+                        report.failures.push(PreflightFailure::VersionTooOld {
+                            minimum: minimum.clone(),
+                        })
+                    }
+                    (Some(_), None) => report.failures.push(PreflightFailure::VersionUnknown),
+                    _ => {}
+                }
+                report.version = Some(version);
+            }
+            Err(err) => report.failures.push(PreflightFailure::Version(err)),
+        }
+
+        let key = match self.probe_key(server, nonce) {
+            Some(key) => key,
+            None => {
+                report.failures.push(PreflightFailure::NoProbeKey);
+                return report;
+            }
+        };
+        let mut server = match server.lock() {
+            Ok(server) => server,
+            Err(error) => {
+                report.failures.push(PreflightFailure::RoundTrip { step: "set", error });
+                return report;
+            }
+        };
+        if let Err(error) = server.call("set", |proto| proto.set(&key, PROBE_VALUE, 0, 60)) {
+            report.failures.push(PreflightFailure::RoundTrip { step: "set", error });
+            return report;
+        }
+        match server.call("get", |proto| proto.get(&key)) {
+            Ok((value, _)) if value == PROBE_VALUE => {}
+            Ok(..) => report.failures.push(PreflightFailure::ValueMismatch),
+            Err(error) => report.failures.push(PreflightFailure::RoundTrip { step: "get", error }),
+        }
+        if let Err(error) = server.call("delete", |proto| proto.delete(&key)) {
+            report
+                .failures
+                .push(PreflightFailure::RoundTrip { step: "delete", error });
+        }
+        report
+    }
+
+    /// A key that the ring places on `server`
+    fn probe_key(&self, server: &ServerRef, nonce: &str) -> Option<Vec<u8>> {
+        (0..PREFLIGHT_MAX_PROBE_CANDIDATES)
+            .map(|i| format!("memcached-rs:preflight:{}:{}", nonce, i).into_bytes())
+            .find(|key| Rc::ptr_eq(self.find_server_by_key(key), server))
+    }
+}
+
+/// Tells apart the probe keys of clients running preflight at the same time
+fn probe_nonce() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}:{}:{:x}", process::id(), now, random::u64(..))
+}
+
+#[cfg(test)]
+mod test {
+    use semver::Version;
+
+    use super::PreflightFailure;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    fn cluster(mocks: &[MockServer]) -> Client {
+        mocks
+            .iter()
+            .fold(Client::builder(ProtoType::Binary).replicas_per_node(40), |builder, mock| {
+                builder.add_server(mock.url(), 1)
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_preflight_healthy() {
+        let mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = cluster(&mocks);
+
+        let report = client.preflight(Some(&Version::new(1, 4, 0)));
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.servers.len(), 3);
+        for (server, mock) in report.servers.iter().zip(mocks.iter()) {
+            assert_eq!(server.addr, mock.url());
+            assert!(server.latency.is_some());
+            assert_eq!(server.version.as_ref().unwrap().raw, "1.6.0");
+        }
+
+        // Probe keys are cleaned up
+        assert!(mocks.iter().all(|mock| mock.item_count() == 0));
+        assert!(client.preflight(None).is_ok());
+    }
+
+    #[test]
+    fn test_preflight_mixed() {
+        let mut mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = cluster(&mocks);
+        mocks[1].stop();
+
+        let report = client.preflight(Some(&Version::new(1, 7, 0)));
+        assert!(!report.is_ok());
+        assert_eq!(report.failed().count(), 3);
+
+        let healthy = &report.servers[0];
+        assert!(matches!(
+            healthy.failures[..],
+            [PreflightFailure::VersionTooOld { ref minimum }] if *minimum == Version::new(1, 7, 0)
+        ));
+
+        let down = &report.servers[1];
+        assert!(down.latency.is_none() && down.version.is_none());
+        assert!(matches!(
+            down.failures[..],
+            [
+                PreflightFailure::Version(..),
+                PreflightFailure::RoundTrip { step: "set", .. }
+            ]
+        ));
+
+        let report = client.preflight(None);
+        let failed: Vec<&str> = report.failed().map(|server| &server.addr[..]).collect();
+        assert_eq!(failed, vec![&mocks[1].url()[..]]);
+    }
+}
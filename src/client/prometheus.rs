@@ -0,0 +1,126 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Prometheus text exposition of `ClientStats` and the ring
+
+use std::fmt::Write;
+
+use super::stats::{ClientStats, LATENCY_BUCKETS};
+
+/// Append the metrics of `stats` and of `servers`, as `(address, ring points)`, to `buf`
+pub(crate) fn render(stats: &ClientStats, servers: &[(String, usize)], client_label: Option<&str>, buf: &mut String) {
+    if let Some(label) = client_label {
+        header(buf, "memcached_client_info", "gauge", "Label of the client");
+        writeln!(buf, "memcached_client_info{{client=\"{}\"}} 1", escape(label)).unwrap();
+    }
+
+    header(buf, "memcached_client_ops_total", "counter", "Single key operations by outcome");
+    for (op, op_stats) in stats.by_op() {
+        for (status, count) in op_stats.outcomes.iter() {
+            writeln!(buf, "memcached_client_ops_total{{op=\"{}\",status=\"{}\"}} {}", op, status, count).unwrap();
+        }
+    }
+
+    header(buf, "memcached_client_op_bytes_total", "counter", "Value bytes sent and received");
+    for (op, op_stats) in stats.by_op() {
+        writeln!(buf, "memcached_client_op_bytes_total{{op=\"{}\"}} {}", op, op_stats.bytes).unwrap();
+    }
+
+    header(buf, "memcached_client_op_duration_seconds", "histogram", "Latency of single key operations");
+    for (op, op_stats) in stats.by_op() {
+        let latency = &op_stats.latency;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets()) {
+            cumulative += count;
+            writeln!(
+                buf,
+                "memcached_client_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                op,
+                bound.as_secs_f64(),
+                cumulative
+            )
+            .unwrap();
+        }
+        writeln!(buf, "memcached_client_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}", op, latency.count())
+            .unwrap();
+        writeln!(buf, "memcached_client_op_duration_seconds_sum{{op=\"{}\"}} {}", op, latency.sum().as_secs_f64())
+            .unwrap();
+        writeln!(buf, "memcached_client_op_duration_seconds_count{{op=\"{}\"}} {}", op, latency.count()).unwrap();
+    }
+
+    let classes: [(&str, &str, fn(&super::ClassStats) -> u64); 4] = [
+        ("memcached_client_class_hits_total", "Gets that found the key", |c| c.hits),
+        ("memcached_client_class_misses_total", "Gets that did not find the key", |c| c.misses),
+        ("memcached_client_class_sets_total", "Successful stores", |c| c.sets),
+        ("memcached_client_class_bytes_total", "Value bytes stored and hit", |c| c.bytes),
+    ];
+    for (name, help, counter) in classes.iter() {
+        header(buf, name, "counter", help);
+        for (class, class_stats) in stats.by_class() {
+            writeln!(buf, "{}{{class=\"{}\"}} {}", name, escape(class), counter(class_stats)).unwrap();
+        }
+    }
+
+    header(buf, "memcached_client_ring_points", "gauge", "Points of each server on the consistent hash ring");
+    for (server, points) in servers {
+        writeln!(buf, "memcached_client_ring_points{{server=\"{}\"}} {}", escape(server), points).unwrap();
+    }
+}
+
+fn header(buf: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(buf, "# HELP {} {}", name, help).unwrap();
+    writeln!(buf, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escape a label value as the text format requires
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use crate::client::stats::{Classifier, ClientStats};
+    use crate::proto::{self, binary, MemCachedResult};
+    use std::time::Duration;
+
+    fn not_found<R>() -> MemCachedResult<R> {
+        Err(proto::Error::BinaryProtoError(binary::Error::from_status(binary::Status::KeyNotFound, None)))
+    }
+
+    #[test]
+    fn test_render_golden() {
+        let classifier = Classifier::Callback(|key| if key.starts_with(b"user:") { "user" } else { "other" });
+        let mut stats = ClientStats::default();
+        let mut record = |op, key: &[u8], value: &[u8], micros, result: MemCachedResult<(Vec<u8>, u32)>| {
+            stats.record_op(op, value, Duration::from_micros(micros), &result);
+            stats.class_mut(classifier.classify(key)).record(op, value, &result);
+        };
+        record("set", b"user:1", b"hello", 120, Ok((Vec::new(), 0)));
+        record("get", b"user:1", b"", 80, Ok((b"hello".to_vec(), 0)));
+        record("get", b"user:2", b"", 300, not_found());
+        record("get", b"feed:1", b"", 2_000_000, not_found());
+
+        let servers = vec![
+            ("tcp://10.0.0.1:11211".to_owned(), 160),
+            ("unix:///run/\"memcached\".sock".to_owned(), 320),
+        ];
+        let mut buf = String::new();
+        render(&stats, &servers, None, &mut buf);
+        assert_eq!(buf, include_str!("testdata/metrics.prom"));
+
+        let mut labelled = String::new();
+        render(&stats, &servers, Some("checkout \"eu-1\""), &mut labelled);
+        assert!(labelled.starts_with(
+            "# HELP memcached_client_info Label of the client\n# TYPE memcached_client_info gauge\n\
+             memcached_client_info{client=\"checkout \\\"eu-1\\\"\"} 1\n"
+        ));
+        assert!(labelled.ends_with(&buf));
+    }
+}
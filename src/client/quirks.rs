@@ -0,0 +1,217 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Workarounds for servers that misbehave in known ways
+//!
+//! Every workaround is a field of `Quirks`, and `KNOWN_QUIRKS` says which server versions need it
+//! and where that comes from. A new workaround is a new field, an entry in the table, and the one
+//! place that consults it.
+
+use crate::proto::{self, binary::Status, ServerVersion};
+
+/// Known misbehaviours of a server, each turning on a workaround
+///
+/// Derived from the version of each server with `ClientBuilder::detect_quirks`, or set for every
+/// server with `ClientBuilder::quirks`. All off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quirks {
+    /// A touch of a missing key fails with `ItemNotStored` instead of `KeyNotFound`
+    ///
+    /// `touch` and `touch_cas` report it as `KeyNotFound`.
+    pub touch_returns_item_not_stored: bool,
+    /// The server has no get-and-touch command
+    ///
+    /// `gat_item` reads the item with `get_cas` and stores it again with the new expiration with
+    /// `set_cas` instead, failing with `KeyExists` if it changed in between. Which way is taken
+    /// goes by the server owning the key.
+    pub no_gat: bool,
+    /// The server does not answer the `settings` group of stats
+    ///
+    /// `ClientBuilder::discover_max_value_size` and `Client::check_settings` do not ask it.
+    pub stat_settings_missing: bool,
+}
+
+/// A workaround, and the servers that need it
+struct KnownQuirk {
+    applies: fn(&ServerVersion) -> bool,
+    enable: fn(&mut Quirks),
+}
+
+/// Every workaround applied by `Quirks::detect`
+const KNOWN_QUIRKS: &[KnownQuirk] = &[
+    // Touch and get-and-touch only came to the binary protocol with memcached 1.4.8
+    KnownQuirk {
+        applies: |version| release(version).is_some_and(|release| release < (1, 4, 8)),
+        enable: |quirks| quirks.no_gat = true,
+    },
+    // The first 1.4 releases with touch answered a miss with ItemNotStored, fixed in 1.4.14
+    KnownQuirk {
+        applies: |version| release(version).is_some_and(|release| ((1, 4, 8)..(1, 4, 14)).contains(&release)),
+        enable: |quirks| quirks.touch_returns_item_not_stored = true,
+    },
+    // Proxies answer version with their own name and do not pass stats settings on to the servers
+    // behind them
+    KnownQuirk {
+        applies: |version| !version.raw.starts_with(|c: char| c.is_ascii_digit()),
+        enable: |quirks| quirks.stat_settings_missing = true,
+    },
+];
+
+/// `(major, minor, patch)` of a memcached release, also for versions with a build suffix like
+/// `1.6.21_1_ga4216c6` that are not semver
+fn release(version: &ServerVersion) -> Option<(u64, u64, u64)> {
+    if let Some(ref semver) = version.semver {
+        return Some((semver.major, semver.minor, semver.patch));
+    }
+    let numbers = version.raw.split(|c: char| !c.is_ascii_digit() && c != '.').next()?;
+    let mut parts = numbers.split('.').map(|part| part.parse::<u64>().ok());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch))) => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+impl Quirks {
+    /// The workarounds a server reporting `version` needs
+    pub fn detect(version: &ServerVersion) -> Quirks {
+        let mut quirks = Quirks::default();
+        for known in KNOWN_QUIRKS {
+            if (known.applies)(version) {
+                (known.enable)(&mut quirks);
+            }
+        }
+        quirks
+    }
+
+    /// The error `op` should have failed with on a well-behaved server
+    pub(crate) fn adjust_error(&self, op: &str, err: proto::Error) -> proto::Error {
+        let touch = matches!(op, "touch" | "touch_cas");
+        if touch && self.touch_returns_item_not_stored && err.status() == Some(Status::ItemNotStored) {
+            return proto::binary::Error::from_status(Status::KeyNotFound, None).into();
+        }
+        err
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::Quirks;
+    use crate::proto::binary::Status;
+    use crate::proto::binarydef::{Command, DataType, RequestPacket, ResponsePacket};
+    use crate::proto::{self, BinaryProto, Operation, ServerVersion};
+    use crate::test_support::{Direction, ReplayProto, ReplayStream, Transcript};
+
+    /// A connection answering each request with its scripted response
+    fn scripted(exchanges: &[(RequestPacket, ResponsePacket)]) -> ReplayProto {
+        let mut transcript = Transcript::default();
+        for (req, resp) in exchanges {
+            let (mut sent, mut received) = (Vec::new(), Vec::new());
+            req.write_to(&mut sent).unwrap();
+            resp.write_to(&mut received).unwrap();
+            transcript.chunks.push((Direction::Sent, sent));
+            transcript.chunks.push((Direction::Received, received));
+        }
+        BinaryProto::new(ReplayStream::new(transcript).normalize_opaques())
+    }
+
+    fn request(command: Command, cas: u64, extra: &[u8], key: &[u8], value: &[u8]) -> RequestPacket {
+        RequestPacket::new(
+            command,
+            DataType::RawBytes,
+            0,
+            0,
+            cas,
+            Bytes::copy_from_slice(extra),
+            Bytes::copy_from_slice(key),
+            Bytes::copy_from_slice(value),
+        )
+    }
+
+    fn response(command: Command, status: Status, cas: u64, extra: &[u8], value: &[u8]) -> ResponsePacket {
+        ResponsePacket::new(
+            command,
+            DataType::RawBytes,
+            status,
+            0,
+            cas,
+            Bytes::copy_from_slice(extra),
+            Bytes::new(),
+            Bytes::copy_from_slice(value),
+        )
+    }
+
+    fn detect(raw: &str) -> Quirks {
+        Quirks::detect(&ServerVersion::parse(raw))
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect("1.6.21"), Quirks::default());
+        assert_eq!(detect("1.6.21_1_ga4216c6"), Quirks::default());
+        assert_eq!(detect("1.4.14"), Quirks::default());
+        assert!(detect("1.4.13").touch_returns_item_not_stored);
+        assert!(detect("1.4.8").touch_returns_item_not_stored);
+        assert!(!detect("1.4.8").no_gat);
+        assert!(detect("1.4.5").no_gat);
+        assert!(!detect("1.4.5").touch_returns_item_not_stored);
+        assert!(detect("1.4.5_debian").no_gat);
+        assert!(detect("mcproxy 0.9").stat_settings_missing);
+        assert!(!detect("1.6.21").stat_settings_missing);
+    }
+
+    #[test]
+    fn test_touch_returns_item_not_stored() {
+        let touch = request(Command::Touch, 0, &60u32.to_be_bytes(), b"missing", &[]);
+        let answer = response(Command::Touch, Status::ItemNotStored, 0, &[], &[]);
+        let mut proto = scripted(&[(touch.clone(), answer.clone()), (touch, answer)]);
+
+        let quirky = Quirks {
+            touch_returns_item_not_stored: true,
+            ..Quirks::default()
+        };
+        let err = proto.touch(b"missing", 60).unwrap_err();
+        assert_eq!(quirky.adjust_error("touch", err).status(), Some(Status::KeyNotFound));
+        let err = proto.touch(b"missing", 60).unwrap_err();
+        assert_eq!(Quirks::default().adjust_error("touch", err).status(), Some(Status::ItemNotStored));
+
+        // Stores really can fail with it
+        let err = proto::binary::Error::from_status(Status::ItemNotStored, None).into();
+        assert_eq!(quirky.adjust_error("add", err).status(), Some(Status::ItemNotStored));
+    }
+
+    #[test]
+    fn test_no_gat() {
+        let mut extra = 0xcafeu32.to_be_bytes().to_vec();
+        extra.extend_from_slice(&60u32.to_be_bytes());
+        let mut proto = scripted(&[
+            (
+                request(Command::Get, 0, &[], b"key", &[]),
+                response(Command::Get, Status::NoError, 7, &0xcafeu32.to_be_bytes(), b"value"),
+            ),
+            (request(Command::Set, 7, &extra, b"key", b"value"), response(Command::Set, Status::NoError, 8, &[], &[])),
+            (
+                request(Command::Get, 0, &[], b"key", &[]),
+                response(Command::Get, Status::NoError, 9, &0xcafeu32.to_be_bytes(), b"value"),
+            ),
+            (
+                request(Command::Set, 9, &extra, b"key", b"value"),
+                response(Command::Set, Status::KeyExists, 0, &[], &[]),
+            ),
+        ]);
+
+        let item = proto::gat_by_cas(&mut proto, b"key", 60).unwrap();
+        assert_eq!((&item.value[..], item.flags, item.cas, item.ttl), (&b"value"[..], 0xcafe, Some(8), Some(60)));
+        // Changed between the get and the set
+        let err = proto::gat_by_cas(&mut proto, b"key", 60).unwrap_err();
+        assert_eq!(err.status(), Some(Status::KeyExists));
+    }
+}
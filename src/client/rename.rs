@@ -0,0 +1,309 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Moving a value from one key to another
+
+use crate::proto::{self, binary::Status, CasOperation, MemCachedResult};
+
+/// Outcome of `Client::rename`
+///
+/// Memcached has no rename, and the two keys may even live on different servers, so a rename is a
+/// copy followed by a delete. Every point where that sequence can stop halfway has its own variant.
+#[derive(Debug)]
+pub enum RenameOutcome {
+    /// The value now lives under the new key and the old key is gone
+    Renamed,
+    /// The old key does not exist, nothing was written
+    SourceMissing,
+    /// The new key already exists and overwriting was not requested, nothing was written
+    DestinationExists,
+    /// The old key was modified or deleted by someone else while renaming, so the copy under the
+    /// new key was deleted again. With `overwrite`, whatever the new key held before is lost.
+    RolledBack,
+    /// The value was copied, but deleting the old key failed; both keys now hold the value
+    SourceRemains(proto::Error),
+    /// The old key changed while renaming and deleting the stale copy under the new key failed too;
+    /// the new key holds the old value and the old key holds the newer one
+    RollbackFailed(proto::Error),
+}
+
+impl RenameOutcome {
+    /// Whether the value now lives under the new key and the old key is gone
+    ///
+    /// False for a missing old key as for every other outcome, for callers that only need to know
+    /// whether the rename happened.
+    pub fn is_renamed(&self) -> bool {
+        matches!(self, RenameOutcome::Renamed)
+    }
+}
+
+fn is_status(err: &proto::Error, status: Status) -> bool {
+    err.status() == Some(status)
+}
+
+/// Rename over anything that routes keys like a `Client` does
+pub(crate) fn rename<C: CasOperation + ?Sized>(
+    client: &mut C,
+    old_key: &[u8],
+    new_key: &[u8],
+    overwrite: bool,
+    expiration: u32,
+) -> MemCachedResult<RenameOutcome> {
+    let (value, flags, old_cas) = match client.get_cas(old_key) {
+        Ok(item) => item,
+        Err(ref err) if is_status(err, Status::KeyNotFound) => return Ok(RenameOutcome::SourceMissing),
+        Err(err) => return Err(err),
+    };
+
+    let copied = if overwrite {
+        client.set_cas(new_key, &value, flags, expiration, 0)
+    } else {
+        client.add_cas(new_key, &value, flags, expiration)
+    };
+    let new_cas = match copied {
+        Ok(cas) => cas,
+        Err(ref err) if !overwrite && is_status(err, Status::KeyExists) => return Ok(RenameOutcome::DestinationExists),
+        Err(err) => return Err(err),
+    };
+
+    // Only delete the exact version that was copied, anything newer must not be thrown away
+    match client.delete_cas(old_key, old_cas) {
+        Ok(()) => Ok(RenameOutcome::Renamed),
+        Err(ref err) if is_status(err, Status::KeyExists) || is_status(err, Status::KeyNotFound) => {
+            match client.delete_cas(new_key, new_cas) {
+                Ok(()) => Ok(RenameOutcome::RolledBack),
+                Err(err) => Ok(RenameOutcome::RollbackFailed(err)),
+            }
+        }
+        Err(err) => Ok(RenameOutcome::SourceRemains(err)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::{rename, RenameOutcome};
+    use crate::client::Client;
+    use crate::proto::{self, CasOperation, Item, MemCachedResult, ProtoType};
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Inject {
+        Nothing,
+        GetFails,
+        CopyFails,
+        SourceChanged,
+        DeleteFails,
+        RollbackFails,
+    }
+
+    /// Forwards to a real client, failing or racing at the requested step
+    struct Injecting {
+        client: Client,
+        inject: Inject,
+        deletes: usize,
+    }
+
+    fn injected() -> proto::Error {
+        proto::Error::IoError(io::Error::other("injected"))
+    }
+
+    impl CasOperation for Injecting {
+        fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
+            if self.inject == Inject::CopyFails {
+                return Err(injected());
+            }
+            self.client.set_cas(key, value, flags, expiration, cas)
+        }
+        fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
+            if self.inject == Inject::CopyFails {
+                return Err(injected());
+            }
+            self.client.add_cas(key, value, flags, expiration)
+        }
+        fn replace_cas(
+            &mut self,
+            key: &[u8],
+            value: &[u8],
+            flags: u32,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<u64> {
+            self.client.replace_cas(key, value, flags, expiration, cas)
+        }
+        fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+            if self.inject == Inject::GetFails {
+                return Err(injected());
+            }
+            self.client.get_cas(key)
+        }
+        fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
+            self.client.getk_cas(key)
+        }
+        fn increment_cas(
+            &mut self,
+            key: &[u8],
+            amount: u64,
+            initial: u64,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<(u64, u64)> {
+            self.client.increment_cas(key, amount, initial, expiration, cas)
+        }
+        fn decrement_cas(
+            &mut self,
+            key: &[u8],
+            amount: u64,
+            initial: u64,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<(u64, u64)> {
+            self.client.decrement_cas(key, amount, initial, expiration, cas)
+        }
+        fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+            self.client.append_cas(key, value, cas)
+        }
+        fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+            self.client.prepend_cas(key, value, cas)
+        }
+        fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64> {
+            self.client.touch_cas(key, expiration, cas)
+        }
+        fn delete_cas(&mut self, key: &[u8], cas: u64) -> MemCachedResult<()> {
+            self.deletes += 1;
+            match (self.inject, self.deletes) {
+                (Inject::DeleteFails, 1) => return Err(injected()),
+                (Inject::SourceChanged, 1) | (Inject::RollbackFails, 1) => {
+                    self.client.set(key, b"newer", 0, 120)?;
+                }
+                (Inject::RollbackFails, 2) => return Err(injected()),
+                _ => {}
+            }
+            self.client.delete_cas(key, cas)
+        }
+        fn delete_returning_cas(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+            self.client.delete_returning_cas(key)
+        }
+        fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+            self.client.gat_item(key, expiration)
+        }
+    }
+
+    fn single_server() -> Client {
+        Client::connect(&[("tcp://127.0.0.1:11211", 1)], ProtoType::Binary).unwrap()
+    }
+
+    fn run(
+        inject: Inject,
+        old_key: &[u8],
+        new_key: &[u8],
+        overwrite: bool,
+    ) -> (Client, MemCachedResult<RenameOutcome>) {
+        let mut client = Injecting {
+            client: single_server(),
+            inject,
+            deletes: 0,
+        };
+        let _ = client.client.delete(new_key);
+        client.client.set(old_key, b"value", 0xcafe, 120).unwrap();
+
+        let outcome = rename(&mut client, old_key, new_key, overwrite, 120);
+        (client.client, outcome)
+    }
+
+    #[test]
+    fn test_rename_same_server() {
+        const OLD: &[u8] = b"test:rename_same_old";
+        const NEW: &[u8] = b"test:rename_same_new";
+        let mut client = single_server();
+
+        client.set(OLD, b"value", 0xcafe, 120).unwrap();
+        let _ = client.delete(NEW);
+
+        assert!(client.rename(OLD, NEW, false, 120).unwrap().is_renamed());
+        assert_eq!(client.get(NEW).unwrap(), (b"value".to_vec(), 0xcafe));
+        client.get(OLD).unwrap_err();
+
+        let missing = client.rename(OLD, NEW, false, 120).unwrap();
+        assert!(matches!(missing, RenameOutcome::SourceMissing));
+        assert!(!missing.is_renamed());
+
+        client.set(OLD, b"other", 0, 120).unwrap();
+        assert!(matches!(client.rename(OLD, NEW, false, 120), Ok(RenameOutcome::DestinationExists)));
+        assert_eq!(client.get(NEW).unwrap().0, b"value".to_vec());
+
+        assert!(matches!(client.rename(OLD, NEW, true, 120), Ok(RenameOutcome::Renamed)));
+        assert_eq!(client.get(NEW).unwrap().0, b"other".to_vec());
+        client.get(OLD).unwrap_err();
+
+        client.delete(NEW).unwrap();
+    }
+
+    #[test]
+    fn test_rename_cross_server() {
+        const OLD: &[u8] = b"test:rename_cross_old";
+
+        // Two names for the same memcached, so the ring holds two separate connections
+        let mut client =
+            Client::connect(&[("tcp://127.0.0.1:11211", 1), ("tcp://localhost:11211", 1)], ProtoType::Binary).unwrap();
+
+        let old_server = client.find_server_by_key(OLD).borrow().addr.clone();
+        let new_key = (0..)
+            .map(|i| format!("test:rename_cross_new{}", i).into_bytes())
+            .find(|key| client.find_server_by_key(key).borrow().addr != old_server)
+            .unwrap();
+
+        client.set(OLD, b"value", 0xcafe, 120).unwrap();
+        let _ = client.delete(&new_key);
+
+        assert!(matches!(client.rename(OLD, &new_key, false, 120), Ok(RenameOutcome::Renamed)));
+        assert_eq!(client.get(&new_key).unwrap(), (b"value".to_vec(), 0xcafe));
+        client.get(OLD).unwrap_err();
+
+        client.delete(&new_key).unwrap();
+    }
+
+    #[test]
+    fn test_rename_injected_failures() {
+        const OLD: &[u8] = b"test:rename_inject_old";
+        const NEW: &[u8] = b"test:rename_inject_new";
+
+        let (mut client, outcome) = run(Inject::Nothing, OLD, NEW, false);
+        assert!(matches!(outcome, Ok(RenameOutcome::Renamed)));
+        client.get(OLD).unwrap_err();
+        client.delete(NEW).unwrap();
+
+        let (mut client, outcome) = run(Inject::GetFails, OLD, NEW, false);
+        assert!(matches!(outcome, Err(proto::Error::IoError(..))));
+        client.get(NEW).unwrap_err();
+
+        let (mut client, outcome) = run(Inject::CopyFails, OLD, NEW, true);
+        assert!(matches!(outcome, Err(proto::Error::IoError(..))));
+        assert_eq!(client.get(OLD).unwrap().0, b"value".to_vec());
+        client.get(NEW).unwrap_err();
+
+        let (mut client, outcome) = run(Inject::SourceChanged, OLD, NEW, false);
+        assert!(matches!(outcome, Ok(RenameOutcome::RolledBack)));
+        assert_eq!(client.get(OLD).unwrap().0, b"newer".to_vec());
+        client.get(NEW).unwrap_err();
+
+        let (mut client, outcome) = run(Inject::DeleteFails, OLD, NEW, false);
+        assert!(matches!(outcome, Ok(RenameOutcome::SourceRemains(..))));
+        assert_eq!(client.get(OLD).unwrap().0, b"value".to_vec());
+        assert_eq!(client.get(NEW).unwrap().0, b"value".to_vec());
+
+        let (mut client, outcome) = run(Inject::RollbackFails, OLD, NEW, false);
+        assert!(matches!(outcome, Ok(RenameOutcome::RollbackFailed(..))));
+        assert_eq!(client.get(OLD).unwrap().0, b"newer".to_vec());
+        assert_eq!(client.get(NEW).unwrap().0, b"value".to_vec());
+
+        client.delete(OLD).unwrap();
+        client.delete(NEW).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Consistent hash ring with ordered successor walks
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+use conhash::Node;
+
+/// Direct-mapped cache of the owners of recently routed keys, see `ClientBuilder::route_cache`
+///
+/// Each key has one slot, picked by a hash much cheaper than the md5 of the ring. A slot keeps the
+/// whole key, so a hit is never a different key that landed in the same slot.
+struct RouteCache {
+    slots: Vec<Option<(Vec<u8>, usize)>>,
+}
+
+impl RouteCache {
+    fn slot(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.slots.len() as u64) as usize
+    }
+
+    fn get(&self, key: &[u8]) -> Option<usize> {
+        match self.slots[self.slot(key)] {
+            Some((ref cached, index)) if cached[..] == *key => Some(index),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], index: usize) {
+        let slot = self.slot(key);
+        match self.slots[slot] {
+            Some((ref mut cached, ref mut cached_index)) => {
+                cached.clear();
+                cached.extend_from_slice(key);
+                *cached_index = index;
+            }
+            None => self.slots[slot] = Some((key.to_vec(), index)),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+    }
+}
+
+/// Consistent hash ring that places nodes and keys exactly like `conhash::ConsistentHash`
+///
+/// Unlike `ConsistentHash`, it can walk the ring from the owner of a key to the following
+/// distinct nodes, which replication needs.
+pub(crate) struct Ring<N> {
+    points: BTreeMap<[u8; 16], usize>,
+    nodes: Vec<N>,
+    /// Flushed whenever a node is added or removed
+    cache: Option<RefCell<RouteCache>>,
+}
+
+impl<N: Node> Ring<N> {
+    pub(crate) fn new() -> Ring<N> {
+        Ring {
+            points: BTreeMap::new(),
+            nodes: Vec::new(),
+            cache: None,
+        }
+    }
+
+    /// Remember the owners of up to `entries` keys, `get` then skips the md5 of a cached key
+    pub(crate) fn set_route_cache(&mut self, entries: usize) {
+        self.cache = Some(RefCell::new(RouteCache {
+            slots: vec![None; entries.max(1)],
+        }));
+    }
+
+    fn flush_cache(&mut self) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.get_mut().flush();
+        }
+    }
+
+    /// Add `node` with `points` virtual nodes
+    pub(crate) fn add(&mut self, node: N, points: usize) {
+        let name = node.name();
+        let index = self.nodes.len();
+        for point in 0..points {
+            self.points.insert(md5::compute(format!("{}:{}", name, point)).0, index);
+        }
+        self.nodes.push(node);
+        self.flush_cache();
+    }
+
+    /// Remove the node named `name` and its points, the keys it owned go to the next nodes on the ring
+    pub(crate) fn remove(&mut self, name: &str) -> Option<N> {
+        let index = self.nodes.iter().position(|node| node.name() == name)?;
+        self.points.retain(|_, node| *node != index);
+        for node in self.points.values_mut() {
+            if *node > index {
+                *node -= 1;
+            }
+        }
+        self.flush_cache();
+        Some(self.nodes.remove(index))
+    }
+
+    /// Every node with its number of points, in the order they were added
+    #[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+    pub(crate) fn points_per_node(&self) -> impl Iterator<Item = (&N, usize)> {
+        let mut points = vec![0; self.nodes.len()];
+        for &index in self.points.values() {
+            points[index] += 1;
+        }
+        self.nodes.iter().zip(points)
+    }
+
+    /// The node that owns `key`
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&N> {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return self.successors(key).next(),
+        };
+        if let Some(index) = cache.borrow().get(key) {
+            return Some(&self.nodes[index]);
+        }
+        let index = self.successor_indices(key).next()?;
+        cache.borrow_mut().insert(key, index);
+        Some(&self.nodes[index])
+    }
+
+    /// Every node once, in ring order starting with the owner of `key`
+    pub(crate) fn successors<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = &'a N> + 'a {
+        self.successor_indices(key).map(move |index| &self.nodes[index])
+    }
+
+    fn successor_indices<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = usize> + 'a {
+        let hashed = md5::compute(key).0;
+        let mut seen = vec![false; self.nodes.len()];
+        self.points
+            .range(hashed..)
+            .chain(self.points.range(..hashed))
+            .filter(move |&(_, &index)| !std::mem::replace(&mut seen[index], true))
+            .take(self.nodes.len())
+            .map(|(_, &index)| index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Ring;
+    use conhash::{ConsistentHash, Node};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct NamedNode(String);
+
+    impl Node for NamedNode {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_same_placement_as_conhash() {
+        let mut ring = Ring::new();
+        let mut conhash = ConsistentHash::new();
+        for i in 0..5 {
+            let node = NamedNode(format!("tcp://10.0.0.{}:11211", i));
+            ring.add(node.clone(), 40);
+            conhash.add(&node, 40);
+        }
+
+        for i in 0..2000 {
+            let key = format!("test:ring_{}", i);
+            assert_eq!(ring.get(key.as_bytes()), conhash.get(key.as_bytes()));
+        }
+
+        let removed = NamedNode("tcp://10.0.0.2:11211".to_owned());
+        assert_eq!(ring.remove(&removed.0), Some(removed.clone()));
+        conhash.remove(&removed);
+        assert!(ring.remove(&removed.0).is_none());
+        for i in 0..2000 {
+            let key = format!("test:ring_{}", i);
+            assert_eq!(ring.get(key.as_bytes()), conhash.get(key.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_route_cache() {
+        let mut ring = Ring::new();
+        let mut conhash = ConsistentHash::new();
+        // Far fewer slots than keys, so keys keep evicting each other
+        ring.set_route_cache(64);
+        for i in 0..5 {
+            let node = NamedNode(format!("tcp://10.0.0.{}:11211", i));
+            ring.add(node.clone(), 40);
+            conhash.add(&node, 40);
+        }
+
+        for _ in 0..2 {
+            for i in 0..500 {
+                let key = format!("test:ring_{}", i % 100);
+                assert_eq!(ring.get(key.as_bytes()), conhash.get(key.as_bytes()));
+            }
+        }
+
+        // Cached owners go away with the topology they were computed on
+        let removed = conhash.get(b"test:ring_0").unwrap().clone();
+        ring.remove(&removed.0);
+        conhash.remove(&removed);
+        ring.add(NamedNode("tcp://10.0.0.9:11211".to_owned()), 40);
+        conhash.add(&NamedNode("tcp://10.0.0.9:11211".to_owned()), 40);
+        for i in 0..500 {
+            let key = format!("test:ring_{}", i % 100);
+            assert_eq!(ring.get(key.as_bytes()), conhash.get(key.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_successors() {
+        let mut ring = Ring::new();
+        for i in 0..4 {
+            ring.add(NamedNode(format!("tcp://10.0.0.{}:11211", i)), 16);
+        }
+
+        for i in 0..200 {
+            let key = format!("test:ring_{}", i);
+            let successors: Vec<&NamedNode> = ring.successors(key.as_bytes()).collect();
+            assert_eq!(successors.len(), 4);
+            assert_eq!(Some(successors[0]), ring.get(key.as_bytes()));
+            for (j, node) in successors.iter().enumerate() {
+                assert!(!successors[..j].contains(node));
+            }
+        }
+
+        let empty: Ring<NamedNode> = Ring::new();
+        assert!(empty.get(b"key").is_none());
+    }
+}
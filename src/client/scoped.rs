@@ -0,0 +1,649 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! A prefix for every key, with a way to delete what was written under it
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::Client;
+use crate::proto::{
+    self, binary::Status, BatchResult, CasOperation, Item, MemCachedResult, MultiOperation, NoReplyOperation,
+    Operation, TouchMultiSummary,
+};
+
+/// Number of keys a `ScopedClient` remembers for `purge` unless `max_tracked` says otherwise
+pub const SCOPE_MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Outcome of `ScopedClient::purge`
+#[derive(Debug, Default)]
+pub struct Purged {
+    /// Number of tracked keys that were deleted, or were already gone
+    pub deleted: usize,
+    /// Tracked keys the server answered with another error, with that error; they stay tracked
+    pub failures: Vec<(Vec<u8>, proto::Error)>,
+}
+
+/// `Client` operations under a key prefix, returned by `Client::scoped`
+///
+/// Every key is sent as the prefix followed by the key the caller gave, and keys the servers
+/// return have the prefix removed again, so two scopes with different prefixes never see each
+/// other's items. The keys written through the scope are tracked so that `purge` can delete
+/// them. There is no way to delete every key under a prefix in memcached, so once `max_tracked`
+/// keys are tracked, a write of another key fails before it is sent rather than leave an item
+/// `purge` cannot find.
+pub struct ScopedClient<'a> {
+    client: &'a mut Client,
+    prefix: Vec<u8>,
+    written: HashSet<Vec<u8>>,
+    max_tracked: usize,
+    purge_on_drop: bool,
+}
+
+impl<'a> ScopedClient<'a> {
+    pub(crate) fn new(client: &'a mut Client, prefix: &[u8]) -> ScopedClient<'a> {
+        ScopedClient {
+            client,
+            prefix: prefix.to_vec(),
+            written: HashSet::new(),
+            max_tracked: SCOPE_MAX_TRACKED_KEYS,
+            purge_on_drop: false,
+        }
+    }
+
+    /// Remember at most `max` written keys for `purge`, `SCOPE_MAX_TRACKED_KEYS` by default
+    ///
+    /// Writes of further keys fail until `purge` or a delete makes room.
+    pub fn max_tracked(mut self, max: usize) -> ScopedClient<'a> {
+        self.max_tracked = max;
+        self
+    }
+
+    /// `purge` when the scope is dropped, ignoring any error; off by default
+    pub fn purge_on_drop(mut self, purge: bool) -> ScopedClient<'a> {
+        self.purge_on_drop = purge;
+        self
+    }
+
+    /// The prefix of every key of the scope
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Number of written keys `purge` would delete
+    pub fn tracked(&self) -> usize {
+        self.written.len()
+    }
+
+    /// Delete every tracked key, with `MultiOperation::delete_multi_collect`
+    ///
+    /// Keys that are already gone count as deleted. Keys that fail otherwise stay tracked for the
+    /// next `purge`.
+    pub fn purge(&mut self) -> MemCachedResult<Purged> {
+        let keys: Vec<Vec<u8>> = self.written.drain().collect();
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let result = match MultiOperation::delete_multi_collect(self.client, &refs) {
+            Ok(result) => result,
+            Err(err) => {
+                self.written.extend(keys);
+                return Err(err);
+            }
+        };
+
+        let mut purged = Purged {
+            deleted: result.succeeded,
+            failures: Vec::new(),
+        };
+        for (key, err) in result.failures {
+            if err.status() == Some(Status::KeyNotFound) {
+                purged.deleted += 1;
+            } else {
+                self.written.insert(key.clone());
+                purged.failures.push((self.strip(key), err));
+            }
+        }
+        Ok(purged)
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut scoped = Vec::with_capacity(self.prefix.len() + key.len());
+        scoped.extend_from_slice(&self.prefix);
+        scoped.extend_from_slice(key);
+        scoped
+    }
+
+    fn keys(&self, keys: &[&[u8]]) -> Vec<Vec<u8>> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    /// `key` as the caller knows it
+    fn strip(&self, mut key: Vec<u8>) -> Vec<u8> {
+        if key.starts_with(&self.prefix) {
+            key.drain(..self.prefix.len());
+        }
+        key
+    }
+
+    fn strip_all<V>(&self, map: HashMap<Vec<u8>, V>) -> HashMap<Vec<u8>, V> {
+        map.into_iter().map(|(key, value)| (self.strip(key), value)).collect()
+    }
+
+    fn strip_failures(&self, failures: Vec<(Vec<u8>, proto::Error)>) -> Vec<(Vec<u8>, proto::Error)> {
+        failures.into_iter().map(|(key, err)| (self.strip(key), err)).collect()
+    }
+
+    /// Fail if tracking the scoped `keys` would go past `max_tracked`, before anything is sent
+    fn reserve(&self, keys: &[Vec<u8>]) -> MemCachedResult<()> {
+        let new: HashSet<&Vec<u8>> = keys.iter().filter(|key| !self.written.contains(*key)).collect();
+        if self.written.len() + new.len() > self.max_tracked {
+            return Err(proto::Error::OtherError {
+                desc: "Scope tracks too many keys",
+                detail: Some(format!(
+                    "{} new keys with {} of `max_tracked` {} tracked, `purge` to make room",
+                    new.len(),
+                    self.written.len(),
+                    self.max_tracked
+                )),
+            });
+        }
+        Ok(())
+    }
+
+    /// Track the scoped `keys` unless the write was refused by the server, which left them as
+    /// they were
+    ///
+    /// Any other failure may have happened after some of the keys were stored.
+    fn track<T>(&mut self, keys: Vec<Vec<u8>>, result: &MemCachedResult<T>) {
+        match *result {
+            Err(ref err) if err.status().is_some() => {}
+            _ => self.written.extend(keys),
+        }
+    }
+
+    /// Run a write of the scoped `key`, tracking it
+    fn write<T>(&mut self, key: &[u8], f: impl FnOnce(&mut Client, &[u8]) -> MemCachedResult<T>) -> MemCachedResult<T> {
+        let key = self.key(key);
+        self.reserve(std::slice::from_ref(&key))?;
+        let result = f(self.client, &key);
+        self.track(vec![key], &result);
+        result
+    }
+
+    /// Run a write of every scoped key of `keys`, tracking them
+    fn write_multi<T>(
+        &mut self,
+        keys: &[&[u8]],
+        f: impl FnOnce(&mut Client, &[&[u8]]) -> MemCachedResult<T>,
+    ) -> MemCachedResult<T> {
+        let keys = self.keys(keys);
+        self.reserve(&keys)?;
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let result = f(self.client, &refs);
+        self.track(keys, &result);
+        result
+    }
+
+    /// Run a delete of the scoped `key`, which no longer needs to be purged if it succeeds
+    fn remove<T>(
+        &mut self,
+        key: &[u8],
+        f: impl FnOnce(&mut Client, &[u8]) -> MemCachedResult<T>,
+    ) -> MemCachedResult<T> {
+        let key = self.key(key);
+        let result = f(self.client, &key)?;
+        self.written.remove(&key);
+        Ok(result)
+    }
+}
+
+impl Drop for ScopedClient<'_> {
+    fn drop(&mut self) {
+        if self.purge_on_drop {
+            let _ = self.purge();
+        }
+    }
+}
+
+impl Operation for ScopedClient<'_> {
+    fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| Operation::set(client, key, value, flags, expiration))
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| Operation::add(client, key, value, flags, expiration))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> MemCachedResult<()> {
+        self.remove(key, Operation::delete)
+    }
+
+    fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| Operation::replace(client, key, value, flags, expiration))
+    }
+
+    fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
+        Operation::get(self.client, &self.key(key))
+    }
+
+    fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
+        let (key, value, flags) = Operation::getk(self.client, &self.key(key))?;
+        Ok((self.strip(key), value, flags))
+    }
+
+    fn increment(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
+        self.write(key, |client, key| Operation::increment(client, key, amount, initial, expiration))
+    }
+
+    fn decrement(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
+        self.write(key, |client, key| Operation::decrement(client, key, amount, initial, expiration))
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.write(key, |client, key| Operation::append(client, key, value))
+    }
+
+    fn prepend(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.write(key, |client, key| Operation::prepend(client, key, value))
+    }
+
+    fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()> {
+        Operation::touch(self.client, &self.key(key), expiration)
+    }
+
+    fn exists(&mut self, key: &[u8]) -> MemCachedResult<bool> {
+        Operation::exists(self.client, &self.key(key))
+    }
+}
+
+impl CasOperation for ScopedClient<'_> {
+    fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
+        self.write(key, |client, key| CasOperation::set_cas(client, key, value, flags, expiration, cas))
+    }
+
+    fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
+        self.write(key, |client, key| client.add_cas(key, value, flags, expiration))
+    }
+
+    fn replace_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
+        self.write(key, |client, key| client.replace_cas(key, value, flags, expiration, cas))
+    }
+
+    fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+        CasOperation::get_cas(self.client, &self.key(key))
+    }
+
+    fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
+        let (key, value, flags, cas) = self.client.getk_cas(&self.key(key))?;
+        Ok((self.strip(key), value, flags, cas))
+    }
+
+    fn increment_cas(
+        &mut self,
+        key: &[u8],
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+        cas: u64,
+    ) -> MemCachedResult<(u64, u64)> {
+        self.write(key, |client, key| client.increment_cas(key, amount, initial, expiration, cas))
+    }
+
+    fn decrement_cas(
+        &mut self,
+        key: &[u8],
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+        cas: u64,
+    ) -> MemCachedResult<(u64, u64)> {
+        self.write(key, |client, key| client.decrement_cas(key, amount, initial, expiration, cas))
+    }
+
+    fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+        self.write(key, |client, key| client.append_cas(key, value, cas))
+    }
+
+    fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+        self.write(key, |client, key| client.prepend_cas(key, value, cas))
+    }
+
+    fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64> {
+        self.client.touch_cas(&self.key(key), expiration, cas)
+    }
+
+    fn delete_cas(&mut self, key: &[u8], cas: u64) -> MemCachedResult<()> {
+        self.remove(key, |client, key| client.delete_cas(key, cas))
+    }
+
+    fn delete_returning_cas(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+        self.remove(key, |client, key| client.delete_returning_cas(key))
+    }
+
+    fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+        let mut item = self.client.gat_item(&self.key(key), expiration)?;
+        if let Some(key) = item.key.take() {
+            item.key = Some(self.strip(key.to_vec()).into());
+        }
+        Ok(item)
+    }
+}
+
+impl NoReplyOperation for ScopedClient<'_> {
+    fn set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.set_noreply(key, value, flags, expiration))
+    }
+
+    fn add_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.add_noreply(key, value, flags, expiration))
+    }
+
+    /// The key stays tracked, the server may still refuse the delete
+    fn delete_noreply(&mut self, key: &[u8]) -> MemCachedResult<()> {
+        self.client.delete_noreply(&self.key(key))
+    }
+
+    fn replace_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.replace_noreply(key, value, flags, expiration))
+    }
+
+    fn increment_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.increment_noreply(key, amount, initial, expiration))
+    }
+
+    fn decrement_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.decrement_noreply(key, amount, initial, expiration))
+    }
+
+    fn append_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.append_noreply(key, value))
+    }
+
+    fn prepend_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.prepend_noreply(key, value))
+    }
+
+    fn try_set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.write(key, |client, key| client.try_set_noreply(key, value, flags, expiration))
+    }
+
+    fn send_pending(&mut self) -> MemCachedResult<()> {
+        self.client.send_pending()
+    }
+
+    fn drain_errors(&mut self) -> MemCachedResult<Vec<proto::Error>> {
+        self.client.drain_errors()
+    }
+}
+
+impl MultiOperation for ScopedClient<'_> {
+    fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
+        let keys: Vec<&[u8]> = kv.keys().copied().collect();
+        self.write_multi(&keys, |client, scoped| {
+            MultiOperation::set_multi(client, scoped.iter().copied().zip(kv.values().copied()).collect())
+        })
+    }
+
+    fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
+        let keys = self.keys(keys);
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        MultiOperation::delete_multi(self.client, &refs)?;
+        for key in keys.iter() {
+            self.written.remove(key);
+        }
+        Ok(())
+    }
+
+    fn increment_multi(&mut self, kv: HashMap<&[u8], (u64, u64, u32)>) -> MemCachedResult<HashMap<Vec<u8>, u64>> {
+        let (keys, amounts): (Vec<&[u8]>, Vec<(u64, u64, u32)>) = kv.into_iter().unzip();
+        let values = self.write_multi(&keys, |client, scoped| {
+            MultiOperation::increment_multi(client, scoped.iter().copied().zip(amounts).collect())
+        })?;
+        Ok(self.strip_all(values))
+    }
+
+    fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
+        let keys = self.keys(keys);
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let found = MultiOperation::get_multi(self.client, &refs)?;
+        Ok(self.strip_all(found))
+    }
+
+    fn get_multi_foreach(&mut self, keys: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8], u32)) -> MemCachedResult<usize> {
+        let keys = self.keys(keys);
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let prefix = &self.prefix[..];
+        MultiOperation::get_multi_foreach(self.client, &refs, &mut |key, value, flags| {
+            f(key.strip_prefix(prefix).unwrap_or(key), value, flags)
+        })
+    }
+
+    fn touch_multi(&mut self, keys: &[(&[u8], u32)], dry_run: bool) -> MemCachedResult<TouchMultiSummary> {
+        let keys: Vec<(Vec<u8>, u32)> = keys
+            .iter()
+            .map(|&(key, expiration)| (self.key(key), expiration))
+            .collect();
+        let refs: Vec<(&[u8], u32)> = keys.iter().map(|(key, expiration)| (&key[..], *expiration)).collect();
+        let summary = MultiOperation::touch_multi(self.client, &refs, dry_run)?;
+        Ok(TouchMultiSummary {
+            touched: summary.touched.into_iter().map(|key| self.strip(key)).collect(),
+            missing: summary.missing.into_iter().map(|key| self.strip(key)).collect(),
+            errors: self.strip_failures(summary.errors),
+            duplicates: summary.duplicates,
+        })
+    }
+
+    fn gets_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        let keys = self.keys(keys);
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let found = MultiOperation::gets_multi(self.client, &refs)?;
+        Ok(self.strip_all(found))
+    }
+
+    fn set_cas_multi(&mut self, items: &[(&[u8], &[u8], u32, u32, u64)]) -> MemCachedResult<Vec<MemCachedResult<u64>>> {
+        let keys: Vec<&[u8]> = items.iter().map(|item| item.0).collect();
+        self.write_multi(&keys, |client, scoped| {
+            let items: Vec<_> = scoped
+                .iter()
+                .zip(items)
+                .map(|(&key, &(_, value, flags, expiration, cas))| (key, value, flags, expiration, cas))
+                .collect();
+            MultiOperation::set_cas_multi(client, &items)
+        })
+    }
+
+    fn set_multi_collect(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<BatchResult> {
+        let keys: Vec<&[u8]> = kv.keys().copied().collect();
+        let mut result = self.write_multi(&keys, |client, scoped| {
+            MultiOperation::set_multi_collect(client, scoped.iter().copied().zip(kv.values().copied()).collect())
+        })?;
+        result.failures = self.strip_failures(result.failures);
+        Ok(result)
+    }
+
+    fn delete_multi_collect(&mut self, keys: &[&[u8]]) -> MemCachedResult<BatchResult> {
+        let keys = self.keys(keys);
+        let refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let mut result = MultiOperation::delete_multi_collect(self.client, &refs)?;
+        let failed: HashSet<&Vec<u8>> = result.failures.iter().map(|(key, _)| key).collect();
+        for key in keys.iter().filter(|key| !failed.contains(key)) {
+            self.written.remove(key);
+        }
+        result.failures = self.strip_failures(result.failures);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::SCOPE_MAX_TRACKED_KEYS;
+    use crate::client::Client;
+    use crate::proto::{CasOperation, Error, MultiOperation, NoReplyOperation, Operation, ProtoType};
+    use crate::test_support::MockServer;
+
+    fn mock_client() -> (MockServer, Client) {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+        (mock, client)
+    }
+
+    #[test]
+    fn test_scopes_are_isolated() {
+        let (_mock, mut client) = mock_client();
+
+        let mut first = client.scoped(b"test:scope_a:");
+        first.set(b"key", b"first", 1, 0).unwrap();
+        assert_eq!(first.getk(b"key").unwrap(), (b"key".to_vec(), b"first".to_vec(), 1));
+        drop(first);
+
+        let mut second = client.scoped(b"test:scope_b:");
+        assert_eq!(second.get_opt(b"key").unwrap(), None);
+        second.set(b"key", b"second", 2, 0).unwrap();
+        second.add(b"key", b"added", 2, 0).unwrap_err();
+        assert_eq!(second.getk_cas(b"key").unwrap().0, b"key".to_vec());
+        drop(second);
+
+        let mut first = client.scoped(b"test:scope_a:");
+        assert_eq!(first.get(b"key").unwrap(), (b"first".to_vec(), 1));
+        drop(first);
+        assert_eq!(client.get("test:scope_a:key").unwrap(), (b"first".to_vec(), 1));
+        assert_eq!(client.get("test:scope_b:key").unwrap(), (b"second".to_vec(), 2));
+    }
+
+    #[test]
+    fn test_purge_deletes_tracked_keys() {
+        let (mock, mut client) = mock_client();
+        client.set("test:scope:outside", "kept", 0, 0).unwrap();
+
+        let mut scope = client.scoped(b"test:scope:");
+        scope.set(b"one", b"1", 0, 0).unwrap();
+        scope.set(b"one", b"again", 0, 0).unwrap();
+        scope.increment(b"counter", 1, 5, 0).unwrap();
+        scope.set_cas(b"two", b"2", 0, 0, 0).unwrap();
+        scope.set(b"gone", b"x", 0, 0).unwrap();
+        scope.delete(b"gone").unwrap();
+        scope.add(b"one", b"taken", 0, 0).unwrap_err();
+        assert_eq!(scope.tracked(), 3);
+
+        let purged = scope.purge().unwrap();
+        assert_eq!(purged.deleted, 3);
+        assert!(purged.failures.is_empty());
+        assert_eq!(scope.tracked(), 0);
+        assert_eq!(scope.get_opt(b"one").unwrap(), None);
+        assert_eq!(scope.get_opt(b"two").unwrap(), None);
+        drop(scope);
+
+        assert_eq!(mock.item_count(), 1);
+        assert_eq!(client.get("test:scope:outside").unwrap(), (b"kept".to_vec(), 0));
+    }
+
+    #[test]
+    fn test_purge_overflow_and_drop() {
+        let (mock, mut client) = mock_client();
+
+        let mut scope = client.scoped(b"test:scope_overflow:").max_tracked(2);
+        scope.set(b"a", b"value", 0, 0).unwrap();
+        scope.set(b"b", b"value", 0, 0).unwrap();
+        let err = scope.set(b"c", b"value", 0, 0).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::OtherError {
+                desc: "Scope tracks too many keys",
+                ..
+            }
+        ));
+        scope.set_noreply(b"c", b"value", 0, 0).unwrap_err();
+        scope
+            .set_multi(
+                vec![(&b"a"[..], (&b"value"[..], 0, 0)), (b"c", (b"value", 0, 0))]
+                    .into_iter()
+                    .collect(),
+            )
+            .unwrap_err();
+        scope.set(b"a", b"again", 0, 0).unwrap();
+        assert_eq!(scope.get_opt(b"c").unwrap(), None);
+        assert_eq!(mock.item_count(), 2);
+
+        scope.delete(b"b").unwrap();
+        scope.set(b"c", b"value", 0, 0).unwrap();
+        assert_eq!(scope.tracked(), 2);
+        let purged = scope.purge().unwrap();
+        assert_eq!(purged.deleted, 2);
+        scope
+            .set_multi(
+                vec![(&b"d"[..], (&b"value"[..], 0, 0)), (b"e", (b"value", 0, 0))]
+                    .into_iter()
+                    .collect(),
+            )
+            .unwrap();
+        drop(scope);
+        assert_eq!(mock.item_count(), 2);
+        client.delete("test:scope_overflow:d").unwrap();
+        client.delete("test:scope_overflow:e").unwrap();
+
+        let mut scope = client.scoped(b"test:scope_drop:").purge_on_drop(true);
+        assert_eq!(scope.max_tracked, SCOPE_MAX_TRACKED_KEYS);
+        scope.set(b"key", b"value", 0, 0).unwrap();
+        drop(scope);
+        assert_eq!(mock.item_count(), 0);
+    }
+
+    #[test]
+    fn test_noreply_and_multi_are_scoped() {
+        let (mock, mut client) = mock_client();
+        client.set("outside", "kept", 0, 0).unwrap();
+
+        let mut scope = client.scoped(b"test:scope_multi:");
+        scope.set_noreply(b"quiet", b"q", 0, 0).unwrap();
+        scope.append_noreply(b"quiet", b"!").unwrap();
+        assert!(scope.drain_errors().unwrap().is_empty());
+        scope
+            .set_multi(
+                vec![(&b"one"[..], (&b"1"[..], 1, 0)), (b"two", (b"2", 2, 0))]
+                    .into_iter()
+                    .collect(),
+            )
+            .unwrap();
+        let results = scope.set_cas_multi(&[(b"three", b"3", 3, 0, 0)]).unwrap();
+        assert!(results[0].is_ok());
+        let counters: HashMap<&[u8], _> = vec![(&b"hits"[..], (1, 10, 0)), (b"misses", (1, 20, 0))]
+            .into_iter()
+            .collect();
+        let counted = scope.increment_multi(counters).unwrap();
+        assert_eq!((counted[&b"hits".to_vec()], counted[&b"misses".to_vec()]), (10, 20));
+        assert_eq!(scope.tracked(), 6);
+
+        let keys = [&b"quiet"[..], b"one", b"two", b"three", b"outside"];
+        let found = scope.get_multi(&keys).unwrap();
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[&b"quiet".to_vec()], (b"q!".to_vec(), 0));
+        assert_eq!(found[&b"two".to_vec()], (b"2".to_vec(), 2));
+        assert_eq!(scope.gets_multi(&keys).unwrap()[&b"three".to_vec()].1, 3);
+        let mut seen = Vec::new();
+        assert_eq!(
+            scope
+                .get_multi_foreach(&keys, &mut |key, _, _| seen.push(key.to_vec()))
+                .unwrap(),
+            4
+        );
+        seen.sort();
+        assert_eq!(seen, vec![b"one".to_vec(), b"quiet".to_vec(), b"three".to_vec(), b"two".to_vec()]);
+        let touched = scope.touch_multi(&[(b"one", 100), (b"outside", 100)], true).unwrap();
+        assert_eq!((touched.touched, touched.missing), (vec![b"one".to_vec()], vec![b"outside".to_vec()]));
+
+        let deleted = scope.delete_multi_collect(&[b"one", b"two"]).unwrap();
+        assert!(deleted.is_complete());
+        assert_eq!(scope.tracked(), 4);
+        assert_eq!(scope.purge().unwrap().deleted, 4);
+        drop(scope);
+
+        assert_eq!(mock.item_count(), 1);
+        assert_eq!(client.get("outside").unwrap(), (b"kept".to_vec(), 0));
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Absolute expirations on the clock of the server instead of the local one
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{Client, ServerRef};
+use crate::proto::{self, MemCachedResult};
+
+/// Seconds the clock of a server is ahead of the local one, and when that was measured
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClockOffset {
+    seconds: i64,
+    measured: Instant,
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+/// The `time` statistic, the server's unix time in seconds
+fn parse_server_time(stats: &BTreeMap<String, String>) -> MemCachedResult<SystemTime> {
+    stats
+        .get("time")
+        .and_then(|time| time.parse::<u64>().ok())
+        .map(|time| UNIX_EPOCH + Duration::from_secs(time))
+        .ok_or_else(|| proto::Error::OtherError {
+            desc: "Server did not report its time",
+            detail: stats.get("time").map(|time| format!("time stat is {:?}", time)),
+        })
+}
+
+impl Client {
+    /// Current time on the server added as `addr`, from its `time` statistic
+    ///
+    /// memcached reports it in whole seconds.
+    pub fn server_time(&mut self, addr: &str) -> MemCachedResult<SystemTime> {
+        self.on_server(addr, "stat", |proto| proto.stat())
+            .and_then(|stats| parse_server_time(&stats))
+    }
+
+    /// Expiration to send with `key` so that it expires at `at`, as an absolute unix time
+    ///
+    /// memcached compares absolute expirations with its own clock, so an application server whose
+    /// clock runs behind gets its keys expired early. With `ClientBuilder::server_clock` the time
+    /// is shifted by how far the clock of the server holding `key` is ahead of the local one.
+    /// That offset is measured with a stat request on the first call, and again once it is older
+    /// than the refresh interval; a new connection starts without one.
+    pub fn expiration_at(&mut self, key: &[u8], at: SystemTime) -> MemCachedResult<u32> {
+        let offset = match self.server_clock_refresh {
+            Some(refresh) => {
+                let server = self.find_server_by_key(&self.wire_key(key)?).clone();
+                clock_offset(&server, refresh)?
+            }
+            None => 0,
+        };
+        Ok((unix_seconds(at) + offset).clamp(1, i64::from(u32::MAX)) as u32)
+    }
+}
+
+/// The clock offset of `server`, measured again if older than `refresh`
+fn clock_offset(server: &ServerRef, refresh: Duration) -> MemCachedResult<i64> {
+    let mut server = server.lock()?;
+    if let Some(offset) = server.clock_offset {
+        if offset.measured.elapsed() < refresh {
+            return Ok(offset.seconds);
+        }
+    }
+    let stats = server.call("stat", |proto| proto.stat())?;
+    let seconds = unix_seconds(parse_server_time(&stats)?) - unix_seconds(SystemTime::now());
+    server.clock_offset = Some(ClockOffset {
+        seconds,
+        measured: Instant::now(),
+    });
+    Ok(seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::unix_seconds;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn assert_close(actual: i64, expected: i64) {
+        assert!((actual - expected).abs() <= 1, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn test_server_time() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+
+        let local = unix_seconds(SystemTime::now());
+        assert_close(unix_seconds(client.server_time(&mock.url()).unwrap()), local);
+        mock.advance_clock(HOUR);
+        assert_close(unix_seconds(client.server_time(&mock.url()).unwrap()), local + 3600);
+        assert!(client.server_time("tcp://127.0.0.1:1").is_err());
+    }
+
+    #[test]
+    fn test_expiration_at_skewed_server() {
+        const KEY: &[u8] = b"test:expiration_at";
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        // The server's clock runs an hour ahead
+        mock.advance_clock(HOUR);
+        let at = SystemTime::now() + Duration::from_secs(60);
+        let local = unix_seconds(at);
+
+        // On the local clock the key is already past its expiration on the server
+        let mut naive = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+        let expiration = naive.expiration_at(KEY, at).unwrap();
+        assert_eq!(i64::from(expiration), local);
+        naive.set(KEY, b"value", 0, expiration).unwrap();
+        assert!(naive.get_opt(KEY).unwrap().is_none());
+
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .server_clock(HOUR)
+            .build()
+            .unwrap();
+        assert_eq!(client.config().server_clock_refresh, Some(HOUR));
+        let expiration = client.expiration_at(KEY, at).unwrap();
+        assert_close(i64::from(expiration), local + 3600);
+        client.set(KEY, b"value", 0, expiration).unwrap();
+        assert_eq!(client.get(KEY).unwrap().0, b"value".to_vec());
+        mock.advance_clock(Duration::from_secs(61));
+        assert!(client.get_opt(KEY).unwrap().is_none());
+
+        // The measured offset is kept until the refresh interval passes, a new connection
+        // measures again
+        assert_close(i64::from(client.expiration_at(KEY, at).unwrap()), local + 3600);
+        let mut reconnected = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .server_clock(HOUR)
+            .build()
+            .unwrap();
+        assert_close(i64::from(reconnected.expiration_at(KEY, at).unwrap()), local + 3661);
+
+        let mut refreshing = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .server_clock(Duration::ZERO)
+            .build()
+            .unwrap();
+        refreshing.expiration_at(KEY, at).unwrap();
+        mock.advance_clock(HOUR);
+        assert_close(i64::from(refreshing.expiration_at(KEY, at).unwrap()), local + 7261);
+    }
+}
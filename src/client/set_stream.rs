@@ -0,0 +1,204 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Bulk loading that slows down when the servers are overloaded
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::Client;
+use crate::proto::{self, binary::Status, MemCachedResult, MultiOperation};
+
+/// Largest number of items `Client::set_stream` sends in one pipelined batch, and the first window
+pub const SET_STREAM_MAX_WINDOW: usize = 128;
+
+/// First pause of `Client::set_stream` after an overloaded batch, doubled while the overload lasts
+pub const SET_STREAM_PAUSE: Duration = Duration::from_millis(10);
+
+/// Longest pause of `Client::set_stream`
+pub const SET_STREAM_MAX_PAUSE: Duration = Duration::from_secs(1);
+
+/// Number of times `Client::set_stream` resends an item the servers were too busy to store
+pub const SET_STREAM_MAX_RETRIES: usize = 10;
+
+/// Outcome of `Client::set_stream`
+#[derive(Debug, Default)]
+pub struct SetStreamSummary {
+    /// Items stored
+    pub stored: u64,
+    /// Keys that were not stored, with the last error of each
+    pub failed: Vec<(Vec<u8>, proto::Error)>,
+    /// Batches that met an overloaded server and were followed by a pause
+    pub throttled: u64,
+    /// Time spent paused
+    pub paused: Duration,
+    /// Smallest window used
+    pub min_window: usize,
+}
+
+fn is_overloaded(err: &proto::Error) -> bool {
+    matches!(err.status(), Some(Status::Busy) | Some(Status::TemporaryFailure))
+}
+
+impl Client {
+    /// Store every `(key, value, flags)` of `items` with `expiration`, in pipelined batches
+    ///
+    /// Batches go through `set_cas_multi` without a CAS check. The first batch has
+    /// `SET_STREAM_MAX_WINDOW` items. When a server answers an item with `Busy` or
+    /// `TemporaryFailure`, the window is halved and the client pauses before resending those
+    /// items. The pause starts at `SET_STREAM_PAUSE` and doubles while the overload lasts, up to
+    /// `SET_STREAM_MAX_PAUSE`. A `Backpressure` retry hint (see `ClientBuilder::busy_backpressure`)
+    /// is used as is. Each batch without overload doubles the window again. Items still refused
+    /// after `SET_STREAM_MAX_RETRIES` resends, and items failing with other statuses, are reported
+    /// in `failed` without stopping the load. Errors of a whole connection are returned right away.
+    pub fn set_stream<I>(&mut self, items: I, expiration: u32) -> MemCachedResult<SetStreamSummary>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>, u32)>,
+    {
+        let mut items = items.into_iter();
+        let mut retries: VecDeque<((Vec<u8>, Vec<u8>, u32), usize)> = VecDeque::new();
+        let mut window = SET_STREAM_MAX_WINDOW;
+        let mut pause = SET_STREAM_PAUSE;
+        let mut summary = SetStreamSummary {
+            min_window: window,
+            ..SetStreamSummary::default()
+        };
+
+        loop {
+            // Refused items go first, so they are not overtaken by later writes of the same key
+            let mut batch: Vec<((Vec<u8>, Vec<u8>, u32), usize)> = Vec::with_capacity(window);
+            while batch.len() < window {
+                match retries.pop_front().or_else(|| items.next().map(|item| (item, 0))) {
+                    Some(item) => batch.push(item),
+                    None => break,
+                }
+            }
+            if batch.is_empty() {
+                return Ok(summary);
+            }
+
+            let requests: Vec<_> = batch
+                .iter()
+                .map(|((key, value, flags), _)| (&key[..], &value[..], *flags, expiration, 0))
+                .collect();
+            let results = self.set_cas_multi(&requests)?;
+
+            let mut retry_after = None;
+            let mut overloaded = false;
+            for ((item, resent), result) in batch.into_iter().zip(results) {
+                match result {
+                    Ok(_) => summary.stored += 1,
+                    Err(err) if is_overloaded(&err) && resent < SET_STREAM_MAX_RETRIES => {
+                        if let proto::Error::Backpressure {
+                            retry_after: Some(hint),
+                        } = *err.root()
+                        {
+                            retry_after = Some(hint);
+                        }
+                        overloaded = true;
+                        retries.push_back((item, resent + 1));
+                    }
+                    Err(err) => summary.failed.push((item.0, err)),
+                }
+            }
+
+            if overloaded {
+                window = (window / 2).max(1);
+                summary.min_window = summary.min_window.min(window);
+                let wait = retry_after.unwrap_or(pause);
+                pause = (pause * 2).min(SET_STREAM_MAX_PAUSE);
+                self.clock.sleep(wait);
+                summary.throttled += 1;
+                summary.paused += wait;
+            } else {
+                window = (window * 2).min(SET_STREAM_MAX_WINDOW);
+                pause = SET_STREAM_PAUSE;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::{SET_STREAM_MAX_RETRIES, SET_STREAM_MAX_WINDOW, SET_STREAM_PAUSE};
+    use crate::client::{Client, Clock};
+    use crate::proto::{binary::Status, ProtoType};
+    use crate::test_support::MockServer;
+
+    /// Adds up the pauses instead of sleeping
+    struct Skipping(Rc<Cell<Duration>>);
+
+    impl Clock for Skipping {
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn items(count: usize) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
+        (0..count)
+            .map(|i| (format!("test:set_stream_{}", i).into_bytes(), format!("value{}", i).into_bytes(), 1))
+            .collect()
+    }
+
+    #[test]
+    fn test_set_stream_throttles() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let slept = Rc::new(Cell::new(Duration::ZERO));
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .clock(Skipping(slept.clone()))
+            .build()
+            .unwrap();
+
+        let summary = client.set_stream(items(300), 0).unwrap();
+        assert_eq!(summary.stored, 300);
+        assert_eq!(summary.throttled, 0);
+        assert_eq!(summary.min_window, SET_STREAM_MAX_WINDOW);
+
+        // Busy through the first two batches and part of the third
+        mock.busy_for(SET_STREAM_MAX_WINDOW + SET_STREAM_MAX_WINDOW / 2 + 10);
+        let summary = client.set_stream(items(500), 0).unwrap();
+        assert_eq!(summary.stored, 500);
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.throttled, 3);
+        assert_eq!(summary.min_window, SET_STREAM_MAX_WINDOW / 8);
+        assert_eq!(summary.paused, SET_STREAM_PAUSE * 7);
+        assert_eq!(slept.get(), summary.paused);
+        for (key, value, flags) in items(500) {
+            assert_eq!(client.get(&key).unwrap(), (value, flags));
+        }
+    }
+
+    #[test]
+    fn test_set_stream_gives_up() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let slept = Rc::new(Cell::new(Duration::ZERO));
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .busy_backpressure(Duration::from_millis(5))
+            .clock(Skipping(slept.clone()))
+            .build()
+            .unwrap();
+
+        mock.busy_for(usize::MAX);
+        let summary = client.set_stream(items(1), 0).unwrap();
+        assert_eq!(summary.stored, 0);
+        assert_eq!(summary.failed.len(), 1);
+        assert!(summary.failed.iter().all(|(_, err)| err.status() == Some(Status::Busy)));
+        assert_eq!(summary.throttled, SET_STREAM_MAX_RETRIES as u64);
+        assert_eq!(summary.min_window, 1);
+        // The hint of the server replaces the doubling pauses
+        assert_eq!(summary.paused, Duration::from_millis(5) * SET_STREAM_MAX_RETRIES as u32);
+        assert_eq!(slept.get(), summary.paused);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Flagging risky server settings
+
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use super::Client;
+
+/// With evictions off, a server with less memory than this is flagged
+pub const NO_EVICTIONS_MIN_MAXBYTES: u64 = 1024 * 1024 * 1024;
+
+/// Growth factors outside of this range are flagged
+pub const GROWTH_FACTOR_RANGE: (f64, f64) = (1.05, 2.0);
+
+/// A server setting that is likely to hurt, found by `Client::check_settings`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingsWarning {
+    /// Address the server was added with
+    pub server: String,
+    /// Name of the setting in `stats settings`
+    pub setting: &'static str,
+    /// Its value, or the error for a server whose settings could not be read
+    pub value: String,
+    pub reason: &'static str,
+}
+
+impl Client {
+    /// Read the `settings` stats of every server and flag the risky ones
+    ///
+    /// Flags evictions turned off on a server with less than `NO_EVICTIONS_MIN_MAXBYTES` of
+    /// memory, growth factors outside of `GROWTH_FACTOR_RANGE`, CAS turned off, and SASL turned
+    /// off on a server listening on every interface. Servers that could not answer are reported
+    /// with the setting `settings`, servers with `Quirks::stat_settings_missing` are skipped.
+    pub fn check_settings(&mut self) -> Vec<SettingsWarning> {
+        let mut warnings = Vec::new();
+        for server in self.nodes.iter() {
+            let addr = server.addr().to_owned();
+            if server
+                .try_borrow()
+                .is_ok_and(|server| server.quirks.stat_settings_missing)
+            {
+                debug!("Not checking the settings of {}, it does not report them", addr);
+                continue;
+            }
+            match server
+                .lock()
+                .and_then(|mut server| server.call("stat", |proto| proto.stat_key("settings")))
+            {
+                Ok(settings) => {
+                    warnings.extend(
+                        check(&settings)
+                            .into_iter()
+                            .map(|(setting, value, reason)| SettingsWarning {
+                                server: addr.clone(),
+                                setting,
+                                value,
+                                reason,
+                            }),
+                    )
+                }
+                Err(err) => warnings.push(SettingsWarning {
+                    server: addr,
+                    setting: "settings",
+                    value: err.to_string(),
+                    reason: "the settings could not be read",
+                }),
+            }
+        }
+        warnings
+    }
+}
+
+/// `(setting, value, reason)` of each risky value in `settings`
+fn check(settings: &BTreeMap<String, String>) -> Vec<(&'static str, String, &'static str)> {
+    let get = |name: &str| settings.get(name).map(|value| &value[..]);
+    let mut warnings = Vec::new();
+
+    if get("evictions") == Some("off") {
+        if let Some(maxbytes) = get("maxbytes").and_then(|value| value.parse::<u64>().ok()) {
+            if maxbytes < NO_EVICTIONS_MIN_MAXBYTES {
+                warnings.push((
+                    "evictions",
+                    "off".to_owned(),
+                    "evictions are off on a small cache, stores fail with out of memory once it is full",
+                ));
+            }
+        }
+    }
+    if let Some(factor) = get("growth_factor").and_then(|value| value.parse::<f64>().ok()) {
+        if factor < GROWTH_FACTOR_RANGE.0 {
+            warnings.push((
+                "growth_factor",
+                factor.to_string(),
+                "a growth factor this small makes many slab classes that each hold back memory",
+            ));
+        } else if factor > GROWTH_FACTOR_RANGE.1 {
+            warnings.push((
+                "growth_factor",
+                factor.to_string(),
+                "a growth factor this large wastes most of each chunk on items just above a class size",
+            ));
+        }
+    }
+    if get("cas_enabled") == Some("no") {
+        warnings.push(("cas_enabled", "no".to_owned(), "CAS is off, every CAS operation of this client fails"));
+    }
+    if get("sasl") == Some("no") && matches!(get("inter"), Some("NULL") | Some("0.0.0.0") | Some("::")) {
+        warnings.push(("sasl", "no".to_owned(), "the server listens on every interface without authentication"));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{check, SettingsWarning};
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    fn settings(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let safe = settings(&[
+            ("maxbytes", "67108864"),
+            ("evictions", "on"),
+            ("growth_factor", "1.25"),
+            ("cas_enabled", "yes"),
+            ("sasl", "no"),
+            ("inter", "10.0.0.1"),
+        ]);
+        assert!(check(&safe).is_empty());
+        assert!(check(&BTreeMap::new()).is_empty());
+
+        let risky = settings(&[
+            ("maxbytes", "67108864"),
+            ("evictions", "off"),
+            ("growth_factor", "1.01"),
+            ("cas_enabled", "no"),
+            ("sasl", "no"),
+            ("inter", "NULL"),
+        ]);
+        let flagged: Vec<&str> = check(&risky).into_iter().map(|(setting, ..)| setting).collect();
+        assert_eq!(flagged, ["evictions", "growth_factor", "cas_enabled", "sasl"]);
+
+        // Plenty of memory makes evictions off a choice
+        let large = settings(&[
+            ("maxbytes", "68719476736"),
+            ("evictions", "off"),
+            ("growth_factor", "3"),
+        ]);
+        assert_eq!(check(&large)[0].1, "3");
+        assert_eq!(check(&large).len(), 1);
+    }
+
+    #[test]
+    fn test_check_settings() {
+        let mocks: Vec<MockServer> = (0..2).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mocks[0].url(), 1)
+            .add_server(mocks[1].url(), 1)
+            .build()
+            .unwrap();
+        assert!(client.check_settings().is_empty());
+
+        mocks[1].set_setting("inter", "NULL");
+        assert_eq!(
+            client.check_settings(),
+            vec![SettingsWarning {
+                server: mocks[1].url(),
+                setting: "sasl",
+                value: "no".to_owned(),
+                reason: "the server listens on every interface without authentication",
+            }]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Following slab page moves
+
+use std::collections::BTreeMap;
+
+use super::Client;
+use crate::proto::{self, MemCachedResult};
+
+/// Progress of slab page moves on a server, returned by `Client::slab_reassign_status`
+///
+/// The counters are totals since the server started. Servers too old to report one of them
+/// report 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassignStatus {
+    /// A page is being moved right now
+    pub running: bool,
+    /// Pages moved between slab classes
+    pub slabs_moved: u64,
+    /// Items moved elsewhere to free a page
+    pub rescues: u64,
+    /// Chunks of large items moved elsewhere to free a page
+    pub chunk_rescues: u64,
+    /// Items evicted because there was no memory to move them to
+    pub evictions_nomem: u64,
+    /// Items reclaimed in place while freeing a page
+    pub inline_reclaim: u64,
+    /// Times a page could not be freed yet because of items in use
+    pub busy_items: u64,
+    /// Items deleted because they stayed busy
+    pub busy_deletes: u64,
+}
+
+/// The `slab_reassign_*` and `slabs_moved` statistics
+fn parse_reassign_status(stats: &BTreeMap<String, String>) -> MemCachedResult<ReassignStatus> {
+    let running = match stats.get("slab_reassign_running").map(|running| &running[..]) {
+        Some("0") => false,
+        Some("1") => true,
+        running => {
+            return Err(proto::Error::OtherError {
+                desc: "Server did not report its slab reassignment",
+                detail: running.map(|running| format!("slab_reassign_running stat is {:?}", running)),
+            })
+        }
+    };
+    let counter = |name: &str| stats.get(name).and_then(|value| value.parse().ok()).unwrap_or(0);
+    Ok(ReassignStatus {
+        running,
+        slabs_moved: counter("slabs_moved"),
+        rescues: counter("slab_reassign_rescues"),
+        chunk_rescues: counter("slab_reassign_chunk_rescues"),
+        evictions_nomem: counter("slab_reassign_evictions_nomem"),
+        inline_reclaim: counter("slab_reassign_inline_reclaim"),
+        busy_items: counter("slab_reassign_busy_items"),
+        busy_deletes: counter("slab_reassign_busy_deletes"),
+    })
+}
+
+impl Client {
+    /// Slab page moves on the server added as `addr`, from its general statistics
+    ///
+    /// Poll it after `slabs reassign` until `running` is false to wait for the move to finish.
+    pub fn slab_reassign_status(&mut self, addr: &str) -> MemCachedResult<ReassignStatus> {
+        self.on_server(addr, "stat", |proto| proto.stat())
+            .and_then(|stats| parse_reassign_status(&stats))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{parse_reassign_status, ReassignStatus};
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    #[test]
+    fn test_parse_reassign_status() {
+        // From the general statistics of memcached 1.6.21 in the middle of a move
+        let stats: BTreeMap<String, String> = [
+            ("pid", "4211"),
+            ("uptime", "86012"),
+            ("curr_items", "1835112"),
+            ("evictions", "0"),
+            ("slab_reassign_rescues", "1532"),
+            ("slab_reassign_chunk_rescues", "4"),
+            ("slab_reassign_evictions_nomem", "17"),
+            ("slab_reassign_inline_reclaim", "88"),
+            ("slab_reassign_busy_items", "3"),
+            ("slab_reassign_busy_deletes", "0"),
+            ("slab_reassign_running", "1"),
+            ("slabs_moved", "42"),
+            ("lru_crawler_running", "0"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(
+            parse_reassign_status(&stats).unwrap(),
+            ReassignStatus {
+                running: true,
+                slabs_moved: 42,
+                rescues: 1532,
+                chunk_rescues: 4,
+                evictions_nomem: 17,
+                inline_reclaim: 88,
+                busy_items: 3,
+                busy_deletes: 0,
+            }
+        );
+
+        let mut old = BTreeMap::new();
+        old.insert("slab_reassign_running".to_owned(), "0".to_owned());
+        old.insert("slabs_moved".to_owned(), "5".to_owned());
+        assert_eq!(
+            parse_reassign_status(&old).unwrap(),
+            ReassignStatus {
+                slabs_moved: 5,
+                ..ReassignStatus::default()
+            }
+        );
+        assert!(parse_reassign_status(&BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_slab_reassign_status() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        assert_eq!(client.slab_reassign_status(&mock.url()).unwrap(), ReassignStatus::default());
+        client.slab_reassign_status("tcp://127.0.0.1:1").unwrap_err();
+    }
+}
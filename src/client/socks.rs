@@ -0,0 +1,252 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Connecting through a SOCKS5 proxy
+//!
+//! Servers added as `socks5://proxyhost:port/targethost:targetport` are reached through a tunnel
+//! the proxy opens to the target with the CONNECT command of RFC 1928. Only proxies accepting
+//! clients without authentication are supported. The target host is resolved by the proxy unless
+//! it is an IP address.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Read and write timeout of the negotiation if there is no connect timeout
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn invalid(detail: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, detail)
+}
+
+/// Open a tunnel to the target of `addr`, `proxyhost:port/targethost:targetport`
+///
+/// `connect_timeout` bounds the connection to the proxy and each step of the negotiation. The
+/// returned stream has no read or write timeout.
+pub(crate) fn connect(addr: &str, connect_timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let (proxy, target) = addr
+        .split_once('/')
+        .ok_or_else(|| invalid(format!("{} is not in the form proxyhost:port/targethost:targetport", addr)))?;
+    let request = connect_request(target)?;
+
+    let mut stream = match connect_timeout {
+        Some(timeout) => {
+            let proxy_addr: SocketAddr = proxy
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| invalid(format!("{} does not resolve", proxy)))?;
+            TcpStream::connect_timeout(&proxy_addr, timeout)?
+        }
+        None => TcpStream::connect(proxy)?,
+    };
+    let timeout = connect_timeout.or(Some(NEGOTIATION_TIMEOUT));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    negotiate(&mut stream, &request)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+/// The CONNECT request for `target`, `host:port` with IPv6 addresses in brackets
+fn connect_request(target: &str) -> io::Result<Vec<u8>> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or_else(|| invalid(format!("target {} has no port", target)))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid(format!("target {} has an invalid port", target)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.is_empty() || host.len() > 255 => {
+            return Err(invalid(format!("target host {:?} should be 1 to 255 bytes long", host)))
+        }
+        Err(_) => {
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+/// Agree on no authentication, send the CONNECT `request` and read the reply
+fn negotiate<S: Read + Write>(stream: &mut S, request: &[u8]) -> io::Result<()> {
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice)?;
+    match choice {
+        [VERSION, NO_AUTHENTICATION] => {}
+        [VERSION, NO_ACCEPTABLE_METHODS] => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 proxy requires authentication"))
+        }
+        _ => return Err(io::Error::other(format!("unexpected SOCKS5 method selection {:?}", choice))),
+    }
+
+    stream.write_all(request)?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(io::Error::other(format!("unexpected SOCKS5 reply version {}", reply[0])));
+    }
+    if reply[1] != 0 {
+        return Err(reply_error(reply[1]));
+    }
+    // The address the proxy connected from, of no use to us
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(io::Error::other(format!("unexpected SOCKS5 address type {}", atyp))),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+/// The error of a failed CONNECT, by the reply code of RFC 1928
+fn reply_error(code: u8) -> io::Error {
+    let (kind, reason) = match code {
+        2 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+        3 => (io::ErrorKind::Other, "network unreachable"),
+        4 => (io::ErrorKind::Other, "host unreachable"),
+        5 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (io::ErrorKind::TimedOut, "TTL expired"),
+        7 => (io::ErrorKind::Unsupported, "command not supported"),
+        8 => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general SOCKS server failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy failed to connect: {} ({})", reason, code))
+}
+
+#[cfg(all(test, feature = "socks"))]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use super::connect_request;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    /// A SOCKS5 proxy without authentication for one connection, answering CONNECT with `reply`
+    ///
+    /// On success, it forwards the connection to the target. Returns the proxy address and the
+    /// CONNECT request it received, without the length byte of a domain name.
+    fn stub_proxy(reply: u8) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            client.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0; 4];
+            client.read_exact(&mut request).unwrap();
+            let mut target = match request[3] {
+                1 => vec![0; 4],
+                4 => vec![0; 16],
+                _ => {
+                    let mut len = [0; 1];
+                    client.read_exact(&mut len).unwrap();
+                    vec![0; len[0] as usize]
+                }
+            };
+            client.read_exact(&mut target).unwrap();
+            let mut port = [0; 2];
+            client.read_exact(&mut port).unwrap();
+            client.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+            let mut received = request.to_vec();
+            received.extend_from_slice(&target);
+            received.extend_from_slice(&port);
+            if reply != 0 {
+                return received;
+            }
+            let host = match request[3] {
+                1 => format!("{}.{}.{}.{}", target[0], target[1], target[2], target[3]),
+                _ => String::from_utf8(target).unwrap(),
+            };
+            let server = TcpStream::connect((&host[..], u16::from_be_bytes(port))).unwrap();
+            let (mut client_read, mut server_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+            let upstream = thread::spawn(move || {
+                let _ = io::copy(&mut client_read, &mut server_write);
+                let _ = server_write.shutdown(Shutdown::Both);
+            });
+            let (mut server_read, mut client_write) = (server, client);
+            let _ = io::copy(&mut server_read, &mut client_write);
+            let _ = upstream.join();
+            received
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_connect_request() {
+        assert_eq!(connect_request("10.0.0.1:11211").unwrap(), [5, 1, 0, 1, 10, 0, 0, 1, 0x2b, 0xcb]);
+        assert_eq!(connect_request("cache:11211").unwrap(), [5, 1, 0, 3, 5, b'c', b'a', b'c', b'h', b'e', 0x2b, 0xcb]);
+        let v6 = connect_request("[::1]:11211").unwrap();
+        assert_eq!((v6[3], v6.len()), (4, 4 + 16 + 2));
+        connect_request("cache").unwrap_err();
+        connect_request("cache:port").unwrap_err();
+        connect_request(":11211").unwrap_err();
+    }
+
+    #[test]
+    fn test_socks5() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let (proxy, received) = stub_proxy(0);
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(format!("socks5://{}/localhost:{}", proxy, mock.addr().port()), 1)
+            .build()
+            .unwrap();
+        client.set(b"test:socks5", b"tunneled", 3, 0).unwrap();
+        assert_eq!(client.get(b"test:socks5").unwrap(), (b"tunneled".to_vec(), 3));
+        assert_eq!(mock.item_count(), 1);
+        drop(client);
+        let received = received.join().unwrap();
+        assert_eq!(received[3], 3);
+        assert_eq!(&received[4..13], b"localhost");
+
+        // The proxy refusing the connection fails it
+        let (proxy, _) = stub_proxy(5);
+        let err = Client::builder(ProtoType::Binary)
+            .add_server(format!("socks5://{}/{}", proxy, mock.addr()), 1)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Client side counters
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::proto::{self, binary::Status, MemCachedResult};
+
+/// Class of keys no prefix matched
+pub const DEFAULT_CLASS: &str = "default";
+
+/// Counters of one class of keys
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ClassStats {
+    /// Gets that found the key
+    pub hits: u64,
+    /// Gets that did not find the key
+    pub misses: u64,
+    /// Stores, including appends and prepends
+    pub sets: u64,
+    /// Value bytes sent by stores and received by hits
+    pub bytes: u64,
+}
+
+impl ClassStats {
+    /// Count the outcome of `op` which sent `value`
+    pub(crate) fn record<R: Payload>(&mut self, op: &str, value: &[u8], result: &MemCachedResult<R>) {
+        match op {
+            "get" | "getk" | "get_cas" | "getk_cas" => match *result {
+                Ok(ref item) => {
+                    self.hits += 1;
+                    self.bytes += item.payload_len() as u64;
+                }
+                Err(ref err) if err.status() == Some(Status::KeyNotFound) => self.misses += 1,
+                Err(..) => {}
+            },
+            _ if is_store(op) && result.is_ok() => {
+                self.sets += 1;
+                self.bytes += value.len() as u64;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_store(op: &str) -> bool {
+    let base = op.trim_start_matches("try_").split('_').next().unwrap_or(op);
+    matches!(base, "set" | "add" | "replace" | "append" | "prepend")
+}
+
+/// Upper bounds of the `LatencyHistogram` buckets, the last bucket has no bound
+pub const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_secs(1),
+];
+
+/// Distribution of operation latencies over `LATENCY_BUCKETS`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn observe(&mut self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| elapsed <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += elapsed;
+    }
+
+    /// Number of latencies in each bucket, the last one counts those above every bound
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Number of latencies observed
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Total of the latencies observed
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+/// Counters of one kind of operation, e.g. `"get"`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OpStats {
+    /// Operations by outcome: `"ok"`, `"not_found"`, `"exists"`, `"not_stored"`, `"server_error"`,
+    /// `"io_error"`, `"timeout"` or `"error"`
+    pub outcomes: BTreeMap<&'static str, u64>,
+    /// Value bytes sent and received
+    pub bytes: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// Short name of the outcome of an operation
+pub(crate) fn outcome_of<R>(result: &MemCachedResult<R>) -> &'static str {
+    let err = match *result {
+        Ok(..) => return "ok",
+        Err(ref err) => err.root(),
+    };
+    match *err {
+        proto::Error::BinaryProtoError(ref err) => match err.status() {
+            Status::KeyNotFound => "not_found",
+            Status::KeyExists => "exists",
+            Status::ItemNotStored => "not_stored",
+            _ => "server_error",
+        },
+        proto::Error::IoError(..) => "io_error",
+        proto::Error::Timeout { .. } => "timeout",
+        proto::Error::Backpressure { .. } => "busy",
+        _ => "error",
+    }
+}
+
+/// Counters collected by a `Client`
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    by_class: BTreeMap<&'static str, ClassStats>,
+    by_op: BTreeMap<&'static str, OpStats>,
+}
+
+impl ClientStats {
+    /// Counters per key class, see `ClientBuilder::key_classes`
+    ///
+    /// Empty unless key classification is configured.
+    pub fn by_class(&self) -> &BTreeMap<&'static str, ClassStats> {
+        &self.by_class
+    }
+
+    /// Counters per single key operation
+    pub fn by_op(&self) -> &BTreeMap<&'static str, OpStats> {
+        &self.by_op
+    }
+
+    pub(crate) fn class_mut(&mut self, class: &'static str) -> &mut ClassStats {
+        self.by_class.entry(class).or_default()
+    }
+
+    /// Count the outcome of `op` which sent `value` and took `elapsed`
+    pub(crate) fn record_op<R: Payload>(
+        &mut self,
+        op: &'static str,
+        value: &[u8],
+        elapsed: Duration,
+        result: &MemCachedResult<R>,
+    ) {
+        let stats = self.by_op.entry(op).or_default();
+        *stats.outcomes.entry(outcome_of(result)).or_default() += 1;
+        stats.bytes += (value.len() + result.as_ref().map_or(0, Payload::payload_len)) as u64;
+        stats.latency.observe(elapsed);
+    }
+}
+
+/// Maps keys to the class their longest matching prefix belongs to
+#[derive(Debug)]
+pub(crate) struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    class: Option<&'static str>,
+}
+
+impl PrefixTrie {
+    pub(crate) fn new(prefixes: &[(&[u8], &'static str)]) -> PrefixTrie {
+        let mut trie = PrefixTrie {
+            nodes: vec![TrieNode::default()],
+        };
+        for &(prefix, class) in prefixes {
+            let mut node = 0;
+            for &byte in prefix {
+                node = match trie.nodes[node].children.iter().find(|&&(b, _)| b == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        trie.nodes.push(TrieNode::default());
+                        let child = trie.nodes.len() - 1;
+                        trie.nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            trie.nodes[node].class = Some(class);
+        }
+        trie
+    }
+
+    pub(crate) fn classify(&self, key: &[u8]) -> &'static str {
+        let mut class = self.nodes[0].class.unwrap_or(DEFAULT_CLASS);
+        let mut node = 0;
+        for &byte in key {
+            node = match self.nodes[node].children.iter().find(|&&(b, _)| b == byte) {
+                Some(&(_, child)) => child,
+                None => break,
+            };
+            if let Some(c) = self.nodes[node].class {
+                class = c;
+            }
+        }
+        class
+    }
+}
+
+/// How keys are assigned to classes
+pub(crate) enum Classifier {
+    Prefixes(PrefixTrie),
+    Callback(fn(&[u8]) -> &'static str),
+}
+
+impl Classifier {
+    pub(crate) fn classify(&self, key: &[u8]) -> &'static str {
+        match *self {
+            Classifier::Prefixes(ref trie) => trie.classify(key),
+            Classifier::Callback(f) => f(key),
+        }
+    }
+}
+
+/// Length of the value carried by an operation's result
+pub(crate) trait Payload {
+    fn payload_len(&self) -> usize {
+        0
+    }
+}
+
+impl Payload for () {}
+impl Payload for bool {}
+impl Payload for u64 {}
+impl Payload for (u64, u64) {}
+impl Payload for Option<u64> {}
+impl Payload for Vec<proto::Error> {}
+
+impl Payload for (Vec<u8>, u32) {
+    fn payload_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Payload for (Vec<u8>, u32, u64) {
+    fn payload_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Payload for (Vec<u8>, Vec<u8>, u32) {
+    fn payload_len(&self) -> usize {
+        self.1.len()
+    }
+}
+
+impl Payload for (Vec<u8>, Vec<u8>, u32, u64) {
+    fn payload_len(&self) -> usize {
+        self.1.len()
+    }
+}
+
+impl Payload for proto::Item {
+    fn payload_len(&self) -> usize {
+        self.value.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LatencyHistogram, PrefixTrie, DEFAULT_CLASS, LATENCY_BUCKETS};
+    use std::time::Duration;
+
+    #[test]
+    fn test_prefix_trie_longest_wins() {
+        let trie = PrefixTrie::new(&[
+            (b"user:", "user"),
+            (b"user:session:", "session"),
+            (b"u", "u"),
+            (b"feed:", "feed"),
+        ]);
+
+        assert_eq!(trie.classify(b"user:42"), "user");
+        assert_eq!(trie.classify(b"user:session:42"), "session");
+        assert_eq!(trie.classify(b"user:sess"), "user");
+        assert_eq!(trie.classify(b"usr"), "u");
+        assert_eq!(trie.classify(b"feed:1"), "feed");
+        assert_eq!(trie.classify(b"feed"), DEFAULT_CLASS);
+        assert_eq!(trie.classify(b""), DEFAULT_CLASS);
+        assert_eq!(trie.classify(b"other"), DEFAULT_CLASS);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_micros(101));
+        histogram.observe(Duration::from_secs(2));
+
+        assert_eq!(histogram.buckets().len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(histogram.buckets()[0], 1);
+        assert_eq!(histogram.buckets()[1], 1);
+        assert_eq!(histogram.buckets()[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.sum(), Duration::from_micros(2_000_201));
+    }
+}
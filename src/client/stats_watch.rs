@@ -0,0 +1,213 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Watching the rate of server counters
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::Client;
+
+/// A snapshot of the watched counters of one server
+struct Snapshot {
+    at: Instant,
+    counters: BTreeMap<String, u64>,
+}
+
+/// The state of `Client::watch_stats`
+pub(crate) struct StatsWatch {
+    interval: Duration,
+    /// Counter name and the rate per second above which it is reported
+    thresholds: Vec<(String, f64)>,
+    callback: Box<dyn FnMut(&str, &str, f64)>,
+    last_poll: Instant,
+    snapshots: HashMap<String, Snapshot>,
+}
+
+impl StatsWatch {
+    fn due(&self) -> bool {
+        self.last_poll.elapsed() >= self.interval
+    }
+
+    /// Record the stats of `server`, reporting the counters that grew faster than their threshold
+    /// since the last snapshot
+    fn observe(&mut self, server: &str, at: Instant, stats: &BTreeMap<String, String>) {
+        let counters: BTreeMap<String, u64> = self
+            .thresholds
+            .iter()
+            .filter_map(|(name, _)| Some((name.clone(), stats.get(name)?.parse().ok()?)))
+            .collect();
+        let current = Snapshot { at, counters };
+        if let Some(previous) = self.snapshots.get(server) {
+            for (name, rate) in exceeded(previous, &current, &self.thresholds) {
+                (self.callback)(server, name, rate);
+            }
+        }
+        self.snapshots.insert(server.to_owned(), current);
+    }
+}
+
+/// Counters of `thresholds` that grew faster than their rate per second from `previous` to
+/// `current`, with that rate
+///
+/// A counter that went down, e.g. because the server restarted, or that is missing from either
+/// snapshot is skipped.
+fn exceeded<'t>(previous: &Snapshot, current: &Snapshot, thresholds: &'t [(String, f64)]) -> Vec<(&'t str, f64)> {
+    let elapsed = current.at.saturating_duration_since(previous.at).as_secs_f64();
+    if elapsed <= 0.0 {
+        return Vec::new();
+    }
+    thresholds
+        .iter()
+        .filter_map(|(name, threshold)| {
+            let delta = current.counters.get(name)?.checked_sub(*previous.counters.get(name)?)?;
+            let rate = delta as f64 / elapsed;
+            (rate > *threshold).then_some((&name[..], rate))
+        })
+        .collect()
+}
+
+impl Client {
+    /// Poll the general stats of every server each `interval`, and call `callback(server, stat,
+    /// rate)` for each counter growing faster than its threshold
+    ///
+    /// `thresholds` pairs a counter of the general stats, e.g. `"evictions"` or `"get_misses"`,
+    /// with a rate per second. The client has no thread of its own, so the servers are polled
+    /// right after the first operation that completes once `interval` passed, which takes that
+    /// much longer, or by `poll_stats_watch`. The first snapshot is taken right away. A server that
+    /// cannot be polled is skipped and starts over with a new snapshot. Calling it again replaces
+    /// the watch.
+    pub fn watch_stats<F>(&mut self, interval: Duration, thresholds: &[(&str, f64)], callback: F)
+    where
+        F: FnMut(&str, &str, f64) + 'static,
+    {
+        self.stats_watch = Some(StatsWatch {
+            interval,
+            thresholds: thresholds
+                .iter()
+                .map(|&(name, threshold)| (name.to_owned(), threshold))
+                .collect(),
+            callback: Box::new(callback),
+            last_poll: Instant::now(),
+            snapshots: HashMap::new(),
+        });
+        self.poll_stats_watch();
+    }
+
+    /// Stop the watch started by `watch_stats`
+    pub fn unwatch_stats(&mut self) {
+        self.stats_watch = None;
+    }
+
+    /// Poll the servers for `watch_stats` if the interval passed
+    pub(crate) fn poll_stats_watch_if_due(&mut self) {
+        if self.stats_watch.as_ref().is_some_and(StatsWatch::due) {
+            self.poll_stats_watch();
+        }
+    }
+
+    /// Poll the servers for `watch_stats` now, whether the interval passed or not
+    ///
+    /// For applications that would rather poll from a timer of their own than from their
+    /// operations. Does nothing without a watch.
+    pub fn poll_stats_watch(&mut self) {
+        let mut watch = match self.stats_watch.take() {
+            Some(watch) => watch,
+            None => return,
+        };
+        watch.last_poll = Instant::now();
+        for server in self.nodes.iter() {
+            match server
+                .lock()
+                .and_then(|mut server| server.call("stat", |proto| proto.stat()))
+            {
+                Ok(stats) => watch.observe(server.addr(), Instant::now(), &stats),
+                Err(_) => {
+                    watch.snapshots.remove(server.addr());
+                }
+            }
+        }
+        self.stats_watch = Some(watch);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{exceeded, Snapshot};
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    fn snapshot(at: Instant, counters: &[(&str, u64)]) -> Snapshot {
+        Snapshot {
+            at,
+            counters: counters.iter().map(|&(name, value)| (name.to_owned(), value)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let thresholds = vec![("evictions".to_owned(), 10.0), ("get_misses".to_owned(), 100.0)];
+        let start = Instant::now();
+        let previous = snapshot(start, &[("evictions", 1000), ("get_misses", 5000)]);
+
+        // 50 evictions and 100 misses per second
+        let current = snapshot(start + Duration::from_secs(2), &[("evictions", 1100), ("get_misses", 5200)]);
+        assert_eq!(exceeded(&previous, &current, &thresholds), vec![("evictions", 50.0)]);
+
+        let quiet = snapshot(start + Duration::from_secs(2), &[("evictions", 1010), ("get_misses", 5000)]);
+        assert!(exceeded(&previous, &quiet, &thresholds).is_empty());
+
+        // A restarted server counts from 0 again, a missing counter is not a rate
+        let restarted = snapshot(start + Duration::from_secs(2), &[("evictions", 3)]);
+        assert!(exceeded(&previous, &restarted, &thresholds).is_empty());
+        assert!(exceeded(&previous, &snapshot(start, &[("evictions", 2000)]), &thresholds).is_empty());
+    }
+
+    #[test]
+    fn test_watch_stats() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .build()
+            .unwrap();
+        let reported = Rc::new(RefCell::new(Vec::new()));
+        let sink = reported.clone();
+        // `time` is the only counter the mock moves, by a second per second
+        client.watch_stats(Duration::from_millis(50), &[("time", 1000.0)], move |server, stat, _| {
+            sink.borrow_mut().push((server.to_owned(), stat.to_owned()))
+        });
+
+        thread::sleep(Duration::from_millis(60));
+        client.set(b"test:watch_stats", b"value", 0, 0).unwrap();
+        assert!(reported.borrow().is_empty());
+
+        mock.advance_clock(Duration::from_secs(3600));
+        thread::sleep(Duration::from_millis(60));
+        client.get(b"test:watch_stats").unwrap();
+        assert_eq!(*reported.borrow(), vec![(mock.url(), "time".to_owned())]);
+
+        // Polled on demand, before the interval passed
+        mock.advance_clock(Duration::from_secs(3600));
+        client.poll_stats_watch();
+        assert_eq!(reported.borrow().len(), 2);
+
+        client.unwatch_stats();
+        mock.advance_clock(Duration::from_secs(3600));
+        thread::sleep(Duration::from_millis(60));
+        client.get(b"test:watch_stats").unwrap();
+        client.poll_stats_watch();
+        assert_eq!(reported.borrow().len(), 2);
+    }
+}
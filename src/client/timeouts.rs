@@ -0,0 +1,294 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Read timeouts that follow the latency of each server
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[cfg(unix)]
+use unix_socket::UnixStream;
+
+/// z-score of the 99th percentile of a normal distribution
+const P99_Z: f64 = 2.326;
+
+/// Settings of `ClientBuilder::adaptive_timeouts`
+///
+/// Once an operation has `warmup` latencies on a server, its read timeout there is
+/// `multiplier * p99`, clamped to `min..=max`, where `p99` is estimated from an exponentially
+/// weighted mean and variance spanning about the last `window` latencies. Before that, the
+/// static `ClientBuilder::read_timeout` applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdaptiveTimeouts {
+    pub min: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    pub window: u32,
+    pub warmup: u32,
+}
+
+impl Default for AdaptiveTimeouts {
+    fn default() -> AdaptiveTimeouts {
+        AdaptiveTimeouts {
+            min: Duration::from_millis(5),
+            max: Duration::from_secs(1),
+            multiplier: 3,
+            window: 100,
+            warmup: 20,
+        }
+    }
+}
+
+/// Exponentially weighted latency of one operation on one server, see `Client::latency_estimate`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyEstimate {
+    /// In nanoseconds
+    mean: f64,
+    /// In squared nanoseconds
+    variance: f64,
+    samples: u64,
+}
+
+impl LatencyEstimate {
+    /// Add `elapsed`, older latencies fade out over about `window` samples
+    pub(crate) fn observe(&mut self, elapsed: Duration, window: u32) {
+        let latency = elapsed.as_nanos() as f64;
+        self.samples += 1;
+        if self.samples == 1 {
+            self.mean = latency;
+            return;
+        }
+        let alpha = 2.0 / (f64::from(window.max(1)) + 1.0);
+        let diff = latency - self.mean;
+        let step = alpha * diff;
+        self.mean += step;
+        self.variance = (1.0 - alpha) * (self.variance + diff * step);
+    }
+
+    /// Number of latencies observed, including those that no longer weigh in
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_nanos(self.mean.round() as u64)
+    }
+
+    /// Estimated 99th percentile, assuming latencies are roughly normal
+    pub fn p99(&self) -> Duration {
+        Duration::from_nanos((self.mean + P99_Z * self.variance.sqrt()).round() as u64)
+    }
+}
+
+/// A connection whose read timeout can be changed between operations
+pub(crate) trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Latency estimates of one server and the timeouts they lead to
+pub(crate) struct Adaptive {
+    config: AdaptiveTimeouts,
+    /// The static read timeout, used until an operation has enough latencies
+    fallback: Option<Duration>,
+    socket: Box<dyn ReadTimeout>,
+    estimates: HashMap<&'static str, LatencyEstimate>,
+}
+
+impl Adaptive {
+    pub(crate) fn new(config: AdaptiveTimeouts, fallback: Option<Duration>, socket: Box<dyn ReadTimeout>) -> Adaptive {
+        Adaptive {
+            config,
+            fallback,
+            socket,
+            estimates: HashMap::new(),
+        }
+    }
+
+    /// Read timeout of the next `op`
+    pub(crate) fn deadline(&self, op: &'static str) -> Option<Duration> {
+        match self.estimates.get(op) {
+            Some(estimate) if estimate.samples >= u64::from(self.config.warmup) => Some(
+                (estimate.p99() * self.config.multiplier)
+                    .max(self.config.min)
+                    .min(self.config.max),
+            ),
+            _ => self.fallback,
+        }
+    }
+
+    /// Set the read timeout of the connection for `op`
+    pub(crate) fn before(&mut self, op: &'static str) -> io::Result<()> {
+        self.socket.set_read_timeout(self.deadline(op))
+    }
+
+    /// Count the latency of `op`, also when it failed: a timed out operation pushes the estimate
+    /// towards the deadline, so a server that slowed down for good gets longer timeouts
+    pub(crate) fn after(&mut self, op: &'static str, elapsed: Duration) {
+        let window = self.config.window;
+        self.estimates.entry(op).or_default().observe(elapsed, window);
+    }
+
+    pub(crate) fn estimate(&self, op: &str) -> Option<LatencyEstimate> {
+        self.estimates.get(op).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::{Adaptive, AdaptiveTimeouts, LatencyEstimate, ReadTimeout};
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    /// Keeps the read timeouts set on it
+    struct Recording(Rc<RefCell<Vec<Option<Duration>>>>);
+
+    impl ReadTimeout for Recording {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.0.borrow_mut().push(timeout);
+            Ok(())
+        }
+    }
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn config() -> AdaptiveTimeouts {
+        AdaptiveTimeouts {
+            min: 2 * MS,
+            max: 100 * MS,
+            multiplier: 3,
+            window: 20,
+            warmup: 5,
+        }
+    }
+
+    #[test]
+    fn test_latency_estimate() {
+        let mut estimate = LatencyEstimate::default();
+        for _ in 0..10 {
+            estimate.observe(4 * MS, 20);
+        }
+        assert_eq!(estimate.samples(), 10);
+        assert_eq!((estimate.mean(), estimate.p99()), (4 * MS, 4 * MS));
+
+        // Spread out latencies raise the p99 above the mean
+        for i in 0..200 {
+            estimate.observe(if i % 2 == 0 { 2 * MS } else { 6 * MS }, 20);
+        }
+        let (mean, p99) = (estimate.mean().as_secs_f64(), estimate.p99().as_secs_f64());
+        assert!((mean - 0.004).abs() < 0.0003, "{}", mean);
+        assert!(p99 > 0.008 && p99 < 0.0095, "{}", p99);
+
+        // Old latencies fade out
+        for _ in 0..200 {
+            estimate.observe(40 * MS, 20);
+        }
+        let mean = estimate.mean().as_secs_f64();
+        assert!((mean - 0.040).abs() < 0.0001, "{}", mean);
+        assert_eq!(estimate.samples(), 410);
+    }
+
+    #[test]
+    fn test_adaptive_deadline() {
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        let mut adaptive = Adaptive::new(config(), Some(Duration::from_secs(2)), Box::new(Recording(recorded.clone())));
+
+        // Cold start
+        for _ in 0..5 {
+            adaptive.before("get").unwrap();
+            adaptive.after("get", 10 * MS);
+        }
+        adaptive.before("get").unwrap();
+        adaptive.after("get", 10 * MS);
+        adaptive.before("set").unwrap();
+        assert_eq!(
+            *recorded.borrow(),
+            [
+                vec![Some(Duration::from_secs(2)); 5],
+                vec![Some(30 * MS), Some(Duration::from_secs(2))]
+            ]
+            .concat()
+        );
+
+        // Clamped on both ends
+        for _ in 0..5 {
+            adaptive.after("set", Duration::from_micros(100));
+            adaptive.after("delete", 50 * MS);
+        }
+        assert_eq!(adaptive.deadline("set"), Some(2 * MS));
+        assert_eq!(adaptive.deadline("delete"), Some(100 * MS));
+        assert_eq!(adaptive.estimate("delete").unwrap().samples(), 5);
+        assert!(adaptive.estimate("touch").is_none());
+    }
+
+    #[test]
+    fn test_adaptive_timeouts_applied() {
+        const KEY: &[u8] = b"test:adaptive_timeouts";
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let url = mock.url();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(&url, 1)
+            .read_timeout(Duration::from_secs(2))
+            .adaptive_timeouts(AdaptiveTimeouts {
+                min: 50 * MS,
+                max: Duration::from_secs(1),
+                ..config()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(client.config().adaptive_timeouts.unwrap().min, 50 * MS);
+
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        client.nodes[0].borrow_mut().timeouts.as_mut().unwrap().socket = Box::new(Recording(recorded.clone()));
+
+        client.set(KEY, b"value", 0, 0).unwrap();
+        for _ in 0..6 {
+            client.get(KEY).unwrap();
+        }
+        // Local round-trips are far below the minimum
+        assert_eq!(*recorded.borrow(), [vec![Some(Duration::from_secs(2)); 6], vec![Some(50 * MS)]].concat());
+        assert_eq!(client.latency_estimate(&url, "get").unwrap().samples(), 6);
+        assert_eq!(client.latency_estimate(&url, "set").unwrap().samples(), 1);
+        assert!(client.latency_estimate(&url, "delete").is_none());
+
+        // A slow stretch of sets
+        {
+            let mut server = client.nodes[0].borrow_mut();
+            let timeouts = server.timeouts.as_mut().unwrap();
+            for _ in 0..5 {
+                timeouts.after("set", 120 * MS);
+            }
+        }
+        let expected = client.latency_estimate(&url, "set").unwrap().p99() * 3;
+        client.set(KEY, b"value", 0, 0).unwrap();
+        assert_eq!(recorded.borrow().last(), Some(&Some(expected)));
+        assert!(expected > 300 * MS && expected < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Deleting keys in two steps: a tombstone that keeps writers out, then the purge
+
+use crate::flags::{is_tombstone, reserved};
+use crate::proto::{binary::Status, CasOperation, MemCachedResult};
+
+/// Maximum number of read-then-write rounds the tombstone aware writes try on a contended key
+pub const TOMBSTONE_MAX_ATTEMPTS: usize = 8;
+
+/// What `Client::get_checked` found under a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checked {
+    /// A live value and its flags
+    Value(Vec<u8>, u32),
+    /// Nothing, or a tombstone that expired
+    Missing,
+    /// The key was deleted with `Client::tombstone` and is not purged yet
+    Tombstoned,
+}
+
+/// The write lost against another client and the key should be read again
+fn is_conflict(status: Option<Status>) -> bool {
+    status == Some(Status::KeyExists) || status == Some(Status::KeyNotFound)
+}
+
+pub(crate) fn tombstone<C: CasOperation + ?Sized>(client: &mut C, key: &[u8], ttl: u32) -> MemCachedResult<()> {
+    client.set_cas(key, b"", reserved::TOMBSTONE, ttl, 0).map(|_| ())
+}
+
+pub(crate) fn get_checked<C: CasOperation + ?Sized>(client: &mut C, key: &[u8]) -> MemCachedResult<Checked> {
+    Ok(match client.get_cas_opt(key)? {
+        None => Checked::Missing,
+        Some((_, flags, _)) if is_tombstone(flags) => Checked::Tombstoned,
+        Some((value, flags, _)) => Checked::Value(value, flags),
+    })
+}
+
+pub(crate) fn set_checked<C: CasOperation + ?Sized>(
+    client: &mut C,
+    key: &[u8],
+    value: &[u8],
+    flags: u32,
+    expiration: u32,
+    force: bool,
+) -> MemCachedResult<bool> {
+    if force {
+        return client.set_cas(key, value, flags, expiration, 0).map(|_| true);
+    }
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        // A plain set could land right after someone else's tombstone, so every write is conditional
+        let written = match client.get_cas_opt(key)? {
+            Some((_, current, _)) if is_tombstone(current) => return Ok(false),
+            Some((_, _, cas)) => client.set_cas(key, value, flags, expiration, cas),
+            None => client.add_cas(key, value, flags, expiration),
+        };
+        match written {
+            Err(ref err) if is_conflict(err.status()) && attempts < TOMBSTONE_MAX_ATTEMPTS => continue,
+            result => return result.map(|_| true),
+        }
+    }
+}
+
+pub(crate) fn add_checked<C: CasOperation + ?Sized>(
+    client: &mut C,
+    key: &[u8],
+    value: &[u8],
+    flags: u32,
+    expiration: u32,
+    force: bool,
+) -> MemCachedResult<bool> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        let exists = match client.add_cas(key, value, flags, expiration) {
+            Err(err) if err.status() == Some(Status::KeyExists) => err,
+            result => return result.map(|_| true),
+        };
+        let written = match client.get_cas_opt(key)? {
+            Some((_, current, cas)) if is_tombstone(current) => {
+                if !force {
+                    return Ok(false);
+                }
+                client.set_cas(key, value, flags, expiration, cas)
+            }
+            Some(_) => return Err(exists),
+            // Gone again before it could be read, add once more
+            None => continue,
+        };
+        match written {
+            Err(ref err) if is_conflict(err.status()) && attempts < TOMBSTONE_MAX_ATTEMPTS => continue,
+            result => return result.map(|_| true),
+        }
+    }
+}
+
+pub(crate) fn purge<C: CasOperation + ?Sized>(client: &mut C, key: &[u8]) -> MemCachedResult<bool> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        let cas = match client.get_cas_opt(key)? {
+            Some((_, flags, cas)) if is_tombstone(flags) => cas,
+            _ => return Ok(false),
+        };
+        // Whatever replaced the tombstone in between is not ours to delete
+        match client.delete_cas(key, cas) {
+            Err(ref err) if is_conflict(err.status()) && attempts < TOMBSTONE_MAX_ATTEMPTS => continue,
+            result => return result.map(|_| true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Checked;
+    use crate::client::Client;
+    use crate::flags::reserved;
+    use crate::proto::{binary::Status, CasOperation, ProtoType};
+    use crate::test_support::MockServer;
+
+    fn mock_client() -> (MockServer, Client) {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let client = Client::connect(&[(&mock.url()[..], 1)], ProtoType::Binary).unwrap();
+        (mock, client)
+    }
+
+    #[test]
+    fn test_tombstone_blocks_writes() {
+        const KEY: &[u8] = b"test:tombstone_writes";
+        let (_mock, mut client) = mock_client();
+
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Missing);
+        assert!(client.set_checked(KEY, b"value", 3, 0, false).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Value(b"value".to_vec(), 3));
+        assert!(client.set_checked(KEY, b"newer", 3, 0, false).unwrap());
+        let err = client.add_checked(KEY, b"added", 3, 0, false).unwrap_err();
+        assert_eq!(err.status(), Some(Status::KeyExists));
+
+        client.tombstone(KEY, 60).unwrap();
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Tombstoned);
+        assert!(!client.set_checked(KEY, b"stale", 3, 0, false).unwrap());
+        assert!(!client.add_checked(KEY, b"stale", 3, 0, false).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Tombstoned);
+
+        assert!(client.add_checked(KEY, b"forced", 4, 0, true).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Value(b"forced".to_vec(), 4));
+
+        client.tombstone(KEY, 60).unwrap();
+        assert!(client.set_checked(KEY, b"forced", 5, 0, true).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Value(b"forced".to_vec(), 5));
+    }
+
+    #[test]
+    fn test_purge() {
+        const KEY: &[u8] = b"test:tombstone_purge";
+        let (_mock, mut client) = mock_client();
+
+        client.set(KEY, b"value", 0, 0).unwrap();
+        assert!(!client.purge(KEY).unwrap());
+        assert_eq!(client.get(KEY).unwrap().0, b"value".to_vec());
+
+        client.tombstone(KEY, 60).unwrap();
+        assert!(client.purge(KEY).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Missing);
+        assert!(client.get_cas_opt(KEY).unwrap().is_none());
+        assert!(!client.purge(KEY).unwrap());
+
+        assert!(client.add_checked(KEY, b"value", 0, 0, false).unwrap());
+    }
+
+    #[test]
+    fn test_tombstone_expires() {
+        const KEY: &[u8] = b"test:tombstone_expiry";
+        let (mock, mut client) = mock_client();
+
+        client.set(KEY, b"value", 0, 0).unwrap();
+        client.tombstone(KEY, 30).unwrap();
+        mock.advance_clock(Duration::from_secs(29));
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Tombstoned);
+        assert!(!client.set_checked(KEY, b"stale", 0, 0, false).unwrap());
+
+        mock.advance_clock(Duration::from_secs(2));
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Missing);
+        assert!(client.set_checked(KEY, b"fresh", 0, 0, false).unwrap());
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Value(b"fresh".to_vec(), 0));
+    }
+
+    #[test]
+    fn test_user_flags_are_not_tombstones() {
+        const KEY: &[u8] = b"test:tombstone_user_flags";
+        let (_mock, mut client) = mock_client();
+
+        client.set(KEY, b"", reserved::USER, 0).unwrap();
+        assert_eq!(client.get_checked(KEY).unwrap(), Checked::Value(Vec::new(), reserved::USER));
+        assert!(client.set_checked(KEY, b"value", 0, 0, false).unwrap());
+        assert!(!client.purge(KEY).unwrap());
+    }
+}
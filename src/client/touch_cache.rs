@@ -0,0 +1,160 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Skipping touches of keys touched moments ago
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+struct Touched {
+    at: Instant,
+    expiration: u32,
+    /// Position in `TouchCache::order`
+    tick: u64,
+}
+
+/// The last successful touch of the most recently touched keys, see `ClientBuilder::touch_cache`
+pub(crate) struct TouchCache {
+    granularity: Duration,
+    entries: usize,
+    touched: HashMap<Vec<u8>, Touched>,
+    /// Keys of `touched` from the least recently touched up
+    order: BTreeMap<u64, Vec<u8>>,
+    next_tick: u64,
+}
+
+impl TouchCache {
+    pub(crate) fn new(granularity: Duration, entries: usize) -> TouchCache {
+        TouchCache {
+            granularity,
+            entries: entries.max(1),
+            touched: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    /// Whether `key` was touched with `expiration` less than the granularity ago
+    pub(crate) fn is_fresh(&self, key: &[u8], expiration: u32) -> bool {
+        self.touched
+            .get(key)
+            .is_some_and(|touched| touched.at.elapsed() < self.granularity && touched.expiration == expiration)
+    }
+
+    /// Record a successful touch of `key`, evicting the least recently touched key when full
+    pub(crate) fn insert(&mut self, key: &[u8], expiration: u32) {
+        self.forget(key);
+        if self.touched.len() >= self.entries {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.touched.remove(&oldest);
+            }
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, key.to_vec());
+        self.touched.insert(
+            key.to_vec(),
+            Touched {
+                at: Instant::now(),
+                expiration,
+                tick,
+            },
+        );
+    }
+
+    pub(crate) fn forget(&mut self, key: &[u8]) {
+        if let Some(touched) = self.touched.remove(key) {
+            self.order.remove(&touched.tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::TouchCache;
+    use crate::client::Client;
+    use crate::proto::{MemCachedResult, MultiOperation, NoReplyOperation, ProtoType};
+    use crate::test_support::MockServer;
+
+    #[test]
+    fn test_touch_cache() {
+        let mut cache = TouchCache::new(Duration::from_secs(60), 2);
+        cache.insert(b"a", 10);
+        assert!(cache.is_fresh(b"a", 10));
+        assert!(!cache.is_fresh(b"a", 20));
+
+        // `a` is the least recently touched once touched again after `b`
+        cache.insert(b"b", 10);
+        cache.insert(b"a", 10);
+        cache.insert(b"c", 10);
+        assert!(cache.is_fresh(b"a", 10) && cache.is_fresh(b"c", 10));
+        assert!(!cache.is_fresh(b"b", 10));
+
+        cache.forget(b"a");
+        assert!(!cache.is_fresh(b"a", 10));
+
+        let mut short = TouchCache::new(Duration::from_millis(20), 8);
+        short.insert(b"a", 10);
+        thread::sleep(Duration::from_millis(40));
+        assert!(!short.is_fresh(b"a", 10));
+    }
+
+    #[test]
+    fn test_touch_dedup() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .touch_cache(Duration::from_secs(60), 128)
+            .build()
+            .unwrap();
+        client.set(b"test:touch_cache", b"value", 0, 300).unwrap();
+
+        client.touch(b"test:touch_cache", 300).unwrap();
+        client.touch(b"test:touch_cache", 300).unwrap();
+        assert!(client.exists(b"test:touch_cache").unwrap());
+        assert_eq!(mock.touch_count(), 1);
+
+        // Another expiration is not the same touch
+        client.touch(b"test:touch_cache", 600).unwrap();
+        assert_eq!(mock.touch_count(), 2);
+
+        // Nor is a touch after a delete, which fails again
+        client.delete(b"test:touch_cache").unwrap();
+        client.touch(b"test:touch_cache", 600).unwrap_err();
+        client.touch(b"test:touch_cache", 600).unwrap_err();
+        assert!(!client.exists(b"test:touch_cache").unwrap());
+        assert_eq!(mock.touch_count(), 4);
+
+        // Every other write forgets the touch too, whether it succeeds or not
+        let writes: [fn(&mut Client) -> MemCachedResult<()>; 6] = [
+            |client| client.set(b"test:touch_cache", b"value", 0, 300),
+            |client| client.add(b"test:touch_cache", b"value", 0, 300).map(|_| ()),
+            |client| client.increment(b"test:touch_cache", 1, 0, 300).map(|_| ()),
+            |client| client.set_cas(b"test:touch_cache", b"value", 0, 300, 1).map(|_| ()),
+            |client| client.set_noreply(b"test:touch_cache", b"value", 0, 300),
+            |client| {
+                let keys: Vec<&[u8]> = vec![b"test:touch_cache", b"test:touch_cache_other"];
+                client.delete_multi_collect(&keys).map(|_| ())
+            },
+        ];
+        client.set(b"test:touch_cache", b"value", 0, 300).unwrap();
+        for (i, write) in writes.iter().enumerate() {
+            client.touch(b"test:touch_cache", 300).unwrap();
+            let _ = write(&mut client);
+            client.send_pending().unwrap();
+            let touches = mock.touch_count();
+            let _ = client.touch(b"test:touch_cache", 300);
+            assert_eq!(mock.touch_count(), touches + 1, "write {}", i);
+            client.set(b"test:touch_cache", b"value", 0, 300).unwrap();
+        }
+    }
+}
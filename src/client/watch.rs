@@ -0,0 +1,213 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Streaming the logs of a server with the `watch` command of memcached 1.6
+//!
+//! `watch` is a text protocol command that turns the connection into a log stream, so it runs on
+//! a connection of its own instead of the one the client sends its operations on.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use log::debug;
+#[cfg(unix)]
+use unix_socket::UnixStream;
+
+use super::timeouts::ReadTimeout;
+use super::{Client, HANDSHAKE_TIMEOUT};
+use crate::proto::{self, MemCachedResult};
+
+trait Stream: Read + Write + ReadTimeout + Send {}
+
+impl<T: Read + Write + ReadTimeout + Send> Stream for T {}
+
+/// Log lines of a server, returned by `Client::watch`
+///
+/// Iterating blocks until the server logs something. It ends when the connection is closed or
+/// fails. Dropping it closes the connection, which stops the server from logging to it.
+pub struct Watch {
+    reader: BufReader<Box<dyn Stream>>,
+}
+
+impl Iterator for Watch {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                line.truncate(line.trim_end_matches(['\r', '\n']).len());
+                Some(line)
+            }
+            Err(err) => {
+                debug!("Watch stream failed: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Open a new connection to `addr`, in the form `Client::connect` takes
+fn open(addr: &str, connect_timeout: Option<Duration>) -> io::Result<Box<dyn Stream>> {
+    match addr.split_once("://") {
+        Some(("tcp", addr)) => {
+            let stream = match connect_timeout {
+                Some(timeout) => {
+                    let socket_addr: SocketAddr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, format!("{} does not resolve", addr))
+                    })?;
+                    TcpStream::connect_timeout(&socket_addr, timeout)?
+                }
+                None => TcpStream::connect(addr)?,
+            };
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(feature = "socks")]
+        Some(("socks5", addr)) => Ok(Box::new(super::socks::connect(addr, connect_timeout)?)),
+        #[cfg(unix)]
+        Some(("unix", path)) => Ok(Box::new(UnixStream::connect(Path::new(path))?)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot watch {}, unsupported address", addr))),
+    }
+}
+
+/// The `watch` command line for `flags`, each of which should be a single word
+fn watch_command(flags: &[&str]) -> MemCachedResult<String> {
+    if let Some(flag) = flags.iter().find(|flag| {
+        flag.is_empty()
+            || flag
+                .bytes()
+                .any(|byte| byte.is_ascii_whitespace() || byte.is_ascii_control())
+    }) {
+        return Err(proto::Error::OtherError {
+            desc: "Invalid watch flag",
+            detail: Some(format!("{:?} is not a single word", flag)),
+        });
+    }
+    let mut command = String::from("watch");
+    for flag in flags {
+        command.push(' ');
+        command.push_str(flag);
+    }
+    command.push_str("\r\n");
+    Ok(command)
+}
+
+impl Client {
+    /// Stream the logs of the server added as `addr`, with the `watch` command of memcached 1.6
+    ///
+    /// `flags` selects the logs, e.g. `["fetchers", "mutations", "evictions"]`, the server picks
+    /// its default with none. The logs come on a new connection, so the client keeps working while
+    /// they are read, e.g. from another thread. Fails if the server does not answer `OK`, as servers
+    /// older than 1.6 or with SASL enabled do.
+    pub fn watch(&mut self, addr: &str, flags: &[&str]) -> MemCachedResult<Watch> {
+        self.server_by_addr(addr)?;
+        let command = watch_command(flags)?;
+        let mut stream = open(addr, self.config.connect_timeout)?;
+        stream.set_read_timeout(self.config.read_timeout.or(Some(HANDSHAKE_TIMEOUT)))?;
+        stream.write_all(command.as_bytes())?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut answer = String::new();
+        reader.read_line(&mut answer)?;
+        if answer.trim_end() != "OK" {
+            return Err(proto::Error::OtherError {
+                desc: "Server refused to watch",
+                detail: Some(format!("{} answered {:?}", addr, answer.trim_end())),
+            });
+        }
+        reader.get_ref().set_read_timeout(None)?;
+        Ok(Watch { reader })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    use super::watch_command;
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+
+    /// A server that ignores the first connection, for the client's operations, and answers the
+    /// `watch` command of the second one with `answer` then `lines`
+    ///
+    /// Returns the address and the command it got, once the watch connection is closed.
+    fn stub_server(answer: &'static str, lines: &'static [&'static str]) -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (_operations, _) = listener.accept().unwrap();
+            let (mut watch, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(watch.try_clone().unwrap());
+            let mut command = String::new();
+            reader.read_line(&mut command).unwrap();
+            watch.write_all(answer.as_bytes()).unwrap();
+            for line in lines {
+                watch.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+            }
+            // Keep logging nothing until the client hangs up
+            let _ = reader.read_to_end(&mut Vec::new());
+            command
+        });
+        (addr, handle)
+    }
+
+    fn client(addr: SocketAddr) -> Client {
+        Client::builder(ProtoType::Binary)
+            .add_server(format!("tcp://{}", addr), 1)
+            .handshake(false)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_watch_command() {
+        assert_eq!(watch_command(&[]).unwrap(), "watch\r\n");
+        assert_eq!(watch_command(&["fetchers", "evictions"]).unwrap(), "watch fetchers evictions\r\n");
+        watch_command(&["fetchers\r\nflush_all"]).unwrap_err();
+        watch_command(&[""]).unwrap_err();
+    }
+
+    #[test]
+    fn test_watch() {
+        let lines = &[
+            "ts=1700000000.123456 gid=1 type=item_get key=foo status=found clsid=1 cfd=20 size=3",
+            "ts=1700000000.234567 gid=2 type=eviction key=bar fetch=no ttl=-1 la=12 clsid=1",
+        ];
+        let (addr, command) = stub_server("OK\r\n", lines);
+        let mut client = client(addr);
+        let url = format!("tcp://{}", addr);
+        let mut watch = client.watch(&url, &["fetchers", "evictions"]).unwrap();
+        assert_eq!(watch.next().as_deref(), Some(lines[0]));
+        assert_eq!(watch.next().as_deref(), Some(lines[1]));
+
+        // Dropping the stream hangs up, which is what ends the stub
+        drop(watch);
+        assert_eq!(command.join().unwrap(), "watch fetchers evictions\r\n");
+
+        assert!(client.watch("tcp://127.0.0.1:1", &[]).is_err());
+    }
+
+    #[test]
+    fn test_watch_refused() {
+        let (addr, command) = stub_server("ERROR\r\n", &[]);
+        let mut client = client(addr);
+        let err = client.watch(&format!("tcp://{}", addr), &["mutations"]).err().unwrap();
+        assert!(err.to_string().contains("ERROR"), "{}", err);
+        assert_eq!(command.join().unwrap(), "watch mutations\r\n");
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Layout of the item flags
+//!
+//! The crate only takes bits of the flags for the features that tag the values they store, and
+//! only bits a feature needs: `reserved::TOMBSTONE` and `reserved::ENCRYPTED`, the top two. The
+//! other 30 bits, `reserved::USER`, are left to applications. Flags with reserved bits set are
+//! only given a meaning by the feature that owns them when it is in use; a plain `get` returns
+//! them unchanged. Code that rewrites flags must keep reserved bits it does not know about, so
+//! that values written by a client with more features enabled survive a round-trip through one
+//! with fewer.
+
+/// Bits of the flags reserved by the crate
+pub mod reserved {
+    /// A deleted key, see `Client::tombstone`
+    pub const TOMBSTONE: u32 = 1 << 31;
+    /// Value is encrypted, see `ClientBuilder::value_cipher`
+    pub const ENCRYPTED: u32 = 1 << 30;
+
+    /// Every bit owned by the crate
+    pub const ALL: u32 = TOMBSTONE | ENCRYPTED;
+    /// Bits left to applications, the low 30
+    pub const USER: u32 = !ALL;
+
+    const _: () = assert!(TOMBSTONE & ENCRYPTED == 0, "reserved flags overlap");
+    const _: () = assert!(ALL == 0xc000_0000, "reserved flags must stay in the top 2 bits");
+}
+
+/// Application part of `flags`
+pub fn user_bits(flags: u32) -> u32 {
+    flags & reserved::USER
+}
+
+/// Replace the application part of `flags`, keeping every reserved bit
+///
+/// Panics if `user` has any reserved bit set.
+pub fn with_user_bits(flags: u32, user: u32) -> u32 {
+    assert!(user & reserved::ALL == 0, "flags {:#x} overlap the reserved bits", user);
+    (flags & reserved::ALL) | user
+}
+
+/// Whether any of `bits` is set in `flags`
+pub fn has(flags: u32, bits: u32) -> bool {
+    flags & bits != 0
+}
+
+/// Whether `flags` mark a tombstone rather than a value
+pub fn is_tombstone(flags: u32) -> bool {
+    has(flags, reserved::TOMBSTONE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has, is_tombstone, reserved, user_bits, with_user_bits};
+
+    #[test]
+    fn test_vectors() {
+        assert_eq!(reserved::TOMBSTONE, 0x8000_0000);
+        assert_eq!(reserved::ENCRYPTED, 0x4000_0000);
+        assert_eq!(reserved::USER, 0x3fff_ffff);
+
+        let flags = reserved::ENCRYPTED | 0x1234;
+        assert_eq!(flags, 0x4000_1234);
+        assert_eq!(user_bits(flags), 0x1234);
+        assert_eq!(with_user_bits(flags, 0x3bcd_ef01), 0x7bcd_ef01);
+        assert!(has(flags, reserved::ENCRYPTED));
+        assert!(!has(flags, reserved::TOMBSTONE));
+
+        assert!(is_tombstone(reserved::TOMBSTONE | 0x42));
+        assert!(!is_tombstone(reserved::ENCRYPTED | reserved::USER));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_user_bits_overlap() {
+        with_user_bits(0, reserved::ENCRYPTED);
+    }
+}
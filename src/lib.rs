@@ -17,4 +17,10 @@ extern crate test;
 pub use client::Client;
 
 pub mod client;
+pub mod flags;
 pub mod proto;
+mod random;
+pub mod ring;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod warmup;
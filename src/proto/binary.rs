@@ -7,20 +7,25 @@
 // notice may not be copied, modified, or distributed except
 // according to those terms.
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error;
 use std::fmt;
-use std::io::{BufRead, BufReader, Cursor, Write};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
 use std::str;
 use std::string::String;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Bytes;
-use log::debug;
-use semver::Version;
-
-use crate::proto::{self, AuthResponse, MemCachedResult};
-use proto::binarydef::{Command, DataType, RequestHeader, RequestPacket, RequestPacketRef, ResponsePacket};
+use log::{debug, warn};
+
+use crate::proto::{
+    self, Anomaly, AuthResponse, BatchResult, Item, MemCachedResult, OpKind, ReadBufferPool, ReadBufferStats,
+    ServerVersion, TouchMultiSummary,
+};
+use proto::binarydef::{
+    Command, DataType, RequestHeader, RequestPacket, RequestPacketRef, ResponseHeader, ResponsePacket,
+};
+use proto::opaque::OpaqueSource;
 use proto::{AuthOperation, CasOperation, MultiOperation, NoReplyOperation, Operation, ServerOperation};
 
 pub use proto::binarydef::Status;
@@ -33,7 +38,7 @@ pub struct Error {
 }
 
 impl Error {
-    fn from_status(status: Status, detail: Option<String>) -> Error {
+    pub(crate) fn from_status(status: Status, detail: Option<String>) -> Error {
         Error {
             status,
             desc: status.desc(),
@@ -64,8 +69,163 @@ impl fmt::Display for Error {
 
 impl error::Error for Error {}
 
+/// Size of a binary protocol request packet header
+pub const REQUEST_HEADER_LEN: usize = 24;
+
+/// Bytes `resync` discards looking for the response to its NOOP before giving up, room for a
+/// whole abandoned value of the default 1 MiB `item_size_max` and then some
+pub const RESYNC_MAX_DISCARD: usize = 2 * 1024 * 1024;
+
+/// Values shorter than this are copied out of the buffer they were read into, see
+/// `BinaryProto::set_value_copy_cutoff`
+pub const DEFAULT_VALUE_COPY_CUTOFF: usize = 1024;
+
+/// Number of bytes a request for `op` occupies on the wire
+///
+/// This is the 24 bytes header, the command specific extras, the key and, for operations that
+/// carry one, the value. `value_len` is ignored for operations without a value. Quiet and CAS
+/// variants of an operation have the same size.
+pub fn request_size(op: OpKind, key_len: usize, value_len: usize) -> usize {
+    let (extra_len, value_len) = match op {
+        OpKind::Set | OpKind::Add | OpKind::Replace => (8, value_len),
+        OpKind::Increment | OpKind::Decrement => (20, 0),
+        OpKind::Touch => (4, 0),
+        OpKind::Append | OpKind::Prepend => (0, value_len),
+        OpKind::Delete | OpKind::Get | OpKind::GetKey => (0, 0),
+    };
+    REQUEST_HEADER_LEN + extra_len + key_len + value_len
+}
+
+/// Longest version string the handshake accepts, anything longer is not a memcached server
+const HANDSHAKE_MAX_VERSION_LEN: u32 = 256;
+
+/// Check that `stream` is connected to a server speaking the binary protocol
+///
+/// Sends a Version request and validates the header of the response, then reads the version it
+/// carries. Version is one of the few commands a server requiring SASL answers before the client
+/// authenticates. Anything else, like the reply of an HTTP or Redis server, fails with
+/// `io::ErrorKind::InvalidData` showing the first bytes received.
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    let opaque = OpaqueSource::default().next();
+    let mut req = Vec::with_capacity(REQUEST_HEADER_LEN);
+    RequestPacket::new(Command::Version, DataType::RawBytes, 0, opaque, 0, Bytes::new(), Bytes::new(), Bytes::new())
+        .write_to(&mut req)?;
+    stream.write_all(&req)?;
+    stream.flush()?;
+
+    let mut buf = [0u8; 24];
+    let mut len = 0;
+    while len < buf.len() {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if len == 0 => {
+                return Err(io::Error::new(err.kind(), format!("no response to handshake: {}", err)))
+            }
+            Err(_) => break,
+        }
+    }
+
+    let body_len = match ResponseHeader::read_from(&mut &buf[..]) {
+        Ok(header)
+            if len == buf.len()
+                && header.command == Command::Version
+                && header.status == Status::NoError
+                && header.opaque == opaque
+                && header.body_len() <= HANDSHAKE_MAX_VERSION_LEN =>
+        {
+            header.body_len()
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "endpoint does not speak the memcached binary protocol, received \"{}\"",
+                    buf[..len].escape_ascii()
+                ),
+            ))
+        }
+    };
+    let mut version = vec![0u8; body_len as usize];
+    stream.read_exact(&mut version)
+}
+
+/// Stream counting the bytes written since anything was last read from it
+///
+/// The server answers requests in order, so once any response arrives everything written before
+/// the request it belongs to has been processed.
+struct Accounted<T> {
+    inner: T,
+    unsynced: usize,
+}
+
+impl<T: Read> Read for Accounted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.unsynced = 0;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: BufRead> BufRead for Accounted<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let buf = self.inner.fill_buf()?;
+        if !buf.is_empty() {
+            self.unsynced = 0;
+        }
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<T: Write> Write for Accounted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.unsynced += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub struct BinaryProto<T: BufRead + Write + Send> {
-    stream: T,
+    stream: Accounted<T>,
+    max_outstanding_bytes: Option<usize>,
+    noreply_errors: Vec<proto::Error>,
+    coalesce_noreply: bool,
+    missing_flags: MissingFlags,
+    missing_flags_count: u64,
+    strict: bool,
+    read_buffer_pool: Option<ReadBufferPool>,
+    max_pipeline_depth: Option<usize>,
+    value_copy_cutoff: usize,
+    opaques: OpaqueSource,
+}
+
+/// What to do with a get response whose extras are too short to hold the flags
+///
+/// Some proxies answer gets of keys they synthesize without any extras.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MissingFlags {
+    /// Fail with an `io::ErrorKind::UnexpectedEof` error, the value is lost
+    Strict,
+    /// Return the value with these flags
+    Lenient(u32),
+}
+
+impl Default for MissingFlags {
+    fn default() -> MissingFlags {
+        MissingFlags::Lenient(0)
+    }
 }
 
 // impl<T: BufRead + Write + Send> Proto for BinaryProto<T> {
@@ -76,11 +236,239 @@ pub struct BinaryProto<T: BufRead + Write + Send> {
 
 impl<T: BufRead + Write + Send> BinaryProto<T> {
     pub fn new(stream: T) -> BinaryProto<T> {
-        BinaryProto { stream }
+        BinaryProto {
+            stream: Accounted {
+                inner: stream,
+                unsynced: 0,
+            },
+            max_outstanding_bytes: None,
+            noreply_errors: Vec::new(),
+            coalesce_noreply: false,
+            missing_flags: MissingFlags::default(),
+            missing_flags_count: 0,
+            strict: false,
+            read_buffer_pool: None,
+            max_pipeline_depth: None,
+            value_copy_cutoff: DEFAULT_VALUE_COPY_CUTOFF,
+            opaques: OpaqueSource::default(),
+        }
+    }
+
+    /// Read response bodies into buffers recycled by `pool`, or allocate each of them for `None`,
+    /// the default
+    pub fn set_read_buffer_pool(&mut self, pool: Option<ReadBufferPool>) {
+        self.read_buffer_pool = pool;
+    }
+
+    /// Counters of the read buffer pool, if there is one
+    pub fn read_buffer_stats(&self) -> Option<ReadBufferStats> {
+        self.read_buffer_pool.as_ref().map(ReadBufferPool::stats)
+    }
+
+    /// Send at most `depth` requests of a multi operation before a Noop, and read them all back
+    /// before sending more, or everything at once for `None`, the default
+    ///
+    /// memcached handles a limited number of requests of a connection per event loop iteration
+    /// (its `-R` option), so a huge pipeline keeps the connection busy for many iterations and
+    /// delays the other clients of the same worker thread.
+    pub fn set_max_pipeline_depth(&mut self, depth: Option<usize>) {
+        assert!(depth != Some(0), "pipeline depth should be positive");
+        self.max_pipeline_depth = depth;
+    }
+
+    /// Copy values shorter than `cutoff` bytes into an allocation of their own before returning
+    /// them as `Bytes`, `DEFAULT_VALUE_COPY_CUTOFF` by default
+    ///
+    /// Values returned as `Bytes`, like `Item::value`, share the buffer their response was read
+    /// into, which with `set_read_buffer_pool` holds the responses of many other keys. Keeping a
+    /// short value would keep that whole buffer alive, so those are copied; longer ones are worth
+    /// sharing. 0 never copies.
+    pub fn set_value_copy_cutoff(&mut self, cutoff: usize) {
+        self.value_copy_cutoff = cutoff;
+    }
+
+    /// `value` as handed out to callers, see `set_value_copy_cutoff`
+    fn detach(&self, value: Bytes) -> Bytes {
+        if value.len() < self.value_copy_cutoff {
+            Bytes::copy_from_slice(&value)
+        } else {
+            value
+        }
+    }
+
+    fn read_response(&mut self) -> io::Result<ResponsePacket> {
+        match self.read_buffer_pool {
+            Some(ref mut pool) => ResponsePacket::read_from_pool(&mut self.stream, pool),
+            None => ResponsePacket::read_from(&mut self.stream),
+        }
+    }
+
+    /// Fail on protocol anomalies with `Error::ProtocolAnomaly` instead of coping with them, off by
+    /// default
+    ///
+    /// Every anomaly, listed by `Anomaly`, goes through `anomaly`. The error poisons the
+    /// connection of a `Client`. Meant for testing servers and proxies, production is better
+    /// served by the lenient default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Whether protocol anomalies are errors
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Report `anomaly`, an error in strict mode, logged and coped with otherwise
+    fn anomaly(&self, anomaly: Anomaly) -> MemCachedResult<()> {
+        if self.strict {
+            return Err(proto::Error::ProtocolAnomaly(anomaly));
+        }
+        debug!("Protocol anomaly: {}, coping ...", anomaly);
+        Ok(())
+    }
+
+    /// Report a response of a pipeline matching none of its requests
+    fn unexpected_opaque(&self, got: u32) -> MemCachedResult<()> {
+        self.anomaly(Anomaly::UnexpectedOpaque { expected: None, got })
+    }
+
+    /// Read responses up to the one to the request sent with `opaque`
+    fn read_response_for(&mut self, opaque: u32) -> MemCachedResult<ResponsePacket> {
+        loop {
+            let resp = self.read_response()?;
+            if resp.header.opaque == opaque {
+                return Ok(resp);
+            }
+            self.anomaly(Anomaly::UnexpectedOpaque {
+                expected: Some(opaque),
+                got: resp.header.opaque,
+            })?;
+        }
+    }
+
+    /// `proto::dedup_keys`, reporting the first repeated key as an anomaly
+    fn dedup_keys<'a, K, F>(&self, items: &[K], key_of: F) -> MemCachedResult<(Vec<K>, usize)>
+    where
+        K: Copy,
+        F: Fn(&K) -> &'a [u8],
+    {
+        let (unique, duplicates) = proto::dedup_keys(items, &key_of);
+        if duplicates > 0 {
+            let mut seen = HashSet::with_capacity(items.len());
+            if let Some(key) = items.iter().map(&key_of).find(|key| !seen.insert(*key)) {
+                self.anomaly(Anomaly::DuplicateKey { key: key.to_vec() })?;
+            }
+        }
+        Ok((unique, duplicates))
+    }
+
+    /// How to handle get responses without flags, `MissingFlags::Lenient(0)` by default
+    pub fn set_missing_flags(&mut self, missing_flags: MissingFlags) {
+        self.missing_flags = missing_flags;
+    }
+
+    /// Number of get responses that had no flags and were accepted leniently
+    pub fn missing_flags_count(&self) -> u64 {
+        self.missing_flags_count
+    }
+
+    /// Read the flags from the extras of a get response
+    fn read_flags(&mut self, extra: &[u8]) -> MemCachedResult<u32> {
+        if extra.len() < 4 {
+            self.anomaly(Anomaly::MissingFlags {
+                extras_len: extra.len(),
+            })?;
+        }
+        match self.missing_flags {
+            MissingFlags::Lenient(flags) if extra.len() < 4 => {
+                self.missing_flags_count += 1;
+                warn!("Get response with {} bytes of extras, using flags 0x{:x}", extra.len(), flags);
+                Ok(flags)
+            }
+            _ => Ok((&extra[..]).read_u32::<BigEndian>()?),
+        }
+    }
+
+    /// Leave noreply requests in the write buffer instead of flushing each of them
+    ///
+    /// Consecutive noreply requests then go out in as few writes as the buffer allows. They are sent
+    /// when the buffer fills up, or by the next operation that waits for a response, e.g.
+    /// `drain_errors`.
+    pub fn set_coalesce_noreply(&mut self, coalesce: bool) {
+        self.coalesce_noreply = coalesce;
+    }
+
+    fn flush_noreply(&mut self) -> io::Result<()> {
+        if self.coalesce_noreply {
+            return Ok(());
+        }
+        self.stream.flush()
+    }
+
+    /// Limit the bytes of noreply requests the server has not been seen to process
+    ///
+    /// Once the limit would be exceeded, noreply operations wait for the server to catch up first.
+    /// Any operation that reads a response resets the count. `None`, the default, never waits.
+    pub fn set_noreply_max_outstanding_bytes(&mut self, max: Option<usize>) {
+        self.max_outstanding_bytes = max;
+    }
+
+    /// Bytes written since the last response was read
+    pub fn outstanding_bytes(&self) -> usize {
+        self.stream.unsynced
+    }
+
+    fn has_room(&self, len: usize) -> bool {
+        match self.max_outstanding_bytes {
+            Some(max) => self.stream.unsynced == 0 || self.stream.unsynced + len <= max,
+            None => true,
+        }
+    }
+
+    fn wait_for_room(&mut self, len: usize) -> MemCachedResult<()> {
+        if self.has_room(len) {
+            return Ok(());
+        }
+        debug!("{} bytes outstanding, waiting for the server", self.stream.unsynced);
+        self.sync_noreply()
+    }
+
+    /// Delete `key` if its CAS is `cas`, or unconditionally for 0, returns the CAS in the response
+    fn send_delete(&mut self, key: &[u8], cas: u64) -> MemCachedResult<u64> {
+        let opaque = self.opaques.next();
+        debug!("Delete key: {:?} {:?}, cas: {}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"), cas);
+        let req_header =
+            RequestHeader::from_payload(Command::Delete, DataType::RawBytes, 0, opaque, cas, key, &[], &[]);
+        let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+        req_packet.write_to(&mut self.stream)?;
+        self.stream.flush()?;
+
+        let resp = self.read_response_for(opaque)?;
+
+        match resp.header.status {
+            Status::NoError => Ok(resp.header.cas),
+            _ => Err(From::from(Error::from_status(resp.header.status, None))),
+        }
+    }
+
+    /// Round-trip a Noop, keeping the errors of quiet requests answered before it
+    fn sync_noreply(&mut self) -> MemCachedResult<()> {
+        let opaque = self.send_noop()?;
+        loop {
+            let resp = self.read_response()?;
+            if resp.header.command == Command::Noop && resp.header.opaque == opaque {
+                return Ok(());
+            }
+            if resp.header.status != Status::NoError {
+                self.noreply_errors
+                    .push(From::from(Error::from_status(resp.header.status, None)));
+            }
+        }
     }
 
     fn send_noop(&mut self) -> MemCachedResult<u32> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Sending NOOP");
         let req_packet = RequestPacket::new(
             Command::Noop,
@@ -102,7 +490,7 @@ impl<T: BufRead + Write + Send> BinaryProto<T> {
 
 impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     fn set(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Set key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
             key,
@@ -125,11 +513,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -138,7 +522,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn add(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Add key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
             key,
@@ -161,11 +545,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -174,28 +554,11 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn delete(&mut self, key: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
-        debug!("Delete key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
-        let req_header = RequestHeader::from_payload(Command::Delete, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
-        let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
-
-        req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
-
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
-
-        match resp.header.status {
-            Status::NoError => Ok(()),
-            _ => Err(From::from(Error::from_status(resp.header.status, None))),
-        }
+        self.send_delete(key, 0).map(|_| ())
     }
 
     fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Replace key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
             key,
@@ -218,11 +581,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -231,7 +590,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Get key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
         let req_header = RequestHeader::from_payload(Command::Get, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
@@ -239,16 +598,11 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
-                let mut extrabufr = BufReader::new(&resp.extra[..]);
-                let flags = extrabufr.read_u32::<BigEndian>()?;
+                let flags = self.read_flags(&resp.extra)?;
 
                 Ok((resp.value.to_vec(), flags))
             }
@@ -257,7 +611,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("GetK key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
         let req_header = RequestHeader::from_payload(Command::GetKey, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
@@ -265,16 +619,11 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
-                let mut extrabufr = BufReader::new(&resp.extra[..]);
-                let flags = extrabufr.read_u32::<BigEndian>()?;
+                let flags = self.read_flags(&resp.extra)?;
 
                 Ok((resp.key.to_vec(), resp.value.to_vec(), flags))
             }
@@ -283,7 +632,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn increment(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Increment key: {:?} {:?}, amount: {}, initial: {}, expiration: {}",
             key,
@@ -307,11 +656,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
@@ -323,7 +668,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn decrement(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Decrement key: {:?} {:?}, amount: {}, initial: {}, expiration: {}",
             key,
@@ -347,11 +692,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
@@ -363,7 +704,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn append(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Append key: {:?} {:?}, value: {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"), value);
         let req_header =
             RequestHeader::from_payload(Command::Append, DataType::RawBytes, 0, opaque, 0, key, &[], value);
@@ -372,11 +713,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -385,7 +722,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn prepend(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Prepend key: {:?} {:?}, value: {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"), value);
         let req_header =
             RequestHeader::from_payload(Command::Prepend, DataType::RawBytes, 0, opaque, 0, key, &[], value);
@@ -394,11 +731,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -407,7 +740,7 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
     }
 
     fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Touch key: {:?} {:?}, expiration: {}",
             key,
@@ -427,22 +760,49 @@ impl<T: BufRead + Write + Send> Operation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
             _ => Err(From::from(Error::from_status(resp.header.status, None))),
         }
     }
+
+    fn exists(&mut self, key: &[u8]) -> MemCachedResult<bool> {
+        // GetQ only answers hits, so the Noop behind it tells a miss apart without an error response
+        let opaque = self.opaques.next();
+        debug!("Exists key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
+        let req_header =
+            RequestHeader::from_payload(Command::GetQuietly, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
+        let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+        req_packet.write_to(&mut self.stream)?;
+        let noop_opaque = self.send_noop()?;
+
+        let mut result = Ok(false);
+        loop {
+            let resp = self.read_response()?;
+            if resp.header.command == Command::Noop && resp.header.opaque == noop_opaque {
+                return result;
+            }
+            if resp.header.opaque != opaque {
+                self.anomaly(Anomaly::UnexpectedOpaque {
+                    expected: Some(opaque),
+                    got: resp.header.opaque,
+                })?;
+                continue;
+            }
+            result = match resp.header.status {
+                Status::NoError => Ok(true),
+                _ => Err(From::from(Error::from_status(resp.header.status, None))),
+            };
+        }
+    }
 }
 
 impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
     fn quit(&mut self) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Quit");
         let req_header = RequestHeader::from_payload(Command::Quit, DataType::RawBytes, 0, opaque, 0, &[], &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], &[], &[]);
@@ -450,11 +810,7 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -463,7 +819,7 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
     }
 
     fn flush(&mut self, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Expiration flush: {}", expiration);
         let mut extra = [0u8; 4];
         {
@@ -478,12 +834,32 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
+        let resp = self.read_response_for(opaque)?;
+
+        match resp.header.status {
+            Status::NoError => Ok(()),
+            _ => Err(From::from(Error::from_status(resp.header.status, None))),
+        }
+    }
+
+    fn verbosity(&mut self, level: u32) -> MemCachedResult<()> {
+        let opaque = self.opaques.next();
+        debug!("Verbosity: {}", level);
+        let mut extra = [0u8; 4];
+        {
+            let mut extra_buf = Cursor::new(&mut extra[..]);
+            extra_buf.write_u32::<BigEndian>(level)?;
         }
 
+        let req_header =
+            RequestHeader::from_payload(Command::Verbosity, DataType::RawBytes, 0, opaque, 0, &[], &extra, &[]);
+        let req_packet = RequestPacketRef::new(&req_header, &extra, &[], &[]);
+
+        req_packet.write_to(&mut self.stream)?;
+        self.stream.flush()?;
+
+        let resp = self.read_response_for(opaque)?;
+
         match resp.header.status {
             Status::NoError => Ok(()),
             _ => Err(From::from(Error::from_status(resp.header.status, None))),
@@ -493,11 +869,7 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
     fn noop(&mut self) -> MemCachedResult<()> {
         debug!("Noop");
         let opaque = self.send_noop()?;
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(()),
@@ -505,8 +877,8 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
         }
     }
 
-    fn version(&mut self) -> MemCachedResult<Version> {
-        let opaque = fastrand::u32(..);
+    fn version(&mut self) -> MemCachedResult<ServerVersion> {
+        let opaque = self.opaques.next();
         debug!("Version");
         let req_header = RequestHeader::new(Command::Version, DataType::RawBytes, 0, opaque, 0, 0, 0, 0);
         let req_packet = RequestPacketRef::new(&req_header, &[], &[], &[]);
@@ -514,11 +886,7 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
@@ -533,34 +901,34 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
                     }
                 };
 
-                Ok(match Version::parse(verstr) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        return Err(proto::Error::OtherError {
-                            desc: "Unrecognized version string",
-                            detail: Some(err.to_string()),
-                        })
-                    }
-                })
+                Ok(ServerVersion::parse(verstr))
             }
             _ => Err(From::from(Error::from_status(resp.header.status, None))),
         }
     }
 
     fn stat(&mut self) -> MemCachedResult<BTreeMap<String, String>> {
-        let opaque = fastrand::u32(..);
-        debug!("Stat");
-        let req_header = RequestHeader::new(Command::Stat, DataType::RawBytes, 0, opaque, 0, 0, 0, 0);
-        let req_packet = RequestPacketRef::new(&req_header, &[], &[], &[]);
+        self.stat_key("")
+    }
+
+    fn stat_key(&mut self, key: &str) -> MemCachedResult<BTreeMap<String, String>> {
+        let opaque = self.opaques.next();
+        debug!("Stat {:?}", key);
+        let req_header =
+            RequestHeader::from_payload(Command::Stat, DataType::RawBytes, 0, opaque, 0, key.as_bytes(), &[], &[]);
+        let req_packet = RequestPacketRef::new(&req_header, &[], key.as_bytes(), &[]);
 
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
         let mut result = BTreeMap::new();
         loop {
-            let resp = ResponsePacket::read_from(&mut self.stream)?;
+            let resp = self.read_response()?;
             if resp.header.opaque != opaque {
-                debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
+                self.anomaly(Anomaly::UnexpectedOpaque {
+                    expected: Some(opaque),
+                    got: resp.header.opaque,
+                })?;
                 continue;
             }
             match resp.header.status {
@@ -597,11 +965,92 @@ impl<T: BufRead + Write + Send> ServerOperation for BinaryProto<T> {
 
         Ok(result)
     }
+
+    fn resync(&mut self) -> MemCachedResult<()> {
+        debug!("Resync");
+        let opaque = self.send_noop()?;
+
+        // The stream may be positioned anywhere, even in the middle of a packet, so scan byte by byte
+        // for the header of our NOOP response instead of trying to parse packets.
+        let mut expected = Vec::with_capacity(24);
+        ResponseHeader::new(Command::Noop, DataType::RawBytes, Status::NoError, opaque, 0, 0, 0, 0)
+            .write_to(&mut expected)?;
+
+        let mut window = VecDeque::with_capacity(expected.len());
+        let mut discarded = 0usize;
+        loop {
+            let byte = self.stream.read_u8()?;
+            if window.len() == expected.len() {
+                window.pop_front();
+                discarded += 1;
+                if discarded > RESYNC_MAX_DISCARD {
+                    let msg = format!("no NOOP response within {} bytes", RESYNC_MAX_DISCARD);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+                }
+            }
+            window.push_back(byte);
+
+            // Only magic, opcode and opaque are checked, servers may fill in status or CAS differently
+            if window.len() == expected.len()
+                && window[0] == expected[0]
+                && window[1] == expected[1]
+                && window.range(12..16).eq(expected[12..16].iter())
+            {
+                debug!("Resynced after discarding {} bytes", discarded);
+                return Ok(());
+            }
+        }
+    }
 }
 
-impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
-    fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
-        for (key, (value, flags, expiration)) in kv.into_iter() {
+/// Reads the responses left before the Noop `opaque` if dropped before it was reached
+///
+/// Keeps the connection at a request boundary when a pipeline is abandoned halfway, e.g. because
+/// a callback panicked or the server refused a request.
+struct DrainToNoop<'a, T: BufRead + Write + Send> {
+    proto: &'a mut BinaryProto<T>,
+    opaque: u32,
+    reached: bool,
+}
+
+impl<T: BufRead + Write + Send> Drop for DrainToNoop<'_, T> {
+    fn drop(&mut self) {
+        while !self.reached {
+            match self.proto.read_response() {
+                Ok(resp) => self.reached = resp.header.command == Command::Noop && resp.header.opaque == self.opaque,
+                // The connection is broken anyway
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Check every item of a multi store before any of it is written, a packet the server cannot
+/// parse would fail the pipeline halfway
+fn validate_stores<'a>(items: impl Iterator<Item = (&'a [u8], &'a [u8])>) -> MemCachedResult<()> {
+    proto::validated(
+        items
+            .filter_map(|(key, value)| Some((key.to_vec(), proto::check_store(key, value.len())?)))
+            .collect(),
+    )
+}
+
+/// One pipeline of each multi operation, ended by a Noop, see `BinaryProto::set_max_pipeline_depth`
+impl<T: BufRead + Write + Send> BinaryProto<T> {
+    /// Run `batch` on pipelines of at most `max_pipeline_depth` of `items`, reading each one back
+    /// before sending the next
+    fn pipelined<I, R, F>(&mut self, items: &[I], mut batch: F) -> MemCachedResult<Vec<R>>
+    where
+        F: FnMut(&mut Self, &[I]) -> MemCachedResult<R>,
+    {
+        match self.max_pipeline_depth {
+            Some(depth) if items.len() > depth => items.chunks(depth).map(|chunk| batch(self, chunk)).collect(),
+            _ => Ok(vec![batch(self, items)?]),
+        }
+    }
+
+    fn set_multi_batch(&mut self, kv: &[(&[u8], (&[u8], u32, u32))]) -> MemCachedResult<()> {
+        for &(key, (value, flags, expiration)) in kv {
             let mut extra = [0u8; 8];
             {
                 let mut extra_buf = Cursor::new(&mut extra[..]);
@@ -618,7 +1067,7 @@ impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
         self.send_noop()?;
 
         loop {
-            let resp = ResponsePacket::read_from(&mut self.stream)?;
+            let resp = self.read_response()?;
 
             match resp.header.status {
                 Status::NoError => {}
@@ -631,18 +1080,18 @@ impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
         }
     }
 
-    fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
+    fn delete_multi_batch(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
         for key in keys.iter() {
             let req_header =
-                RequestHeader::from_payload(Command::DeleteQuietly, DataType::RawBytes, 0, 0, 0, *key, &[], &[]);
-            let req_packet = RequestPacketRef::new(&req_header, &[], *key, &[]);
+                RequestHeader::from_payload(Command::DeleteQuietly, DataType::RawBytes, 0, 0, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
 
             req_packet.write_to(&mut self.stream)?;
         }
         self.send_noop()?;
 
         loop {
-            let resp = ResponsePacket::read_from(&mut self.stream)?;
+            let resp = self.read_response()?;
 
             match resp.header.status {
                 Status::NoError | Status::KeyNotFound => {}
@@ -655,61 +1104,62 @@ impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
         }
     }
 
-    fn increment_multi<'a>(
-        &mut self,
-        kv: HashMap<&'a [u8], (u64, u64, u32)>,
-    ) -> MemCachedResult<HashMap<&'a [u8], u64>> {
-        let opaques: MemCachedResult<HashMap<_, _>> = kv
-            .into_iter()
-            .map(|(key, (amount, initial, expiration))| {
-                let opaque = fastrand::u32(..);
-                let mut extra = [0u8; 20];
-                {
-                    let mut extra_buf = Cursor::new(&mut extra[..]);
-                    extra_buf.write_u64::<BigEndian>(amount)?;
-                    extra_buf.write_u64::<BigEndian>(initial)?;
-                    extra_buf.write_u32::<BigEndian>(expiration)?;
-                }
-
-                let req_header =
-                    RequestHeader::from_payload(Command::Increment, DataType::RawBytes, 0, opaque, 0, key, &extra, &[]);
-                let req_packet = RequestPacketRef::new(&req_header, &extra, key, &[]);
-
-                req_packet.write_to(&mut self.stream)?;
-                Ok((opaque, key))
-            })
-            .collect();
+    fn increment_multi_batch(&mut self, kv: &[(&[u8], (u64, u64, u32))]) -> MemCachedResult<HashMap<Vec<u8>, u64>> {
+        let first_opaque = self.opaques.reserve(kv.len());
+        let mut pending = HashMap::with_capacity(kv.len());
+        for (i, &(key, (amount, initial, expiration))) in kv.iter().enumerate() {
+            let opaque = first_opaque.wrapping_add(i as u32);
+            let mut extra = [0u8; 20];
+            {
+                let mut extra_buf = Cursor::new(&mut extra[..]);
+                extra_buf.write_u64::<BigEndian>(amount)?;
+                extra_buf.write_u64::<BigEndian>(initial)?;
+                extra_buf.write_u32::<BigEndian>(expiration)?;
+            }
 
-        let opaques = opaques?;
+            let req_header =
+                RequestHeader::from_payload(Command::Increment, DataType::RawBytes, 0, opaque, 0, key, &extra, &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &extra, key, &[]);
 
-        self.send_noop()?;
-        self.stream.flush()?;
+            req_packet.write_to(&mut self.stream)?;
+            pending.insert(opaque, key.to_vec());
+        }
+        let noop_opaque = self.send_noop()?;
 
-        let mut results = HashMap::with_capacity(opaques.len());
+        // Increment is not quiet, every key is answered before the Noop
+        let mut results = HashMap::with_capacity(pending.len());
         loop {
-            let resp = ResponsePacket::read_from(&mut self.stream)?;
-            match resp.header.status {
-                Status::NoError => {}
-                _ => return Err(From::from(Error::from_status(resp.header.status, None))),
-            }
-
-            if resp.header.command == Command::Noop {
+            let resp = self.read_response()?;
+            if resp.header.command == Command::Noop && resp.header.opaque == noop_opaque {
                 return Ok(results);
             }
 
-            if let Some(key) = opaques.get(&resp.header.opaque) {
-                let mut bufr = BufReader::new(&resp.value[..]);
-                let val = bufr.read_u64::<BigEndian>()?;
-                results.insert(key, val);
+            let key = match pending.remove(&resp.header.opaque) {
+                Some(key) => key,
+                None => {
+                    self.unexpected_opaque(resp.header.opaque)?;
+                    continue;
+                }
+            };
+            match resp.header.status {
+                Status::NoError => {
+                    let val = (&resp.value[..]).read_u64::<BigEndian>()?;
+                    results.insert(key, val);
+                }
+                status => warn!(
+                    "Increment of {:?} failed: {}",
+                    str::from_utf8(&key).unwrap_or("<not-utf8-key>"),
+                    status.desc()
+                ),
             }
         }
     }
 
-    fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
+    fn get_multi_batch(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
         for key in keys.iter() {
             let req_header =
-                RequestHeader::from_payload(Command::GetKeyQuietly, DataType::RawBytes, 0, 0, 0, *key, &[], &[]);
-            let req_packet = RequestPacketRef::new(&req_header, &[], *key, &[]);
+                RequestHeader::from_payload(Command::GetKeyQuietly, DataType::RawBytes, 0, 0, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
 
             req_packet.write_to(&mut self.stream)?;
         }
@@ -717,7 +1167,7 @@ impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
 
         let mut result = HashMap::with_capacity(keys.len());
         loop {
-            let resp = ResponsePacket::read_from(&mut self.stream)?;
+            let resp = self.read_response()?;
             match resp.header.status {
                 Status::NoError => {}
                 _ => return Err(From::from(Error::from_status(resp.header.status, None))),
@@ -727,47 +1177,398 @@ impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
                 return Ok(result);
             }
 
-            let mut extrabufr = BufReader::new(&resp.extra[..]);
-            let flags = extrabufr.read_u32::<BigEndian>()?;
+            let flags = self.read_flags(&resp.extra)?;
 
             result.insert(resp.key.to_vec(), (resp.value.to_vec(), flags));
         }
     }
-}
 
-impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
-    fn set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
-        debug!(
-            "Set noreply key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
-            key,
-            str::from_utf8(key).unwrap_or("<not-utf8-key>"),
-            value,
-            flags,
-            expiration
-        );
-        let mut extra = [0u8; 8];
-        {
-            let mut extra_buf = Cursor::new(&mut extra[..]);
-            extra_buf.write_u32::<BigEndian>(flags)?;
-            extra_buf.write_u32::<BigEndian>(expiration)?;
+    fn get_multi_foreach_batch(
+        &mut self,
+        keys: &[&[u8]],
+        f: &mut dyn FnMut(&[u8], &[u8], u32),
+    ) -> MemCachedResult<usize> {
+        for key in keys.iter() {
+            let req_header =
+                RequestHeader::from_payload(Command::GetKeyQuietly, DataType::RawBytes, 0, 0, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+            req_packet.write_to(&mut self.stream)?;
         }
+        let opaque = self.send_noop()?;
 
-        let req_header =
-            RequestHeader::from_payload(Command::SetQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, value);
-        let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
+        let mut guard = DrainToNoop {
+            proto: self,
+            opaque,
+            reached: false,
+        };
+        let mut hits = 0;
+        loop {
+            let resp = guard.proto.read_response().inspect_err(|_| guard.reached = true)?;
+            if resp.header.command == Command::Noop && resp.header.opaque == opaque {
+                guard.reached = true;
+                return Ok(hits);
+            }
+            if resp.header.status != Status::NoError {
+                return Err(From::from(Error::from_status(resp.header.status, None)));
+            }
 
-        req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+            let flags = guard.proto.read_flags(&resp.extra)?;
+            f(&resp.key, &resp.value, flags);
+            hits += 1;
+        }
+    }
+
+    fn touch_multi_batch(&mut self, keys: &[(&[u8], u32)], dry_run: bool) -> MemCachedResult<TouchMultiSummary> {
+        // Touch has no quiet variant, so every key gets an answer. The dry run uses GetKQ, which only
+        // answers hits; keys still pending when the Noop arrives are misses in both modes.
+        let mut pending = HashMap::with_capacity(keys.len());
+        let first_opaque = self.opaques.reserve(keys.len());
+        for (i, &(key, expiration)) in keys.iter().enumerate() {
+            // Consecutive opaques never collide within a batch, unlike random ones
+            let opaque = first_opaque.wrapping_add(i as u32);
+            let mut extra = [0u8; 4];
+            let (command, extra) = if dry_run {
+                (Command::GetKeyQuietly, &extra[..0])
+            } else {
+                Cursor::new(&mut extra[..]).write_u32::<BigEndian>(expiration)?;
+                (Command::Touch, &extra[..])
+            };
 
-        Ok(())
-    }
+            let req_header = RequestHeader::from_payload(command, DataType::RawBytes, 0, opaque, 0, key, extra, &[]);
+            let req_packet = RequestPacketRef::new(&req_header, extra, key, &[]);
 
-    fn add_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
-        debug!(
-            "Add noreply key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
-            key,
+            req_packet.write_to(&mut self.stream)?;
+            pending.insert(opaque, key);
+        }
+        let noop_opaque = self.send_noop()?;
+
+        let mut summary = TouchMultiSummary::default();
+        loop {
+            let resp = self.read_response()?;
+
+            if resp.header.command == Command::Noop && resp.header.opaque == noop_opaque {
+                summary.missing.extend(pending.into_values().map(|key| key.to_vec()));
+                return Ok(summary);
+            }
+
+            let key = match pending.remove(&resp.header.opaque) {
+                Some(key) => key.to_vec(),
+                None => {
+                    self.unexpected_opaque(resp.header.opaque)?;
+                    continue;
+                }
+            };
+
+            match resp.header.status {
+                Status::NoError => summary.touched.push(key),
+                Status::KeyNotFound => summary.missing.push(key),
+                status => summary.errors.push((key, From::from(Error::from_status(status, None)))),
+            }
+        }
+    }
+
+    fn gets_multi_batch(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        for key in keys.iter() {
+            let req_header =
+                RequestHeader::from_payload(Command::GetKeyQuietly, DataType::RawBytes, 0, 0, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+            req_packet.write_to(&mut self.stream)?;
+        }
+        self.send_noop()?;
+
+        let mut result = HashMap::with_capacity(keys.len());
+        loop {
+            let resp = self.read_response()?;
+            match resp.header.status {
+                Status::NoError => {}
+                _ => return Err(From::from(Error::from_status(resp.header.status, None))),
+            }
+
+            if resp.header.command == Command::Noop {
+                return Ok(result);
+            }
+
+            let flags = self.read_flags(&resp.extra)?;
+
+            result.insert(resp.key.to_vec(), (resp.value.to_vec(), flags, resp.header.cas));
+        }
+    }
+
+    fn get_multi_items_batch(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, Item>> {
+        for key in keys.iter() {
+            let req_header =
+                RequestHeader::from_payload(Command::GetKeyQuietly, DataType::RawBytes, 0, 0, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+            req_packet.write_to(&mut self.stream)?;
+        }
+        self.send_noop()?;
+
+        let mut result = HashMap::with_capacity(keys.len());
+        loop {
+            let resp = self.read_response()?;
+            match resp.header.status {
+                Status::NoError => {}
+                _ => return Err(From::from(Error::from_status(resp.header.status, None))),
+            }
+
+            if resp.header.command == Command::Noop {
+                return Ok(result);
+            }
+
+            let flags = self.read_flags(&resp.extra)?;
+            let item = Item {
+                key: Some(Bytes::copy_from_slice(&resp.key)),
+                cas: Some(resp.header.cas),
+                ..Item::new(self.detach(resp.value), flags)
+            };
+            result.insert(resp.key.to_vec(), item);
+        }
+    }
+
+    fn set_multi_collect_batch(&mut self, kv: &[(&[u8], (&[u8], u32, u32))]) -> MemCachedResult<BatchResult> {
+        let first_opaque = self.opaques.reserve(kv.len());
+        for (i, &(key, (value, flags, expiration))) in kv.iter().enumerate() {
+            let mut extra = [0u8; 8];
+            {
+                let mut extra_buf = Cursor::new(&mut extra[..]);
+                extra_buf.write_u32::<BigEndian>(flags)?;
+                extra_buf.write_u32::<BigEndian>(expiration)?;
+            }
+
+            let opaque = first_opaque.wrapping_add(i as u32);
+            let req_header =
+                RequestHeader::from_payload(Command::SetQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, value);
+            let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
+
+            req_packet.write_to(&mut self.stream)?;
+        }
+        let keys: Vec<&[u8]> = kv.iter().map(|&(key, _)| key).collect();
+        self.collect_quiet(&keys, first_opaque, &[])
+    }
+
+    fn delete_multi_collect_batch(&mut self, keys: &[&[u8]]) -> MemCachedResult<BatchResult> {
+        let first_opaque = self.opaques.reserve(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            let opaque = first_opaque.wrapping_add(i as u32);
+            let req_header =
+                RequestHeader::from_payload(Command::DeleteQuietly, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
+            let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
+
+            req_packet.write_to(&mut self.stream)?;
+        }
+        self.collect_quiet(keys, first_opaque, &[Status::KeyNotFound])
+    }
+
+    /// Read the answers to quiet requests for `keys`, sent with consecutive opaques from
+    /// `first_opaque`, up to a Noop sent now
+    ///
+    /// Quiet requests are only answered when they fail, every other key succeeded. Statuses in
+    /// `ignored` count as success.
+    fn collect_quiet(&mut self, keys: &[&[u8]], first_opaque: u32, ignored: &[Status]) -> MemCachedResult<BatchResult> {
+        let noop_opaque = self.send_noop()?;
+
+        let mut failed: Vec<Option<Status>> = keys.iter().map(|_| None).collect();
+        loop {
+            let resp = self.read_response()?;
+            if resp.header.command == Command::Noop && resp.header.opaque == noop_opaque {
+                break;
+            }
+            if resp.header.status == Status::NoError || ignored.contains(&resp.header.status) {
+                continue;
+            }
+
+            let index = resp.header.opaque.wrapping_sub(first_opaque) as usize;
+            match failed.get_mut(index) {
+                Some(slot) => *slot = Some(resp.header.status),
+                None => self.unexpected_opaque(resp.header.opaque)?,
+            }
+        }
+
+        let failures: Vec<(Vec<u8>, proto::Error)> = keys
+            .iter()
+            .zip(failed)
+            .filter_map(|(key, status)| Some((key.to_vec(), From::from(Error::from_status(status?, None)))))
+            .collect();
+        Ok(BatchResult {
+            succeeded: keys.len() - failures.len(),
+            failures,
+        })
+    }
+
+    fn set_cas_multi_batch(
+        &mut self,
+        items: &[(&[u8], &[u8], u32, u32, u64)],
+    ) -> MemCachedResult<Vec<MemCachedResult<u64>>> {
+        // Non-quiet Sets, so that every item is answered with its new CAS token or its error
+        let first_opaque = self.opaques.reserve(items.len());
+        for (i, &(key, value, flags, expiration, cas)) in items.iter().enumerate() {
+            let mut extra = [0u8; 8];
+            {
+                let mut extra_buf = Cursor::new(&mut extra[..]);
+                extra_buf.write_u32::<BigEndian>(flags)?;
+                extra_buf.write_u32::<BigEndian>(expiration)?;
+            }
+
+            let opaque = first_opaque.wrapping_add(i as u32);
+            let req_header =
+                RequestHeader::from_payload(Command::Set, DataType::RawBytes, 0, opaque, cas, key, &extra, value);
+            let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
+
+            req_packet.write_to(&mut self.stream)?;
+        }
+        let noop_opaque = self.send_noop()?;
+
+        let mut results: Vec<Option<MemCachedResult<u64>>> = items.iter().map(|_| None).collect();
+        loop {
+            let resp = self.read_response()?;
+
+            if resp.header.command == Command::Noop && resp.header.opaque == noop_opaque {
+                break;
+            }
+
+            let index = resp.header.opaque.wrapping_sub(first_opaque) as usize;
+            match results.get_mut(index) {
+                Some(slot) => {
+                    *slot = Some(match resp.header.status {
+                        Status::NoError => Ok(resp.header.cas),
+                        status => Err(From::from(Error::from_status(status, None))),
+                    })
+                }
+                None => self.unexpected_opaque(resp.header.opaque)?,
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| {
+                result.ok_or_else(|| proto::Error::OtherError {
+                    desc: "Missing response",
+                    detail: Some("server did not answer a pipelined set".to_owned()),
+                })
+            })
+            .collect()
+    }
+}
+
+impl<T: BufRead + Write + Send> MultiOperation for BinaryProto<T> {
+    fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
+        let kv: Vec<_> = kv.into_iter().collect();
+        validate_stores(kv.iter().map(|&(key, (value, ..))| (key, value)))?;
+        self.pipelined(&kv, Self::set_multi_batch).map(|_| ())
+    }
+
+    fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        self.pipelined(&keys, Self::delete_multi_batch).map(|_| ())
+    }
+
+    fn increment_multi(&mut self, kv: HashMap<&[u8], (u64, u64, u32)>) -> MemCachedResult<HashMap<Vec<u8>, u64>> {
+        let kv: Vec<_> = kv.into_iter().collect();
+        let batches = self.pipelined(&kv, Self::increment_multi_batch)?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        let batches = self.pipelined(&keys, Self::get_multi_batch)?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    fn get_multi_foreach(&mut self, keys: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8], u32)) -> MemCachedResult<usize> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        let hits = self.pipelined(&keys, |proto, batch| proto.get_multi_foreach_batch(batch, f))?;
+        Ok(hits.into_iter().sum())
+    }
+
+    fn touch_multi(&mut self, keys: &[(&[u8], u32)], dry_run: bool) -> MemCachedResult<TouchMultiSummary> {
+        let (keys, duplicates) = self.dedup_keys(keys, |&(key, _)| key)?;
+        let mut summary = TouchMultiSummary {
+            duplicates,
+            ..Default::default()
+        };
+        for batch in self.pipelined(&keys, |proto, batch| proto.touch_multi_batch(batch, dry_run))? {
+            summary.merge(batch);
+        }
+        Ok(summary)
+    }
+
+    fn gets_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        let batches = self.pipelined(&keys, Self::gets_multi_batch)?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    /// Values share the buffers they were read into unless shorter than the cutoff of
+    /// `set_value_copy_cutoff`
+    fn get_multi_items(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, Item>> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        let batches = self.pipelined(&keys, Self::get_multi_items_batch)?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    fn set_cas_multi(&mut self, items: &[(&[u8], &[u8], u32, u32, u64)]) -> MemCachedResult<Vec<MemCachedResult<u64>>> {
+        validate_stores(items.iter().map(|&(key, value, ..)| (key, value)))?;
+        let batches = self.pipelined(items, Self::set_cas_multi_batch)?;
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    fn set_multi_collect(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<BatchResult> {
+        let kv: Vec<_> = kv.into_iter().collect();
+        validate_stores(kv.iter().map(|&(key, (value, ..))| (key, value)))?;
+        let mut result = BatchResult::default();
+        for batch in self.pipelined(&kv, Self::set_multi_collect_batch)? {
+            result.merge(batch);
+        }
+        Ok(result)
+    }
+
+    fn delete_multi_collect(&mut self, keys: &[&[u8]]) -> MemCachedResult<BatchResult> {
+        let (keys, _) = self.dedup_keys(keys, |key| *key)?;
+        let mut result = BatchResult::default();
+        for batch in self.pipelined(&keys, Self::delete_multi_collect_batch)? {
+            result.merge(batch);
+        }
+        Ok(result)
+    }
+}
+
+impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
+    fn set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        let opaque = self.opaques.next();
+        debug!(
+            "Set noreply key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
+            key,
+            str::from_utf8(key).unwrap_or("<not-utf8-key>"),
+            value,
+            flags,
+            expiration
+        );
+        let mut extra = [0u8; 8];
+        {
+            let mut extra_buf = Cursor::new(&mut extra[..]);
+            extra_buf.write_u32::<BigEndian>(flags)?;
+            extra_buf.write_u32::<BigEndian>(expiration)?;
+        }
+
+        let req_header =
+            RequestHeader::from_payload(Command::SetQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, value);
+        let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
+
+        self.wait_for_room(req_header.packet_len())?;
+        req_packet.write_to(&mut self.stream)?;
+        self.flush_noreply()?;
+
+        Ok(())
+    }
+
+    fn add_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        let opaque = self.opaques.next();
+        debug!(
+            "Add noreply key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
+            key,
             str::from_utf8(key).unwrap_or("<not-utf8-key>"),
             value,
             flags,
@@ -784,27 +1585,29 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::AddQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, value);
         let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn delete_noreply(&mut self, key: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Delete noreply key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
         let req_header =
             RequestHeader::from_payload(Command::DeleteQuietly, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn replace_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Replace noreply key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
             key,
@@ -824,14 +1627,15 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::ReplaceQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, value);
         let req_packet = RequestPacketRef::new(&req_header, &extra, key, value);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn increment_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Increment noreply key: {:?} {:?}, amount: {}, initial: {}, expiration: {}",
             key,
@@ -852,14 +1656,15 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::IncrementQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, &[]);
         let req_packet = RequestPacketRef::new(&req_header, &extra, key, &[]);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn decrement_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Decrement noreply key: {:?} {:?}, amount: {}, initial: {}, expiration: {}",
             key,
@@ -880,14 +1685,15 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::DecrementQuietly, DataType::RawBytes, 0, opaque, 0, key, &extra, &[]);
         let req_packet = RequestPacketRef::new(&req_header, &extra, key, &[]);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn append_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Append noreply key: {:?} {:?}, value: {:?}",
             key,
@@ -898,14 +1704,15 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::AppendQuietly, DataType::RawBytes, 0, opaque, 0, key, &[], value);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, value);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
 
         Ok(())
     }
 
     fn prepend_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Prepend noreply key: {:?} {:?}, value: {:?}",
             key,
@@ -916,16 +1723,37 @@ impl<T: BufRead + Write + Send> NoReplyOperation for BinaryProto<T> {
             RequestHeader::from_payload(Command::PrependQuietly, DataType::RawBytes, 0, opaque, 0, key, &[], value);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, value);
 
+        self.wait_for_room(req_header.packet_len())?;
         req_packet.write_to(&mut self.stream)?;
-        self.stream.flush()?;
+        self.flush_noreply()?;
+
+        Ok(())
+    }
+
+    fn try_set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        if !self.has_room(request_size(OpKind::Set, key.len(), value.len())) {
+            return Err(proto::Error::IoError(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many outstanding noreply bytes",
+            )));
+        }
+        self.set_noreply(key, value, flags, expiration)
+    }
 
+    fn send_pending(&mut self) -> MemCachedResult<()> {
+        self.stream.flush()?;
         Ok(())
     }
+
+    fn drain_errors(&mut self) -> MemCachedResult<Vec<proto::Error>> {
+        self.sync_noreply()?;
+        Ok(std::mem::take(&mut self.noreply_errors))
+    }
 }
 
 impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Set cas key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}, cas: {}",
             key,
@@ -949,11 +1777,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
@@ -962,7 +1786,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Add cas key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}",
             key,
@@ -985,11 +1809,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
@@ -998,7 +1818,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn replace_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Replace cas key: {:?} {:?}, value: {:?}, flags: 0x{:x}, expiration: {}, cas: {}",
             key,
@@ -1022,11 +1842,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
@@ -1035,7 +1851,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Get cas key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
         let req_header = RequestHeader::from_payload(Command::Get, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
@@ -1043,16 +1859,11 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
-                let mut extrabufr = BufReader::new(&resp.extra[..]);
-                let flags = extrabufr.read_u32::<BigEndian>()?;
+                let flags = self.read_flags(&resp.extra)?;
 
                 Ok((resp.value.to_vec(), flags, resp.header.cas))
             }
@@ -1061,7 +1872,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("GetK cas key: {:?} {:?}", key, str::from_utf8(key).unwrap_or("<not-utf8-key>"));
         let req_header = RequestHeader::from_payload(Command::GetKey, DataType::RawBytes, 0, opaque, 0, key, &[], &[]);
         let req_packet = RequestPacketRef::new(&req_header, &[], key, &[]);
@@ -1069,16 +1880,11 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
-                let mut extrabufr = BufReader::new(&resp.extra[..]);
-                let flags = extrabufr.read_u32::<BigEndian>()?;
+                let flags = self.read_flags(&resp.extra)?;
 
                 Ok((resp.key.to_vec(), resp.value.to_vec(), flags, resp.header.cas))
             }
@@ -1094,7 +1900,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         expiration: u32,
         cas: u64,
     ) -> MemCachedResult<(u64, u64)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Increment cas key: {:?} {:?}, amount: {}, initial: {}, expiration: {}, cas: {}",
             key,
@@ -1119,11 +1925,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
@@ -1142,7 +1944,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         expiration: u32,
         cas: u64,
     ) -> MemCachedResult<(u64, u64)> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Decrement cas key: {:?} {:?}, amount: {}, initial: {}, expiration: {}, cas: {}",
             key,
@@ -1167,11 +1969,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {
@@ -1183,7 +1981,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Append cas key: {:?} {:?}, value: {:?}, cas: {}",
             key,
@@ -1198,11 +1996,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
@@ -1211,7 +2005,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Prepend cas key: {:?} {:?}, value: {:?}, cas: {}",
             key,
@@ -1226,11 +2020,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
@@ -1239,7 +2029,7 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
     }
 
     fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!(
             "Touch cas key: {:?} {:?}, expiration: {:?}, cas: {}",
             key,
@@ -1260,33 +2050,70 @@ impl<T: BufRead + Write + Send> CasOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => Ok(resp.header.cas),
             _ => Err(From::from(Error::from_status(resp.header.status, None))),
         }
     }
+
+    fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+        let opaque = self.opaques.next();
+        debug!(
+            "Get and touch key: {:?} {:?}, expiration: {:?}",
+            key,
+            str::from_utf8(key).unwrap_or("<not-utf8-key>"),
+            expiration
+        );
+        let mut extra = [0u8; 4];
+        {
+            let mut extra_buf = Cursor::new(&mut extra[..]);
+            extra_buf.write_u32::<BigEndian>(expiration)?;
+        }
+
+        let req_header =
+            RequestHeader::from_payload(Command::GetAndTouch, DataType::RawBytes, 0, opaque, 0, key, &extra, &[]);
+        let req_packet = RequestPacketRef::new(&req_header, &extra, key, &[]);
+
+        req_packet.write_to(&mut self.stream)?;
+        self.stream.flush()?;
+
+        let resp = self.read_response_for(opaque)?;
+
+        match resp.header.status {
+            Status::NoError => {
+                let flags = self.read_flags(&resp.extra)?;
+                Ok(Item {
+                    cas: Some(resp.header.cas),
+                    ttl: Item::ttl_after(expiration),
+                    ..Item::new(self.detach(resp.value), flags)
+                })
+            }
+            _ => Err(From::from(Error::from_status(resp.header.status, None))),
+        }
+    }
+
+    fn delete_cas(&mut self, key: &[u8], cas: u64) -> MemCachedResult<()> {
+        self.send_delete(key, cas).map(|_| ())
+    }
+
+    fn delete_returning_cas(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+        self.send_delete(key, 0)
+            .map(|cas| if cas == 0 { None } else { Some(cas) })
+    }
 }
 
 impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
     fn list_mechanisms(&mut self) -> MemCachedResult<Vec<String>> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("List mechanisms");
         let req_header = RequestHeader::new(Command::SaslListMechanisms, DataType::RawBytes, 0, opaque, 0, 0, 0, 0);
         let req_packet = RequestPacketRef::new(&req_header, &[], &[], &[]);
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::NoError => {}
@@ -1303,7 +2130,7 @@ impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
     }
 
     fn auth_start(&mut self, mech: &str, init: &[u8]) -> MemCachedResult<AuthResponse> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Auth start, mechanism: {:?}, init: {:?}", mech, init);
         let req_header = RequestHeader::from_payload(
             Command::SaslAuthenticate,
@@ -1319,11 +2146,7 @@ impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::AuthenticationFurtherStepRequired => Ok(AuthResponse::Continue(resp.value.to_vec())),
@@ -1334,7 +2157,7 @@ impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
     }
 
     fn auth_continue(&mut self, mech: &str, data: &[u8]) -> MemCachedResult<AuthResponse> {
-        let opaque = fastrand::u32(..);
+        let opaque = self.opaques.next();
         debug!("Auth continue, mechanism: {:?}, data: {:?}", mech, data);
         let req_header = RequestHeader::from_payload(
             Command::SaslStep,
@@ -1350,11 +2173,7 @@ impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
         req_packet.write_to(&mut self.stream)?;
         self.stream.flush()?;
 
-        let mut resp = ResponsePacket::read_from(&mut self.stream)?;
-        while resp.header.opaque != opaque {
-            debug!("Expecting opaque: {} but got {}, trying again ...", opaque, resp.header.opaque);
-            resp = ResponsePacket::read_from(&mut self.stream)?;
-        }
+        let resp = self.read_response_for(opaque)?;
 
         match resp.header.status {
             Status::AuthenticationFurtherStepRequired => Ok(AuthResponse::Continue(resp.value.to_vec())),
@@ -1367,11 +2186,28 @@ impl<T: BufRead + Write + Send> AuthOperation for BinaryProto<T> {
 
 #[cfg(test)]
 mod test {
-    use crate::proto::{BinaryProto, CasOperation, MultiOperation, NoReplyOperation, Operation, ServerOperation};
-    use std::collections::{BTreeMap, HashMap};
+    use crate::proto::{
+        self, Anomaly, BinaryProto, CasOperation, Item, MemCachedResult, MultiOperation, NoReplyOperation, OpKind,
+        Operation, ReadBufferPool, ServerOperation, StoreOutcome,
+    };
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::io::{self, BufRead, Cursor, Read, Write};
+
+    use proptest::prelude::*;
     use std::net::TcpStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use bufstream::BufStream;
+    use bytes::Bytes;
+
+    use super::{
+        request_size, Command, DataType, MissingFlags, RequestPacket, ResponsePacket, Status, REQUEST_HEADER_LEN,
+        RESYNC_MAX_DISCARD,
+    };
+    use crate::test_support::{check_arithmetic, MockServer, RecordingStream, ReplayStream, Transcript};
 
     const SERVER_ADDR: &str = "127.0.0.1:11211";
 
@@ -1380,23 +2216,347 @@ mod test {
         BinaryProto::new(BufStream::new(stream))
     }
 
-    #[test]
-    fn test_set_get_delete() {
-        const KEY: &[u8] = b"test:set_get_delete";
-        const VAL: &[u8] = b"world";
+    /// Scenarios replayed from `testdata/transcripts`, regenerate them with
+    /// `cargo test record_transcripts -- --ignored`
+    const SCENARIOS: &[(&str, fn(&mut BinaryProto<RecordingStream<BufStream<TcpStream>>>))] = &[
+        ("set_get_delete", set_get_delete),
+        ("set_get_delete_multi", set_get_delete_multi),
+    ];
 
-        let mut client = get_client();
-        client.set(KEY, VAL, 0xdead_beef, 120).unwrap();
+    fn transcript_path(name: &str) -> String {
+        format!("{}/testdata/transcripts/{}.mcrt", env!("CARGO_MANIFEST_DIR"), name)
+    }
 
-        let get_resp = client.get(KEY);
-        assert_eq!(get_resp.unwrap(), (VAL.to_vec(), 0xdead_beef));
+    /// Run `scenario` against the recorded server side of `name`
+    fn replay(name: &str, scenario: fn(&mut BinaryProto<ReplayStream>)) {
+        let transcript = Transcript::load(transcript_path(name)).unwrap();
+        let mut client = BinaryProto::new(ReplayStream::new(transcript).normalize_opaques());
+        scenario(&mut client);
+        assert!(client.stream.inner.is_done(), "{} was not replayed to the end", name);
+    }
 
-        let getk_resp = client.getk(KEY);
-        assert_eq!(getk_resp.unwrap(), (KEY.to_vec(), VAL.to_vec(), 0xdead_beef));
+    #[test]
+    #[ignore]
+    fn record_transcripts() {
+        for &(name, scenario) in SCENARIOS {
+            let mock = MockServer::start("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(mock.addr()).unwrap();
+            let recording = RecordingStream::new(BufStream::new(stream));
+            let transcript = recording.transcript();
+            scenario(&mut BinaryProto::new(recording));
+            transcript.lock().unwrap().save(transcript_path(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_request_size() {
+        assert_eq!(request_size(OpKind::Set, 5, 10), 24 + 8 + 5 + 10);
+        assert_eq!(request_size(OpKind::Get, 5, 10), 24 + 5);
+
+        // Compare against what actually gets serialized
+        let cases: &[(OpKind, Command, usize)] = &[
+            (OpKind::Set, Command::Set, 8),
+            (OpKind::Add, Command::Add, 8),
+            (OpKind::Replace, Command::Replace, 8),
+            (OpKind::Delete, Command::Delete, 0),
+            (OpKind::Get, Command::Get, 0),
+            (OpKind::GetKey, Command::GetKey, 0),
+            (OpKind::Increment, Command::Increment, 20),
+            (OpKind::Decrement, Command::Decrement, 20),
+            (OpKind::Append, Command::Append, 0),
+            (OpKind::Prepend, Command::Prepend, 0),
+            (OpKind::Touch, Command::Touch, 4),
+        ];
+        for &(op, command, extra_len) in cases {
+            let value: &[u8] = match op {
+                OpKind::Set | OpKind::Add | OpKind::Replace | OpKind::Append | OpKind::Prepend => b"0123456789",
+                _ => b"",
+            };
+            let mut buf = Vec::new();
+            RequestPacket::new(
+                command,
+                DataType::RawBytes,
+                0,
+                0,
+                0,
+                vec![0; extra_len].into(),
+                b"hello".as_ref().into(),
+                value.into(),
+            )
+            .write_to(&mut buf)
+            .unwrap();
+            assert_eq!(request_size(op, 5, 10), buf.len(), "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn test_append_prepend_or_create() {
+        let mut client = get_client();
+
+        const KEY: &[u8] = b"test:append_or_create";
+        let _ = client.delete(KEY);
+
+        client.append(KEY, b"lost").unwrap_err();
+        client.append_or_create(KEY, b"abc", 0xcafe, 120).unwrap();
+        assert_eq!(client.get(KEY).unwrap(), (b"abc".to_vec(), 0xcafe));
+        client.append_or_create(KEY, b"def", 0, 120).unwrap();
+        assert_eq!(client.get(KEY).unwrap(), (b"abcdef".to_vec(), 0xcafe));
+        client.delete(KEY).unwrap();
 
+        client.prepend_or_create(KEY, b"def", 0xbeef, 120).unwrap();
+        client.prepend_or_create(KEY, b"abc", 0, 120).unwrap();
+        assert_eq!(client.get(KEY).unwrap(), (b"abcdef".to_vec(), 0xbeef));
         client.delete(KEY).unwrap();
     }
 
+    #[test]
+    fn test_opt_variants() {
+        const KEY: &[u8] = b"test:opt_variants";
+
+        let mut client = get_client();
+        let _ = client.delete(KEY);
+
+        assert_eq!(client.get_opt(KEY).unwrap(), None);
+        assert_eq!(client.getk_opt(KEY).unwrap(), None);
+        assert_eq!(client.get_cas_opt(KEY).unwrap(), None);
+        assert_eq!(client.getk_cas_opt(KEY).unwrap(), None);
+        assert!(!client.delete_opt(KEY).unwrap());
+
+        client.set(KEY, b"value", 0xcafe, 120).unwrap();
+        assert_eq!(client.get_opt(KEY).unwrap(), Some((b"value".to_vec(), 0xcafe)));
+        assert_eq!(client.getk_opt(KEY).unwrap(), Some((KEY.to_vec(), b"value".to_vec(), 0xcafe)));
+        let (value, flags, cas) = client.get_cas_opt(KEY).unwrap().unwrap();
+        assert_eq!((&value[..], flags), (&b"value"[..], 0xcafe));
+        assert_eq!(client.getk_cas_opt(KEY).unwrap(), Some((KEY.to_vec(), value, flags, cas)));
+        assert!(client.delete_opt(KEY).unwrap());
+
+        assert_eq!(client.peek(KEY).unwrap(), None);
+        client.set(KEY, b"value", 0, 120).unwrap();
+        let before = client.peek(KEY).unwrap().unwrap();
+        assert_eq!(client.peek(KEY).unwrap(), Some(before));
+        client.append(KEY, b"!").unwrap();
+        let after = client.peek(KEY).unwrap().unwrap();
+        assert_ne!(after, before);
+        assert_eq!(client.get_cas(KEY).unwrap().2, after);
+
+        // Errors other than a miss are kept
+        client.set(KEY, b"not a number", 0, 120).unwrap();
+        assert!(proto::miss_as_none(client.increment(KEY, 1, 0, 120)).is_err());
+        client.delete(KEY).unwrap();
+    }
+
+    #[test]
+    fn test_get_if_changed() {
+        const KEY: &[u8] = b"test:get_if_changed";
+
+        let mut client = get_client();
+        let _ = client.delete(KEY);
+        assert!(client.get_if_changed(KEY, 0).is_err());
+
+        client.set(KEY, b"value", 0xcafe, 120).unwrap();
+        let (value, flags, cas) = client.get_if_changed(KEY, 0).unwrap().unwrap();
+        assert_eq!((&value[..], flags), (&b"value"[..], 0xcafe));
+        assert_eq!(client.get_if_changed(KEY, cas).unwrap(), None);
+
+        client.append(KEY, b"!").unwrap();
+        let (value, _, changed) = client.get_if_changed(KEY, cas).unwrap().unwrap();
+        assert_eq!((&value[..], changed == cas), (&b"value!"[..], false));
+        assert_eq!(client.get_if_changed(KEY, changed).unwrap(), None);
+        client.delete(KEY).unwrap();
+    }
+
+    #[test]
+    fn test_max_key_len() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+
+        let longest = vec![b'k'; proto::MAX_KEY_LEN];
+        client.set(&longest, b"value", 0xcafe, 0).unwrap();
+        assert_eq!(client.get(&longest).unwrap(), (b"value".to_vec(), 0xcafe));
+        assert_eq!(client.getk(&longest).unwrap().0, longest);
+
+        let too_long = vec![b'k'; proto::MAX_KEY_LEN + 1];
+        let err = client.set(&too_long, b"value", 0, 0).unwrap_err();
+        assert_eq!(err.status(), Some(Status::InvalidArguments));
+
+        // Refused before a byte is written, so the connection stays in sync
+        let unencodable = vec![b'k'; usize::from(u16::MAX) + 1];
+        match client.get(&unencodable) {
+            Err(proto::Error::IoError(ref err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(client.get(&longest).unwrap().0, b"value");
+    }
+
+    #[test]
+    fn test_store_detailed() {
+        const KEY: &[u8] = b"test:store_detailed";
+
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        assert_eq!(client.replace_detailed(KEY, b"v", 0, 0, 0).unwrap(), StoreOutcome::NotFound);
+
+        let cas = match client.add_detailed(KEY, b"value", 0xcafe, 0).unwrap() {
+            StoreOutcome::Stored { cas } => cas,
+            other => panic!("unexpected outcome {:?}", other),
+        };
+        assert_eq!(
+            client.add_detailed(KEY, b"other", 0, 0).unwrap(),
+            StoreOutcome::Conflict {
+                existing_flags: 0xcafe,
+                existing_size: 5,
+                existing_cas: cas,
+            }
+        );
+
+        let replaced = match client.replace_detailed(KEY, b"longer value", 7, 0, cas).unwrap() {
+            StoreOutcome::Stored { cas } => cas,
+            other => panic!("unexpected outcome {:?}", other),
+        };
+        assert_eq!(
+            client.replace_detailed(KEY, b"stale", 0, 0, cas).unwrap(),
+            StoreOutcome::Conflict {
+                existing_flags: 7,
+                existing_size: 12,
+                existing_cas: replaced,
+            }
+        );
+        assert_eq!(client.get(KEY).unwrap(), (b"longer value".to_vec(), 7));
+    }
+
+    #[test]
+    fn test_arithmetic_conformance() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        check_arithmetic(&mut client, "test:arithmetic_").unwrap();
+    }
+
+    #[test]
+    fn test_items() {
+        const KEY: &[u8] = b"test:items";
+
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        let cas = client.set_cas(KEY, b"value", 0xcafe, 0, 0).unwrap();
+
+        let item = client.get_item(KEY).unwrap();
+        assert_eq!(item, Item::from((b"value".to_vec(), 0xcafe, cas)));
+        assert_eq!((item.key, item.cas, item.ttl), (None, Some(cas), None));
+        let item = client.getk_item(KEY).unwrap();
+        assert_eq!(item.key, Some(Bytes::from_static(KEY)));
+        assert_eq!(<(Vec<u8>, u32)>::from(item), (b"value".to_vec(), 0xcafe));
+
+        // The new expiration applies, the old one would have outlived the clock
+        client.set(KEY, b"value", 0xcafe, 3600).unwrap();
+        let item = client.gat_item(KEY, 10).unwrap();
+        assert_eq!((&item.value[..], item.flags, item.key), (&b"value"[..], 0xcafe, None));
+        assert_eq!(item.ttl, Some(10));
+        mock.advance_clock(Duration::from_secs(60));
+        assert_eq!(client.get_item(KEY).unwrap_err().status(), Some(Status::KeyNotFound));
+        assert_eq!(client.gat_item(KEY, 10).unwrap_err().status(), Some(Status::KeyNotFound));
+
+        client.set(b"test:items_a", b"a", 1, 0).unwrap();
+        client.set(b"test:items_b", b"b", 2, 0).unwrap();
+        let items = client
+            .get_multi_items(&[b"test:items_a", b"test:items_b", b"test:items_missing"])
+            .unwrap();
+        assert_eq!(items.len(), 2);
+        for (key, item) in items {
+            assert_eq!(item.key.as_deref(), Some(&key[..]));
+            assert_eq!(client.get_cas(&key).unwrap(), (item.value.to_vec(), item.flags, item.cas.unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_try_lock() {
+        const KEY: &[u8] = b"test:try_lock";
+
+        let mut client = get_client();
+        let mut other = get_client();
+        let _ = client.delete(KEY);
+
+        assert!(client.try_lock(KEY, 60).unwrap());
+        assert!(!other.try_lock(KEY, 60).unwrap());
+        assert_eq!(client.get(KEY).unwrap(), (proto::LOCK_SENTINEL.to_vec(), 0));
+        client.unlock(KEY).unwrap();
+        assert!(other.try_lock(KEY, 1).unwrap());
+        assert!(!client.try_lock(KEY, 1).unwrap());
+
+        // Left locked, the TTL releases it
+        thread::sleep(Duration::from_millis(2100));
+        assert!(client.try_lock(KEY, 60).unwrap());
+        client.unlock(KEY).unwrap();
+        client.unlock(KEY).unwrap();
+    }
+
+    const DUP_KEYS: [&[u8]; 8] = [
+        b"test:dup0",
+        b"test:dup1",
+        b"test:dup2",
+        b"test:dup3",
+        b"test:dup4",
+        b"test:dup5",
+        b"test:dup6",
+        b"test:dup7",
+    ];
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_multi_duplicate_keys(picks in prop::collection::vec(0..DUP_KEYS.len(), 0..32)) {
+            let mut client = get_client();
+            // Even keys exist, odd keys do not
+            for (i, key) in DUP_KEYS.iter().enumerate() {
+                if i % 2 == 0 {
+                    client.set(key, key, 0, 120).unwrap();
+                } else {
+                    let _ = client.delete(key);
+                }
+            }
+
+            let keys: Vec<&[u8]> = picks.iter().map(|&i| DUP_KEYS[i]).collect();
+            let distinct: BTreeSet<&[u8]> = keys.iter().cloned().collect();
+            let existing: BTreeSet<&[u8]> = picks.iter().filter(|&&i| i % 2 == 0).map(|&i| DUP_KEYS[i]).collect();
+
+            let found = client.get_multi(&keys).unwrap();
+            prop_assert_eq!(found.keys().map(|key| &key[..]).collect::<BTreeSet<_>>(), existing.clone());
+
+            let req: Vec<(&[u8], u32)> = keys.iter().map(|&key| (key, 120)).collect();
+            let summary = client.touch_multi(&req, true).unwrap();
+            prop_assert_eq!(summary.duplicates, keys.len() - distinct.len());
+            prop_assert_eq!(summary.touched.len() + summary.missing.len(), distinct.len());
+            prop_assert_eq!(summary.touched.iter().map(|key| &key[..]).collect::<BTreeSet<_>>(), existing);
+            prop_assert!(summary.errors.is_empty());
+
+            client.delete_multi(&keys).unwrap();
+            for key in distinct {
+                prop_assert!(client.get(key).is_err());
+            }
+        }
+    }
+
+    fn set_get_delete<T: BufRead + Write + Send>(client: &mut BinaryProto<T>) {
+        const KEY: &[u8] = b"test:set_get_delete";
+        const VAL: &[u8] = b"world";
+
+        client.set(KEY, VAL, 0xdead_beef, 120).unwrap();
+
+        let get_resp = client.get(KEY);
+        assert_eq!(get_resp.unwrap(), (VAL.to_vec(), 0xdead_beef));
+
+        let getk_resp = client.getk(KEY);
+        assert_eq!(getk_resp.unwrap(), (KEY.to_vec(), VAL.to_vec(), 0xdead_beef));
+
+        client.delete(KEY).unwrap();
+        assert!(client.get_opt(KEY).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        replay("set_get_delete", set_get_delete);
+    }
+
     #[test]
     fn test_incr_decr() {
         const KEY: &[u8] = b"test:incr_decr";
@@ -1429,7 +2589,24 @@ mod test {
     #[test]
     fn test_version() {
         let mut client = get_client();
-        client.version().unwrap();
+        let version = client.version().unwrap();
+        assert!(version.semver.is_some(), "{}", version);
+    }
+
+    #[test]
+    fn test_version_build_suffix() {
+        let mut client = BinaryProto::new(BufStream::new(Answering {
+            written: Vec::new(),
+            responses: Cursor::new(Vec::new()),
+            noops: Arc::new(AtomicUsize::new(0)),
+        }));
+        let version = client.version().unwrap();
+        assert_eq!(version.raw, "1.6.21_1_ga4216c6");
+        assert_eq!(version.semver, None);
+
+        let version = proto::ServerVersion::parse("1.6.21");
+        assert_eq!(version.semver, Some(semver::Version::new(1, 6, 21)));
+        assert_eq!(version.to_string(), "1.6.21");
     }
 
     #[test]
@@ -1512,6 +2689,8 @@ mod test {
     fn test_stat() {
         let mut client = get_client();
         client.stat().unwrap();
+        assert!(client.stat_key("settings").unwrap().contains_key("maxconns"));
+        client.stat_key("no such group").unwrap_err();
     }
 
     #[test]
@@ -1524,14 +2703,41 @@ mod test {
     }
 
     #[test]
-    fn test_set_get_delete_incr_muti() {
+    fn test_touch_multi() {
+        const DRY: &[u8] = b"test:touch_multi_dry";
+        const REAL: &[u8] = b"test:touch_multi_real";
+        const MISSING: &[u8] = b"test:touch_multi_missing";
+
         let mut client = get_client();
+        let _ = client.delete(MISSING);
+        client.set(DRY, b"dry", 0, 2).unwrap();
+        client.set(REAL, b"real", 0, 2).unwrap();
+
+        let mut summary = client.touch_multi(&[(DRY, 100), (MISSING, 100)], true).unwrap();
+        summary.missing.sort();
+        assert_eq!(summary.touched, vec![DRY.to_vec()]);
+        assert_eq!(summary.missing, vec![MISSING.to_vec()]);
+        assert!(summary.errors.is_empty());
+
+        let summary = client.touch_multi(&[(REAL, 100), (MISSING, 50)], false).unwrap();
+        assert_eq!(summary.touched, vec![REAL.to_vec()]);
+        assert_eq!(summary.missing, vec![MISSING.to_vec()]);
+        assert!(summary.errors.is_empty());
 
+        // Only the real touch extended the TTL, the dry run left it at 2 seconds
+        thread::sleep(Duration::from_secs(3));
+        client.get(DRY).unwrap_err();
+        assert_eq!(client.get(REAL).unwrap().0, b"real".to_vec());
+        client.get(MISSING).unwrap_err();
+
+        client.delete(REAL).unwrap();
+    }
+
+    #[allow(clippy::unnecessary_to_owned)]
+    fn set_get_delete_multi<T: BufRead + Write + Send>(client: &mut BinaryProto<T>) {
         let mut data = BTreeMap::new();
         data.insert(&b"test:multi_hello1"[..], (&b"world1"[..], 0xdead_beef, 120));
         data.insert(&b"test:multi_hello2"[..], (&b"world2"[..], 0xdead_beef, 120));
-        data.insert(&b"test:multi_num1"[..], (&b"100"[..], 0xdead_beef, 120));
-        data.insert(&b"test:multi_num2"[..], (&b"200"[..], 0xdead_beef, 120));
         data.insert(&b"test:multi_lastone"[..], (&b"last!"[..], 0xdead_beef, 120));
 
         client.set_multi(data).unwrap();
@@ -1554,11 +2760,41 @@ mod test {
         assert_eq!(get_resp_map.get(&b"test:multi_hello2".to_vec()), None);
         assert_eq!(get_resp_map.get(&b"test:multi_lastone".to_vec()), Some(&(b"last!".to_vec(), 0xdead_beef)));
 
+        client
+            .delete_multi(&[b"test:multi_lastone", b"not_exists!!!!"])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_set_get_delete_multi() {
+        replay("set_get_delete_multi", set_get_delete_multi);
+    }
+
+    /// `increment_multi` sends in hash map order, which differs between runs, so it is not replayed
+    #[test]
+    #[allow(clippy::unnecessary_to_owned)]
+    fn test_increment_multi() {
+        let mut client = get_client();
+
+        let mut data = BTreeMap::new();
+        data.insert(&b"test:multi_num1"[..], (&b"100"[..], 0xdead_beef, 120));
+        data.insert(&b"test:multi_num2"[..], (&b"200"[..], 0xdead_beef, 120));
+        client.set_multi(data).unwrap();
+        client.delete_multi(&[b"test:multi_num3"]).unwrap();
+
         let mut data = HashMap::new();
         data.insert(&b"test:multi_num1"[..], (10, 50, 120));
         data.insert(&b"test:multi_num2"[..], (20, 50, 120));
         data.insert(&b"test:multi_num3"[..], (30, 50, 120));
-        client.increment_multi(data).unwrap();
+        let incremented = client.increment_multi(data).unwrap();
+        let expected: HashMap<Vec<u8>, u64> = vec![
+            (b"test:multi_num1".to_vec(), 110),
+            (b"test:multi_num2".to_vec(), 220),
+            (b"test:multi_num3".to_vec(), 50),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(incremented, expected);
 
         let get_resp_map = client
             .get_multi(&[b"test:multi_num1", b"test:multi_num2", b"test:multi_num3"])
@@ -1567,7 +2803,17 @@ mod test {
         assert_eq!(get_resp_map.get(&b"test:multi_num2".to_vec()), Some(&(b"220".to_vec(), 0xdead_beef)));
         assert_eq!(get_resp_map.get(&b"test:multi_num3".to_vec()), Some(&(b"50".to_vec(), 0x0)));
 
-        client.delete_multi(&[b"lastone", b"not_exists!!!!"]).unwrap();
+        // A key that cannot be incremented is left out, the others still are
+        client.set(b"test:multi_num2", b"not a number", 0, 120).unwrap();
+        let mut data = HashMap::new();
+        data.insert(&b"test:multi_num1"[..], (1, 0, 120));
+        data.insert(&b"test:multi_num2"[..], (1, 0, 120));
+        data.insert(&b"test:multi_num3"[..], (1, 0, 120));
+        let incremented = client.increment_multi(data).unwrap();
+        assert_eq!(incremented.len(), 2);
+        assert_eq!(incremented[&b"test:multi_num1"[..]], 111);
+        assert_eq!(incremented[&b"test:multi_num3"[..]], 51);
+        assert_eq!(client.get(b"test:multi_num2").unwrap().0, b"not a number".to_vec());
     }
 
     #[test]
@@ -1668,6 +2914,876 @@ mod test {
         client.delete(KEY).unwrap();
     }
 
+    #[test]
+    fn test_delete_cas() {
+        const KEY: &[u8] = b"test:delete_cas";
+        let mut client = get_client();
+
+        let set_cas = client.set_cas(KEY, b"value", 0, 120, 0).unwrap();
+        client.delete_cas(KEY, set_cas + 1).unwrap_err();
+        client.get(KEY).unwrap();
+
+        client.delete_cas(KEY, set_cas).unwrap();
+        client.get(KEY).unwrap_err();
+    }
+
+    #[test]
+    fn test_exists() {
+        const KEY: &[u8] = b"test:exists";
+        let mut client = get_client();
+
+        let _ = client.delete(KEY);
+        assert!(!client.exists(KEY).unwrap());
+
+        client.set(KEY, b"value", 0, 3).unwrap();
+        assert!(client.exists(KEY).unwrap());
+        // Unlike touch, exists leaves the expiration alone
+        thread::sleep(Duration::from_millis(1100));
+        assert!(client.exists(KEY).unwrap());
+        thread::sleep(Duration::from_millis(2100));
+        assert!(!client.exists(KEY).unwrap());
+    }
+
+    #[test]
+    fn test_response_cas() {
+        const KEY: &[u8] = b"test:response_cas";
+        let mut client = get_client();
+
+        let _ = client.delete(KEY);
+        let current = |client: &mut BinaryProto<_>| client.get_cas(KEY).unwrap().2;
+
+        let add_cas = client.add_cas(KEY, b"1", 0, 120).unwrap();
+        assert_eq!(add_cas, current(&mut client));
+        let touch_cas = client.touch_cas(KEY, 120, 0).unwrap();
+        assert_eq!(touch_cas, current(&mut client));
+        let (_, incr_cas) = client.increment_cas(KEY, 1, 0, 120, 0).unwrap();
+        assert_eq!(incr_cas, current(&mut client));
+
+        // memcached does not report the CAS of deleted items
+        assert_eq!(client.delete_returning_cas(KEY).unwrap(), None);
+        client.get(KEY).unwrap_err();
+        client.delete_returning_cas(KEY).unwrap_err();
+
+        let mut client = BinaryProto::new(BufStream::new(Flagless::default()));
+        assert_eq!(client.delete_returning_cas(KEY).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn test_append_bounded() {
+        const KEY: &[u8] = b"test:append_bounded";
+        let mut client = get_client();
+
+        let _ = client.delete(KEY);
+        client.set(KEY, b"12345", 0, 120).unwrap();
+
+        client.append_bounded(KEY, b"67890", 10).unwrap();
+        assert_eq!(client.get(KEY).unwrap().0, b"1234567890".to_vec());
+
+        match client.append_bounded(KEY, b"!", 10) {
+            Err(proto::Error::AppendLimitExceeded {
+                current_len: 10,
+                append_len: 1,
+                max_len: 10,
+            }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(client.get(KEY).unwrap().0, b"1234567890".to_vec());
+
+        client.delete(KEY).unwrap();
+        client.append_bounded(KEY, b"x", 10).unwrap_err();
+    }
+
+    /// Delegates to a real connection, but lets a second connection append to the key right after
+    /// the first `get_cas`, so the following `append_cas` sees a stale token.
+    struct RacingAppender {
+        inner: BinaryProto<BufStream<TcpStream>>,
+        other: BinaryProto<BufStream<TcpStream>>,
+        races_left: usize,
+    }
+
+    impl CasOperation for RacingAppender {
+        fn set_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32, cas: u64) -> MemCachedResult<u64> {
+            self.inner.set_cas(key, value, flags, expiration, cas)
+        }
+        fn add_cas(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<u64> {
+            self.inner.add_cas(key, value, flags, expiration)
+        }
+        fn replace_cas(
+            &mut self,
+            key: &[u8],
+            value: &[u8],
+            flags: u32,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<u64> {
+            self.inner.replace_cas(key, value, flags, expiration, cas)
+        }
+        fn get_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32, u64)> {
+            let resp = self.inner.get_cas(key)?;
+            if self.races_left > 0 {
+                self.races_left -= 1;
+                self.other.append(key, b"+")?;
+            }
+            Ok(resp)
+        }
+        fn getk_cas(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32, u64)> {
+            self.inner.getk_cas(key)
+        }
+        fn increment_cas(
+            &mut self,
+            key: &[u8],
+            amount: u64,
+            initial: u64,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<(u64, u64)> {
+            self.inner.increment_cas(key, amount, initial, expiration, cas)
+        }
+        fn decrement_cas(
+            &mut self,
+            key: &[u8],
+            amount: u64,
+            initial: u64,
+            expiration: u32,
+            cas: u64,
+        ) -> MemCachedResult<(u64, u64)> {
+            self.inner.decrement_cas(key, amount, initial, expiration, cas)
+        }
+        fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+            self.inner.append_cas(key, value, cas)
+        }
+        fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64> {
+            self.inner.prepend_cas(key, value, cas)
+        }
+        fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64> {
+            self.inner.touch_cas(key, expiration, cas)
+        }
+        fn delete_cas(&mut self, key: &[u8], cas: u64) -> MemCachedResult<()> {
+            self.inner.delete_cas(key, cas)
+        }
+        fn delete_returning_cas(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+            self.inner.delete_returning_cas(key)
+        }
+        fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+            self.inner.gat_item(key, expiration)
+        }
+    }
+
+    #[test]
+    fn test_update_deadline() {
+        const KEY: &[u8] = b"test:update_deadline";
+        let mut client = RacingAppender {
+            inner: get_client(),
+            other: get_client(),
+            races_left: 1,
+        };
+        client.inner.set(KEY, b"1", 0xcafe, 120).unwrap();
+
+        // One conflict, then the retry goes through on the fresh value
+        let deadline = Instant::now() + Duration::from_secs(5);
+        client.update(KEY, 120, deadline, |v| [v, b"2"].concat()).unwrap();
+        assert_eq!(client.inner.get(KEY).unwrap(), (b"1+2".to_vec(), 0xcafe));
+
+        // Someone always gets in between, so only the deadline ends the loop
+        client.races_left = usize::MAX;
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(200);
+        match client.update(KEY, 120, deadline, |v| [v, b"3"].concat()) {
+            Err(proto::Error::Timeout { attempts }) => assert!(attempts > 1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!client.inner.get(KEY).unwrap().0.ends_with(b"3"));
+
+        // The first attempt of append_bounded_until always runs, the retry is past the deadline
+        match client.append_bounded_until(KEY, b"4", usize::MAX, Instant::now()) {
+            Err(proto::Error::Timeout { attempts: 1 }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // A deadline in the past does not even try
+        client.races_left = 0;
+        match client.update(KEY, 120, Instant::now(), |v| v.to_vec()) {
+            Err(proto::Error::Timeout { attempts: 0 }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        client.inner.delete(KEY).unwrap();
+    }
+
+    #[test]
+    fn test_append_bounded_conflict_retry() {
+        const KEY: &[u8] = b"test:append_bounded_conflict";
+        let mut client = RacingAppender {
+            inner: get_client(),
+            other: get_client(),
+            races_left: 1,
+        };
+
+        client.inner.set(KEY, b"abc", 0, 120).unwrap();
+
+        // The racing append lands first, the retry re-measures and still fits
+        client.append_bounded(KEY, b"de", 6).unwrap();
+        assert_eq!(client.inner.get(KEY).unwrap().0, b"abc+de".to_vec());
+
+        // Now the racing append uses up the last byte, so the re-check must refuse
+        client.races_left = 1;
+        client.inner.set(KEY, b"abc", 0, 120).unwrap();
+        match client.append_bounded(KEY, b"de", 5) {
+            Err(proto::Error::AppendLimitExceeded { current_len: 4, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(client.inner.get(KEY).unwrap().0, b"abc+".to_vec());
+
+        client.inner.delete(KEY).unwrap();
+    }
+
+    /// Yields `stale` before anything read from the socket, like leftovers of an abandoned operation
+    struct StaleStream {
+        stale: Cursor<Vec<u8>>,
+        inner: TcpStream,
+    }
+
+    impl Read for StaleStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.stale.read(buf)? {
+                0 => self.inner.read(buf),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for StaleStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Ignores requests and answers them with canned bytes
+    struct Scripted {
+        responses: Cursor<Vec<u8>>,
+    }
+
+    impl Scripted {
+        fn new(responses: &[u8]) -> BufStream<Scripted> {
+            BufStream::new(Scripted {
+                responses: Cursor::new(responses.to_vec()),
+            })
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_text_server_mismatch() {
+        let mut client = BinaryProto::new(Scripted::new(b"ERROR\r\n"));
+        match client.get(b"test:text_server") {
+            Err(proto::Error::IoError(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert!(err.to_string().contains("text protocol"), "{}", err);
+            }
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    /// Answers requests in memory: gets hit, quiet sets of `FAILING_KEY` fail, other quiet requests succeed
+    struct Answering {
+        written: Vec<u8>,
+        responses: Cursor<Vec<u8>>,
+        noops: Arc<AtomicUsize>,
+    }
+
+    const FAILING_KEY: &[u8] = b"fail";
+    const ANSWERING_VERSION: &[u8] = b"1.6.21_1_ga4216c6";
+
+    impl Read for Answering {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.responses.position() as usize == self.responses.get_ref().len() {
+                let mut responses = Vec::new();
+                let mut requests = Cursor::new(std::mem::take(&mut self.written));
+                while (requests.position() as usize) < requests.get_ref().len() {
+                    let req = RequestPacket::read_from(&mut requests)?;
+                    let (status, extra, value) = match req.header.command {
+                        Command::Get => (Status::NoError, vec![0, 0, 0, 0], b"value".to_vec()),
+                        Command::Version => (Status::NoError, Vec::new(), ANSWERING_VERSION.to_vec()),
+                        Command::Noop => {
+                            self.noops.fetch_add(1, Ordering::SeqCst);
+                            (Status::NoError, Vec::new(), Vec::new())
+                        }
+                        Command::SetQuietly if &req.key[..] == FAILING_KEY => {
+                            (Status::KeyExists, Vec::new(), Vec::new())
+                        }
+                        _ => continue,
+                    };
+                    ResponsePacket::new(
+                        req.header.command,
+                        DataType::RawBytes,
+                        status,
+                        req.header.opaque,
+                        0,
+                        extra.into(),
+                        Bytes::new(),
+                        value.into(),
+                    )
+                    .write_to(&mut responses)?;
+                }
+                self.responses = Cursor::new(responses);
+            }
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Answering {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Answers every get variant with a hit that has no extras, like some proxies do, and deletes
+    /// with the CAS of the deleted item
+    #[derive(Default)]
+    struct Flagless {
+        written: Vec<u8>,
+        responses: Cursor<Vec<u8>>,
+    }
+
+    impl Read for Flagless {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.responses.position() as usize == self.responses.get_ref().len() {
+                let mut responses = Vec::new();
+                let mut requests = Cursor::new(std::mem::take(&mut self.written));
+                while (requests.position() as usize) < requests.get_ref().len() {
+                    let req = RequestPacket::read_from(&mut requests)?;
+                    let (key, value) = match req.header.command {
+                        Command::Get => (Bytes::new(), Bytes::from_static(b"synthesized")),
+                        Command::GetKey | Command::GetKeyQuietly => (req.key, Bytes::from_static(b"synthesized")),
+                        Command::Noop | Command::Delete => (Bytes::new(), Bytes::new()),
+                        _ => continue,
+                    };
+                    ResponsePacket::new(
+                        req.header.command,
+                        DataType::RawBytes,
+                        Status::NoError,
+                        req.header.opaque,
+                        7,
+                        Bytes::new(),
+                        key,
+                        value,
+                    )
+                    .write_to(&mut responses)?;
+                }
+                self.responses = Cursor::new(responses);
+            }
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Flagless {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_missing_flags() {
+        let value = b"synthesized".to_vec();
+        let mut client = BinaryProto::new(BufStream::new(Flagless::default()));
+        assert_eq!(client.get(b"a").unwrap(), (value.clone(), 0));
+        assert_eq!(client.getk(b"a").unwrap(), (b"a".to_vec(), value.clone(), 0));
+        assert_eq!(client.get_cas(b"a").unwrap(), (value.clone(), 0, 7));
+        assert_eq!(client.getk_cas(b"a").unwrap(), (b"a".to_vec(), value.clone(), 0, 7));
+        assert_eq!(client.get_multi(&[b"a", b"b"]).unwrap()[&b"b"[..]], (value.clone(), 0));
+        assert_eq!(client.missing_flags_count(), 6);
+
+        client.set_missing_flags(MissingFlags::Lenient(0xdead));
+        assert_eq!(client.get(b"a").unwrap(), (value, 0xdead));
+
+        client.set_missing_flags(MissingFlags::Strict);
+        let is_eof = |result: MemCachedResult<()>| match result {
+            Err(proto::Error::IoError(ref err)) => err.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        };
+        assert!(is_eof(client.get(b"a").map(|_| ())));
+        assert!(is_eof(client.getk(b"a").map(|_| ())));
+        assert!(is_eof(client.get_cas(b"a").map(|_| ())));
+        assert!(is_eof(client.getk_cas(b"a").map(|_| ())));
+        assert!(is_eof(client.get_multi(&[b"a", b"b"]).map(|_| ())));
+        assert_eq!(client.missing_flags_count(), 7);
+    }
+
+    /// Answers each request with the responses `script` makes for it
+    struct Anomalous {
+        written: Vec<u8>,
+        responses: Cursor<Vec<u8>>,
+        script: fn(&RequestPacket) -> Vec<ResponsePacket>,
+    }
+
+    impl Anomalous {
+        fn proto(script: fn(&RequestPacket) -> Vec<ResponsePacket>) -> BinaryProto<BufStream<Anomalous>> {
+            BinaryProto::new(BufStream::new(Anomalous {
+                written: Vec::new(),
+                responses: Cursor::new(Vec::new()),
+                script,
+            }))
+        }
+    }
+
+    impl Read for Anomalous {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.responses.position() as usize == self.responses.get_ref().len() {
+                let mut responses = Vec::new();
+                let mut requests = Cursor::new(std::mem::take(&mut self.written));
+                while (requests.position() as usize) < requests.get_ref().len() {
+                    let req = RequestPacket::read_from(&mut requests)?;
+                    for resp in (self.script)(&req) {
+                        resp.write_to(&mut responses)?;
+                    }
+                }
+                self.responses = Cursor::new(responses);
+            }
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Anomalous {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn scripted_response(
+        req: &RequestPacket,
+        status: Status,
+        opaque: u32,
+        extra: &[u8],
+        value: &[u8],
+    ) -> ResponsePacket {
+        ResponsePacket::new(
+            req.header.command,
+            DataType::RawBytes,
+            status,
+            opaque,
+            0,
+            Bytes::copy_from_slice(extra),
+            Bytes::new(),
+            Bytes::copy_from_slice(value),
+        )
+    }
+
+    /// Like a memcached with a hit for every key, quiet requests answered only on failure
+    fn well_behaved(req: &RequestPacket) -> Vec<ResponsePacket> {
+        let opaque = req.header.opaque;
+        match req.header.command {
+            Command::Get => vec![scripted_response(req, Status::NoError, opaque, &[0; 4], b"value")],
+            Command::DeleteQuietly => Vec::new(),
+            _ => vec![scripted_response(req, Status::NoError, opaque, &[], &[])],
+        }
+    }
+
+    /// Answers every request but Noop after a failure response to a request that was never sent
+    fn stray_opaques(req: &RequestPacket) -> Vec<ResponsePacket> {
+        let mut responses = Vec::new();
+        if req.header.command != Command::Noop {
+            let stray = req.header.opaque ^ 0x8000_0000;
+            responses.push(scripted_response(req, Status::KeyExists, stray, &[], &[]));
+        }
+        responses.extend(well_behaved(req));
+        responses
+    }
+
+    /// Answers gets without extras
+    fn flagless(req: &RequestPacket) -> Vec<ResponsePacket> {
+        match req.header.command {
+            Command::Get => vec![scripted_response(
+                req,
+                Status::NoError,
+                req.header.opaque,
+                &[],
+                b"value",
+            )],
+            _ => well_behaved(req),
+        }
+    }
+
+    #[test]
+    fn test_strict_anomalies() {
+        type Proto = BinaryProto<BufStream<Anomalous>>;
+        let stray_single = |anomaly: &Anomaly| matches!(anomaly, Anomaly::UnexpectedOpaque { expected: Some(_), .. });
+        let stray_pipelined = |anomaly: &Anomaly| matches!(anomaly, Anomaly::UnexpectedOpaque { expected: None, .. });
+        let cases: Vec<(
+            &str,
+            fn(&RequestPacket) -> Vec<ResponsePacket>,
+            fn(&mut Proto) -> MemCachedResult<()>,
+            &dyn Fn(&Anomaly) -> bool,
+        )> = vec![
+            ("stray opaque before get", stray_opaques, |proto| proto.get(b"a").map(|_| ()), &stray_single),
+            ("stray opaque in stats", stray_opaques, |proto| proto.stat().map(|_| ()), &stray_single),
+            (
+                "stray opaque in pipeline",
+                stray_opaques,
+                |proto| proto.delete_multi_collect(&[b"a", b"b"]).map(|_| ()),
+                &stray_pipelined,
+            ),
+            ("get without flags", flagless, |proto| proto.get(b"a").map(|_| ()), &|anomaly| {
+                *anomaly == Anomaly::MissingFlags { extras_len: 0 }
+            }),
+            ("duplicate key", well_behaved, |proto| proto.delete_multi(&[b"a", b"b", b"a", b"b"]), &|anomaly| {
+                *anomaly == Anomaly::DuplicateKey { key: b"a".to_vec() }
+            }),
+        ];
+
+        for (name, script, op, expected) in cases {
+            let mut lenient = Anomalous::proto(script);
+            assert!(!lenient.is_strict());
+            if let Err(err) = op(&mut lenient) {
+                panic!("{}: lenient mode failed with {}", name, err);
+            }
+
+            let mut strict = Anomalous::proto(script);
+            strict.set_strict(true);
+            let err = op(&mut strict).expect_err(name);
+            assert!(err.poisons_connection(), "{}: {}", name, err);
+            match err {
+                proto::Error::ProtocolAnomaly(ref anomaly) if expected(anomaly) => {}
+                err => panic!("{}: strict mode failed with {}", name, err),
+            }
+        }
+
+        // A well-behaved server is fine either way
+        let mut strict = Anomalous::proto(well_behaved);
+        strict.set_strict(true);
+        assert_eq!(strict.get(b"a").unwrap(), (b"value".to_vec(), 0));
+        strict.delete_multi_collect(&[b"a", b"b"]).unwrap();
+        strict.delete_multi(&[b"a", b"b"]).unwrap();
+    }
+
+    #[test]
+    fn test_max_pipeline_depth_noops() {
+        let keys: Vec<Vec<u8>> = (0..10_000).map(|i| format!("test:depth_{}", i).into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let noops = Arc::new(AtomicUsize::new(0));
+        let mut client = BinaryProto::new(BufStream::new(Answering {
+            written: Vec::new(),
+            responses: Cursor::new(Vec::new()),
+            noops: noops.clone(),
+        }));
+
+        assert!(client.get_multi(&keys).unwrap().is_empty());
+        assert_eq!(noops.swap(0, Ordering::SeqCst), 1);
+
+        client.set_max_pipeline_depth(Some(1000));
+        assert!(client.get_multi(&keys).unwrap().is_empty());
+        assert_eq!(noops.swap(0, Ordering::SeqCst), 10);
+        client.get_multi(&keys[..1001]).unwrap();
+        assert_eq!(noops.swap(0, Ordering::SeqCst), 2);
+        client.get_multi(&keys[..1000]).unwrap();
+        assert_eq!(noops.swap(0, Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_max_pipeline_depth_results() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut bounded = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        bounded.set_max_pipeline_depth(Some(1000));
+        let mut unbounded = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+
+        let keys: Vec<Vec<u8>> = (0..10_000).map(|i| format!("test:depth_{}", i).into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let stored: BTreeMap<&[u8], (&[u8], u32, u32)> =
+            keys.iter().step_by(3).map(|&key| (key, (key, 0xf1a9, 0))).collect();
+        bounded.set_multi(stored).unwrap();
+        assert_eq!(mock.item_count(), 3334);
+
+        let found = bounded.get_multi(&keys).unwrap();
+        assert_eq!(found.len(), 3334);
+        assert_eq!(found, unbounded.get_multi(&keys).unwrap());
+        assert_eq!(bounded.gets_multi(&keys).unwrap(), unbounded.gets_multi(&keys).unwrap());
+
+        let touches: Vec<(&[u8], u32)> = keys.iter().map(|&key| (key, 0)).collect();
+        let summary = bounded.touch_multi(&touches, true).unwrap();
+        assert_eq!((summary.touched.len(), summary.missing.len()), (3334, 6666));
+
+        bounded.delete_multi(&keys).unwrap();
+        assert_eq!(mock.item_count(), 0);
+    }
+
+    #[test]
+    fn test_get_multi_foreach() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        client.set_max_pipeline_depth(Some(4));
+
+        let keys: Vec<Vec<u8>> = (0..10).map(|i| format!("test:foreach_{}", i).into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let stored: BTreeMap<&[u8], (&[u8], u32, u32)> =
+            keys.iter().step_by(2).map(|&key| (key, (key, 7, 0))).collect();
+        client.set_multi(stored).unwrap();
+
+        let mut found = HashMap::new();
+        let hits = client
+            .get_multi_foreach(&keys, &mut |key, value, flags| {
+                found.insert(key.to_vec(), (value.to_vec(), flags));
+            })
+            .unwrap();
+        assert_eq!(hits, 5);
+        assert_eq!(found, client.get_multi(&keys).unwrap());
+
+        // A panicking callback leaves the rest of its pipeline read
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            client.get_multi_foreach(&keys, &mut |_, _, _| panic!("callback failed"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(client.get(keys[2]).unwrap(), (keys[2].to_vec(), 7));
+        assert_eq!(client.get_multi(&keys).unwrap(), found);
+    }
+
+    #[test]
+    fn test_multi_collect() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        let large = vec![b'x'; 2 * 1024 * 1024];
+        let keys: Vec<Vec<u8>> = (0..10).map(|i| format!("test:collect_{}", i).into_bytes()).collect();
+        let mut kv: BTreeMap<&[u8], (&[u8], u32, u32)> = keys.iter().map(|key| (&key[..], (&b"v"[..], 0, 0))).collect();
+        kv.insert(&keys[3], (&large, 0, 0));
+        kv.insert(&keys[7], (&large, 0, 0));
+
+        for depth in [None, Some(4)] {
+            client.set_max_pipeline_depth(depth);
+            let result = client.set_multi_collect(kv.clone()).unwrap();
+            assert_eq!(result.succeeded, 8);
+            assert!(!result.is_complete());
+            let failed: Vec<&[u8]> = result.failures.iter().map(|(key, _)| &key[..]).collect();
+            assert_eq!(failed, [&keys[3][..], &keys[7][..]]);
+            assert!(result
+                .failures
+                .iter()
+                .all(|(_, err)| err.status() == Some(Status::ValueTooLarge)));
+
+            // The connection is still in sync, and the rest of the batch was stored
+            assert_eq!(client.get(&keys[9]).unwrap().0, b"v");
+            let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+            let result = client.delete_multi_collect(&keys).unwrap();
+            assert_eq!((result.succeeded, result.failures.len()), (10, 0));
+            assert_eq!(mock.item_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_read_buffer_pool() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        assert_eq!(client.read_buffer_stats(), None);
+        client.set_read_buffer_pool(Some(ReadBufferPool::new(4096)));
+
+        let value = vec![b'x'; 500];
+        client.set(b"test:read_buffer_pool", &value, 7, 0).unwrap();
+        for _ in 0..100 {
+            assert_eq!(client.get(b"test:read_buffer_pool").unwrap(), (value.clone(), 7));
+        }
+        let large = vec![b'y'; 2000];
+        client.set(b"test:read_buffer_pool_large", &large, 0, 0).unwrap();
+        assert_eq!(client.get(b"test:read_buffer_pool_large").unwrap().0, large);
+
+        // Values are copied out of the response, so one buffer serves every other read
+        let stats = client.read_buffer_stats().unwrap();
+        assert_eq!((stats.allocated, stats.unpooled), (1, 1));
+        assert_eq!(stats.pooled + stats.reclaimed, 101);
+    }
+
+    #[test]
+    fn test_value_copy_cutoff() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let mut client = BinaryProto::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        client.set_read_buffer_pool(Some(ReadBufferPool::new(64 * 1024)));
+
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("test:value_copy_cutoff{}", i).into_bytes())
+            .collect();
+        for key in keys.iter() {
+            client.set(key, b"0123456789", 0, 0).unwrap();
+        }
+        let medium = vec![b'x'; 4096];
+        client.set(b"test:value_copy_cutoff_medium", &medium, 0, 0).unwrap();
+        let mut req: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        req.push(b"test:value_copy_cutoff_medium");
+
+        // Short values own a right-sized allocation, so dropping the rest frees the batch buffer
+        let mut items = client.get_multi_items(&req).unwrap();
+        let medium_item = items.remove(&b"test:value_copy_cutoff_medium"[..]).unwrap();
+        assert_eq!(medium_item.value, medium);
+        for (_, item) in items {
+            let owned = item.value.try_into_mut().expect("short value shares a buffer");
+            assert_eq!((owned.len(), owned.capacity()), (10, 10));
+        }
+        // Longer ones share it with the pool
+        assert!(medium_item.value.try_into_mut().is_err());
+        let gat = client.gat_item(&keys[0], 0).unwrap();
+        assert!(gat.value.try_into_mut().is_ok());
+
+        client.set_value_copy_cutoff(0);
+        let items = client.get_multi_items(&req[..2]).unwrap();
+        assert!(items.into_values().all(|item| item.value.try_into_mut().is_err()));
+    }
+
+    #[test]
+    fn test_multi_store_validation() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let recording = RecordingStream::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        let transcript = recording.transcript();
+        let mut client = BinaryProto::new(recording);
+
+        let too_long = vec![b'k'; proto::MAX_KEY_LEN + 1];
+        let mut kv: BTreeMap<&[u8], (&[u8], u32, u32)> = BTreeMap::new();
+        kv.insert(b"test:validation_a", (b"a", 0, 0));
+        kv.insert(&too_long, (b"long", 0, 0));
+        kv.insert(b"test:validation_b", (b"b", 0, 0));
+        let items: Vec<_> = kv.iter().map(|(&key, &(value, ..))| (key, value, 0, 0, 0)).collect();
+
+        let invalid = |err: proto::Error| match err {
+            proto::Error::ValidationFailed { invalid } => invalid,
+            err => panic!("unexpected error {}", err),
+        };
+        for invalid in [
+            invalid(client.set_multi(kv.clone()).unwrap_err()),
+            invalid(client.set_multi_collect(kv.clone()).unwrap_err()),
+            invalid(client.set_cas_multi(&items).unwrap_err()),
+        ] {
+            assert_eq!(invalid.len(), 1);
+            assert_eq!(invalid[0].0, too_long);
+            assert!(matches!(invalid[0].1, proto::Error::KeyTooLong { len } if len == too_long.len()));
+        }
+        // Nothing of any batch was written
+        assert!(transcript.lock().unwrap().chunks.is_empty());
+        assert_eq!(mock.item_count(), 0);
+
+        kv.remove(&too_long[..]);
+        client.set_multi(kv).unwrap();
+        assert_eq!(mock.item_count(), 2);
+    }
+
+    #[test]
+    fn test_noreply_max_outstanding_bytes() {
+        let noops = Arc::new(AtomicUsize::new(0));
+        let mut client = BinaryProto::new(BufStream::new(Answering {
+            written: Vec::new(),
+            responses: Cursor::new(Vec::new()),
+            noops: noops.clone(),
+        }));
+        let set_len = request_size(OpKind::Set, 1, 10);
+        client.set_noreply_max_outstanding_bytes(Some(2 * set_len + 1));
+
+        client.set_noreply(b"a", b"0123456789", 0, 0).unwrap();
+        client.try_set_noreply(b"b", b"0123456789", 0, 0).unwrap();
+        assert_eq!(client.outstanding_bytes(), 2 * set_len);
+
+        match client.try_set_noreply(b"c", b"0123456789", 0, 0) {
+            Err(proto::Error::IoError(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+            other => panic!("expected WouldBlock, got {:?}", other),
+        }
+        assert_eq!(client.outstanding_bytes(), 2 * set_len);
+
+        // Reading the response to a get proves the earlier sets were processed
+        client.get(b"a").unwrap();
+        assert_eq!(client.outstanding_bytes(), 0);
+        client.try_set_noreply(b"c", b"0123456789", 0, 0).unwrap();
+        client.set_noreply(b"d", b"0123456789", 0, 0).unwrap();
+        assert_eq!(noops.load(Ordering::SeqCst), 0);
+
+        // The blocking variant waits for a Noop round-trip instead of failing
+        client.set_noreply(FAILING_KEY, b"0123456789", 0, 0).unwrap();
+        assert_eq!(noops.load(Ordering::SeqCst), 1);
+        assert_eq!(client.outstanding_bytes(), request_size(OpKind::Set, FAILING_KEY.len(), 10));
+
+        let errors = client.drain_errors().unwrap();
+        assert_eq!(noops.load(Ordering::SeqCst), 2);
+        assert_eq!(client.outstanding_bytes(), 0);
+        assert_eq!(errors.len(), 1);
+        match errors[0] {
+            proto::Error::BinaryProtoError(ref err) => assert_eq!(err.status(), Status::KeyExists),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert!(client.drain_errors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resync() {
+        const KEY: &[u8] = b"test:resync";
+
+        // Garbage, then the tail of a packet, then a complete response nobody is waiting for
+        let mut stale = b"garbage".to_vec();
+        stale.extend_from_slice(&[0x81, 0x00, 0x00]);
+        ResponsePacket::new(
+            Command::Get,
+            DataType::RawBytes,
+            Status::NoError,
+            0xdead_beef,
+            0,
+            vec![0, 0, 0, 0].into(),
+            Bytes::new(),
+            b"stale value".as_ref().into(),
+        )
+        .write_to(&mut stale)
+        .unwrap();
+
+        let stream = StaleStream {
+            stale: Cursor::new(stale),
+            inner: TcpStream::connect(SERVER_ADDR).unwrap(),
+        };
+        let mut client = BinaryProto::new(BufStream::new(stream));
+
+        client.get(KEY).unwrap_err();
+        client.resync().unwrap();
+
+        client.set(KEY, b"fresh", 0, 120).unwrap();
+        assert_eq!(client.get(KEY).unwrap(), (b"fresh".to_vec(), 0));
+        client.delete(KEY).unwrap();
+    }
+
+    #[test]
+    fn test_resync_gives_up() {
+        let stream = StaleStream {
+            stale: Cursor::new(vec![0; RESYNC_MAX_DISCARD + REQUEST_HEADER_LEN + 1]),
+            inner: TcpStream::connect(SERVER_ADDR).unwrap(),
+        };
+        let mut client = BinaryProto::new(BufStream::new(stream));
+
+        let err = client.resync().unwrap_err();
+        assert!(err.poisons_connection(), "{}", err);
+    }
+
     #[test]
     fn test_if_noreply_failed() {
         let key = b"test:noreply_fail_key";
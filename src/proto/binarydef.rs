@@ -36,13 +36,16 @@
 //   Total 24 bytes
 
 #![allow(dead_code)]
-#![allow(clippy::too_many_arguments)]
+#![allow(clippy::too_many_arguments, clippy::io_other_error)]
 
+use std::convert::TryFrom;
 use std::io::{self, Read, Write};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Bytes, BytesMut};
 
+use crate::proto::ReadBufferPool;
+
 #[rustfmt::skip]
 mod consts {
     pub const MAGIC_REQUEST:  u8 = 0x80;
@@ -435,13 +438,16 @@ impl RequestHeader {
         extra: &[u8],
         value: &[u8],
     ) -> RequestHeader {
-        let key_len = key.len() as u16;
-        let extra_len = extra.len() as u8;
-        let body_len = (key.len() + extra.len() + value.len()) as u32;
+        let (key_len, extra_len, body_len) = payload_lengths(key, extra, value);
 
         RequestHeader::new(cmd, dtype, vbid, opaque, cas, key_len, extra_len, body_len)
     }
 
+    /// Size of the whole packet this header starts
+    pub fn packet_len(&self) -> usize {
+        24 + self.body_len as usize
+    }
+
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_u8(consts::MAGIC_REQUEST)?;
@@ -545,13 +551,17 @@ impl ResponseHeader {
         extra: &[u8],
         value: &[u8],
     ) -> ResponseHeader {
-        let key_len = key.len() as u16;
-        let extra_len = extra.len() as u8;
-        let body_len = (key.len() + extra.len() + value.len()) as u32;
+        let (key_len, extra_len, body_len) = payload_lengths(key, extra, value);
 
         ResponseHeader::new(cmd, dtype, status, opaque, cas, key_len, extra_len, body_len)
     }
 
+    /// Number of bytes of key, extras and value following the header
+    #[inline]
+    pub fn body_len(&self) -> u32 {
+        self.body_len
+    }
+
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         writer.write_u8(consts::MAGIC_RESPONSE)?;
@@ -572,7 +582,7 @@ impl ResponseHeader {
         let magic = reader.read_u8()?;
 
         if magic != consts::MAGIC_RESPONSE {
-            return Err(io::Error::new(io::ErrorKind::Other, "Invalid magic"));
+            return Err(invalid_response_magic(magic));
         }
 
         Ok(ResponseHeader {
@@ -597,6 +607,39 @@ impl ResponseHeader {
     }
 }
 
+/// Header fields for a payload, saturated rather than wrapped around when a part is too long
+///
+/// A saturated length no longer matches its payload, which `check_lengths` refuses to write.
+fn payload_lengths(key: &[u8], extra: &[u8], value: &[u8]) -> (u16, u8, u32) {
+    (
+        u16::try_from(key.len()).unwrap_or(u16::MAX),
+        u8::try_from(extra.len()).unwrap_or(u8::MAX),
+        u32::try_from(key.len() + extra.len() + value.len()).unwrap_or(u32::MAX),
+    )
+}
+
+/// Fail before anything is written if the header lengths do not describe the payload, e.g. for a
+/// key longer than the 65535 bytes a 16 bit key length can tell
+fn check_lengths(key_len: u16, extra_len: u8, body_len: u32, extra: &[u8], key: &[u8], value: &[u8]) -> io::Result<()> {
+    let too_long = |part: &str, len: usize, max: usize| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} of {} bytes is longer than the {} the binary protocol can encode", part, len, max),
+        ))
+    };
+    if key.len() != usize::from(key_len) {
+        return too_long("key", key.len(), u16::MAX.into());
+    }
+    if extra.len() != usize::from(extra_len) {
+        return too_long("extras", extra.len(), u8::MAX.into());
+    }
+    let body = key.len() + extra.len() + value.len();
+    if body != body_len as usize {
+        return too_long("body", body, u32::MAX as usize);
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct RequestPacket {
     pub header: RequestHeader,
@@ -626,6 +669,8 @@ impl RequestPacket {
 
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = &self.header;
+        check_lengths(header.key_len, header.extra_len, header.body_len, &self.extra, &self.key, &self.value)?;
         self.header.write_to(writer)?;
         writer.write_all(&self.extra)?;
         writer.write_all(&self.key)?;
@@ -687,6 +732,8 @@ impl<'a> RequestPacketRef<'a> {
 
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = self.header;
+        check_lengths(header.key_len, header.extra_len, header.body_len, self.extra, self.key, self.value)?;
         self.header.write_to(writer)?;
         writer.write_all(self.extra)?;
         writer.write_all(self.key)?;
@@ -725,6 +772,8 @@ impl ResponsePacket {
 
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = &self.header;
+        check_lengths(header.key_len, header.extra_len, header.body_len, &self.extra, &self.key, &self.value)?;
         self.header.write_to(writer)?;
         writer.write_all(&self.extra)?;
         writer.write_all(&self.key)?;
@@ -737,14 +786,27 @@ impl ResponsePacket {
     pub fn read_from<R: Read>(reader: &mut R) -> io::Result<ResponsePacket> {
         let header = ResponseHeader::read_from(reader)?;
 
-        let extra_len = header.extra_len as usize;
-        let key_len = header.key_len as usize;
         let body_len = header.body_len as usize;
-
         let mut buf = BytesMut::with_capacity(body_len);
         unsafe {
             buf.set_len(body_len);
         }
+        ResponsePacket::read_body(header, buf, reader)
+    }
+
+    /// Like `read_from`, with the body read into a buffer from `pool`
+    #[inline]
+    pub fn read_from_pool<R: Read>(reader: &mut R, pool: &mut ReadBufferPool) -> io::Result<ResponsePacket> {
+        let header = ResponseHeader::read_from(reader)?;
+
+        let buf = pool.take(header.body_len as usize);
+        ResponsePacket::read_body(header, buf, reader)
+    }
+
+    /// Read the body of `header` into `buf`, which is exactly as long
+    fn read_body<R: Read>(header: ResponseHeader, mut buf: BytesMut, reader: &mut R) -> io::Result<ResponsePacket> {
+        let extra_len = header.extra_len as usize;
+        let key_len = header.key_len as usize;
 
         let mut extra = buf.split_to(extra_len);
         let mut key = buf.split_to(key_len);
@@ -781,6 +843,8 @@ impl<'a> ResponsePacketRef<'a> {
 
     #[inline]
     pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = self.header;
+        check_lengths(header.key_len, header.extra_len, header.body_len, self.extra, self.key, self.value)?;
         self.header.write_to(writer)?;
         writer.write_all(self.extra)?;
         writer.write_all(self.key)?;
@@ -790,13 +854,32 @@ impl<'a> ResponsePacketRef<'a> {
     }
 }
 
+/// Error for a response that does not start with the binary protocol's magic byte
+///
+/// Text protocol responses all start with an uppercase letter, like `ERROR` or `VALUE`, so such a
+/// byte most likely means the server only speaks the text protocol.
+fn invalid_response_magic(magic: u8) -> io::Error {
+    if magic.is_ascii_uppercase() {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Invalid magic 0x{:02x} ('{}'): the server answered in text, it probably speaks the memcached \
+                 text protocol instead of the binary protocol",
+                magic, magic as char
+            ),
+        )
+    } else {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid magic 0x{:02x}", magic))
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::io::Write;
+    use std::io::{self, Write};
     use std::net::TcpStream;
 
     use crate::proto;
-    use crate::proto::binarydef::{Command, DataType, RequestPacket, ResponsePacket};
+    use crate::proto::binarydef::{Command, DataType, RequestPacket, ResponseHeader, ResponsePacket};
 
     use bufstream::BufStream;
     use bytes::Bytes;
@@ -805,6 +888,43 @@ mod test {
         TcpStream::connect("127.0.0.1:11211").unwrap()
     }
 
+    #[test]
+    fn test_text_response_magic() {
+        let err = ResponseHeader::read_from(&mut &b"ERROR\r\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Invalid magic 0x45 ('E'): the server answered in text, it probably speaks the memcached text \
+             protocol instead of the binary protocol"
+        );
+
+        let err = ResponseHeader::read_from(&mut &[0x00u8; 24][..]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid magic 0x00");
+    }
+
+    #[test]
+    fn test_oversized_key() {
+        let key = vec![b'k'; usize::from(u16::MAX) + 1];
+        let req_packet =
+            RequestPacket::new(Command::Get, DataType::RawBytes, 0, 0, 0, Bytes::new(), key.into(), Bytes::new());
+        assert_eq!(req_packet.header.key_len, u16::MAX);
+
+        let mut written = Vec::new();
+        let err = req_packet.write_to(&mut written).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "key of 65536 bytes is longer than the 65535 the binary protocol can encode");
+        assert!(written.is_empty());
+        assert!(req_packet.as_ref().write_to(&mut written).is_err());
+        assert!(written.is_empty());
+
+        // The longest key that can be encoded
+        let key = vec![b'k'; usize::from(u16::MAX)];
+        let req_packet =
+            RequestPacket::new(Command::Get, DataType::RawBytes, 0, 0, 0, Bytes::new(), key.into(), Bytes::new());
+        req_packet.write_to(&mut written).unwrap();
+        assert_eq!(written.len(), 24 + usize::from(u16::MAX));
+    }
+
     #[test]
     fn test_binary_protocol() {
         let mut stream = BufStream::new(test_stream());
@@ -0,0 +1,678 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Requests as the operations they ask for, for code that reads requests instead of sending them
+//!
+//! A proxy or a replay of a transcript gets `RequestPacket`s with the arguments packed into the
+//! extras, laid out differently for each opcode. `DecodedRequest` unpacks them, and converts back
+//! into the one canonical packet for the operation.
+
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+use crate::proto::binarydef::{Command, DataType, RequestPacket};
+
+/// A request with its extras decoded, see `TryFrom<RequestPacket>`
+///
+/// `quiet` stands for the quiet opcode of the same command. The opaque and vbucket of the packet
+/// are not kept, a converted packet has both 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedRequest {
+    Get {
+        key: Bytes,
+        quiet: bool,
+    },
+    /// A get answered with the key
+    GetKey {
+        key: Bytes,
+        quiet: bool,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+        quiet: bool,
+    },
+    Add {
+        key: Bytes,
+        value: Bytes,
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+        quiet: bool,
+    },
+    Replace {
+        key: Bytes,
+        value: Bytes,
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+        quiet: bool,
+    },
+    Delete {
+        key: Bytes,
+        cas: u64,
+        quiet: bool,
+    },
+    Increment {
+        key: Bytes,
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+        cas: u64,
+        quiet: bool,
+    },
+    Decrement {
+        key: Bytes,
+        amount: u64,
+        initial: u64,
+        expiration: u32,
+        cas: u64,
+        quiet: bool,
+    },
+    Append {
+        key: Bytes,
+        value: Bytes,
+        cas: u64,
+        quiet: bool,
+    },
+    Prepend {
+        key: Bytes,
+        value: Bytes,
+        cas: u64,
+        quiet: bool,
+    },
+    Touch {
+        key: Bytes,
+        expiration: u32,
+        cas: u64,
+    },
+    GetAndTouch {
+        key: Bytes,
+        expiration: u32,
+        quiet: bool,
+    },
+    /// `expiration` is `None` for a flush without extras, which flushes right away
+    Flush {
+        expiration: Option<u32>,
+        quiet: bool,
+    },
+    Noop,
+    Quit {
+        quiet: bool,
+    },
+    Version,
+    /// An empty `key` asks for the general stats
+    Stat {
+        key: Bytes,
+    },
+    Verbosity {
+        level: u32,
+    },
+    /// Any other command, SASL for one, kept as it came
+    Raw {
+        command: Command,
+        cas: u64,
+        extra: Bytes,
+        key: Bytes,
+        value: Bytes,
+    },
+}
+
+/// Length of the extras of a store: flags and expiration
+const STORE_EXTRAS_LEN: usize = 8;
+/// Length of the extras of an increment or decrement: amount, initial value and expiration
+const ARITHMETIC_EXTRAS_LEN: usize = 20;
+/// Length of the extras holding a single `u32`, the expiration of a touch or a flush or the level
+/// of verbosity
+const U32_EXTRAS_LEN: usize = 4;
+
+fn malformed(command: Command, detail: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?} request {}", command, detail))
+}
+
+/// Fail unless the extras of `req` are one of the `expected` lengths
+fn expect_extras(req: &RequestPacket, expected: &[usize]) -> io::Result<()> {
+    if expected.contains(&req.extra.len()) {
+        Ok(())
+    } else {
+        Err(malformed(req.header.command, format!("with {} bytes of extras, expected {:?}", req.extra.len(), expected)))
+    }
+}
+
+/// Fail if `req` has a value its command has no place for
+fn expect_no_value(req: &RequestPacket) -> io::Result<()> {
+    if req.value.is_empty() {
+        Ok(())
+    } else {
+        Err(malformed(req.header.command, format!("with a value of {} bytes", req.value.len())))
+    }
+}
+
+/// Decodes the extras by the layout of the opcode
+///
+/// Fails with `InvalidData` for extras of the wrong length, or a value for a command that takes
+/// none. Commands without a variant of their own decode to `Raw`.
+impl TryFrom<RequestPacket> for DecodedRequest {
+    type Error = io::Error;
+
+    fn try_from(req: RequestPacket) -> io::Result<DecodedRequest> {
+        use self::Command::*;
+
+        let cas = req.header.cas;
+        let decoded = match req.header.command {
+            command @ (Get | GetQuietly | GetKey | GetKeyQuietly) => {
+                expect_extras(&req, &[0])?;
+                expect_no_value(&req)?;
+                let (key, quiet) = (req.key, matches!(command, GetQuietly | GetKeyQuietly));
+                if matches!(command, Get | GetQuietly) {
+                    DecodedRequest::Get { key, quiet }
+                } else {
+                    DecodedRequest::GetKey { key, quiet }
+                }
+            }
+            command @ (Set | SetQuietly | Add | AddQuietly | Replace | ReplaceQuietly) => {
+                expect_extras(&req, &[STORE_EXTRAS_LEN])?;
+                let (key, value) = (req.key, req.value);
+                let flags = BigEndian::read_u32(&req.extra[0..4]);
+                let expiration = BigEndian::read_u32(&req.extra[4..8]);
+                match command {
+                    Set | SetQuietly => DecodedRequest::Set {
+                        key,
+                        value,
+                        flags,
+                        expiration,
+                        cas,
+                        quiet: command == SetQuietly,
+                    },
+                    Add | AddQuietly => DecodedRequest::Add {
+                        key,
+                        value,
+                        flags,
+                        expiration,
+                        cas,
+                        quiet: command == AddQuietly,
+                    },
+                    _ => DecodedRequest::Replace {
+                        key,
+                        value,
+                        flags,
+                        expiration,
+                        cas,
+                        quiet: command == ReplaceQuietly,
+                    },
+                }
+            }
+            command @ (Delete | DeleteQuietly) => {
+                expect_extras(&req, &[0])?;
+                expect_no_value(&req)?;
+                DecodedRequest::Delete {
+                    key: req.key,
+                    cas,
+                    quiet: command == DeleteQuietly,
+                }
+            }
+            command @ (Increment | IncrementQuietly | Decrement | DecrementQuietly) => {
+                expect_extras(&req, &[ARITHMETIC_EXTRAS_LEN])?;
+                expect_no_value(&req)?;
+                let key = req.key;
+                let amount = BigEndian::read_u64(&req.extra[0..8]);
+                let initial = BigEndian::read_u64(&req.extra[8..16]);
+                let expiration = BigEndian::read_u32(&req.extra[16..20]);
+                if matches!(command, Increment | IncrementQuietly) {
+                    DecodedRequest::Increment {
+                        key,
+                        amount,
+                        initial,
+                        expiration,
+                        cas,
+                        quiet: command == IncrementQuietly,
+                    }
+                } else {
+                    DecodedRequest::Decrement {
+                        key,
+                        amount,
+                        initial,
+                        expiration,
+                        cas,
+                        quiet: command == DecrementQuietly,
+                    }
+                }
+            }
+            command @ (Append | AppendQuietly | Prepend | PrependQuietly) => {
+                expect_extras(&req, &[0])?;
+                let (key, value) = (req.key, req.value);
+                if matches!(command, Append | AppendQuietly) {
+                    DecodedRequest::Append {
+                        key,
+                        value,
+                        cas,
+                        quiet: command == AppendQuietly,
+                    }
+                } else {
+                    DecodedRequest::Prepend {
+                        key,
+                        value,
+                        cas,
+                        quiet: command == PrependQuietly,
+                    }
+                }
+            }
+            Touch => {
+                expect_extras(&req, &[U32_EXTRAS_LEN])?;
+                expect_no_value(&req)?;
+                DecodedRequest::Touch {
+                    expiration: BigEndian::read_u32(&req.extra),
+                    key: req.key,
+                    cas,
+                }
+            }
+            command @ (GetAndTouch | GetAndTouchQuietly) => {
+                expect_extras(&req, &[U32_EXTRAS_LEN])?;
+                expect_no_value(&req)?;
+                DecodedRequest::GetAndTouch {
+                    expiration: BigEndian::read_u32(&req.extra),
+                    key: req.key,
+                    quiet: command == GetAndTouchQuietly,
+                }
+            }
+            command @ (Flush | FlushQuietly) => {
+                expect_extras(&req, &[0, U32_EXTRAS_LEN])?;
+                expect_no_value(&req)?;
+                DecodedRequest::Flush {
+                    expiration: (!req.extra.is_empty()).then(|| BigEndian::read_u32(&req.extra)),
+                    quiet: command == FlushQuietly,
+                }
+            }
+            Noop => DecodedRequest::Noop,
+            command @ (Quit | QuitQuietly) => DecodedRequest::Quit {
+                quiet: command == QuitQuietly,
+            },
+            Version => DecodedRequest::Version,
+            Stat => {
+                expect_extras(&req, &[0])?;
+                expect_no_value(&req)?;
+                DecodedRequest::Stat { key: req.key }
+            }
+            Verbosity => {
+                expect_extras(&req, &[U32_EXTRAS_LEN])?;
+                expect_no_value(&req)?;
+                DecodedRequest::Verbosity {
+                    level: BigEndian::read_u32(&req.extra),
+                }
+            }
+            command => DecodedRequest::Raw {
+                command,
+                cas,
+                extra: req.extra,
+                key: req.key,
+                value: req.value,
+            },
+        };
+        Ok(decoded)
+    }
+}
+
+fn quietly(quiet: bool, loud: Command, quiet_command: Command) -> Command {
+    if quiet {
+        quiet_command
+    } else {
+        loud
+    }
+}
+
+fn store_extras(flags: u32, expiration: u32) -> Bytes {
+    let mut extra = [0u8; STORE_EXTRAS_LEN];
+    BigEndian::write_u32(&mut extra[0..4], flags);
+    BigEndian::write_u32(&mut extra[4..8], expiration);
+    Bytes::copy_from_slice(&extra)
+}
+
+fn arithmetic_extras(amount: u64, initial: u64, expiration: u32) -> Bytes {
+    let mut extra = [0u8; ARITHMETIC_EXTRAS_LEN];
+    BigEndian::write_u64(&mut extra[0..8], amount);
+    BigEndian::write_u64(&mut extra[8..16], initial);
+    BigEndian::write_u32(&mut extra[16..20], expiration);
+    Bytes::copy_from_slice(&extra)
+}
+
+fn u32_extras(n: u32) -> Bytes {
+    Bytes::copy_from_slice(&n.to_be_bytes())
+}
+
+/// The canonical packet: opaque and vbucket 0, and extras only where the command takes them
+impl From<DecodedRequest> for RequestPacket {
+    fn from(decoded: DecodedRequest) -> RequestPacket {
+        use self::Command::*;
+
+        let none = Bytes::new;
+        let (command, cas, extra, key, value) = match decoded {
+            DecodedRequest::Get { key, quiet } => (quietly(quiet, Get, GetQuietly), 0, none(), key, none()),
+            DecodedRequest::GetKey { key, quiet } => (quietly(quiet, GetKey, GetKeyQuietly), 0, none(), key, none()),
+            DecodedRequest::Set {
+                key,
+                value,
+                flags,
+                expiration,
+                cas,
+                quiet,
+            } => (quietly(quiet, Set, SetQuietly), cas, store_extras(flags, expiration), key, value),
+            DecodedRequest::Add {
+                key,
+                value,
+                flags,
+                expiration,
+                cas,
+                quiet,
+            } => (quietly(quiet, Add, AddQuietly), cas, store_extras(flags, expiration), key, value),
+            DecodedRequest::Replace {
+                key,
+                value,
+                flags,
+                expiration,
+                cas,
+                quiet,
+            } => (quietly(quiet, Replace, ReplaceQuietly), cas, store_extras(flags, expiration), key, value),
+            DecodedRequest::Delete { key, cas, quiet } => {
+                (quietly(quiet, Delete, DeleteQuietly), cas, none(), key, none())
+            }
+            DecodedRequest::Increment {
+                key,
+                amount,
+                initial,
+                expiration,
+                cas,
+                quiet,
+            } => (
+                quietly(quiet, Increment, IncrementQuietly),
+                cas,
+                arithmetic_extras(amount, initial, expiration),
+                key,
+                none(),
+            ),
+            DecodedRequest::Decrement {
+                key,
+                amount,
+                initial,
+                expiration,
+                cas,
+                quiet,
+            } => (
+                quietly(quiet, Decrement, DecrementQuietly),
+                cas,
+                arithmetic_extras(amount, initial, expiration),
+                key,
+                none(),
+            ),
+            DecodedRequest::Append { key, value, cas, quiet } => {
+                (quietly(quiet, Append, AppendQuietly), cas, none(), key, value)
+            }
+            DecodedRequest::Prepend { key, value, cas, quiet } => {
+                (quietly(quiet, Prepend, PrependQuietly), cas, none(), key, value)
+            }
+            DecodedRequest::Touch { key, expiration, cas } => (Touch, cas, u32_extras(expiration), key, none()),
+            DecodedRequest::GetAndTouch { key, expiration, quiet } => {
+                (quietly(quiet, GetAndTouch, GetAndTouchQuietly), 0, u32_extras(expiration), key, none())
+            }
+            DecodedRequest::Flush { expiration, quiet } => {
+                (quietly(quiet, Flush, FlushQuietly), 0, expiration.map(u32_extras).unwrap_or_default(), none(), none())
+            }
+            DecodedRequest::Noop => (Noop, 0, none(), none(), none()),
+            DecodedRequest::Quit { quiet } => (quietly(quiet, Quit, QuitQuietly), 0, none(), none(), none()),
+            DecodedRequest::Version => (Version, 0, none(), none(), none()),
+            DecodedRequest::Stat { key } => (Stat, 0, none(), key, none()),
+            DecodedRequest::Verbosity { level } => (Verbosity, 0, u32_extras(level), none(), none()),
+            DecodedRequest::Raw {
+                command,
+                cas,
+                extra,
+                key,
+                value,
+            } => (command, cas, extra, key, value),
+        };
+        RequestPacket::new(command, DataType::RawBytes, 0, 0, cas, extra, key, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use std::io;
+
+    use bytes::Bytes;
+    use proptest::prelude::*;
+
+    use super::DecodedRequest;
+    use crate::proto::binarydef::{Command, DataType, RequestPacket};
+
+    fn packet(command: Command, cas: u64, extra: &[u8], key: &[u8], value: &[u8]) -> RequestPacket {
+        RequestPacket::new(
+            command,
+            DataType::RawBytes,
+            0,
+            0,
+            cas,
+            Bytes::copy_from_slice(extra),
+            Bytes::copy_from_slice(key),
+            Bytes::copy_from_slice(value),
+        )
+    }
+
+    fn decode(command: Command, cas: u64, extra: &[u8], key: &[u8], value: &[u8]) -> io::Result<DecodedRequest> {
+        DecodedRequest::try_from(packet(command, cas, extra, key, value))
+    }
+
+    fn encoded(req: &RequestPacket) -> Vec<u8> {
+        let mut buf = Vec::new();
+        req.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_store_extras() {
+        let extra = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x00, 0x0e, 0x10];
+        assert_eq!(
+            decode(Command::AddQuietly, 7, &extra, b"key", b"value").unwrap(),
+            DecodedRequest::Add {
+                key: Bytes::from_static(b"key"),
+                value: Bytes::from_static(b"value"),
+                flags: 0xdead_beef,
+                expiration: 3600,
+                cas: 7,
+                quiet: true,
+            }
+        );
+        let err = decode(Command::Set, 0, &extra[..4], b"key", b"value").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Set request with 4 bytes of extras, expected [8]");
+    }
+
+    #[test]
+    fn test_arithmetic_extras() {
+        let mut extra = Vec::new();
+        extra.extend_from_slice(&5u64.to_be_bytes());
+        extra.extend_from_slice(&100u64.to_be_bytes());
+        extra.extend_from_slice(&0xffff_ffffu32.to_be_bytes());
+        assert_eq!(
+            decode(Command::Decrement, 0, &extra, b"counter", b"").unwrap(),
+            DecodedRequest::Decrement {
+                key: Bytes::from_static(b"counter"),
+                amount: 5,
+                initial: 100,
+                expiration: 0xffff_ffff,
+                cas: 0,
+                quiet: false,
+            }
+        );
+        decode(Command::Increment, 0, &extra[..16], b"counter", b"").unwrap_err();
+        decode(Command::Increment, 0, &extra, b"counter", b"1").unwrap_err();
+    }
+
+    #[test]
+    fn test_u32_extras() {
+        let extra = 60u32.to_be_bytes();
+        assert_eq!(
+            decode(Command::Touch, 3, &extra, b"key", b"").unwrap(),
+            DecodedRequest::Touch {
+                key: Bytes::from_static(b"key"),
+                expiration: 60,
+                cas: 3,
+            }
+        );
+        assert_eq!(
+            decode(Command::GetAndTouchQuietly, 0, &extra, b"key", b"").unwrap(),
+            DecodedRequest::GetAndTouch {
+                key: Bytes::from_static(b"key"),
+                expiration: 60,
+                quiet: true,
+            }
+        );
+        assert_eq!(
+            decode(Command::Flush, 0, &extra, b"", b"").unwrap(),
+            DecodedRequest::Flush {
+                expiration: Some(60),
+                quiet: false,
+            }
+        );
+        assert_eq!(
+            decode(Command::FlushQuietly, 0, &[], b"", b"").unwrap(),
+            DecodedRequest::Flush {
+                expiration: None,
+                quiet: true,
+            }
+        );
+        assert_eq!(
+            decode(Command::Verbosity, 0, &1u32.to_be_bytes(), b"", b"").unwrap(),
+            DecodedRequest::Verbosity { level: 1 }
+        );
+        decode(Command::Touch, 0, &[], b"key", b"").unwrap_err();
+        decode(Command::Flush, 0, &[0; 8], b"", b"").unwrap_err();
+    }
+
+    #[test]
+    fn test_raw() {
+        let req = packet(Command::SaslAuthenticate, 0, &[], b"PLAIN", b"\0user\0password");
+        let decoded = DecodedRequest::try_from(req.clone()).unwrap();
+        assert!(matches!(
+            decoded,
+            DecodedRequest::Raw {
+                command: Command::SaslAuthenticate,
+                ..
+            }
+        ));
+        assert_eq!(encoded(&RequestPacket::from(decoded)), encoded(&req));
+    }
+
+    fn bytes(max_len: usize) -> impl Strategy<Value = Bytes> {
+        prop::collection::vec(any::<u8>(), 0..max_len).prop_map(Bytes::from)
+    }
+
+    fn decoded() -> impl Strategy<Value = DecodedRequest> {
+        let key = || bytes(250);
+        let value = || bytes(64);
+        prop_oneof![
+            (key(), any::<bool>()).prop_map(|(key, quiet)| DecodedRequest::Get { key, quiet }),
+            (key(), any::<bool>()).prop_map(|(key, quiet)| DecodedRequest::GetKey { key, quiet }),
+            (key(), value(), any::<u32>(), any::<u32>(), any::<u64>(), any::<bool>()).prop_map(
+                |(key, value, flags, expiration, cas, quiet)| DecodedRequest::Set {
+                    key,
+                    value,
+                    flags,
+                    expiration,
+                    cas,
+                    quiet,
+                }
+            ),
+            (key(), value(), any::<u32>(), any::<u32>(), any::<u64>(), any::<bool>()).prop_map(
+                |(key, value, flags, expiration, cas, quiet)| DecodedRequest::Add {
+                    key,
+                    value,
+                    flags,
+                    expiration,
+                    cas,
+                    quiet,
+                }
+            ),
+            (key(), value(), any::<u32>(), any::<u32>(), any::<u64>(), any::<bool>()).prop_map(
+                |(key, value, flags, expiration, cas, quiet)| DecodedRequest::Replace {
+                    key,
+                    value,
+                    flags,
+                    expiration,
+                    cas,
+                    quiet,
+                }
+            ),
+            (key(), any::<u64>(), any::<bool>()).prop_map(|(key, cas, quiet)| DecodedRequest::Delete {
+                key,
+                cas,
+                quiet
+            }),
+            (key(), any::<u64>(), any::<u64>(), any::<u32>(), any::<u64>(), any::<bool>()).prop_map(
+                |(key, amount, initial, expiration, cas, quiet)| DecodedRequest::Increment {
+                    key,
+                    amount,
+                    initial,
+                    expiration,
+                    cas,
+                    quiet,
+                }
+            ),
+            (key(), any::<u64>(), any::<u64>(), any::<u32>(), any::<u64>(), any::<bool>()).prop_map(
+                |(key, amount, initial, expiration, cas, quiet)| DecodedRequest::Decrement {
+                    key,
+                    amount,
+                    initial,
+                    expiration,
+                    cas,
+                    quiet,
+                }
+            ),
+            (key(), value(), any::<u64>(), any::<bool>())
+                .prop_map(|(key, value, cas, quiet)| { DecodedRequest::Append { key, value, cas, quiet } }),
+            (key(), value(), any::<u64>(), any::<bool>())
+                .prop_map(|(key, value, cas, quiet)| { DecodedRequest::Prepend { key, value, cas, quiet } }),
+            (key(), any::<u32>(), any::<u64>()).prop_map(|(key, expiration, cas)| DecodedRequest::Touch {
+                key,
+                expiration,
+                cas
+            }),
+            (key(), any::<u32>(), any::<bool>())
+                .prop_map(|(key, expiration, quiet)| { DecodedRequest::GetAndTouch { key, expiration, quiet } }),
+            (any::<Option<u32>>(), any::<bool>())
+                .prop_map(|(expiration, quiet)| DecodedRequest::Flush { expiration, quiet }),
+            Just(DecodedRequest::Noop),
+            any::<bool>().prop_map(|quiet| DecodedRequest::Quit { quiet }),
+            Just(DecodedRequest::Version),
+            key().prop_map(|key| DecodedRequest::Stat { key }),
+            any::<u32>().prop_map(|level| DecodedRequest::Verbosity { level }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(decoded in decoded()) {
+            let req = RequestPacket::from(decoded.clone());
+            let wire = encoded(&req);
+            let read = RequestPacket::read_from(&mut &wire[..]).unwrap();
+            prop_assert_eq!(DecodedRequest::try_from(read).unwrap(), decoded);
+
+            // Canonical packets come back byte for byte
+            let again = RequestPacket::from(DecodedRequest::try_from(req).unwrap());
+            prop_assert_eq!(encoded(&again), wire);
+        }
+    }
+}
@@ -9,21 +9,29 @@
 
 //! Memcached protocol
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::From;
 use std::error;
 use std::fmt::{self, Display};
 use std::io;
+use std::str;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use semver::Version;
 
-pub use self::binary::BinaryProto;
+pub use self::binary::{BinaryProto, MissingFlags};
+pub use self::read_buffer::{ReadBufferPool, ReadBufferStats};
 
 pub mod binary;
-mod binarydef;
+pub(crate) mod binarydef;
+pub(crate) mod decoded;
+mod opaque;
+mod read_buffer;
 
 /// Protocol type
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ProtoType {
     Binary,
 }
@@ -33,11 +41,203 @@ pub enum Error {
     BinaryProtoError(binary::Error),
     IoError(io::Error),
     OtherError { desc: &'static str, detail: Option<String> },
+    /// `append_bounded` refused to grow a value past its cap
+    AppendLimitExceeded {
+        current_len: usize,
+        append_len: usize,
+        max_len: usize,
+    },
+    /// The key is longer than `MAX_KEY_LEN`, rejected by `Client` before it reaches a server
+    KeyTooLong {
+        len: usize,
+    },
+    /// The value is longer than the server accepts, rejected by `Client` before it reaches the
+    /// server, see `ClientBuilder::max_value_size`
+    ValueTooLarge {
+        len: usize,
+        max_len: usize,
+    },
+    /// Items of a multi store that cannot be sent, each key with why, e.g. `KeyTooLong`
+    ///
+    /// Every item is checked before the first request goes out, so nothing of the batch was stored.
+    ValidationFailed {
+        invalid: Vec<(Vec<u8>, Error)>,
+    },
+    /// A retrying helper ran past its deadline
+    Timeout {
+        attempts: usize,
+    },
+    /// The server answered `Busy`, it is overloaded and should not be retried before `retry_after`
+    ///
+    /// `Client` only returns it when enabled with `ClientBuilder::busy_backpressure`, a bare
+    /// `BinaryProto` returns `BinaryProtoError` with `Status::Busy`.
+    Backpressure {
+        retry_after: Option<Duration>,
+    },
+    /// `source` was returned by the server at `server` while running `op`, added by `Client`
+    ///
+    /// `client` is the label set with `ClientBuilder::client_label`, if any.
+    WithContext {
+        op: &'static str,
+        server: String,
+        client: Option<String>,
+        source: Box<Error>,
+    },
+    /// An operation on `server` started while another one on it was still running, e.g. from a
+    /// callback of `Client` that re-entered it
+    ReentrantUse {
+        server: String,
+    },
+    /// A value could not be decrypted, or was not encrypted although its key should be, see
+    /// `ClientBuilder::value_cipher`
+    IntegrityError {
+        detail: String,
+    },
+    /// An earlier error left the connection out of step with the server, so it is not used until
+    /// `Client::reset_connection`; `cause` describes that error
+    ConnectionPoisoned {
+        cause: String,
+    },
+    /// The server answered something a lenient connection would have coped with, returned
+    /// instead in strict mode, see `BinaryProto::set_strict`
+    ProtocolAnomaly(Anomaly),
+}
+
+/// Deviations from the protocol that are tolerated unless in strict mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Anomaly {
+    /// A response with an opaque matching no request waiting for one, skipped when lenient
+    ///
+    /// `expected` is the opaque of the request waiting alone, `None` for a pipeline.
+    UnexpectedOpaque { expected: Option<u32>, got: u32 },
+    /// A get response with extras too short for the flags, answered with the flags of
+    /// `MissingFlags::Lenient` when lenient
+    MissingFlags { extras_len: usize },
+    /// A key given more than once to a multi operation, sent once when lenient
+    DuplicateKey { key: Vec<u8> },
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Anomaly::UnexpectedOpaque {
+                expected: Some(expected),
+                got,
+            } => write!(f, "response with opaque {} while expecting {}", got, expected),
+            Anomaly::UnexpectedOpaque { expected: None, got } => {
+                write!(f, "response with opaque {} matching no pending request", got)
+            }
+            Anomaly::MissingFlags { extras_len } => {
+                write!(f, "get response with {} bytes of extras, too short for the flags", extras_len)
+            }
+            Anomaly::DuplicateKey { ref key } => {
+                write!(f, "key {:?} given more than once", str::from_utf8(key).unwrap_or("<not-utf8-key>"))
+            }
+        }
+    }
 }
 
 pub type MemCachedResult<T> = Result<T, Error>;
 
-impl error::Error for Error {}
+/// Longest key memcached accepts, in bytes
+pub const MAX_KEY_LEN: usize = 250;
+
+/// Flags and expiration in the extras of a store request
+const STORE_EXTRAS_LEN: usize = 8;
+
+/// Why a store of `key` with a value of `value_len` bytes cannot be sent, if it cannot
+///
+/// The key length field allows more than `MAX_KEY_LEN`, but memcached does not. The body length
+/// is a `u32` holding the key, the extras and the value.
+pub(crate) fn check_store(key: &[u8], value_len: usize) -> Option<Error> {
+    if key.len() > MAX_KEY_LEN {
+        return Some(Error::KeyTooLong { len: key.len() });
+    }
+    let max_len = u32::MAX as usize - STORE_EXTRAS_LEN - key.len();
+    if value_len > max_len {
+        return Some(Error::ValueTooLarge {
+            len: value_len,
+            max_len,
+        });
+    }
+    None
+}
+
+/// `Error::ValidationFailed` if any item of a batch is `invalid`
+pub(crate) fn validated(invalid: Vec<(Vec<u8>, Error)>) -> MemCachedResult<()> {
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ValidationFailed { invalid })
+    }
+}
+
+/// Expiration that makes an increment or decrement of a missing key fail instead of creating it
+pub const NO_CREATE_EXPIRATION: u32 = 0xffff_ffff;
+
+/// Expirations above this many seconds are unix times, like memcached's `REALTIME_MAXDELTA`
+pub const RELATIVE_EXPIRATION_MAX: u32 = 60 * 60 * 24 * 30;
+
+impl Error {
+    /// Whether the connection is left out of step with the server after this error
+    ///
+    /// Bytes that are not the expected response leave the rest of it to be read as the answer to
+    /// the next request. A timeout is not one of them, see `is_timeout`, nor is a closed
+    /// connection, which has nothing left to read.
+    pub fn poisons_connection(&self) -> bool {
+        match *self.root() {
+            Error::IoError(ref err) => err.kind() == io::ErrorKind::InvalidData,
+            Error::ProtocolAnomaly(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a response was given up on halfway because it took too long
+    ///
+    /// The rest of it may still arrive, so the connection needs a `resync` before it is used again.
+    pub fn is_timeout(&self) -> bool {
+        match *self.root() {
+            Error::IoError(ref err) => matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock),
+            _ => false,
+        }
+    }
+
+    /// The error without any context, for matching on what actually went wrong
+    pub fn root(&self) -> &Error {
+        match *self {
+            Error::WithContext { ref source, .. } => source.root(),
+            ref err => err,
+        }
+    }
+
+    /// Owned version of `root`
+    pub fn into_root(self) -> Error {
+        match self {
+            Error::WithContext { source, .. } => source.into_root(),
+            err => err,
+        }
+    }
+
+    /// Status the server answered with, if this is a server error
+    pub fn status(&self) -> Option<binary::Status> {
+        match *self.root() {
+            Error::BinaryProtoError(ref err) => Some(err.status()),
+            Error::Backpressure { .. } => Some(binary::Status::Busy),
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::WithContext { ref source, .. } => Some(&**source),
+            Error::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -51,6 +251,59 @@ impl Display for Error {
                     None => Ok(()),
                 }
             }
+            Error::AppendLimitExceeded {
+                current_len,
+                append_len,
+                max_len,
+            } => write!(f, "append would exceed max length ({} + {} > {})", current_len, append_len, max_len),
+            Error::KeyTooLong { len } => write!(
+                f,
+                "key of {} bytes is longer than the {} memcached allows, hash it or enable \
+                 ClientBuilder::auto_hash_long_keys",
+                len, MAX_KEY_LEN
+            ),
+            Error::ValueTooLarge { len, max_len } => {
+                write!(f, "value of {} bytes is larger than the {} the server accepts", len, max_len)
+            }
+            Error::ValidationFailed { ref invalid } => {
+                write!(f, "{} items of the batch are invalid, nothing was sent:", invalid.len())?;
+                for (i, (key, err)) in invalid.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { "; " };
+                    write!(f, "{}{:?}: {}", sep, str::from_utf8(key).unwrap_or("<not-utf8-key>"), err)?;
+                }
+                Ok(())
+            }
+            Error::Timeout { attempts } => write!(f, "deadline exceeded after {} attempts", attempts),
+            Error::Backpressure {
+                retry_after: Some(after),
+            } => {
+                write!(f, "server is busy, retry after {:?}", after)
+            }
+            Error::Backpressure { retry_after: None } => f.write_str("server is busy"),
+            Error::WithContext {
+                op,
+                ref server,
+                client: Some(ref client),
+                ref source,
+            } => write!(f, "{} on {} by client {} failed: {}", op, server, client, source),
+            Error::WithContext {
+                op,
+                ref server,
+                client: None,
+                ref source,
+            } => write!(f, "{} on {} failed: {}", op, server, source),
+            Error::ReentrantUse { ref server } => write!(
+                f,
+                "{} is already in use by an operation of this client, a callback re-entered the client",
+                server
+            ),
+            Error::IntegrityError { ref detail } => write!(f, "value failed its integrity check: {}", detail),
+            Error::ConnectionPoisoned { ref cause } => write!(
+                f,
+                "connection is out of step with the server since an earlier error ({}), reset it first",
+                cause
+            ),
+            Error::ProtocolAnomaly(ref anomaly) => write!(f, "protocol anomaly in strict mode: {}", anomaly),
         }
     }
 }
@@ -85,11 +338,148 @@ pub trait Operation {
     fn replace(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()>;
     fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)>;
     fn getk(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)>;
+    /// Add `amount` to the number stored under `key`, storing `initial` if `key` is missing
+    ///
+    /// A missing key is created with `initial` as it is, `amount` is not added. The number wraps
+    /// around past `u64::MAX`. A value that is not a decimal number fails with
+    /// `IncrDecrOnNonNumericValue`.
     fn increment(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64>;
+    /// Subtract `amount` from the number stored under `key`, storing `initial` if `key` is missing
+    ///
+    /// Like `increment`, except that the number stops at 0 instead of wrapping around.
     fn decrement(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<u64>;
     fn append(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()>;
     fn prepend(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()>;
     fn touch(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<()>;
+    /// Whether `key` is stored, without changing its expiration like `touch` does
+    ///
+    /// A miss is `Ok(false)` rather than a `KeyNotFound` error. This default fetches the value with
+    /// `get`, backends that can check for a key without transferring it override it.
+    fn exists(&mut self, key: &[u8]) -> MemCachedResult<bool> {
+        Ok(miss_as_none(self.get(key))?.is_some())
+    }
+
+    /// Append `value` to `key`, storing it as a new item with `flags` and `expiration` if `key` is missing
+    ///
+    /// If another client creates `key` between the failed append and the add, the append is retried.
+    /// `flags` and `expiration` only apply when the item is created.
+    fn append_or_create(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        or_create(self, key, value, flags, expiration, Self::append)
+    }
+
+    /// Prepend `value` to `key`, storing it as a new item with `flags` and `expiration` if `key` is missing
+    ///
+    /// Behaves like `append_or_create` otherwise.
+    fn prepend_or_create(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        or_create(self, key, value, flags, expiration, Self::prepend)
+    }
+
+    /// `increment` that never creates `key`, a missing key fails with `KeyNotFound`
+    ///
+    /// The expiration of the item is left as it is.
+    fn increment_strict(&mut self, key: &[u8], amount: u64) -> MemCachedResult<u64> {
+        self.increment(key, amount, 0, NO_CREATE_EXPIRATION)
+    }
+
+    /// `decrement` that never creates `key`, a missing key fails with `KeyNotFound`
+    fn decrement_strict(&mut self, key: &[u8], amount: u64) -> MemCachedResult<u64> {
+        self.decrement(key, amount, 0, NO_CREATE_EXPIRATION)
+    }
+
+    /// `get` that returns `None` for a missing key instead of an error
+    fn get_opt(&mut self, key: &[u8]) -> MemCachedResult<Option<(Vec<u8>, u32)>> {
+        miss_as_none(self.get(key))
+    }
+
+    /// `getk` that returns `None` for a missing key instead of an error
+    fn getk_opt(&mut self, key: &[u8]) -> MemCachedResult<Option<(Vec<u8>, Vec<u8>, u32)>> {
+        miss_as_none(self.getk(key))
+    }
+
+    /// `delete` that returns whether the key existed instead of failing for a missing key
+    fn delete_opt(&mut self, key: &[u8]) -> MemCachedResult<bool> {
+        miss_as_none(self.delete(key)).map(|deleted| deleted.is_some())
+    }
+
+    /// Take the lock named `key` for at most `ttl` seconds, returns whether it was acquired
+    ///
+    /// The lock is a `LOCK_SENTINEL` item created with `add`, which fails while another client holds
+    /// it. The TTL releases the lock of a client that died before calling `unlock`, so it should
+    /// outlast the work done under the lock. It must not be 0, which never expires.
+    fn try_lock(&mut self, key: &[u8], ttl: u32) -> MemCachedResult<bool> {
+        assert!(ttl > 0, "lock ttl should be positive");
+        match self.add(key, LOCK_SENTINEL, 0, ttl) {
+            Ok(()) => Ok(true),
+            Err(ref err)
+                if err.status() == Some(binary::Status::KeyExists)
+                    || err.status() == Some(binary::Status::ItemNotStored) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Release the lock named `key` taken with `try_lock`
+    ///
+    /// Releasing a lock that already expired is not an error. Once the TTL has passed another client
+    /// may hold the lock, which this releases too.
+    fn unlock(&mut self, key: &[u8]) -> MemCachedResult<()> {
+        match self.delete(key) {
+            Err(ref err) if err.status() == Some(binary::Status::KeyNotFound) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Turn the `Status::KeyNotFound` error of a missing key into `None`, keeping other errors
+///
+/// This is what the `_opt` variants of operations, like `Operation::get_opt`, are made of.
+pub fn miss_as_none<T>(result: MemCachedResult<T>) -> MemCachedResult<Option<T>> {
+    match result {
+        Ok(found) => Ok(Some(found)),
+        Err(ref err) if err.status() == Some(binary::Status::KeyNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Default of the trait methods a backend has no way to provide from its required methods
+fn unsupported<T>(op: &'static str) -> MemCachedResult<T> {
+    Err(Error::OtherError {
+        desc: "Operation is not supported",
+        detail: Some(format!("`{}` is not implemented by this backend", op)),
+    })
+}
+
+/// Value of the items `Operation::try_lock` creates
+pub const LOCK_SENTINEL: &[u8] = b"locked";
+
+/// Maximum number of append attempts in `append_or_create` and `prepend_or_create` while the key
+/// keeps appearing and disappearing
+pub const OR_CREATE_MAX_ATTEMPTS: usize = 8;
+
+fn or_create<O, F>(op: &mut O, key: &[u8], value: &[u8], flags: u32, expiration: u32, concat: F) -> MemCachedResult<()>
+where
+    O: Operation + ?Sized,
+    F: Fn(&mut O, &[u8], &[u8]) -> MemCachedResult<()>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        match concat(op, key, value) {
+            Err(ref err) if err.status() == Some(binary::Status::ItemNotStored) => {}
+            result => return result,
+        }
+
+        // Someone else may have created it in the meantime, then concatenating should succeed
+        match op.add(key, value, flags, expiration) {
+            Err(ref err) if err.status() == Some(binary::Status::KeyExists) && attempts < OR_CREATE_MAX_ATTEMPTS => {
+                continue
+            }
+            result => return result,
+        }
+    }
 }
 
 pub trait CasOperation {
@@ -117,24 +507,592 @@ pub trait CasOperation {
     fn append_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64>;
     fn prepend_cas(&mut self, key: &[u8], value: &[u8], cas: u64) -> MemCachedResult<u64>;
     fn touch_cas(&mut self, key: &[u8], expiration: u32, cas: u64) -> MemCachedResult<u64>;
+    /// Delete `key` only if its CAS is still `cas`, a `cas` of 0 deletes whatever is stored
+    ///
+    /// Backends without a conditional delete keep this default, which fails.
+    fn delete_cas(&mut self, key: &[u8], cas: u64) -> MemCachedResult<()> {
+        let _ = (key, cas);
+        unsupported("delete_cas")
+    }
+    /// `delete` that returns the CAS of the deleted item, `None` if the server does not report it
+    ///
+    /// memcached itself always reports 0, other servers return the CAS of the item they removed,
+    /// which tells whether it was newer than the one the caller last saw. This default is a
+    /// `delete_cas` with a CAS of 0, which never reports one.
+    fn delete_returning_cas(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+        self.delete_cas(key, 0).map(|()| None)
+    }
+    /// Get `key` and set its expiration in one request
+    ///
+    /// Backends without a get-and-touch keep this default: a `get_cas`, then a `set_cas` of the
+    /// same value with the new expiration, which fails with `KeyExists` if the key changed in
+    /// between.
+    fn gat_item(&mut self, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+        gat_by_cas(self, key, expiration)
+    }
+
+    /// `get_cas` returning an `Item` without its key
+    fn get_item(&mut self, key: &[u8]) -> MemCachedResult<Item> {
+        self.get_cas(key).map(Item::from)
+    }
+
+    /// `getk_cas` returning an `Item` with its key
+    fn getk_item(&mut self, key: &[u8]) -> MemCachedResult<Item> {
+        self.getk_cas(key).map(Item::from)
+    }
+
+    /// `get_cas` that returns `None` for a missing key instead of an error
+    fn get_cas_opt(&mut self, key: &[u8]) -> MemCachedResult<Option<(Vec<u8>, u32, u64)>> {
+        miss_as_none(self.get_cas(key))
+    }
+
+    /// `getk_cas` that returns `None` for a missing key instead of an error
+    fn getk_cas_opt(&mut self, key: &[u8]) -> MemCachedResult<Option<(Vec<u8>, Vec<u8>, u32, u64)>> {
+        miss_as_none(self.getk_cas(key))
+    }
+
+    /// Just the CAS token of `key`, `None` if it does not exist
+    ///
+    /// Comparing it between polls tells whether the key was modified. The binary protocol has no
+    /// metadata-only get, so this is a `get_cas` whose value is dropped: it saves the caller from
+    /// keeping the value around, not from transferring it.
+    fn peek(&mut self, key: &[u8]) -> MemCachedResult<Option<u64>> {
+        Ok(self.get_cas_opt(key)?.map(|(_, _, cas)| cas))
+    }
+
+    /// `get_cas` that returns `None` when the CAS of `key` is still `known_cas`
+    ///
+    /// Polls only hand out values that changed since the caller last read them. As with `peek`,
+    /// the binary protocol cannot make the get conditional, so the comparison happens after the
+    /// value was transferred. A missing key fails with `KeyNotFound` like `get_cas`.
+    fn get_if_changed(&mut self, key: &[u8], known_cas: u64) -> MemCachedResult<Option<(Vec<u8>, u32, u64)>> {
+        let (value, flags, cas) = self.get_cas(key)?;
+        Ok(if cas == known_cas {
+            None
+        } else {
+            Some((value, flags, cas))
+        })
+    }
+
+    /// `add_cas` that describes the item already stored under `key` instead of failing with
+    /// `KeyExists`
+    ///
+    /// The binary protocol does not return the existing item with the refusal, so it is fetched
+    /// with a follow-up `get_cas`. If that item is gone by then, the `KeyExists` error is returned.
+    fn add_detailed(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<StoreOutcome> {
+        match self.add_cas(key, value, flags, expiration) {
+            Ok(cas) => Ok(StoreOutcome::Stored { cas }),
+            Err(err) if err.status() == Some(binary::Status::KeyExists) => conflict(self, key, err),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// `replace_cas` that describes the item stored under `key` when `cas` does not match it, and
+    /// returns `NotFound` for a missing key instead of an error
+    ///
+    /// A `cas` of 0 replaces whatever is stored. Like `add_detailed`, the existing item comes from
+    /// a follow-up `get_cas`.
+    fn replace_detailed(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+    ) -> MemCachedResult<StoreOutcome> {
+        match self.replace_cas(key, value, flags, expiration, cas) {
+            Ok(cas) => Ok(StoreOutcome::Stored { cas }),
+            Err(err) if err.status() == Some(binary::Status::KeyExists) => conflict(self, key, err),
+            Err(err) if err.status() == Some(binary::Status::KeyNotFound) => Ok(StoreOutcome::NotFound),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Append `value` to `key` only if the result stays within `max_len` bytes
+    ///
+    /// The current length is measured with `get_cas` and the append is issued with `append_cas`,
+    /// so another writer changing the value in between makes the append fail with `KeyExists`
+    /// instead of silently pushing the value past the cap. On such a conflict the length is
+    /// measured again and the append retried, up to `APPEND_BOUNDED_MAX_ATTEMPTS` times.
+    ///
+    /// The cap only holds against writers that also go through CAS; a plain `append` racing
+    /// between the measurement and the append still invalidates the token and is retried, but
+    /// plain appends issued after ours can grow the value further.
+    ///
+    /// Returns the CAS of the updated item, or `Error::AppendLimitExceeded` if the value would
+    /// grow beyond `max_len`.
+    fn append_bounded(&mut self, key: &[u8], value: &[u8], max_len: usize) -> MemCachedResult<u64> {
+        bounded_append(self, key, value, max_len, None)
+    }
+
+    /// `append_bounded` that also stops retrying at `deadline`, failing with `Error::Timeout`
+    fn append_bounded_until(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        max_len: usize,
+        deadline: Instant,
+    ) -> MemCachedResult<u64> {
+        bounded_append(self, key, value, max_len, Some(deadline))
+    }
+
+    /// Replace the value of `key` with `f(current value)`, keeping its flags
+    ///
+    /// Reads with `get_cas` and writes with `set_cas`, calling `f` again on the fresh value whenever
+    /// another writer got in between. There is no limit on the number of attempts, but none is
+    /// started after `deadline`; the update then fails with `Error::Timeout`. Returns the CAS of
+    /// the stored item.
+    fn update<F>(&mut self, key: &[u8], expiration: u32, deadline: Instant, mut f: F) -> MemCachedResult<u64>
+    where
+        Self: Sized,
+        F: FnMut(&[u8]) -> Vec<u8>,
+    {
+        let mut attempts = 0;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout { attempts });
+            }
+            attempts += 1;
+
+            let (current, flags, cas) = self.get_cas(key)?;
+            let value = f(&current);
+            match self.set_cas(key, &value, flags, expiration, cas) {
+                Err(ref err) if err.status() == Some(binary::Status::KeyExists) => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Get-and-touch with a `get_cas` and a `set_cas`, see `CasOperation::gat_item`
+pub(crate) fn gat_by_cas<C: CasOperation + ?Sized>(op: &mut C, key: &[u8], expiration: u32) -> MemCachedResult<Item> {
+    let (value, flags, cas) = op.get_cas(key)?;
+    let cas = op.set_cas(key, &value, flags, expiration, cas)?;
+    Ok(Item {
+        ttl: Item::ttl_after(expiration),
+        ..Item::from((value, flags, cas))
+    })
+}
+
+/// Maximum number of measure-then-append rounds `append_bounded` tries before giving up on a contended key
+pub const APPEND_BOUNDED_MAX_ATTEMPTS: usize = 8;
+
+fn bounded_append<C: CasOperation + ?Sized>(
+    op: &mut C,
+    key: &[u8],
+    value: &[u8],
+    max_len: usize,
+    deadline: Option<Instant>,
+) -> MemCachedResult<u64> {
+    let mut attempts = 0;
+    loop {
+        if attempts > 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::Timeout { attempts });
+        }
+        attempts += 1;
+
+        let (current, _, cas) = op.get_cas(key)?;
+        if current.len() + value.len() > max_len {
+            return Err(Error::AppendLimitExceeded {
+                current_len: current.len(),
+                append_len: value.len(),
+                max_len,
+            });
+        }
+
+        match op.append_cas(key, value, cas) {
+            Err(ref err)
+                if err.status() == Some(binary::Status::KeyExists) && attempts < APPEND_BOUNDED_MAX_ATTEMPTS =>
+            {
+                continue
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Version reported by a server
+///
+/// Some builds report versions that are not valid semver, like `1.6.21_1_ga4216c6`. Those keep
+/// their `raw` string with `semver` left empty.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerVersion {
+    pub semver: Option<Version>,
+    pub raw: String,
+}
+
+impl ServerVersion {
+    pub fn parse(raw: &str) -> ServerVersion {
+        ServerVersion {
+            semver: Version::parse(raw).ok(),
+            raw: raw.to_owned(),
+        }
+    }
+}
+
+impl Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
 }
 
 pub trait ServerOperation {
     fn quit(&mut self) -> MemCachedResult<()>;
     fn flush(&mut self, expiration: u32) -> MemCachedResult<()>;
     fn noop(&mut self) -> MemCachedResult<()>;
-    fn version(&mut self) -> MemCachedResult<Version>;
+    fn version(&mut self) -> MemCachedResult<ServerVersion>;
     fn stat(&mut self) -> MemCachedResult<BTreeMap<String, String>>;
+    /// Stat command with a key, e.g. `"settings"` for a group of statistics or `"reset"` for a
+    /// subcommand; `stat` is the same with an empty key
+    ///
+    /// Backends without keyed stats keep this default, which only answers the empty key.
+    fn stat_key(&mut self, key: &str) -> MemCachedResult<BTreeMap<String, String>> {
+        if key.is_empty() {
+            self.stat()
+        } else {
+            unsupported("stat_key")
+        }
+    }
+    /// Set the logging verbosity of the server
+    ///
+    /// Backends without a verbosity command keep this default, which fails.
+    fn verbosity(&mut self, level: u32) -> MemCachedResult<()> {
+        let _ = level;
+        unsupported("verbosity")
+    }
+    /// Re-establish a clean request/response boundary on the connection
+    ///
+    /// Sends a NOOP with a fresh opaque and discards everything read until its response shows up,
+    /// so leftovers of an abandoned operation (e.g. after a timeout) no longer confuse the next one.
+    ///
+    /// Protocols that cannot find a response boundary keep this default, which fails.
+    fn resync(&mut self) -> MemCachedResult<()> {
+        Err(Error::OtherError {
+            desc: "Resync is not supported",
+            detail: None,
+        })
+    }
 }
 
 pub trait MultiOperation {
     fn set_multi(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()>;
+    /// Delete every key in one pipelined batch, repeated keys are only deleted once
     fn delete_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<()>;
-    fn increment_multi<'a>(
-        &mut self,
-        kv: HashMap<&'a [u8], (u64, u64, u32)>,
-    ) -> MemCachedResult<HashMap<&'a [u8], u64>>;
+    /// Increment every key by `(amount, initial, expiration)` in one pipelined batch
+    ///
+    /// Returns the new value of each key. A key the server refused, e.g. because its value is not
+    /// a number, is left out of the result while the rest of the batch goes on.
+    fn increment_multi(&mut self, kv: HashMap<&[u8], (u64, u64, u32)>) -> MemCachedResult<HashMap<Vec<u8>, u64>>;
+    /// Get every key in one pipelined batch, repeated keys are only requested once
     fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>>;
+    /// Like `get_multi`, but calls `f(key, value, flags)` on each hit as it is read instead of
+    /// collecting them, and returns the number of hits
+    ///
+    /// The key and value are only borrowed for the call. If `f` panics or the server refuses a key,
+    /// the rest of the batch is read and dropped, so the connection stays usable.
+    ///
+    /// This default collects the hits with `get_multi` first, backends that can stream them
+    /// override it.
+    fn get_multi_foreach(&mut self, keys: &[&[u8]], f: &mut dyn FnMut(&[u8], &[u8], u32)) -> MemCachedResult<usize> {
+        let found = self.get_multi(keys)?;
+        for (key, (value, flags)) in found.iter() {
+            f(key, value, *flags);
+        }
+        Ok(found.len())
+    }
+    /// Touch every key with its own expiration in one pipelined batch
+    ///
+    /// With `dry_run`, nothing is modified: the keys are only checked for existence, and `touched`
+    /// lists the keys that would have been touched. A key given more than once is only touched with
+    /// the expiration of its first occurrence, the others are counted in `duplicates`.
+    ///
+    /// Like the other batch operations below, backends without it keep a default that fails.
+    fn touch_multi(&mut self, keys: &[(&[u8], u32)], dry_run: bool) -> MemCachedResult<TouchMultiSummary> {
+        let _ = (keys, dry_run);
+        unsupported("touch_multi")
+    }
+    /// Get every key with its flags and CAS token in one pipelined batch, repeated keys are only
+    /// requested once
+    fn gets_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32, u64)>> {
+        let _ = keys;
+        unsupported("gets_multi")
+    }
+    /// Store every `(key, value, flags, expiration, cas)` item in one pipelined batch
+    ///
+    /// Returns one result per item in input order: the new CAS token, or the error the server
+    /// answered for that item, e.g. `Status::KeyExists` when its CAS token is stale.
+    fn set_cas_multi(&mut self, items: &[(&[u8], &[u8], u32, u32, u64)]) -> MemCachedResult<Vec<MemCachedResult<u64>>> {
+        let _ = items;
+        unsupported("set_cas_multi")
+    }
+    /// Like `set_multi`, but goes on past items the server refuses and reports each of them with
+    /// its key
+    ///
+    /// `Err` is left for failures of the whole batch, e.g. a broken connection.
+    fn set_multi_collect(&mut self, kv: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<BatchResult> {
+        let _ = kv;
+        unsupported("set_multi_collect")
+    }
+    /// Like `delete_multi`, but goes on past keys the server refuses and reports each of them with
+    /// its key
+    ///
+    /// Keys that do not exist count as deleted, like in `delete_multi`.
+    fn delete_multi_collect(&mut self, keys: &[&[u8]]) -> MemCachedResult<BatchResult> {
+        let _ = keys;
+        unsupported("delete_multi_collect")
+    }
+
+    /// `gets_multi` returning an `Item` with its key for every hit
+    fn get_multi_items(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, Item>> {
+        Ok(self
+            .gets_multi(keys)?
+            .into_iter()
+            .map(|(key, (value, flags, cas))| {
+                let item = Item::from((key.clone(), value, flags, cas));
+                (key, item)
+            })
+            .collect())
+    }
+
+    /// Read-modify-write every key with `f(key, value)`, pipelining the gets and the CAS stores
+    ///
+    /// Each round fetches the pending keys with `gets_multi`, then stores the new values with
+    /// `set_cas_multi`. Only the keys that lost a CAS race are retried in the next round, at most
+    /// `max_rounds` rounds in total; keys still conflicting after that are reported in `conflicted`.
+    /// The flags of each value are kept.
+    fn cas_update_multi(
+        &mut self,
+        keys: &[&[u8]],
+        expiration: u32,
+        max_rounds: usize,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Vec<u8>,
+    ) -> MemCachedResult<CasUpdateSummary> {
+        let (mut pending, _) = dedup_keys(keys, |key| *key);
+        let mut summary = CasUpdateSummary::default();
+        while !pending.is_empty() && summary.rounds < max_rounds {
+            summary.rounds += 1;
+            let fetched = self.gets_multi(&pending)?;
+
+            let mut items = Vec::with_capacity(fetched.len());
+            let mut values = Vec::with_capacity(fetched.len());
+            for &key in pending.iter() {
+                match fetched.get(key) {
+                    Some((value, flags, cas)) => {
+                        values.push(f(key, value));
+                        items.push((key, *flags, *cas));
+                    }
+                    None => summary.missing.push(key.to_vec()),
+                }
+            }
+
+            let batch: Vec<_> = items
+                .iter()
+                .zip(values.iter())
+                .map(|(&(key, flags, cas), value)| (key, &value[..], flags, expiration, cas))
+                .collect();
+            let results = self.set_cas_multi(&batch)?;
+
+            let mut conflicted = Vec::new();
+            for (&(key, _, _), result) in items.iter().zip(results) {
+                match result {
+                    Ok(_) => summary.updated.push(key.to_vec()),
+                    Err(ref err) if err.status() == Some(binary::Status::KeyExists) => conflicted.push(key),
+                    // Deleted between the get and the store
+                    Err(ref err) if err.status() == Some(binary::Status::KeyNotFound) => {
+                        summary.missing.push(key.to_vec())
+                    }
+                    Err(err) => summary.errors.push((key.to_vec(), err)),
+                }
+            }
+            pending = conflicted;
+        }
+        summary.conflicted.extend(pending.into_iter().map(|key| key.to_vec()));
+        Ok(summary)
+    }
+}
+
+/// Per-key outcome of `MultiOperation::touch_multi`
+#[derive(Debug, Default)]
+pub struct TouchMultiSummary {
+    /// Keys that were touched, or exist in a dry run
+    pub touched: Vec<Vec<u8>>,
+    /// Keys that do not exist
+    pub missing: Vec<Vec<u8>>,
+    /// Keys the server answered with an error
+    pub errors: Vec<(Vec<u8>, Error)>,
+    /// Repeated keys that were skipped, only the first occurrence of a key is touched
+    pub duplicates: usize,
+}
+
+impl TouchMultiSummary {
+    /// Add the outcome of another batch, e.g. of another server
+    pub fn merge(&mut self, other: TouchMultiSummary) {
+        self.touched.extend(other.touched);
+        self.missing.extend(other.missing);
+        self.errors.extend(other.errors);
+        self.duplicates += other.duplicates;
+    }
+}
+
+/// Metadata of an `Item` that only some operations report
+///
+/// Empty for now, every field added later is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ItemExtras {}
+
+/// An item read from a server, returned by `get_item` and the other `*_item` operations
+///
+/// Unlike the tuples of `get_cas` and friends, it can grow new metadata without breaking callers.
+/// The tuples convert to and from it. The tuple returning operations are not deprecated for now,
+/// see the changelog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    /// The key, for operations that return it
+    pub key: Option<Bytes>,
+    pub value: Bytes,
+    pub flags: u32,
+    /// The CAS token, for operations that return it
+    pub cas: Option<u64>,
+    /// Seconds left before the item expires, when the operation knows it
+    ///
+    /// The binary protocol never reports expirations, so only `gat_item` with a relative
+    /// expiration fills it in, with the expiration it just set.
+    pub ttl: Option<u32>,
+    pub extra: ItemExtras,
+}
+
+impl Item {
+    /// An item with only a value and flags
+    pub fn new(value: impl Into<Bytes>, flags: u32) -> Item {
+        Item {
+            key: None,
+            value: value.into(),
+            flags,
+            cas: None,
+            ttl: None,
+            extra: ItemExtras::default(),
+        }
+    }
+
+    /// The TTL of an item whose expiration was just set to `expiration`, only known for a relative
+    /// one: 0 never expires, and unix times depend on the clock of the server
+    pub(crate) fn ttl_after(expiration: u32) -> Option<u32> {
+        match expiration {
+            1..=RELATIVE_EXPIRATION_MAX => Some(expiration),
+            _ => None,
+        }
+    }
+}
+
+impl From<(Vec<u8>, u32)> for Item {
+    fn from((value, flags): (Vec<u8>, u32)) -> Item {
+        Item::new(value, flags)
+    }
+}
+
+impl From<(Vec<u8>, u32, u64)> for Item {
+    fn from((value, flags, cas): (Vec<u8>, u32, u64)) -> Item {
+        Item {
+            cas: Some(cas),
+            ..Item::new(value, flags)
+        }
+    }
+}
+
+impl From<(Vec<u8>, Vec<u8>, u32, u64)> for Item {
+    fn from((key, value, flags, cas): (Vec<u8>, Vec<u8>, u32, u64)) -> Item {
+        Item {
+            key: Some(key.into()),
+            cas: Some(cas),
+            ..Item::new(value, flags)
+        }
+    }
+}
+
+impl From<Item> for (Vec<u8>, u32) {
+    fn from(item: Item) -> (Vec<u8>, u32) {
+        (item.value.into(), item.flags)
+    }
+}
+
+/// Outcome of `CasOperation::add_detailed` and `replace_detailed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOutcome {
+    /// The value was stored, with this CAS token
+    Stored { cas: u64 },
+    /// The item stored under the key refused the value: it exists for an add, or has another CAS
+    /// token for a replace
+    Conflict {
+        existing_flags: u32,
+        existing_size: usize,
+        existing_cas: u64,
+    },
+    /// There is nothing to replace
+    NotFound,
+}
+
+/// The `Conflict` describing the item that made a store of `key` fail with `refused`
+fn conflict<P: CasOperation + ?Sized>(proto: &mut P, key: &[u8], refused: Error) -> MemCachedResult<StoreOutcome> {
+    match proto.get_cas_opt(key)? {
+        Some((value, flags, cas)) => Ok(StoreOutcome::Conflict {
+            existing_flags: flags,
+            existing_size: value.len(),
+            existing_cas: cas,
+        }),
+        None => Err(refused),
+    }
+}
+
+/// Outcome of `MultiOperation::set_multi_collect` and `delete_multi_collect`
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    /// Number of items the server accepted
+    pub succeeded: usize,
+    /// Keys the server answered with an error, with that error
+    pub failures: Vec<(Vec<u8>, Error)>,
+}
+
+impl BatchResult {
+    /// Whether every item succeeded
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Add the outcome of another batch, e.g. of another server
+    pub fn merge(&mut self, other: BatchResult) {
+        self.succeeded += other.succeeded;
+        self.failures.extend(other.failures);
+    }
+}
+
+/// Per-key outcome of `MultiOperation::cas_update_multi`
+#[derive(Debug, Default)]
+pub struct CasUpdateSummary {
+    /// Keys whose new value was stored
+    pub updated: Vec<Vec<u8>>,
+    /// Keys that do not exist, or were deleted before their value could be stored
+    pub missing: Vec<Vec<u8>>,
+    /// Keys that still lost their CAS race in the last round
+    pub conflicted: Vec<Vec<u8>>,
+    /// Keys the server answered with another error
+    pub errors: Vec<(Vec<u8>, Error)>,
+    /// Number of get/store rounds that were run
+    pub rounds: usize,
+}
+
+/// Drop repeated keys from a multi operation's input, keeping the first occurrence of each
+///
+/// Returns the remaining items in their original order and the number of items dropped.
+pub(crate) fn dedup_keys<'a, T, F>(items: &[T], key_of: F) -> (Vec<T>, usize)
+where
+    T: Copy,
+    F: Fn(&T) -> &'a [u8],
+{
+    let mut seen = HashSet::with_capacity(items.len());
+    let unique: Vec<T> = items.iter().filter(|item| seen.insert(key_of(item))).cloned().collect();
+    let duplicates = items.len() - unique.len();
+    (unique, duplicates)
 }
 
 pub trait NoReplyOperation {
@@ -146,6 +1104,46 @@ pub trait NoReplyOperation {
     fn decrement_noreply(&mut self, key: &[u8], amount: u64, initial: u64, expiration: u32) -> MemCachedResult<()>;
     fn append_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()>;
     fn prepend_noreply(&mut self, key: &[u8], value: &[u8]) -> MemCachedResult<()>;
+
+    /// Like `set_noreply`, but fails with `io::ErrorKind::WouldBlock` instead of waiting for the
+    /// server when the outstanding bytes limit would be exceeded
+    ///
+    /// This default is `set_noreply`, for backends without such a limit.
+    fn try_set_noreply(&mut self, key: &[u8], value: &[u8], flags: u32, expiration: u32) -> MemCachedResult<()> {
+        self.set_noreply(key, value, flags, expiration)
+    }
+
+    /// Write out the noreply requests still held in the write buffer, without waiting for the
+    /// server to process them
+    ///
+    /// Backends writing out each request right away keep this default, which does nothing.
+    fn send_pending(&mut self) -> MemCachedResult<()> {
+        Ok(())
+    }
+
+    /// Wait for the server to process every noreply request sent so far and return the errors they
+    /// caused, including those collected while waiting for room under the outstanding bytes limit
+    ///
+    /// Backends that do not collect these errors keep this default, which fails.
+    fn drain_errors(&mut self) -> MemCachedResult<Vec<Error>> {
+        unsupported("drain_errors")
+    }
+}
+
+/// Kind of a single-key operation, for computing sizes without building packets
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpKind {
+    Set,
+    Add,
+    Replace,
+    Delete,
+    Get,
+    GetKey,
+    Increment,
+    Decrement,
+    Append,
+    Prepend,
+    Touch,
 }
 
 #[derive(Debug)]
@@ -160,3 +1158,100 @@ pub trait AuthOperation {
     fn auth_start(&mut self, mech: &str, init: &[u8]) -> MemCachedResult<AuthResponse>;
     fn auth_continue(&mut self, mech: &str, data: &[u8]) -> MemCachedResult<AuthResponse>;
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::{binary, Error, MemCachedResult, MultiOperation, Operation};
+
+    /// A backend implementing only the methods the traits required before the defaults were added
+    #[derive(Default)]
+    struct Minimal {
+        items: HashMap<Vec<u8>, (Vec<u8>, u32)>,
+    }
+
+    fn not_found() -> Error {
+        Error::BinaryProtoError(binary::Error::from_status(binary::Status::KeyNotFound, None))
+    }
+
+    impl Operation for Minimal {
+        fn set(&mut self, key: &[u8], value: &[u8], flags: u32, _: u32) -> MemCachedResult<()> {
+            self.items.insert(key.to_vec(), (value.to_vec(), flags));
+            Ok(())
+        }
+        fn add(&mut self, _: &[u8], _: &[u8], _: u32, _: u32) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn delete(&mut self, _: &[u8]) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn replace(&mut self, _: &[u8], _: &[u8], _: u32, _: u32) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn get(&mut self, key: &[u8]) -> MemCachedResult<(Vec<u8>, u32)> {
+            self.items.get(key).cloned().ok_or_else(not_found)
+        }
+        fn getk(&mut self, _: &[u8]) -> MemCachedResult<(Vec<u8>, Vec<u8>, u32)> {
+            unimplemented!()
+        }
+        fn increment(&mut self, _: &[u8], _: u64, _: u64, _: u32) -> MemCachedResult<u64> {
+            unimplemented!()
+        }
+        fn decrement(&mut self, _: &[u8], _: u64, _: u64, _: u32) -> MemCachedResult<u64> {
+            unimplemented!()
+        }
+        fn append(&mut self, _: &[u8], _: &[u8]) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn prepend(&mut self, _: &[u8], _: &[u8]) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn touch(&mut self, _: &[u8], _: u32) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+    }
+
+    impl MultiOperation for Minimal {
+        fn set_multi(&mut self, _: BTreeMap<&[u8], (&[u8], u32, u32)>) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn delete_multi(&mut self, _: &[&[u8]]) -> MemCachedResult<()> {
+            unimplemented!()
+        }
+        fn increment_multi(&mut self, _: HashMap<&[u8], (u64, u64, u32)>) -> MemCachedResult<HashMap<Vec<u8>, u64>> {
+            unimplemented!()
+        }
+        fn get_multi(&mut self, keys: &[&[u8]]) -> MemCachedResult<HashMap<Vec<u8>, (Vec<u8>, u32)>> {
+            Ok(keys
+                .iter()
+                .filter_map(|&key| Some((key.to_vec(), self.items.get(key)?.clone())))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_defaults_of_added_methods() {
+        let mut backend = Minimal::default();
+        backend.set(b"key", b"value", 1, 0).unwrap();
+        assert!(backend.exists(b"key").unwrap());
+        assert!(!backend.exists(b"missing").unwrap());
+
+        let mut hits = Vec::new();
+        let found = backend
+            .get_multi_foreach(&[b"key", b"missing"], &mut |key, value, flags| {
+                hits.push((key.to_vec(), value.to_vec(), flags))
+            })
+            .unwrap();
+        assert_eq!(found, 1);
+        assert_eq!(hits, vec![(b"key".to_vec(), b"value".to_vec(), 1)]);
+
+        match backend.touch_multi(&[(b"key", 10)], false) {
+            Err(Error::OtherError { desc, detail }) => {
+                assert_eq!(desc, "Operation is not supported");
+                assert!(detail.unwrap().contains("touch_multi"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Picking the opaques requests are matched with their responses by
+//!
+//! Opaques are random with the `fastrand` feature, the default, and count up per connection
+//! without it. Either way only the opaques of one batch are guaranteed to be distinct.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Where a connection takes the opaques of its requests from
+#[cfg(feature = "fastrand")]
+pub(crate) type OpaqueSource = RandomOpaques;
+
+/// Where a connection takes the opaques of its requests from
+#[cfg(not(feature = "fastrand"))]
+pub(crate) type OpaqueSource = SequentialOpaques;
+
+/// A random opaque for each request, and for the first request of each batch
+#[cfg(feature = "fastrand")]
+#[derive(Debug, Default)]
+pub(crate) struct RandomOpaques;
+
+#[cfg(feature = "fastrand")]
+impl RandomOpaques {
+    pub(crate) fn next(&self) -> u32 {
+        fastrand::u32(..)
+    }
+
+    /// The first of `count` consecutive opaques, which may wrap around
+    pub(crate) fn reserve(&self, _count: usize) -> u32 {
+        fastrand::u32(..)
+    }
+}
+
+/// Opaques counting up from 1, never 0
+///
+/// A batch that would run through 0 starts over at 1 instead, so the opaques of a batch are
+/// always increasing.
+#[derive(Debug)]
+#[cfg_attr(feature = "fastrand", allow(dead_code))]
+pub(crate) struct SequentialOpaques {
+    /// The next opaque, 0 once the last one was `u32::MAX`
+    next: AtomicU32,
+}
+
+impl Default for SequentialOpaques {
+    fn default() -> SequentialOpaques {
+        SequentialOpaques {
+            next: AtomicU32::new(1),
+        }
+    }
+}
+
+#[cfg_attr(feature = "fastrand", allow(dead_code))]
+impl SequentialOpaques {
+    pub(crate) fn next(&self) -> u32 {
+        self.reserve(1)
+    }
+
+    /// The first of `count` consecutive opaques
+    pub(crate) fn reserve(&self, count: usize) -> u32 {
+        assert!(count < u32::MAX as usize, "batch of {} requests has too many to tell apart", count);
+        let count = count.max(1) as u32;
+        let mut first = 1;
+        // The update never gives up, so it cannot fail
+        let _ = self.next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            first = match next.checked_add(count - 1) {
+                Some(_) if next != 0 => next,
+                _ => 1,
+            };
+            Some(first.wrapping_add(count))
+        });
+        first
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU32;
+
+    use super::SequentialOpaques;
+
+    #[test]
+    fn test_sequential_opaques() {
+        let opaques = SequentialOpaques::default();
+        assert_eq!(opaques.next(), 1);
+        assert_eq!(opaques.reserve(3), 2);
+        assert_eq!(opaques.next(), 5);
+        // An empty batch still takes one, so it cannot share it with the next request
+        assert_eq!(opaques.reserve(0), 6);
+        assert_eq!(opaques.next(), 7);
+
+        let opaques = SequentialOpaques {
+            next: AtomicU32::new(u32::MAX - 3),
+        };
+        assert_eq!(opaques.reserve(3), u32::MAX - 3);
+        assert_eq!(opaques.next(), u32::MAX);
+        assert_eq!(opaques.next(), 1);
+
+        // A batch that does not fit before 0 starts over
+        let opaques = SequentialOpaques {
+            next: AtomicU32::new(u32::MAX - 1),
+        };
+        assert_eq!(opaques.reserve(5), 1);
+        assert_eq!(opaques.next(), 6);
+
+        let opaques = SequentialOpaques {
+            next: AtomicU32::new(u32::MAX - 1000),
+        };
+        let taken: Vec<u32> = (0..3000).map(|_| opaques.next()).collect();
+        assert!(!taken.contains(&0));
+        assert!(taken.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+}
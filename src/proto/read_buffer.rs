@@ -0,0 +1,122 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Recycling the buffers responses are read into
+
+use bytes::BytesMut;
+
+/// Counters of a `ReadBufferPool`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadBufferStats {
+    /// Bodies read into the free space left in the current buffer
+    pub pooled: u64,
+    /// Bodies read into the current buffer again after all values read into it were dropped
+    pub reclaimed: u64,
+    /// Buffers allocated because the values of the current one were still alive
+    pub allocated: u64,
+    /// Bodies too large to be pooled, each read into an allocation of its own
+    pub unpooled: u64,
+}
+
+/// Response bodies are carved out of one buffer of `capacity` bytes
+///
+/// The returned `Bytes` share that buffer, so it is only reused once every value read into it has
+/// been dropped; until then bodies go into the space left, then into a new buffer. A value that
+/// is kept for long keeps its whole buffer alive. Bodies over a quarter of `capacity` are not
+/// pooled, so a few large values do not keep replacing the buffer.
+#[derive(Debug)]
+pub struct ReadBufferPool {
+    buffer: BytesMut,
+    capacity: usize,
+    stats: ReadBufferStats,
+}
+
+impl ReadBufferPool {
+    pub fn new(capacity: usize) -> ReadBufferPool {
+        ReadBufferPool {
+            buffer: BytesMut::new(),
+            capacity,
+            stats: ReadBufferStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> ReadBufferStats {
+        self.stats
+    }
+
+    /// A buffer of `len` uninitialized bytes
+    pub(crate) fn take(&mut self, len: usize) -> BytesMut {
+        if len > self.capacity / 4 {
+            self.stats.unpooled += 1;
+            let mut buf = BytesMut::with_capacity(len);
+            unsafe {
+                buf.set_len(len);
+            }
+            return buf;
+        }
+
+        if self.buffer.capacity() >= len {
+            self.stats.pooled += 1;
+        } else if self.buffer.try_reclaim(len) {
+            self.stats.reclaimed += 1;
+        } else {
+            self.stats.allocated += 1;
+            self.buffer = BytesMut::with_capacity(self.capacity);
+        }
+        unsafe {
+            self.buffer.set_len(len);
+        }
+        self.buffer.split_to(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReadBufferPool, ReadBufferStats};
+
+    #[test]
+    fn test_read_buffer_pool() {
+        let mut pool = ReadBufferPool::new(1024);
+
+        // Dropped right away, the same buffer serves every body
+        for _ in 0..100 {
+            let buf = pool.take(200).freeze();
+            assert_eq!(buf.len(), 200);
+        }
+        assert_eq!(
+            pool.stats(),
+            ReadBufferStats {
+                pooled: 80,
+                reclaimed: 19,
+                allocated: 1,
+                unpooled: 0,
+            }
+        );
+
+        // A value that is kept pins its buffer
+        let kept = pool.take(200).freeze();
+        let ptr = kept.as_ptr();
+        let others: Vec<_> = (0..5).map(|_| pool.take(200).freeze()).collect();
+        assert_eq!(pool.stats().allocated, 2);
+        drop(others);
+        let buf = pool.take(200);
+        assert_eq!(pool.stats().allocated, 2);
+        assert_ne!(buf.as_ptr(), ptr);
+        assert_eq!(kept.as_ptr(), ptr);
+
+        let large = pool.take(257);
+        assert_eq!((large.len(), pool.stats().unpooled), (257, 1));
+        let empty = pool.take(0);
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Random numbers for jitter, unique names and test data
+//!
+//! They come from `fastrand`, or with the `fastrand` feature off from a wyrand generator of our
+//! own. Neither is fit for anything that has to be unpredictable.
+
+#[cfg(feature = "fastrand")]
+pub(crate) use fastrand::{u64, Rng};
+
+#[cfg(not(feature = "fastrand"))]
+pub(crate) use self::wyrand::{u64, Rng};
+
+#[cfg(not(feature = "fastrand"))]
+mod wyrand {
+    use std::cell::Cell;
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::ops::{Bound, RangeBounds};
+
+    thread_local! {
+        static RNG: Rng = Rng::with_seed(RandomState::new().build_hasher().finish());
+    }
+
+    /// A random number in `range`, from a generator of the current thread
+    pub(crate) fn u64(range: impl RangeBounds<u64>) -> u64 {
+        RNG.with(|rng| rng.u64(range))
+    }
+
+    /// The generator of `fastrand`, the same sequence of `u64` for the same seed
+    ///
+    /// Ranges are sampled by remainder, which is slightly biased for spans that are not a power of
+    /// two, unlike `fastrand`.
+    pub(crate) struct Rng(Cell<u64>);
+
+    impl Rng {
+        pub(crate) fn with_seed(seed: u64) -> Rng {
+            Rng(Cell::new(seed))
+        }
+
+        fn gen_u64(&self) -> u64 {
+            let state = self.0.get().wrapping_add(0xa076_1d64_78bd_642f);
+            self.0.set(state);
+            let mixed = u128::from(state) * u128::from(state ^ 0xe703_7ed1_a0b4_28db);
+            (mixed as u64) ^ (mixed >> 64) as u64
+        }
+
+        pub(crate) fn u64(&self, range: impl RangeBounds<u64>) -> u64 {
+            let low = match range.start_bound() {
+                Bound::Included(&low) => low,
+                Bound::Excluded(&low) => low.checked_add(1).expect("empty range"),
+                Bound::Unbounded => 0,
+            };
+            let high = match range.end_bound() {
+                Bound::Included(&high) => high,
+                Bound::Excluded(&high) => high.checked_sub(1).expect("empty range"),
+                Bound::Unbounded => u64::MAX,
+            };
+            assert!(low <= high, "empty range");
+            match (high - low).checked_add(1) {
+                Some(span) => low + self.gen_u64() % span,
+                None => self.gen_u64(),
+            }
+        }
+
+        pub(crate) fn alphanumeric(&self) -> char {
+            const CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+            CHARS[self.u64(..CHARS.len() as u64) as usize] as char
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::Rng;
+
+        #[test]
+        fn test_rng() {
+            let (a, b) = (Rng::with_seed(42), Rng::with_seed(42));
+            let drawn: Vec<u64> = (0..100).map(|_| a.u64(..)).collect();
+            assert_eq!(drawn, (0..100).map(|_| b.u64(..)).collect::<Vec<_>>());
+            assert!(drawn.windows(2).all(|pair| pair[0] != pair[1]));
+
+            for _ in 0..1000 {
+                assert!((10..20).contains(&a.u64(10..20)));
+                assert!(a.u64(..=3) <= 3);
+                assert!(a.alphanumeric().is_ascii_alphanumeric());
+            }
+            assert_eq!(a.u64(7..=7), 7);
+            assert!(super::u64(..) != super::u64(..));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Predicting key placement before servers are added or removed
+//!
+//! Everything here runs on the hash ring alone, without connecting to any server. Keys are placed
+//! exactly like a `Client` connected to the same servers with the same `replicas_per_node` places
+//! them, as long as it does not hash long keys (`ClientBuilder::auto_hash_long_keys`).
+
+use std::collections::BTreeMap;
+
+use conhash::Node;
+
+use crate::client::{ring_points, Ring};
+use crate::random::Rng;
+
+/// A server on the simulated ring, named by its address like the servers of a `Client`
+#[derive(Clone)]
+struct Named(String);
+
+impl Node for Named {
+    fn name(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// Which server owns each key for a set of servers
+pub struct Placement {
+    ring: Ring<Named>,
+    /// `(address, weight)` in the order they were given
+    servers: Vec<(String, usize)>,
+}
+
+impl Placement {
+    /// Place keys on `servers`, `(address, weight)` pairs in the form `Client::connect` takes
+    pub fn new<S: ToString>(servers: &[(S, usize)], replicas_per_node: usize) -> Placement {
+        let mut ring = Ring::new();
+        let servers: Vec<(String, usize)> = servers
+            .iter()
+            .map(|(addr, weight)| (addr.to_string(), *weight))
+            .collect();
+        for (addr, weight) in servers.iter() {
+            ring.add(Named(addr.clone()), ring_points(*weight, replicas_per_node));
+        }
+        Placement { ring, servers }
+    }
+
+    /// Address of the server that owns `key`, `None` without servers
+    pub fn owner(&self, key: &[u8]) -> Option<&str> {
+        self.ring.get(key).map(|node| &node.0[..])
+    }
+
+    /// Fraction of the keys each server should get by its weight
+    fn fair_shares(&self) -> BTreeMap<String, f64> {
+        let total: usize = self.servers.iter().map(|&(_, weight)| weight).sum();
+        self.servers
+            .iter()
+            .map(|(addr, weight)| (addr.clone(), *weight as f64 / total.max(1) as f64))
+            .collect()
+    }
+}
+
+/// What changing the servers does to the placement of a sample of keys, returned by `simulate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RebalanceReport {
+    /// Keys sampled
+    pub keys: usize,
+    /// Fraction of the keys owned by another server after the change
+    pub moved_fraction: f64,
+    /// Fraction of the keys each server owns before the change, servers owning none included
+    pub share_before: BTreeMap<String, f64>,
+    /// Fraction of the keys each server owns after the change, servers owning none included
+    pub share_after: BTreeMap<String, f64>,
+    /// Largest share of a server after the change over its fair share by weight, 1.0 when every
+    /// server gets exactly its fair share
+    pub max_imbalance: f64,
+}
+
+/// Place `keys` on the servers `before` and `after` a change and compare
+///
+/// Servers are `(address, weight)` pairs in the form `Client::connect` takes, and
+/// `replicas_per_node` is the one set with `ClientBuilder::replicas_per_node`. `keys` can be any
+/// iterator of keys, e.g. `sequential_keys`, `random_keys` or the keys of a dump.
+pub fn simulate<S, K, I>(
+    before: &[(S, usize)],
+    after: &[(S, usize)],
+    replicas_per_node: usize,
+    keys: I,
+) -> RebalanceReport
+where
+    S: ToString,
+    K: AsRef<[u8]>,
+    I: IntoIterator<Item = K>,
+{
+    let before = Placement::new(before, replicas_per_node);
+    let after = Placement::new(after, replicas_per_node);
+    let mut counts_before: BTreeMap<String, usize> = before.servers.iter().map(|(addr, _)| (addr.clone(), 0)).collect();
+    let mut counts_after: BTreeMap<String, usize> = after.servers.iter().map(|(addr, _)| (addr.clone(), 0)).collect();
+
+    let (mut sampled, mut moved) = (0, 0);
+    for key in keys {
+        let key = key.as_ref();
+        let (owner_before, owner_after) = (before.owner(key), after.owner(key));
+        if let Some(owner) = owner_before {
+            *counts_before.get_mut(owner).unwrap() += 1;
+        }
+        if let Some(owner) = owner_after {
+            *counts_after.get_mut(owner).unwrap() += 1;
+        }
+        if owner_before != owner_after {
+            moved += 1;
+        }
+        sampled += 1;
+    }
+
+    let shares = |counts: BTreeMap<String, usize>| -> BTreeMap<String, f64> {
+        counts
+            .into_iter()
+            .map(|(addr, count)| (addr, fraction(count, sampled)))
+            .collect()
+    };
+    let share_before = shares(counts_before);
+    let share_after = shares(counts_after);
+    let max_imbalance = after
+        .fair_shares()
+        .iter()
+        .filter(|&(_, &fair)| fair > 0.0)
+        .map(|(addr, fair)| share_after[addr] / fair)
+        .fold(0.0, f64::max);
+    RebalanceReport {
+        keys: sampled,
+        moved_fraction: fraction(moved, sampled),
+        share_before,
+        share_after,
+        max_imbalance,
+    }
+}
+
+fn fraction(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+/// `n` keys `"{prefix}0"`, `"{prefix}1"` and so on
+pub fn sequential_keys(prefix: &str, n: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+    (0..n).map(move |i| format!("{}{}", prefix, i).into_bytes())
+}
+
+/// `n` keys of `len` random alphanumeric characters, the same ones for the same `seed`
+pub fn random_keys(n: usize, len: usize, seed: u64) -> impl Iterator<Item = Vec<u8>> {
+    let rng = Rng::with_seed(seed);
+    (0..n).map(move |_| (0..len).map(|_| rng.alphanumeric() as u8).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{random_keys, sequential_keys, simulate, Placement};
+    use crate::client::Client;
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    fn servers(n: usize) -> Vec<(String, usize)> {
+        (0..n).map(|i| (format!("tcp://10.0.0.{}:11211", i), 1)).collect()
+    }
+
+    #[test]
+    fn test_simulate() {
+        let report = simulate(&servers(4), &servers(5), 100, sequential_keys("test:ring_", 10000));
+        assert_eq!(report.keys, 10000);
+        // A fifth server takes about a fifth of the keys, and only keys moving to it move
+        assert!(report.moved_fraction > 0.1 && report.moved_fraction < 0.3, "{:?}", report);
+        assert_eq!(report.share_before.len(), 4);
+        assert!((report.share_after["tcp://10.0.0.4:11211"] - report.moved_fraction).abs() < 1e-9);
+        assert!(report.max_imbalance >= 1.0 && report.max_imbalance < 1.5, "{:?}", report);
+        let total: f64 = report.share_after.values().sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        let unchanged = simulate(&servers(3), &servers(3), 100, random_keys(1000, 16, 7));
+        assert_eq!(unchanged.moved_fraction, 0.0);
+        assert_eq!(unchanged.share_before, unchanged.share_after);
+        assert_eq!(random_keys(3, 16, 7).collect::<Vec<_>>(), random_keys(3, 16, 7).collect::<Vec<_>>());
+
+        // Twice the weight is twice the fair share
+        let mut weighted = servers(2);
+        weighted[0].1 = 2;
+        let report = simulate(&servers(2), &weighted, 100, sequential_keys("test:ring_", 10000));
+        assert!(report.share_after["tcp://10.0.0.0:11211"] > 0.55, "{:?}", report);
+
+        let empty = simulate(&servers(1), &servers(2), 100, Vec::<Vec<u8>>::new());
+        assert_eq!((empty.keys, empty.moved_fraction), (0, 0.0));
+    }
+
+    #[test]
+    fn test_placement_matches_client() {
+        let mocks: Vec<MockServer> = (0..3).map(|_| MockServer::start("127.0.0.1:0").unwrap()).collect();
+        let servers: Vec<(String, usize)> = mocks
+            .iter()
+            .zip(1..)
+            .map(|(mock, weight)| (mock.url(), weight))
+            .collect();
+        let client = Client::builder(ProtoType::Binary)
+            .add_server(&servers[0].0, 1)
+            .add_server(&servers[1].0, 2)
+            .add_server(&servers[2].0, 3)
+            .replicas_per_node(40)
+            .build()
+            .unwrap();
+
+        let placement = Placement::new(&servers, 40);
+        for key in sequential_keys("test:ring_", 2000).chain(random_keys(2000, 32, 1)) {
+            assert_eq!(placement.owner(&key), Some(&client.route_chain(&key, 0)[0][..]));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Randomly killing and restarting servers
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use super::MockServer;
+use crate::random;
+
+/// How often and for how long servers go down
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    /// Pause between two kills
+    pub interval: Duration,
+    /// How long a killed server stays down
+    pub downtime: Duration,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+        ChaosConfig {
+            interval: Duration::from_millis(500),
+            downtime: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Background thread stopping a random server every `interval` and restarting it after `downtime`
+pub struct Chaos {
+    stop: Arc<AtomicBool>,
+    kills: Arc<AtomicUsize>,
+    handle: JoinHandle<Vec<MockServer>>,
+}
+
+impl Chaos {
+    pub fn spawn(mut servers: Vec<MockServer>, config: ChaosConfig) -> Chaos {
+        assert!(!servers.is_empty(), "chaos needs at least one server");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let kills = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let stop = stop.clone();
+            let kills = kills.clone();
+            thread::spawn(move || {
+                while !sleep_unless_stopped(&stop, config.interval) {
+                    let victim = random::u64(..servers.len() as u64) as usize;
+                    let server = &mut servers[victim];
+                    debug!("Chaos killing {}", server.addr());
+                    server.stop();
+                    kills.fetch_add(1, Ordering::SeqCst);
+
+                    sleep_unless_stopped(&stop, config.downtime);
+                    if let Err(err) = server.restart() {
+                        warn!("Chaos failed to restart {}: {}", server.addr(), err);
+                    }
+                }
+                servers
+            })
+        };
+
+        Chaos { stop, kills, handle }
+    }
+
+    /// Number of servers killed so far
+    pub fn kills(&self) -> usize {
+        self.kills.load(Ordering::SeqCst)
+    }
+
+    /// Stop killing servers and hand them back, all running
+    pub fn stop(self) -> Vec<MockServer> {
+        self.stop.store(true, Ordering::SeqCst);
+        let mut servers = self.handle.join().expect("chaos thread panicked");
+        for server in servers.iter_mut().filter(|server| !server.is_running()) {
+            server.restart().expect("failed to restart server");
+        }
+        servers
+    }
+}
+
+/// Returns whether stopping was requested
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) -> bool {
+    let step = Duration::from_millis(10);
+    let mut slept = Duration::from_millis(0);
+    while slept < duration {
+        if stop.load(Ordering::SeqCst) {
+            return true;
+        }
+        thread::sleep(step);
+        slept += step;
+    }
+    stop.load(Ordering::SeqCst)
+}
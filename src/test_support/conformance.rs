@@ -0,0 +1,70 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Checks that a backend keeps the contract of the operation traits
+
+use std::fmt::Debug;
+
+use crate::proto::binary::Status;
+use crate::proto::{MemCachedResult, Operation};
+
+fn expect<T: Debug + PartialEq>(case: &str, got: MemCachedResult<T>, want: Result<T, Status>) -> Result<(), String> {
+    let got = got.map_err(|err| err.status());
+    let matches = match (&got, &want) {
+        (Ok(got), Ok(want)) => got == want,
+        (Err(got), Err(want)) => *got == Some(*want),
+        _ => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(format!("{}: expected {:?}, got {:?}", case, want, got))
+    }
+}
+
+/// Run the arithmetic of `Operation` on keys starting with `prefix` and compare with the contract
+///
+/// Covers a missing key with `increment`/`decrement` (created with the initial value) and their
+/// `_strict` variants (`KeyNotFound`, nothing created), a numeric value, a value that is not a
+/// number (`IncrDecrOnNonNumericValue`), an increment wrapping around past `u64::MAX` and a
+/// decrement stopping at 0. Fails with the first case that does not match.
+pub fn check_arithmetic<P: Operation>(client: &mut P, prefix: &str) -> Result<(), String> {
+    let key = |name: &str| format!("{}{}", prefix, name).into_bytes();
+    let (missing, number, text, large) = (key("missing"), key("number"), key("text"), key("large"));
+    for key in [&missing, &number, &text, &large] {
+        let _ = client.delete(key);
+    }
+
+    expect("increment_strict of a missing key", client.increment_strict(&missing, 1), Err(Status::KeyNotFound))?;
+    expect("decrement_strict of a missing key", client.decrement_strict(&missing, 1), Err(Status::KeyNotFound))?;
+    expect("get after a strict miss", client.get(&missing).map(|_| ()), Err(Status::KeyNotFound))?;
+    expect("increment of a missing key", client.increment(&missing, 3, 5, 0), Ok(5))?;
+    let _ = client.delete(&missing);
+    expect("decrement of a missing key", client.decrement(&missing, 3, 5, 0), Ok(5))?;
+
+    client.set(&number, b"10", 0, 0).map_err(|err| err.to_string())?;
+    expect("increment of a number", client.increment(&number, 3, 100, 0), Ok(13))?;
+    expect("increment_strict of a number", client.increment_strict(&number, 3), Ok(16))?;
+    expect("decrement_strict of a number", client.decrement_strict(&number, 6), Ok(10))?;
+    expect("decrement below 0", client.decrement(&number, 20, 100, 0), Ok(0))?;
+    expect("get of the result", client.get(&number), Ok((b"0".to_vec(), 0)))?;
+
+    client.set(&text, b"ten", 0, 0).map_err(|err| err.to_string())?;
+    let not_a_number = Err(Status::IncrDecrOnNonNumericValue);
+    expect("increment of text", client.increment(&text, 1, 0, 0), not_a_number)?;
+    expect("increment_strict of text", client.increment_strict(&text, 1), not_a_number)?;
+    expect("decrement of text", client.decrement(&text, 1, 0, 0), not_a_number)?;
+
+    client
+        .set(&large, u64::MAX.to_string().as_bytes(), 0, 0)
+        .map_err(|err| err.to_string())?;
+    expect("increment past u64::MAX", client.increment(&large, 2, 0, 0), Ok(1))?;
+    expect("increment_strict past u64::MAX", client.increment_strict(&large, u64::MAX), Ok(0))?;
+    Ok(())
+}
@@ -0,0 +1,61 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Checks to run after a workload
+
+/// Fails if more than `max_rate` of `ops` failed
+pub fn check_error_rate(errors: u64, ops: u64, max_rate: f64) -> Result<(), String> {
+    if ops == 0 {
+        return Err("no operations were run".to_owned());
+    }
+    let rate = errors as f64 / ops as f64;
+    if rate > max_rate {
+        return Err(format!("error rate {:.4} ({} of {}) above {:.4}", rate, errors, ops, max_rate));
+    }
+    Ok(())
+}
+
+/// Number of open file descriptors of this process, only known on Linux
+pub fn open_fds() -> Option<usize> {
+    if cfg!(target_os = "linux") {
+        std::fs::read_dir("/proc/self/fd").ok().map(|dir| dir.count())
+    } else {
+        None
+    }
+}
+
+/// Detects leaked sockets by comparing the descriptor count against a baseline
+pub struct FdWatch {
+    baseline: Option<usize>,
+}
+
+impl FdWatch {
+    /// Record the current number of descriptors
+    pub fn new() -> FdWatch {
+        FdWatch { baseline: open_fds() }
+    }
+
+    /// Fails if more than `slack` descriptors were opened since `new` and are still open
+    ///
+    /// Always passes where descriptors can not be counted.
+    pub fn check(&self, slack: usize) -> Result<(), String> {
+        match (self.baseline, open_fds()) {
+            (Some(baseline), Some(now)) if now > baseline + slack => {
+                Err(format!("{} file descriptors open, {} at start", now, baseline))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for FdWatch {
+    fn default() -> FdWatch {
+        FdWatch::new()
+    }
+}
@@ -0,0 +1,576 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! In-process memcached speaking the binary protocol
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use log::debug;
+
+use crate::proto::binarydef::{Command, DataType, RequestPacket, ResponsePacket, Status};
+use crate::proto::{MAX_KEY_LEN, NO_CREATE_EXPIRATION, RELATIVE_EXPIRATION_MAX};
+
+struct Item {
+    value: Vec<u8>,
+    flags: u32,
+    cas: u64,
+    expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Shared {
+    store: Mutex<HashMap<Vec<u8>, Item>>,
+    conns: Mutex<HashMap<u64, TcpStream>>,
+    next_conn: AtomicU64,
+    next_cas: AtomicU64,
+    running: AtomicBool,
+    clock_offset: Mutex<Duration>,
+    busy_stores: AtomicUsize,
+    /// Touch and get-and-touch requests received
+    touches: AtomicUsize,
+    /// Delay before answering each request
+    latency: Mutex<Duration>,
+    /// Overrides of `DEFAULT_SETTINGS`
+    settings: Mutex<BTreeMap<String, String>>,
+    /// PLAIN credentials new connections must authenticate with, see `MockServer::require_sasl`
+    sasl: Mutex<Option<Vec<u8>>>,
+}
+
+impl Shared {
+    /// Largest value stored, `ITEM_SIZE_MAX` unless set with `MockServer::set_setting`
+    fn item_size_max(&self) -> usize {
+        let settings = self.settings.lock().unwrap();
+        settings
+            .get("item_size_max")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(ITEM_SIZE_MAX)
+    }
+
+    /// The server's idea of the current time, ahead of the real one by `MockServer::advance_clock`
+    fn now(&self) -> Instant {
+        Instant::now() + *self.clock_offset.lock().unwrap()
+    }
+
+    /// `now` as a wall clock time, reported by stat as `time`
+    fn unix_now(&self) -> SystemTime {
+        SystemTime::now() + *self.clock_offset.lock().unwrap()
+    }
+}
+
+/// Largest value stored, like memcached's default `-I 1m`
+const ITEM_SIZE_MAX: usize = 1024 * 1024;
+
+/// A memcached that can be stopped and restarted on the same address
+///
+/// It understands the commands the `Client` sends for everyday operations: get (including quiet and
+/// key variants), set/add/replace, delete, incr/decr, append/prepend, touch, get-and-touch, flush,
+/// noop, version, stat (`pid`, `time`, `version`, the slab reassignment counters, and the
+/// `settings` group) and quit. Values over 1 MiB, or the `item_size_max` setting, are refused with
+/// `ValueTooLarge`, keys over
+/// `MAX_KEY_LEN` bytes with `InvalidArguments`. Stopping drops every open connection, restarting
+/// starts over with an empty cache just like a restarted memcached would.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Listen on `addr`, use port 0 to pick a free port
+    pub fn start(addr: &str) -> io::Result<MockServer> {
+        let listener = TcpListener::bind(addr)?;
+        let mut server = MockServer {
+            addr: listener.local_addr()?,
+            shared: Arc::new(Shared::default()),
+            acceptor: None,
+        };
+        server.serve(listener)?;
+        Ok(server)
+    }
+
+    /// Address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Address in the form `Client::connect` expects
+    pub fn url(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.acceptor.is_some()
+    }
+
+    /// Stop listening and close every connection
+    pub fn stop(&mut self) {
+        let acceptor = match self.acceptor.take() {
+            Some(acceptor) => acceptor,
+            None => return,
+        };
+        self.shared.running.store(false, Ordering::SeqCst);
+        let _ = acceptor.join();
+
+        for (_, conn) in self.shared.conns.lock().unwrap().drain() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+        debug!("Mock server {} stopped", self.addr);
+    }
+
+    /// Answer the next `stores` set, add and replace requests with `Busy` instead of storing them
+    pub fn busy_for(&self, stores: usize) {
+        self.shared.busy_stores.store(stores, Ordering::SeqCst);
+    }
+
+    /// Wait `latency` before answering each request, like a loaded or far away server
+    pub fn set_latency(&self, latency: Duration) {
+        *self.shared.latency.lock().unwrap() = latency;
+    }
+
+    /// Report `value` for `name` in the `settings` stats group
+    pub fn set_setting(&self, name: &str, value: &str) {
+        self.shared
+            .settings
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), value.to_owned());
+    }
+
+    /// Require new connections to authenticate with SASL PLAIN as `username` and `password`
+    ///
+    /// Like a memcached started with `-S`, an unauthenticated connection only gets answers to the
+    /// SASL commands and Version, every other request is answered with `AuthenticationError`.
+    pub fn require_sasl(&self, username: &str, password: &str) {
+        *self.shared.sasl.lock().unwrap() = Some(format!("\x00{}\x00{}", username, password).into_bytes());
+        self.set_setting("sasl", "yes");
+    }
+
+    /// Number of client connections open
+    pub fn connection_count(&self) -> usize {
+        self.shared.conns.lock().unwrap().len()
+    }
+
+    /// Number of touch and get-and-touch requests received
+    pub fn touch_count(&self) -> usize {
+        self.shared.touches.load(Ordering::SeqCst)
+    }
+
+    /// Number of items stored, including expired ones not read since
+    pub fn item_count(&self) -> usize {
+        self.shared.store.lock().unwrap().len()
+    }
+
+    /// Move the server's clock forward by `by`, expiring items as if that much time had passed
+    ///
+    /// The `time` stat and absolute expirations follow, so this also simulates a server clock
+    /// running ahead of the client's.
+    pub fn advance_clock(&self, by: Duration) {
+        *self.shared.clock_offset.lock().unwrap() += by;
+    }
+
+    /// Listen on the same address again with an empty cache
+    pub fn restart(&mut self) -> io::Result<()> {
+        self.stop();
+        self.shared.store.lock().unwrap().clear();
+        let listener = TcpListener::bind(self.addr)?;
+        self.serve(listener)
+    }
+
+    fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        self.shared.running.store(true, Ordering::SeqCst);
+
+        let shared = self.shared.clone();
+        self.acceptor = Some(thread::spawn(move || {
+            while shared.running.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let id = shared.next_conn.fetch_add(1, Ordering::SeqCst);
+                        let registered = stream
+                            .set_nonblocking(false)
+                            .and_then(|_| stream.try_clone())
+                            .map(|clone| shared.conns.lock().unwrap().insert(id, clone));
+                        if registered.is_err() {
+                            continue;
+                        }
+
+                        let shared = shared.clone();
+                        thread::spawn(move || {
+                            let _ = handle(&shared, stream);
+                            shared.conns.lock().unwrap().remove(&id);
+                        });
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(5)),
+                    Err(err) => {
+                        debug!("Mock server accept failed: {}", err);
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                }
+            }
+        }));
+        debug!("Mock server {} started", self.addr);
+        Ok(())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn handle(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let credentials = shared.sasl.lock().unwrap().clone();
+    let mut authenticated = credentials.is_none();
+
+    loop {
+        let req = RequestPacket::read_from(&mut reader)?;
+        let quit = matches!(req.header.command, Command::Quit | Command::QuitQuietly);
+        let latency = *shared.latency.lock().unwrap();
+        if latency > Duration::ZERO {
+            thread::sleep(latency);
+        }
+        if !authenticated {
+            let resp = match req.header.command {
+                Command::SaslListMechanisms => response(&req, Status::NoError, 0, Vec::new(), &[], b"PLAIN".to_vec()),
+                Command::SaslAuthenticate | Command::SaslStep
+                    if &req.key[..] == b"PLAIN" && Some(&req.value[..]) == credentials.as_deref() =>
+                {
+                    authenticated = true;
+                    response(&req, Status::NoError, 0, Vec::new(), &[], b"Authenticated".to_vec())
+                }
+                Command::Version => execute(shared, &req).expect("Version is answered"),
+                _ => response(&req, Status::AuthenticationError, 0, Vec::new(), &[], Vec::new()),
+            };
+            resp.write_to(&mut writer)?;
+        } else if req.header.command == Command::Stat {
+            for resp in stats(shared, &req) {
+                resp.write_to(&mut writer)?;
+            }
+        } else if let Some(resp) = execute(shared, &req) {
+            resp.write_to(&mut writer)?;
+        }
+        if quit {
+            return writer.flush();
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+fn response(
+    req: &RequestPacket,
+    status: Status,
+    cas: u64,
+    extra: Vec<u8>,
+    key: &[u8],
+    value: Vec<u8>,
+) -> ResponsePacket {
+    ResponsePacket::new(
+        req.header.command,
+        DataType::RawBytes,
+        status,
+        req.header.opaque,
+        cas,
+        extra.into(),
+        Bytes::copy_from_slice(key),
+        value.into(),
+    )
+}
+
+fn status(req: &RequestPacket, status: Status) -> Option<ResponsePacket> {
+    Some(response(req, status, 0, Vec::new(), &[], Vec::new()))
+}
+
+fn expires_at(shared: &Shared, expiration: u32) -> Option<Instant> {
+    match expiration {
+        0 => None,
+        at if at > RELATIVE_EXPIRATION_MAX => {
+            let at = UNIX_EPOCH + Duration::from_secs(at as u64);
+            // Already past on the server's clock: gone by the next read
+            Some(shared.now() + at.duration_since(shared.unix_now()).unwrap_or_default())
+        }
+        secs => Some(shared.now() + Duration::from_secs(secs as u64)),
+    }
+}
+
+/// One response per statistic, then an empty one closing the list
+/// `stats settings` of a memcached started without options, except for listening on localhost only
+const DEFAULT_SETTINGS: &[(&str, &str)] = &[
+    ("maxbytes", "67108864"),
+    ("maxconns", "1024"),
+    ("tcpport", "11211"),
+    ("inter", "127.0.0.1"),
+    ("evictions", "on"),
+    ("growth_factor", "1.25"),
+    ("cas_enabled", "yes"),
+    ("sasl", "no"),
+    ("item_size_max", "1048576"),
+];
+
+fn stats(shared: &Shared, req: &RequestPacket) -> Vec<ResponsePacket> {
+    let mut stats: BTreeMap<String, String> = BTreeMap::new();
+    match &req.key[..] {
+        b"" => {
+            let time = shared
+                .unix_now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            stats.insert("pid".to_owned(), std::process::id().to_string());
+            stats.insert("time".to_owned(), time.to_string());
+            stats.insert("version".to_owned(), "1.6.0".to_owned());
+            stats.insert("slab_reassign_running".to_owned(), "0".to_owned());
+            stats.insert("slabs_moved".to_owned(), "0".to_owned());
+        }
+        b"settings" => {
+            for (name, value) in DEFAULT_SETTINGS {
+                stats.insert(name.to_string(), value.to_string());
+            }
+            stats.extend(shared.settings.lock().unwrap().clone());
+        }
+        _ => {}
+    }
+    stats
+        .iter()
+        .chain(Some((&String::new(), &String::new())))
+        .map(|(name, value)| response(req, Status::NoError, 0, Vec::new(), name.as_bytes(), value.clone().into_bytes()))
+        .collect()
+}
+
+fn execute(shared: &Shared, req: &RequestPacket) -> Option<ResponsePacket> {
+    use self::Command::*;
+
+    let (command, quiet) = match req.header.command {
+        GetQuietly => (Get, true),
+        GetKeyQuietly => (GetKey, true),
+        SetQuietly => (Set, true),
+        AddQuietly => (Add, true),
+        ReplaceQuietly => (Replace, true),
+        DeleteQuietly => (Delete, true),
+        IncrementQuietly => (Increment, true),
+        DecrementQuietly => (Decrement, true),
+        AppendQuietly => (Append, true),
+        PrependQuietly => (Prepend, true),
+        FlushQuietly => (Flush, true),
+        QuitQuietly => (Quit, true),
+        command => (command, false),
+    };
+    if req.key.len() > MAX_KEY_LEN {
+        return status(req, Status::InvalidArguments);
+    }
+    if matches!(command, Set | Add | Replace)
+        && shared
+            .busy_stores
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1))
+            .is_ok()
+    {
+        return status(req, Status::Busy);
+    }
+    if matches!(command, Set | Add | Replace | Append | Prepend) && req.value.len() > shared.item_size_max() {
+        return status(req, Status::ValueTooLarge);
+    }
+
+    let key = &req.key[..];
+    let req_cas = req.header.cas;
+    let next_cas = || shared.next_cas.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let mut store = shared.store.lock().unwrap();
+    if store
+        .get(key)
+        .and_then(|item| item.expires)
+        .is_some_and(|at| at <= shared.now())
+    {
+        store.remove(key);
+    }
+
+    let resp = match command {
+        Get | GetKey => match store.get(key) {
+            Some(item) => {
+                let key = if command == GetKey { key } else { &[] };
+                Some(response(
+                    req,
+                    Status::NoError,
+                    item.cas,
+                    item.flags.to_be_bytes().to_vec(),
+                    key,
+                    item.value.clone(),
+                ))
+            }
+            None if quiet => return None,
+            None => status(req, Status::KeyNotFound),
+        },
+        Set | Add | Replace => {
+            if req.extra.len() != 8 {
+                return status(req, Status::InvalidArguments);
+            }
+            let flags = BigEndian::read_u32(&req.extra[0..4]);
+            let expiration = BigEndian::read_u32(&req.extra[4..8]);
+            let current = store.get(key).map(|item| item.cas);
+            match (command, current) {
+                (Add, Some(_)) => return status(req, Status::KeyExists),
+                (Replace, None) => return status(req, Status::KeyNotFound),
+                (_, None) if req_cas != 0 => return status(req, Status::KeyNotFound),
+                (_, Some(cas)) if req_cas != 0 && req_cas != cas => return status(req, Status::KeyExists),
+                _ => {}
+            }
+            let cas = next_cas();
+            store.insert(
+                key.to_vec(),
+                Item {
+                    value: req.value.to_vec(),
+                    flags,
+                    cas,
+                    expires: expires_at(shared, expiration),
+                },
+            );
+            Some(response(req, Status::NoError, cas, Vec::new(), &[], Vec::new()))
+        }
+        Delete => match store.get(key).map(|item| item.cas) {
+            None => status(req, Status::KeyNotFound),
+            Some(cas) if req_cas != 0 && req_cas != cas => status(req, Status::KeyExists),
+            Some(_) => {
+                store.remove(key);
+                status(req, Status::NoError)
+            }
+        },
+        Increment | Decrement => {
+            if req.extra.len() != 20 {
+                return status(req, Status::InvalidArguments);
+            }
+            let amount = BigEndian::read_u64(&req.extra[0..8]);
+            let initial = BigEndian::read_u64(&req.extra[8..16]);
+            let expiration = BigEndian::read_u32(&req.extra[16..20]);
+            let number = match store.get_mut(key) {
+                Some(item) if req_cas != 0 && req_cas != item.cas => return status(req, Status::KeyExists),
+                Some(item) => {
+                    let current = match std::str::from_utf8(&item.value)
+                        .ok()
+                        .and_then(|s| s.parse::<u64>().ok())
+                    {
+                        Some(current) => current,
+                        None => return status(req, Status::IncrDecrOnNonNumericValue),
+                    };
+                    let number = if command == Increment {
+                        current.wrapping_add(amount)
+                    } else {
+                        current.saturating_sub(amount)
+                    };
+                    item.value = number.to_string().into_bytes();
+                    item.cas = next_cas();
+                    number
+                }
+                None if expiration == NO_CREATE_EXPIRATION => return status(req, Status::KeyNotFound),
+                None => {
+                    store.insert(
+                        key.to_vec(),
+                        Item {
+                            value: initial.to_string().into_bytes(),
+                            flags: 0,
+                            cas: next_cas(),
+                            expires: expires_at(shared, expiration),
+                        },
+                    );
+                    initial
+                }
+            };
+            let cas = store[key].cas;
+            Some(response(req, Status::NoError, cas, Vec::new(), &[], number.to_be_bytes().to_vec()))
+        }
+        Append | Prepend => match store.get_mut(key) {
+            None => status(req, Status::ItemNotStored),
+            Some(item) if req_cas != 0 && req_cas != item.cas => status(req, Status::KeyExists),
+            Some(item) => {
+                if command == Append {
+                    item.value.extend_from_slice(&req.value);
+                } else {
+                    item.value.splice(0..0, req.value.iter().cloned());
+                }
+                item.cas = next_cas();
+                Some(response(req, Status::NoError, item.cas, Vec::new(), &[], Vec::new()))
+            }
+        },
+        Touch | GetAndTouch => {
+            shared.touches.fetch_add(1, Ordering::SeqCst);
+            match store.get_mut(key) {
+                None => status(req, Status::KeyNotFound),
+                Some(_) if req.extra.len() != 4 => status(req, Status::InvalidArguments),
+                Some(item) => {
+                    item.expires = expires_at(shared, BigEndian::read_u32(&req.extra));
+                    if command == GetAndTouch {
+                        let flags = item.flags.to_be_bytes().to_vec();
+                        Some(response(req, Status::NoError, item.cas, flags, &[], item.value.clone()))
+                    } else {
+                        Some(response(req, Status::NoError, item.cas, Vec::new(), &[], Vec::new()))
+                    }
+                }
+            }
+        }
+        Flush => {
+            store.clear();
+            status(req, Status::NoError)
+        }
+        Noop | Quit => status(req, Status::NoError),
+        Version => Some(response(req, Status::NoError, 0, Vec::new(), &[], b"1.6.0".to_vec())),
+        _ => return status(req, Status::UnknownCommand),
+    };
+
+    match resp {
+        // Quiet gets are only quiet about misses, the other quiet commands about success
+        Some(ref resp) if quiet && command != Get && command != GetKey && resp.header.status == Status::NoError => None,
+        resp => resp,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MockServer;
+    use crate::client::Client;
+    use crate::proto::{MultiOperation, ProtoType};
+
+    #[test]
+    fn test_mock_server_restart() {
+        let mut server = MockServer::start("127.0.0.1:0").unwrap();
+        let url = server.url();
+
+        let mut client = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        client.set(b"key", b"value", 0xcafe, 60).unwrap();
+        assert_eq!(client.get(b"key").unwrap(), (b"value".to_vec(), 0xcafe));
+        client.add(b"key", b"value", 0, 60).unwrap_err();
+        assert_eq!(client.increment(b"counter", 2, 40, 60).unwrap(), 40);
+        assert_eq!(client.increment(b"counter", 2, 40, 60).unwrap(), 42);
+        client.append(b"key", b"!").unwrap();
+        let (value, _, cas) = client.get_cas(b"key").unwrap();
+        assert_eq!(value, b"value!".to_vec());
+        client.set_cas(b"key", b"other", 0, 60, cas + 1).unwrap_err();
+        let found = client.get_multi(&[b"key", b"missing"]).unwrap();
+        assert_eq!(found.len(), 1);
+
+        server.stop();
+        assert!(server.shared.conns.lock().unwrap().is_empty());
+        client.get(b"key").unwrap_err();
+        assert!(Client::connect(&[(&url[..], 1)], ProtoType::Binary).is_err());
+
+        server.restart().unwrap();
+        let mut client = Client::connect(&[(&url[..], 1)], ProtoType::Binary).unwrap();
+        client.get(b"key").unwrap_err();
+        client.set(b"key", b"value", 0, 60).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Helpers for testing applications and the crate itself against flaky servers
+//!
+//! Only available with the `test-support` feature.
+
+pub use self::chaos::{Chaos, ChaosConfig};
+pub use self::conformance::check_arithmetic;
+pub use self::invariant::{check_error_rate, open_fds, FdWatch};
+pub use self::mock::MockServer;
+pub use self::transcript::{Direction, RecordingStream, ReplayProto, ReplayStream, Transcript};
+
+mod chaos;
+mod conformance;
+mod invariant;
+mod mock;
+mod transcript;
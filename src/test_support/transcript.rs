@@ -0,0 +1,472 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Recording the bytes of a connection and replaying them without a server
+//!
+//! Wrap the stream of a `BinaryProto` in a `RecordingStream` to capture a session, save the
+//! `Transcript`, and later run the same operations on a `ReplayStream` built from it. A stream
+//! made with `RecordingStream::to_file` writes the transcript out as it goes instead.
+//!
+//! ```no_run
+//! use std::net::TcpStream;
+//!
+//! use bufstream::BufStream;
+//! use memcached::proto::{BinaryProto, Operation};
+//! use memcached::test_support::{RecordingStream, ReplayStream, Transcript};
+//!
+//! let stream = BufStream::new(TcpStream::connect("127.0.0.1:11211").unwrap());
+//! let recording = RecordingStream::new(stream);
+//! let transcript = recording.transcript();
+//! let mut client = BinaryProto::new(recording);
+//! client.set(b"key", b"value", 0, 0).unwrap();
+//! transcript.lock().unwrap().save("set.mcrt").unwrap();
+//!
+//! let replay = ReplayStream::new(Transcript::load("set.mcrt").unwrap()).normalize_opaques();
+//! let mut client = BinaryProto::new(replay);
+//! client.set(b"key", b"value", 0, 0).unwrap();
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder};
+use log::debug;
+
+use crate::proto::BinaryProto;
+
+/// First bytes of a saved transcript
+const MAGIC: &[u8] = b"MCRT1\n";
+
+const HEADER_LEN: usize = 24;
+const BODY_LEN: Range<usize> = 8..12;
+const OPAQUE: Range<usize> = 12..16;
+
+/// Which side of the connection wrote a chunk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Written by the client
+    Sent,
+    /// Read by the client
+    Received,
+}
+
+impl Direction {
+    fn tag(self) -> u8 {
+        match self {
+            Direction::Sent => b'>',
+            Direction::Received => b'<',
+        }
+    }
+}
+
+/// The bytes a client sent and received, in order
+///
+/// Consecutive bytes in the same direction are kept in one chunk, so the transcript does not
+/// depend on how the client split its reads and writes. Saved as `MAGIC`, then for each chunk a
+/// `>` (sent) or `<` (received) byte, a big endian `u32` length and the bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub chunks: Vec<(Direction, Vec<u8>)>,
+}
+
+impl Transcript {
+    fn push(&mut self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.chunks.last_mut() {
+            Some((last, chunk)) if *last == direction => chunk.extend_from_slice(bytes),
+            _ => self.chunks.push((direction, bytes.to_vec())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        for (direction, chunk) in &self.chunks {
+            write_chunk(&mut buf, *direction, chunk).expect("writing to a Vec cannot fail");
+        }
+        buf
+    }
+
+    pub fn from_bytes(mut buf: &[u8]) -> io::Result<Transcript> {
+        let invalid = |detail: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad transcript: {}", detail));
+        if !buf.starts_with(MAGIC) {
+            return Err(invalid("missing header"));
+        }
+        buf = &buf[MAGIC.len()..];
+
+        let mut transcript = Transcript::default();
+        while !buf.is_empty() {
+            if buf.len() < 5 {
+                return Err(invalid("truncated chunk header"));
+            }
+            let direction = match buf[0] {
+                b'>' => Direction::Sent,
+                b'<' => Direction::Received,
+                _ => return Err(invalid("unknown direction")),
+            };
+            let len = BigEndian::read_u32(&buf[1..5]) as usize;
+            buf = &buf[5..];
+            if buf.len() < len {
+                return Err(invalid("truncated chunk"));
+            }
+            transcript.push(direction, &buf[..len]);
+            buf = &buf[len..];
+        }
+        Ok(transcript)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Transcript> {
+        Transcript::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// Append one chunk in the saved transcript format
+fn write_chunk<W: Write>(mut writer: W, direction: Direction, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(&[direction.tag()])?;
+    writer.write_all(&(chunk.len() as u32).to_be_bytes())?;
+    writer.write_all(chunk)
+}
+
+/// A stream that copies everything written to it and read from it into a `Transcript`
+pub struct RecordingStream<T> {
+    inner: T,
+    transcript: Arc<Mutex<Transcript>>,
+    /// Where each chunk is appended as soon as it is recorded, see `to_file`
+    log: Option<File>,
+}
+
+impl<T: BufRead + Write> RecordingStream<T> {
+    pub fn new(inner: T) -> RecordingStream<T> {
+        RecordingStream {
+            inner,
+            transcript: Arc::new(Mutex::new(Transcript::default())),
+            log: None,
+        }
+    }
+
+    /// Like `new`, also writing the transcript to `path` as it is recorded
+    ///
+    /// Each read and write is appended right away, so the file holds everything up to the last
+    /// byte exchanged even if the process dies before it could `save`. `Transcript::load` reads it
+    /// like a saved transcript.
+    pub fn to_file<P: AsRef<Path>>(inner: T, path: P) -> io::Result<RecordingStream<T>> {
+        let mut log = File::create(path)?;
+        log.write_all(MAGIC)?;
+        Ok(RecordingStream {
+            log: Some(log),
+            ..RecordingStream::new(inner)
+        })
+    }
+
+    /// The transcript being recorded, still readable after the stream moved into a client
+    pub fn transcript(&self) -> Arc<Mutex<Transcript>> {
+        self.transcript.clone()
+    }
+}
+
+/// Add `bytes` to `transcript`, and to `log` if there is one
+///
+/// A failing log only loses the recording, the connection goes on.
+fn record(transcript: &Mutex<Transcript>, log: Option<&File>, direction: Direction, bytes: &[u8]) {
+    transcript.lock().unwrap().push(direction, bytes);
+    if let (Some(log), false) = (log, bytes.is_empty()) {
+        if let Err(err) = write_chunk(log, direction, bytes) {
+            debug!("Failed to log {} recorded bytes: {}", bytes.len(), err);
+        }
+    }
+}
+
+impl<T: BufRead + Write> Read for RecordingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        record(&self.transcript, self.log.as_ref(), Direction::Received, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: BufRead + Write> BufRead for RecordingStream<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The bytes are still buffered from the `fill_buf` that came before
+        if let Ok(buf) = self.inner.fill_buf() {
+            let consumed = &buf[..amt.min(buf.len())];
+            record(&self.transcript, self.log.as_ref(), Direction::Received, consumed);
+        }
+        self.inner.consume(amt)
+    }
+}
+
+impl<T: BufRead + Write> Write for RecordingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        record(&self.transcript, self.log.as_ref(), Direction::Sent, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Offsets of the opaque field of every binary protocol packet in `chunk`
+fn opaque_offsets(chunk: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut at = 0;
+    while at + HEADER_LEN <= chunk.len() {
+        offsets.push(at + OPAQUE.start);
+        at += HEADER_LEN + BigEndian::read_u32(&chunk[at + BODY_LEN.start..at + BODY_LEN.end]) as usize;
+    }
+    offsets
+}
+
+/// A `BinaryProto` answered from a transcript instead of a server
+pub type ReplayProto = BinaryProto<ReplayStream>;
+
+/// A stream playing the server side of a `Transcript`
+///
+/// Panics when the client writes anything other than the recorded requests, or reads before it
+/// sent them. Reads past the end of the transcript see the connection closed.
+pub struct ReplayStream {
+    chunks: VecDeque<(Direction, Vec<u8>)>,
+    /// Position in the first chunk
+    at: usize,
+    normalize_opaques: bool,
+    /// Opaques of the recorded requests, by offset in the first chunk while it is a sent one
+    recorded_opaques: Vec<usize>,
+    /// What the client sent of the first chunk while it is a sent one
+    sent: Vec<u8>,
+    /// Opaque the client used for each recorded one
+    opaques: HashMap<u32, u32>,
+}
+
+impl ReplayStream {
+    pub fn new(transcript: Transcript) -> ReplayStream {
+        let mut replay = ReplayStream {
+            chunks: transcript.chunks.into(),
+            at: 0,
+            normalize_opaques: false,
+            recorded_opaques: Vec::new(),
+            sent: Vec::new(),
+            opaques: HashMap::new(),
+        };
+        replay.start_chunk();
+        replay
+    }
+
+    /// Accept any opaque in the requests, and answer with the opaques the client used
+    ///
+    /// Opaques are random, or with the `fastrand` feature off count up per connection, so replays
+    /// of transcripts recorded by another client need this.
+    pub fn normalize_opaques(mut self) -> ReplayStream {
+        self.normalize_opaques = true;
+        self.start_chunk();
+        self
+    }
+
+    /// Whether the client sent and read the whole transcript
+    pub fn is_done(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    fn start_chunk(&mut self) {
+        self.at = 0;
+        self.recorded_opaques.clear();
+        self.sent.clear();
+        if !self.normalize_opaques {
+            return;
+        }
+        match self.chunks.front_mut() {
+            Some((Direction::Sent, chunk)) => self.recorded_opaques = opaque_offsets(chunk),
+            Some((Direction::Received, chunk)) => {
+                for offset in opaque_offsets(chunk) {
+                    let opaque = &mut chunk[offset..offset + 4];
+                    if let Some(&live) = self.opaques.get(&BigEndian::read_u32(opaque)) {
+                        BigEndian::write_u32(opaque, live);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn next_chunk(&mut self) {
+        self.chunks.pop_front();
+        self.start_chunk();
+    }
+
+    fn is_opaque(&self, at: usize) -> bool {
+        self.recorded_opaques
+            .iter()
+            .any(|&offset| (offset..offset + 4).contains(&at))
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let expected = match self.chunks.front() {
+                Some((Direction::Sent, chunk)) => &chunk[self.at..],
+                Some((Direction::Received, chunk)) => panic!(
+                    "client sent {:?} while {} recorded response bytes were not read",
+                    &buf[written..],
+                    chunk.len() - self.at
+                ),
+                None => panic!("client sent {:?} after the end of the transcript", &buf[written..]),
+            };
+            let n = expected.len().min(buf.len() - written);
+            let live = &buf[written..written + n];
+            if (0..n).any(|i| expected[i] != live[i] && !self.is_opaque(self.at + i)) {
+                panic!("client sent {:?}, recorded request was {:?}", live, &expected[..n]);
+            }
+            self.sent.extend_from_slice(live);
+            self.at += n;
+            written += n;
+
+            if self.at == self.chunks[0].1.len() {
+                let chunk = &self.chunks[0].1;
+                for &offset in &self.recorded_opaques {
+                    let opaque = offset..offset + 4;
+                    self.opaques
+                        .insert(BigEndian::read_u32(&chunk[opaque.clone()]), BigEndian::read_u32(&self.sent[opaque]));
+                }
+                self.next_chunk();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ReplayStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self.chunks.front() {
+            Some((Direction::Received, chunk)) => Ok(&chunk[self.at..]),
+            Some((Direction::Sent, chunk)) => panic!(
+                "client waits for a response, {} bytes of the recorded request were not sent",
+                chunk.len() - self.at
+            ),
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.at += amt;
+        if matches!(self.chunks.front(), Some((Direction::Received, chunk)) if self.at >= chunk.len()) {
+            self.next_chunk();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpStream;
+
+    use bufstream::BufStream;
+
+    use super::{Direction, RecordingStream, ReplayProto, ReplayStream, Transcript};
+    use crate::proto::binary::Status;
+    use crate::proto::{BinaryProto, Operation};
+    use crate::test_support::MockServer;
+
+    fn record_set(value: &[u8]) -> Transcript {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let recording = RecordingStream::new(BufStream::new(TcpStream::connect(mock.addr()).unwrap()));
+        let transcript = recording.transcript();
+        let mut client = BinaryProto::new(recording);
+        client.set(b"test:transcript", value, 1, 0).unwrap();
+        assert_eq!(client.get(b"test:transcript").unwrap(), (value.to_vec(), 1));
+        let transcript = transcript.lock().unwrap().clone();
+        transcript
+    }
+
+    #[test]
+    fn test_transcript_round_trip() {
+        let transcript = record_set(b"value");
+        let directions: Vec<Direction> = transcript.chunks.iter().map(|&(direction, _)| direction).collect();
+        assert_eq!(
+            directions,
+            [
+                Direction::Sent,
+                Direction::Received,
+                Direction::Sent,
+                Direction::Received
+            ]
+        );
+        assert_eq!(Transcript::from_bytes(&transcript.to_bytes()).unwrap(), transcript);
+        assert!(Transcript::from_bytes(b"MCRT1\n>\x00\x00\x00\x09short").is_err());
+
+        // Opaques are random, so the requests only match once normalized
+        let mut client = BinaryProto::new(ReplayStream::new(transcript).normalize_opaques());
+        client.set(b"test:transcript", b"value", 1, 0).unwrap();
+        assert_eq!(client.get(b"test:transcript").unwrap(), (b"value".to_vec(), 1));
+    }
+
+    #[test]
+    fn test_record_to_file() {
+        let path = std::env::temp_dir().join(format!("memcached-rs-{}.mcrt", fastrand::u64(..)));
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let stream = BufStream::new(TcpStream::connect(mock.addr()).unwrap());
+        let recording = RecordingStream::to_file(stream, &path).unwrap();
+        let transcript = recording.transcript();
+        let mut client = BinaryProto::new(recording);
+        client.set(b"test:transcript_file", b"value", 3, 0).unwrap();
+        let recorded = client.get(b"test:transcript_file").unwrap();
+        assert!(client.get(b"test:transcript_missing").is_err());
+
+        // Already complete on disk while the connection is still open
+        let logged = Transcript::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(logged, *transcript.lock().unwrap());
+
+        let mut replay: ReplayProto = BinaryProto::new(ReplayStream::new(logged).normalize_opaques());
+        replay.set(b"test:transcript_file", b"value", 3, 0).unwrap();
+        assert_eq!(replay.get(b"test:transcript_file").unwrap(), recorded);
+        match replay.get(b"test:transcript_missing") {
+            Err(err) => assert_eq!(err.status(), Some(Status::KeyNotFound)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "recorded request was")]
+    fn test_replay_rejects_other_requests() {
+        let transcript = record_set(b"value");
+        let mut client = BinaryProto::new(ReplayStream::new(transcript).normalize_opaques());
+        let _ = client.set(b"test:transcript", b"other", 1, 0);
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>,
+// at your option. All files in the project carrying such
+// notice may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Warming a cache from a dump file
+//!
+//! A dump has one record per line, four fields separated by tabs: `key`, `flags`, `expiration`
+//! and `value`.
+//!
+//! `flags` is decimal, `expiration` is an absolute unix time in seconds or 0 for items that never
+//! expire, and `value` is base64 with the standard alphabet. Empty lines and lines starting with
+//! `#` are ignored.
+
+use std::convert::TryFrom;
+use std::io::BufRead;
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::Client;
+use crate::proto::{self, MemCachedResult, MultiOperation};
+
+/// Records stored per pipelined batch by default
+pub const DEFAULT_WARMUP_BATCH_SIZE: usize = 100;
+
+/// Settings of `warm_from_reader`
+pub struct WarmupOpts {
+    /// Records stored per pipelined batch
+    pub batch_size: usize,
+    /// Records stored per second at most, unthrottled for `None`
+    pub ops_per_sec: Option<u32>,
+    /// Records expiring before this time are skipped, e.g. `SystemTime::now()` to leave out those
+    /// that expired since the dump was taken
+    pub cutoff: Option<SystemTime>,
+    /// Called with the report so far after each batch, e.g. to draw a progress bar
+    pub progress: Option<Box<dyn FnMut(&WarmupReport)>>,
+}
+
+impl Default for WarmupOpts {
+    fn default() -> WarmupOpts {
+        WarmupOpts {
+            batch_size: DEFAULT_WARMUP_BATCH_SIZE,
+            ops_per_sec: None,
+            cutoff: None,
+            progress: None,
+        }
+    }
+}
+
+/// Outcome of `warm_from_reader`
+#[derive(Debug, Default)]
+pub struct WarmupReport {
+    /// Records stored
+    pub loaded: u64,
+    /// Records left out by `WarmupOpts::cutoff`
+    pub skipped: u64,
+    /// Records that were malformed or could not be stored, with the error
+    ///
+    /// A malformed record is reported with its first field as the key.
+    pub failed: Vec<(Vec<u8>, proto::Error)>,
+}
+
+struct Record {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    flags: u32,
+    expiration: u64,
+}
+
+/// Store every record of the dump read from `reader`, see the module documentation for its format
+///
+/// Records are stored with `set_cas_multi`, `opts.batch_size` at a time, without a CAS check.
+/// Expirations go through `Client::expiration_at`, so they follow the clock of each server when
+/// `ClientBuilder::server_clock` is set. A batch that fails as a whole, e.g. because its server is
+/// down, reports each of its records as failed and the warm-up goes on. Only errors reading
+/// `reader` stop it.
+pub fn warm_from_reader<R: BufRead>(
+    client: &mut Client,
+    mut reader: R,
+    mut opts: WarmupOpts,
+) -> MemCachedResult<WarmupReport> {
+    assert!(opts.batch_size > 0, "warm-up batch size should not be 0");
+
+    let cutoff = opts.cutoff.map(|cutoff| match cutoff.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs(),
+        Err(_) => 0,
+    });
+    let mut report = WarmupReport::default();
+    let mut batch = Vec::with_capacity(opts.batch_size);
+    let mut line = Vec::new();
+    let mut line_no = 0;
+
+    loop {
+        line.clear();
+        let eof = reader.read_until(b'\n', &mut line)? == 0;
+        if !eof {
+            line_no += 1;
+            match parse_record(&line) {
+                Ok(None) => {}
+                Ok(Some(record)) => match cutoff {
+                    Some(cutoff) if record.expiration != 0 && record.expiration < cutoff => report.skipped += 1,
+                    _ => batch.push(record),
+                },
+                Err((key, reason)) => report.failed.push((
+                    key,
+                    proto::Error::OtherError {
+                        desc: "Malformed warm-up record",
+                        detail: Some(format!("line {}: {}", line_no, reason)),
+                    },
+                )),
+            }
+        }
+
+        if batch.len() == opts.batch_size || (eof && !batch.is_empty()) {
+            let started = Instant::now();
+            let sent = batch.len();
+            store_batch(client, batch.drain(..), &mut report);
+            if let Some(progress) = opts.progress.as_mut() {
+                progress(&report);
+            }
+            if let Some(rate) = opts.ops_per_sec {
+                let due = Duration::from_secs_f64(sent as f64 / f64::from(rate.max(1)));
+                client.sleep(due.saturating_sub(started.elapsed()));
+            }
+        }
+        if eof {
+            return Ok(report);
+        }
+    }
+}
+
+fn store_batch<I: Iterator<Item = Record>>(client: &mut Client, records: I, report: &mut WarmupReport) {
+    let mut items = Vec::new();
+    for record in records {
+        let expiration = match record.expiration {
+            0 => Ok(0),
+            at => client.expiration_at(&record.key, UNIX_EPOCH + Duration::from_secs(at)),
+        };
+        match expiration {
+            Ok(expiration) => items.push((record.key, record.value, record.flags, expiration)),
+            Err(err) => report.failed.push((record.key, err)),
+        }
+    }
+    if items.is_empty() {
+        return;
+    }
+
+    let requests: Vec<_> = items
+        .iter()
+        .map(|(key, value, flags, expiration)| (&key[..], &value[..], *flags, *expiration, 0))
+        .collect();
+    match client.set_cas_multi(&requests) {
+        Ok(results) => {
+            for ((key, ..), result) in items.into_iter().zip(results) {
+                match result {
+                    Ok(_) => report.loaded += 1,
+                    Err(err) => report.failed.push((key, err)),
+                }
+            }
+        }
+        Err(err) => {
+            let detail = err.to_string();
+            for (key, ..) in items {
+                report.failed.push((
+                    key,
+                    proto::Error::OtherError {
+                        desc: "Warm-up batch failed",
+                        detail: Some(detail.clone()),
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// The record on `line`, `None` for comments and empty lines, or the first field and what is wrong
+fn parse_record(line: &[u8]) -> Result<Option<Record>, (Vec<u8>, String)> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() || line[0] == b'#' {
+        return Ok(None);
+    }
+
+    let fields: Vec<&[u8]> = line.split(|&b| b == b'\t').collect();
+    let key = fields[0].to_vec();
+    if fields.len() != 4 {
+        return Err((key, format!("expected 4 fields, found {}", fields.len())));
+    }
+    let number = |field: &[u8], name: &str| {
+        str::from_utf8(field)
+            .ok()
+            .and_then(|field| field.parse::<u64>().ok())
+            .ok_or_else(|| format!("{} is not a number", name))
+    };
+    let flags = number(fields[1], "flags")
+        .and_then(|flags| u32::try_from(flags).map_err(|_| "flags do not fit in 32 bits".to_owned()));
+    let parsed = flags.and_then(|flags| {
+        let expiration = number(fields[2], "expiration")?;
+        let value = decode_base64(fields[3]).ok_or_else(|| "value is not valid base64".to_owned())?;
+        Ok((flags, expiration, value))
+    });
+    match parsed {
+        Ok((flags, expiration, value)) => Ok(Some(Record {
+            key,
+            value,
+            flags,
+            expiration,
+        })),
+        Err(reason) => Err((key, reason)),
+    }
+}
+
+/// Decode standard base64, with or without padding
+fn decode_base64(input: &[u8]) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = match input.iter().position(|&c| c == b'=') {
+        Some(pad) if input.len() % 4 == 0 && input.len() - pad <= 2 && input[pad..].iter().all(|&c| c == b'=') => {
+            &input[..pad]
+        }
+        Some(_) => return None,
+        None => input,
+    };
+    if input.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        let mut bits = 0;
+        for &c in chunk {
+            bits = (bits << 6) | sextet(c)?;
+        }
+        bits <<= 6 * (4 - chunk.len());
+        output.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{decode_base64, warm_from_reader, WarmupOpts, WarmupReport};
+    use crate::client::{Client, Clock};
+    use crate::proto::ProtoType;
+    use crate::test_support::MockServer;
+
+    /// Adds up the pauses instead of sleeping
+    struct Skipping(Rc<Cell<Duration>>);
+
+    impl Clock for Skipping {
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn client(mock: &MockServer, slept: &Rc<Cell<Duration>>) -> Client {
+        Client::builder(ProtoType::Binary)
+            .add_server(mock.url(), 1)
+            .clock(Skipping(slept.clone()))
+            .build()
+            .unwrap()
+    }
+
+    fn dump(count: usize) -> String {
+        (0..count)
+            .map(|i| format!("test:warmup_{}\t{}\t0\tdmFsdWU=\n", i, i))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64(b"").unwrap(), b"");
+        assert_eq!(decode_base64(b"Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64(b"Zm8").unwrap(), b"fo");
+        assert_eq!(decode_base64(b"Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64(b"Zm9vYmE=").unwrap(), b"fooba");
+        assert_eq!(decode_base64(b"/+8=").unwrap(), [0xff, 0xef]);
+        assert!(decode_base64(b"Zm9vY").is_none());
+        assert!(decode_base64(b"Zm=v").is_none());
+        assert!(decode_base64(b"Zm9v!A==").is_none());
+    }
+
+    #[test]
+    fn test_warm_from_reader() {
+        let mock = MockServer::start("127.0.0.1:0").unwrap();
+        let slept = Rc::new(Cell::new(Duration::ZERO));
+        let mut client = client(&mock, &slept);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let input = format!(
+            "# exported for the warm-up test\n\
+             test:warmup_fresh\t7\t{}\taGVsbG8=\r\n\
+             \n\
+             test:warmup_stale\t0\t{}\taGVsbG8=\n\
+             test:warmup_broken\t0\taGVsbG8=\n\
+             test:warmup_flags\tx\t0\taGVsbG8=\n\
+             test:warmup_value\t0\t0\t!!\n\
+             {}",
+            now + 3600,
+            now - 60,
+            dump(5)
+        );
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let calls = progress.clone();
+        let report = warm_from_reader(
+            &mut client,
+            input.as_bytes(),
+            WarmupOpts {
+                batch_size: 2,
+                ops_per_sec: Some(100),
+                cutoff: Some(SystemTime::now()),
+                progress: Some(Box::new(move |report: &WarmupReport| {
+                    calls
+                        .borrow_mut()
+                        .push((report.loaded, report.skipped, report.failed.len()))
+                })),
+            },
+        )
+        .unwrap();
+
+        assert_eq!((report.loaded, report.skipped), (6, 1));
+        let failed: Vec<_> = report.failed.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(
+            failed,
+            [
+                &b"test:warmup_broken"[..],
+                &b"test:warmup_flags"[..],
+                &b"test:warmup_value"[..]
+            ]
+        );
+        assert_eq!(report.failed[0].1.to_string(), "Malformed warm-up record (line 5: expected 4 fields, found 3)");
+        assert_eq!(*progress.borrow(), [(2, 1, 3), (4, 1, 3), (6, 1, 3)]);
+        assert_eq!(client.get(b"test:warmup_fresh").unwrap(), (b"hello".to_vec(), 7));
+        assert_eq!(client.get(b"test:warmup_4").unwrap(), (b"value".to_vec(), 4));
+        assert!(client.get_opt(b"test:warmup_stale").unwrap().is_none());
+
+        // Three batches of two at 100 records per second
+        let slept = slept.get();
+        assert!(slept > Duration::from_millis(50) && slept <= Duration::from_millis(60), "{:?}", slept);
+    }
+
+    #[test]
+    fn test_warm_from_reader_failures() {
+        let mock = Rc::new(RefCell::new(MockServer::start("127.0.0.1:0").unwrap()));
+        let slept = Rc::new(Cell::new(Duration::ZERO));
+        let mut client = client(&mock.borrow(), &slept);
+
+        // A busy server refuses single records
+        mock.borrow().busy_for(2);
+        let report = warm_from_reader(
+            &mut client,
+            dump(5).as_bytes(),
+            WarmupOpts {
+                batch_size: 4,
+                ..WarmupOpts::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.loaded, 3);
+        assert_eq!(report.failed.len(), 2);
+        assert!(report.failed.iter().all(|(_, err)| err.to_string().contains("busy")));
+        assert_eq!(slept.get(), Duration::ZERO);
+
+        // The server goes away after the first batch, the other batches fail as a whole
+        let stopping = mock.clone();
+        let report = warm_from_reader(
+            &mut client,
+            dump(10).as_bytes(),
+            WarmupOpts {
+                batch_size: 4,
+                progress: Some(Box::new(move |_: &WarmupReport| stopping.borrow_mut().stop())),
+                ..WarmupOpts::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.loaded, 4);
+        let failed: Vec<_> = report.failed.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(
+            failed,
+            (4..10)
+                .map(|i| format!("test:warmup_{}", i).into_bytes())
+                .collect::<Vec<_>>()
+        );
+        assert!(report
+            .failed
+            .iter()
+            .all(|(_, err)| err.to_string().starts_with("Warm-up batch failed")));
+    }
+}